@@ -0,0 +1,153 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+/// A saved server the auth dialog can offer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ServerProfile {
+    pub name: String,
+    /// Host or IP as typed in the dialog; the port is added when connecting.
+    pub address: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_username: Option<String>,
+    /// Hex SHA-256 of the server certificate, pinned on first successful connect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cert_pin: Option<String>,
+}
+
+/// Contents of `servers.toml`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ClientConfig {
+    /// Name of the profile to preselect.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub last_used: Option<String>,
+    #[serde(default, rename = "server")]
+    pub servers: Vec<ServerProfile>,
+}
+
+impl ClientConfig {
+    /// `$XDG_CONFIG_HOME/darkrelay/servers.toml`, falling back to `~/.config`.
+    pub fn default_path() -> Option<PathBuf> {
+        let base = env::var_os("XDG_CONFIG_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))?;
+        Some(base.join("darkrelay").join("servers.toml"))
+    }
+
+    pub fn parse(text: &str) -> Result<Self, String> {
+        toml::from_str(text).map_err(|e| e.to_string())
+    }
+
+    /// A missing file is an empty config.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        match fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        let text = toml::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, text)
+    }
+
+    /// Index of the profile to preselect in the dialog.
+    pub fn last_used_index(&self) -> Option<usize> {
+        let name = self.last_used.as_deref()?;
+        self.servers.iter().position(|p| p.name == name)
+    }
+
+    pub fn profile_for(&self, address: &str) -> Option<&ServerProfile> {
+        self.servers.iter().find(|p| p.address == address)
+    }
+
+    /// Record a successful connect: update the profile for `address` (adding
+    /// one named after the address if needed) and mark it last used. An
+    /// existing pin is kept; `cert_pin` only fills an empty one.
+    pub fn remember(&mut self, address: &str, username: Option<&str>, cert_pin: Option<&str>) {
+        let index = match self.servers.iter().position(|p| p.address == address) {
+            Some(i) => i,
+            None => {
+                self.servers.push(ServerProfile {
+                    name: address.to_string(),
+                    address: address.to_string(),
+                    last_username: None,
+                    cert_pin: None,
+                });
+                self.servers.len() - 1
+            }
+        };
+
+        let profile = &mut self.servers[index];
+        if let Some(username) = username {
+            profile.last_username = Some(username.to_string());
+        }
+        if profile.cert_pin.is_none() {
+            profile.cert_pin = cert_pin.map(str::to_string);
+        }
+        self.last_used = Some(profile.name.clone());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_config() {
+        let config = ClientConfig::parse(
+            r#"
+            last_used = "work"
+
+            [[server]]
+            name = "home"
+            address = "192.168.1.10"
+
+            [[server]]
+            name = "work"
+            address = "chat.example.com"
+            last_username = "alice"
+            cert_pin = "abcd"
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.servers.len(), 2);
+        assert_eq!(config.servers[0].last_username, None);
+        assert_eq!(config.last_used_index(), Some(1));
+        assert_eq!(config.profile_for("chat.example.com").unwrap().cert_pin.as_deref(), Some("abcd"));
+
+        assert_eq!(ClientConfig::parse("").unwrap(), ClientConfig::default());
+        assert!(ClientConfig::parse("[[server]]\nname = 1").is_err());
+    }
+
+    #[test]
+    fn test_profile_round_trip() {
+        let dir = env::temp_dir().join(format!("darkrelay-config-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let path = dir.join("darkrelay").join("servers.toml");
+
+        assert_eq!(ClientConfig::load(&path).unwrap(), ClientConfig::default());
+
+        let mut config = ClientConfig::default();
+        config.remember("10.0.0.5", Some("alice"), Some("abcd"));
+        config.remember("10.0.0.5", Some("Alice"), Some("ffff"));
+        config.save(&path).unwrap();
+
+        let loaded = ClientConfig::load(&path).unwrap();
+        assert_eq!(loaded, config);
+        assert_eq!(loaded.servers.len(), 1);
+        let profile = &loaded.servers[loaded.last_used_index().unwrap()];
+        assert_eq!(profile.last_username.as_deref(), Some("Alice"));
+        assert_eq!(profile.cert_pin.as_deref(), Some("abcd"), "first pin is kept");
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
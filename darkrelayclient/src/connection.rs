@@ -1,57 +1,193 @@
 use std::{
-    io,
-    sync::Arc,
+    env, fmt, fs, io,
+    sync::{Arc, Mutex},
     time::Duration,
 };
 
-use bincode;
-use darkrelayprotocol::protocol::{ClientMessage, ServerMessage};
+use chrono::Utc;
+use darkrelayprotocol::{
+    frame,
+    protocol::{ClientMessage, MessageMeta, ServerMessage},
+};
 use serde::{de::DeserializeOwned, Serialize};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
     net::TcpStream,
-    sync::mpsc,
+    sync::{mpsc, oneshot},
 };
 use tokio_rustls::TlsConnector;
-use rustls::{ClientConfig, RootCertStore, client::ServerCertVerifier, Certificate, Error};
-use tracing::warn;
+use rustls::{ClientConfig, RootCertStore, client::ServerCertVerifier, Certificate, Error, PrivateKey};
+use sha2::{Digest, Sha256};
+use tracing::{debug, warn};
+
+/// Hex SHA-256 of a DER certificate, as stored in a profile's `cert_pin`.
+pub fn cert_fingerprint(cert: &Certificate) -> String {
+    hex::encode(Sha256::digest(&cert.0))
+}
+
+/// Client certificate for servers that require mutual TLS, read from the PEM
+/// files named by `DARKRELAY_CLIENT_CERT` and `DARKRELAY_CLIENT_KEY`.
+fn client_identity() -> io::Result<Option<(Vec<Certificate>, PrivateKey)>> {
+    let (Ok(cert_path), Ok(key_path)) = (env::var("DARKRELAY_CLIENT_CERT"), env::var("DARKRELAY_CLIENT_KEY")) else {
+        return Ok(None);
+    };
+
+    let chain: Vec<Certificate> = rustls_pemfile::certs(&mut io::BufReader::new(fs::File::open(cert_path)?))?
+        .into_iter()
+        .map(Certificate)
+        .collect();
+    let key = rustls_pemfile::pkcs8_private_keys(&mut io::BufReader::new(fs::File::open(key_path)?))?
+        .into_iter()
+        .next()
+        .map(PrivateKey)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no private key in DARKRELAY_CLIENT_KEY"))?;
+    Ok(Some((chain, key)))
+}
 
-struct AcceptAnyCertVerifier;
+/// Accepts self-signed certificates. With a pin, only the pinned certificate
+/// is accepted; either way the fingerprint seen is recorded for pinning later.
+struct PinningCertVerifier {
+    pin: Option<String>,
+    seen: Arc<Mutex<Option<String>>>,
+}
 
-impl ServerCertVerifier for AcceptAnyCertVerifier {
+impl ServerCertVerifier for PinningCertVerifier {
     fn verify_server_cert(
         &self,
-        _end_entity: &Certificate,
+        end_entity: &Certificate,
         _intermediates: &[Certificate],
         _server_name: &rustls::ServerName,
         _scts: &mut dyn Iterator<Item = &[u8]>,
         _ocsp_response: &[u8],
         _now: std::time::SystemTime,
     ) -> Result<rustls::client::ServerCertVerified, Error> {
-        warn!("accepting unverified TLS certificate (self-signed)");
+        let fingerprint = cert_fingerprint(end_entity);
+        match &self.pin {
+            Some(pin) if !pin.eq_ignore_ascii_case(&fingerprint) => {
+                return Err(Error::General(format!(
+                    "server certificate {fingerprint} does not match pinned {pin}"
+                )));
+            }
+            Some(_) => {}
+            None => warn!(%fingerprint, "accepting unverified TLS certificate (self-signed)"),
+        }
+        *self.seen.lock().unwrap() = Some(fingerprint);
         Ok(rustls::client::ServerCertVerified::assertion())
     }
 }
 
+/// Per-frame debug logging for people writing other clients, turned on with
+/// `DARKRELAY_DEBUG_FRAMES=1`. Frames carry passwords and any text sent
+/// before encryption is up, so only the message kind is logged unless
+/// `DARKRELAY_UNSAFE_LOG_CONTENT=1` is set as well.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameDebug {
+    pub enabled: bool,
+    pub log_content: bool,
+}
+
+impl FrameDebug {
+    pub fn from_env() -> Self {
+        let flag = |name| env::var(name).is_ok_and(|v| v == "1");
+        Self {
+            enabled: flag("DARKRELAY_DEBUG_FRAMES"),
+            log_content: flag("DARKRELAY_UNSAFE_LOG_CONTENT"),
+        }
+    }
+
+    /// `bytes` is the encoded body length, after compression if any.
+    fn log<T: fmt::Debug>(self, direction: &'static str, msg: &T, bytes: usize) {
+        if !self.enabled {
+            return;
+        }
+        if self.log_content {
+            debug!(target: "darkrelayclient::frames", direction, bytes, "{msg:#?}");
+        } else {
+            let mut kind = MessageKind::default();
+            // Stops with an error at the end of the name, as intended.
+            let _ = fmt::write(&mut kind, format_args!("{msg:?}"));
+            debug!(target: "darkrelayclient::frames", direction, bytes, "{}", kind.0);
+        }
+    }
+}
+
+/// Keeps the variant name a `Debug` rendering starts with and fails the
+/// write right after it, so the fields are never formatted at all.
+#[derive(Default)]
+struct MessageKind(String);
+
+impl fmt::Write for MessageKind {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        match s.find(|c: char| !c.is_alphanumeric()) {
+            Some(end) => {
+                self.0.push_str(&s[..end]);
+                Err(fmt::Error)
+            }
+            None => {
+                self.0.push_str(s);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Capacity of the outbound and inbound message queues.
+const QUEUE_CAPACITY: usize = 256;
+
+/// Why the reader task stopped.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConnectionLost {
+    /// The server closed the stream between frames.
+    Closed,
+    /// The stream ended partway through a frame.
+    Truncated,
+    /// A frame could not be decoded, most likely a protocol version mismatch.
+    Protocol(String),
+    Io(String),
+}
+
+impl fmt::Display for ConnectionLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionLost::Closed => write!(f, "server closed the connection"),
+            ConnectionLost::Truncated => write!(f, "connection dropped mid-frame"),
+            ConnectionLost::Protocol(e) => write!(f, "protocol mismatch: {e}"),
+            ConnectionLost::Io(e) => write!(f, "{e}"),
+        }
+    }
+}
+
 pub struct Connection {
-    outgoing: mpsc::UnboundedSender<ClientMessage>,
-    incoming: mpsc::UnboundedReceiver<ServerMessage>,
+    outgoing: mpsc::Sender<ClientMessage>,
+    incoming: mpsc::Receiver<ServerMessage>,
+    lost: oneshot::Receiver<ConnectionLost>,
+    cert_fingerprint: Option<String>,
 }
 
 impl Connection {
-    pub async fn connect(addr: &str, timeout: Duration) -> io::Result<Self> {
+    /// Connect over TLS. If `cert_pin` is set the server must present that certificate.
+    pub async fn connect(addr: &str, timeout: Duration, cert_pin: Option<&str>) -> io::Result<Self> {
         let tcp_stream = tokio::time::timeout(timeout, TcpStream::connect(addr))
             .await
             .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "connection timeout"))??;
 
         // Create TLS config that accepts self-signed certificates
-        let mut config = ClientConfig::builder()
+        let builder = ClientConfig::builder()
             .with_safe_defaults()
-            .with_root_certificates(RootCertStore::empty())
-            .with_no_client_auth();
+            .with_root_certificates(RootCertStore::empty());
+        let mut config = match client_identity()? {
+            Some((chain, key)) => builder
+                .with_client_auth_cert(chain, key)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?,
+            None => builder.with_no_client_auth(),
+        };
         
+        let seen = Arc::new(Mutex::new(None));
         config.dangerous()
-            .set_certificate_verifier(Arc::new(AcceptAnyCertVerifier));
+            .set_certificate_verifier(Arc::new(PinningCertVerifier {
+                pin: cert_pin.map(str::to_string),
+                seen: Arc::clone(&seen),
+            }));
         
         let connector = TlsConnector::from(Arc::new(config));
         let domain = rustls::ServerName::try_from("localhost")
@@ -59,43 +195,107 @@ impl Connection {
         
         let tls_stream = connector.connect(domain, tcp_stream).await?;
         let (mut reader, mut writer) = tokio::io::split(tls_stream);
+        let frame_debug = FrameDebug::from_env();
 
-        let (out_tx, mut out_rx) = mpsc::unbounded_channel::<ClientMessage>();
-        let (in_tx, in_rx) = mpsc::unbounded_channel::<ServerMessage>();
+        let (out_tx, mut out_rx) = mpsc::channel::<ClientMessage>(QUEUE_CAPACITY);
+        // Bounded so a UI that stops draining pushes back on the socket reader.
+        let (in_tx, in_rx) = mpsc::channel::<ServerMessage>(QUEUE_CAPACITY);
 
         tokio::spawn(async move {
+            let mut compression = false;
             while let Some(msg) = out_rx.recv().await {
-                if write_frame(&mut writer, &msg).await.is_err() {
+                if write_frame(&mut writer, &msg, compression, frame_debug).await.is_err() {
                     break;
                 }
+                if matches!(msg, ClientMessage::RequestCompression { .. }) {
+                    compression = true;
+                }
             }
         });
 
+        let (lost_tx, lost_rx) = oneshot::channel();
         tokio::spawn(async move {
-            loop {
-                match read_frame::<ServerMessage, _>(&mut reader).await {
-                    Ok(msg) => {
-                        if in_tx.send(msg).is_err() {
-                            break;
-                        }
-                    }
-                    Err(_) => break,
-                }
+            if let Some(reason) = read_loop(&mut reader, in_tx, frame_debug).await {
+                warn!(%reason, "connection lost");
+                let _ = lost_tx.send(reason);
             }
         });
 
+        let cert_fingerprint = seen.lock().unwrap().take();
         Ok(Self {
             outgoing: out_tx,
             incoming: in_rx,
+            lost: lost_rx,
+            cert_fingerprint,
         })
     }
 
+    /// Connect as a guest, for bots that only follow channels. This is the
+    /// minimal handshake any client performs:
+    ///
+    /// 1. wait for `AuthChallenge` and answer with `Auth { key }`;
+    /// 2. on `SystemMessage` (not `AuthFailure`), send `GuestLogin`;
+    /// 3. wait for `AuthSuccess`.
+    ///
+    /// `Connect` and the ECDH exchange are optional; without ECDH, channel
+    /// messages arrive as plaintext. Afterwards, send `JoinChannel` for each
+    /// public channel to follow and read `MessageReceived` from `recv`.
+    /// Guests can't send to channels.
+    pub async fn connect_read_only(addr: &str, timeout: Duration, cert_pin: Option<&str>, special_key: &str) -> io::Result<Self> {
+        let mut conn = Self::connect(addr, timeout, cert_pin).await?;
+        let meta = || MessageMeta::new(0, Utc::now());
+
+        match conn.recv_within(timeout).await? {
+            ServerMessage::AuthChallenge { .. } => {}
+            other => return Err(unexpected("AuthChallenge", &other)),
+        }
+        conn.send(ClientMessage::Auth { meta: meta(), key: special_key.to_string() })?;
+        match conn.recv_within(timeout).await? {
+            ServerMessage::SystemMessage { .. } => {}
+            ServerMessage::AuthFailure { reason, .. } => return Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+            other => return Err(unexpected("SystemMessage", &other)),
+        }
+        conn.send(ClientMessage::GuestLogin { meta: meta() })?;
+        match conn.recv_within(timeout).await? {
+            ServerMessage::AuthSuccess { .. } => Ok(conn),
+            ServerMessage::AuthFailure { reason, .. } => Err(io::Error::new(io::ErrorKind::PermissionDenied, reason)),
+            other => Err(unexpected("AuthSuccess", &other)),
+        }
+    }
+
+    async fn recv_within(&mut self, timeout: Duration) -> io::Result<ServerMessage> {
+        tokio::time::timeout(timeout, self.recv())
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "server did not answer"))??
+            .ok_or_else(|| io::Error::new(io::ErrorKind::UnexpectedEof, "server closed"))
+    }
+
+    /// Fingerprint of the certificate the server presented.
+    pub fn cert_fingerprint(&self) -> Option<&str> {
+        self.cert_fingerprint.as_deref()
+    }
+
     pub fn send(&self, msg: ClientMessage) -> io::Result<()> {
+        self.outgoing.try_send(msg).map_err(|e| match e {
+            mpsc::error::TrySendError::Full(_) => {
+                io::Error::new(io::ErrorKind::WouldBlock, "outbound queue full")
+            }
+            mpsc::error::TrySendError::Closed(_) => {
+                io::Error::new(io::ErrorKind::BrokenPipe, "connection closed")
+            }
+        })
+    }
+
+    /// Like `send`, but waits for room in the outbound queue instead of
+    /// failing with `WouldBlock`.
+    pub async fn send_wait(&self, msg: ClientMessage) -> io::Result<()> {
         self.outgoing
             .send(msg)
+            .await
             .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "connection closed"))
     }
 
+    /// `Ok(None)` once the server has closed the connection.
     pub async fn recv(&mut self) -> io::Result<Option<ServerMessage>> {
         Ok(self.incoming.recv().await)
     }
@@ -103,26 +303,266 @@ impl Connection {
     pub fn try_recv(&mut self) -> Option<ServerMessage> {
         self.incoming.try_recv().ok()
     }
+
+    /// Set once the reader has stopped; check after draining `try_recv`.
+    pub fn lost(&mut self) -> Option<ConnectionLost> {
+        self.lost.try_recv().ok()
+    }
+
+    /// In-memory connection for testing code that drives one: returns the
+    /// connection plus the receiving end of its outbound queue and the sending
+    /// end of its inbound one.
+    pub fn test_pair() -> (
+        Self,
+        mpsc::Receiver<ClientMessage>,
+        mpsc::Sender<ServerMessage>,
+    ) {
+        let (out_tx, out_rx) = mpsc::channel(QUEUE_CAPACITY);
+        let (in_tx, in_rx) = mpsc::channel(QUEUE_CAPACITY);
+        let (_, lost) = oneshot::channel();
+        (
+            Self {
+                outgoing: out_tx,
+                incoming: in_rx,
+                lost,
+                cert_fingerprint: None,
+            },
+            out_rx,
+            in_tx,
+        )
+    }
 }
 
-async fn read_frame<T: DeserializeOwned, R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<T> {
-    let len = reader.read_u32().await?;
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
+fn unexpected(expected: &str, got: &ServerMessage) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("expected {expected}, got {got:?}"))
+}
 
-    bincode::deserialize::<T>(&buf)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+/// Forward frames until the stream ends. Returns `None` if the UI side went away first.
+async fn read_loop<R: AsyncRead + Unpin>(
+    reader: &mut R,
+    in_tx: mpsc::Sender<ServerMessage>,
+    frame_debug: FrameDebug,
+) -> Option<ConnectionLost> {
+    let mut compression = false;
+    loop {
+        match read_frame::<ServerMessage, _>(reader, compression, frame_debug).await {
+            Ok(Some(msg)) => {
+                // Transport-level ack; the UI never sees it.
+                if matches!(msg, ServerMessage::CompressionEnabled { .. }) {
+                    compression = true;
+                    continue;
+                }
+                if in_tx.send(msg).await.is_err() {
+                    return None;
+                }
+            }
+            Ok(None) => return Some(ConnectionLost::Closed),
+            // Sent by a newer server; the frame was consumed, so keep reading.
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                warn!(error = %e, "skipping message from a newer protocol version");
+            }
+            Err(e) => {
+                return Some(match e.kind() {
+                    io::ErrorKind::UnexpectedEof => ConnectionLost::Truncated,
+                    io::ErrorKind::InvalidData => ConnectionLost::Protocol(e.to_string()),
+                    _ => ConnectionLost::Io(e.to_string()),
+                });
+            }
+        }
+    }
 }
 
-async fn write_frame<T: Serialize, W: AsyncWrite + Unpin>(writer: &mut W, msg: &T) -> io::Result<()> {
-    let data = bincode::serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
-    let len: u32 = data
-        .len()
-        .try_into()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame too large"))?;
+/// Read one frame; `Ok(None)` means the stream closed cleanly at a frame boundary.
+async fn read_frame<T: DeserializeOwned + fmt::Debug, R: AsyncRead + Unpin>(
+    reader: &mut R,
+    compression: bool,
+    frame_debug: FrameDebug,
+) -> io::Result<Option<T>> {
+    let mut header = [0u8; 5];
+    let header = &mut header[..frame::header_len(compression)];
+    if reader.read(&mut header[..1]).await? == 0 {
+        return Ok(None);
+    }
+    reader.read_exact(&mut header[1..]).await?;
+    let (flag, len) = frame::parse_header(header, compression)?;
 
-    writer.write_u32(len).await?;
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    let msg = frame::decode_body(flag, &buf)?;
+    frame_debug.log("recv", &msg, len);
+    Ok(Some(msg))
+}
+
+async fn write_frame<T: Serialize + fmt::Debug, W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    msg: &T,
+    compression: bool,
+    frame_debug: FrameDebug,
+) -> io::Result<()> {
+    let data = frame::encode_frame(msg, compression)?;
+    frame_debug.log("send", msg, data.len() - frame::header_len(compression));
     writer.write_all(&data).await?;
     writer.flush().await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn lost_after(bytes: &[u8]) -> Option<ConnectionLost> {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        server.write_all(bytes).await.unwrap();
+        drop(server);
+        let (in_tx, _in_rx) = mpsc::channel(QUEUE_CAPACITY);
+        read_loop(&mut client, in_tx, FrameDebug::default()).await
+    }
+
+    #[tokio::test]
+    async fn test_reader_reports_why_it_stopped() {
+        assert_eq!(lost_after(&[]).await, Some(ConnectionLost::Closed));
+
+        // Length prefix promises 10 bytes, only 3 arrive.
+        assert_eq!(lost_after(&[0, 0, 0, 10, 1, 2, 3]).await, Some(ConnectionLost::Truncated));
+        assert_eq!(lost_after(&[0, 0]).await, Some(ConnectionLost::Truncated));
+
+        // A known variant whose fields don't match what this build expects.
+        let garbage = [0, 0, 0, 5, 0, 0, 0, 0, 1];
+        assert!(matches!(lost_after(&garbage).await, Some(ConnectionLost::Protocol(_))));
+    }
+
+    #[tokio::test]
+    async fn test_clean_close_after_frames() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        let msg = ServerMessage::SystemMessage {
+            meta: MessageMeta::new(1, Utc::now()),
+            text: "hi".to_string(),
+        };
+        write_frame(&mut server, &msg, false, FrameDebug::default()).await.unwrap();
+        drop(server);
+
+        let (in_tx, mut in_rx) = mpsc::channel(QUEUE_CAPACITY);
+        assert_eq!(read_loop(&mut client, in_tx, FrameDebug::default()).await, Some(ConnectionLost::Closed));
+        assert!(matches!(in_rx.try_recv(), Ok(ServerMessage::SystemMessage { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_reader_switches_to_flagged_frames_after_ack() {
+        let (mut server, mut client) = tokio::io::duplex(64 * 1024);
+        let ack = ServerMessage::CompressionEnabled {
+            meta: MessageMeta::new(1, Utc::now()),
+        };
+        let big = ServerMessage::SystemMessage {
+            meta: MessageMeta::new(2, Utc::now()),
+            text: "x".repeat(frame::COMPRESSION_THRESHOLD * 4),
+        };
+        write_frame(&mut server, &ack, false, FrameDebug::default()).await.unwrap();
+        write_frame(&mut server, &big, true, FrameDebug::default()).await.unwrap();
+        drop(server);
+
+        let (in_tx, mut in_rx) = mpsc::channel(QUEUE_CAPACITY);
+        assert_eq!(read_loop(&mut client, in_tx, FrameDebug::default()).await, Some(ConnectionLost::Closed));
+        match in_rx.try_recv() {
+            Ok(ServerMessage::SystemMessage { text, .. }) => assert_eq!(text.len(), frame::COMPRESSION_THRESHOLD * 4),
+            other => panic!("expected SystemMessage, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reader_skips_unknown_variants() {
+        let (mut server, mut client) = tokio::io::duplex(1024);
+        let msg = ServerMessage::SystemMessage {
+            meta: MessageMeta::new(1, Utc::now()),
+            text: "after".to_string(),
+        };
+        // Variant index far past anything this build knows, with some payload.
+        server.write_all(&[0, 0, 0, 7, 0xff, 0xff, 0, 0, 1, 2, 3]).await.unwrap();
+        write_frame(&mut server, &msg, false, FrameDebug::default()).await.unwrap();
+        drop(server);
+
+        let (in_tx, mut in_rx) = mpsc::channel(QUEUE_CAPACITY);
+        assert_eq!(read_loop(&mut client, in_tx, FrameDebug::default()).await, Some(ConnectionLost::Closed));
+        assert!(matches!(in_rx.try_recv(), Ok(ServerMessage::SystemMessage { text, .. }) if text == "after"));
+    }
+
+    #[tokio::test]
+    async fn test_debug_frames_logs_each_frame() {
+        use crate::log_capture::LogCapture;
+
+        async fn log_round_trip(frame_debug: FrameDebug) -> String {
+            let capture = LogCapture::default();
+            let subscriber = tracing_subscriber::fmt()
+                .with_max_level(tracing::Level::DEBUG)
+                .with_ansi(false)
+                .with_writer(capture.clone())
+                .finish();
+            let _guard = tracing::subscriber::set_default(subscriber);
+
+            let (mut server, mut client) = tokio::io::duplex(1024);
+            let login = ClientMessage::Login {
+                meta: MessageMeta::new(1, Utc::now()),
+                username: "alice".to_string(),
+                password: "hunter22".to_string(),
+                signing_key: None,
+                public_key: None,
+            };
+            let reply = ServerMessage::SystemMessage {
+                meta: MessageMeta::new(2, Utc::now()),
+                text: "welcome alice".to_string(),
+            };
+            write_frame(&mut client, &login, false, frame_debug).await.unwrap();
+            write_frame(&mut server, &reply, false, FrameDebug::default()).await.unwrap();
+            drop(server);
+            let (in_tx, _in_rx) = mpsc::channel(QUEUE_CAPACITY);
+            read_loop(&mut client, in_tx, frame_debug).await;
+
+            capture.contents()
+        }
+
+        let off = log_round_trip(FrameDebug::default()).await;
+        assert!(off.is_empty());
+
+        let redacted = log_round_trip(FrameDebug { enabled: true, log_content: false }).await;
+        let lines: Vec<_> = redacted.lines().collect();
+        assert_eq!(lines.len(), 2, "one line per frame: {redacted}");
+        assert!(lines[0].contains("Login") && lines[0].contains("direction=\"send\"") && lines[0].contains("bytes="));
+        assert!(lines[1].contains("SystemMessage") && lines[1].contains("direction=\"recv\""));
+        assert!(!redacted.contains("hunter22") && !redacted.contains("welcome"));
+
+        let full = log_round_trip(FrameDebug { enabled: true, log_content: true }).await;
+        assert!(full.contains("hunter22") && full.contains("welcome alice"));
+    }
+
+    #[test]
+    fn test_redacted_frames_skip_formatting_fields() {
+        struct Login;
+        impl fmt::Debug for Login {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("Login")?;
+                f.write_str(" { password: ")?;
+                panic!("fields were formatted");
+            }
+        }
+
+        let mut kind = MessageKind::default();
+        assert!(fmt::write(&mut kind, format_args!("{:?}", Login)).is_err());
+        assert_eq!(kind.0, "Login");
+    }
+
+    #[test]
+    fn test_send_reports_full_queue() {
+        let (conn, mut out_rx, _in_tx) = Connection::test_pair();
+        let msg = || ClientMessage::ListChannels {
+            meta: MessageMeta::new(1, Utc::now()),
+        };
+
+        for _ in 0..QUEUE_CAPACITY {
+            conn.send(msg()).unwrap();
+        }
+        let err = conn.send(msg()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+
+        out_rx.try_recv().unwrap();
+        conn.send(msg()).unwrap();
+    }
+}
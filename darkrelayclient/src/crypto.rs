@@ -1,122 +1,157 @@
-use std::io;
+use std::{
+    collections::HashMap,
+    io,
+    time::{Duration, Instant},
+};
 use aes_gcm::{
     aead::{Aead, KeyInit},
     Aes256Gcm, Nonce,
 };
 use x25519_dalek::{EphemeralSecret, PublicKey, SharedSecret};
 use rand::rngs::OsRng;
-use pbkdf2::pbkdf2_hmac_array;
-use sha2::Sha256;
+use zeroize::{Zeroize, Zeroizing};
+use darkrelayprotocol::crypto::PaddingScheme;
+
+/// How long the secret of a replaced epoch is kept, so messages encrypted
+/// under it (late arrivals, the transcript from before a reconnect) still
+/// decrypt.
+pub const EPOCH_GRACE: Duration = Duration::from_secs(300);
+
+/// `SharedSecret` and `EphemeralSecret` zero themselves on drop, so dropping
+/// an epoch or a pending handshake is enough to wipe it.
+struct EpochSecret {
+    secret: SharedSecret,
+    /// When a newer epoch replaced this one.
+    retired_at: Option<Instant>,
+}
 
 pub struct CryptoState {
-    pub ecdh_secret: Option<SharedSecret>,
-    channel_keys: std::collections::HashMap<String, [u8; 32]>,
+    /// Shared secrets by key epoch. Every completed handshake starts a new
+    /// epoch; older ones are kept for `EPOCH_GRACE`.
+    secrets: HashMap<u64, EpochSecret>,
+    epoch: u64,
+    pending: Option<EcdhHandshake>,
+    /// Wiped when replaced, removed or dropped.
+    channel_keys: HashMap<String, Zeroizing<[u8; 32]>>,
     message_counter: u64,
+    /// Applied to every outgoing plaintext; kept across `reset`.
+    padding: PaddingScheme,
 }
 
 impl CryptoState {
     pub fn new() -> Self {
         Self {
-            ecdh_secret: None,
-            channel_keys: std::collections::HashMap::new(),
+            secrets: HashMap::new(),
+            epoch: 0,
+            pending: None,
+            channel_keys: HashMap::new(),
             message_counter: 0,
+            padding: PaddingScheme::default(),
         }
     }
 
-    /// Generate ephemeral keypair and return public key.
-    pub fn generate_keypair(&mut self) -> Vec<u8> {
-        let secret = EphemeralSecret::random_from_rng(OsRng);
-        let public = PublicKey::from(&secret);
-        let public_bytes = public.as_bytes().to_vec();
-        
-        // Store the secret temporarily - we'll derive shared secret when we get server's key
-        // For now, we'll use a different approach: return both
-        // Actually, we need to store the secret until we receive server's public key
-        // Let's use a thread-local or just regenerate... no, we need to keep it
-        // Let me use a different approach: store in self
-        
-        // This is tricky because EphemeralSecret is not Clone/Copy
-        // Let's store the shared secret after we compute it in complete_handshake
-        
-        // For now, just return the public key
-        // We'll pass the secret to complete_handshake
-        
-        public_bytes
+    pub fn set_padding(&mut self, scheme: PaddingScheme) {
+        self.padding = scheme;
     }
 
-    /// Complete ECDH handshake with server's public key.
-    pub fn complete_handshake(&mut self, server_public_key: &[u8], client_secret: EphemeralSecret) -> Result<(), String> {
-        if server_public_key.len() != 32 {
-            return Err("invalid server public key length".to_string());
-        }
+    pub fn padding(&self) -> PaddingScheme {
+        self.padding
+    }
 
-        let server_public = {
-            let mut bytes = [0u8; 32];
-            bytes.copy_from_slice(server_public_key);
-            PublicKey::from(bytes)
-        };
+    /// Start an ECDH handshake, returning the public key to send to the server.
+    /// Any handshake already in flight is discarded.
+    pub fn begin_handshake(&mut self) -> Vec<u8> {
+        let handshake = EcdhHandshake::new();
+        let public_key = handshake.public_key().to_vec();
+        self.pending = Some(handshake);
+        public_key
+    }
 
-        let shared_secret = client_secret.diffie_hellman(&server_public);
-        self.ecdh_secret = Some(shared_secret);
-        
+    /// Complete the pending handshake with the server's public key. The new
+    /// secret becomes the current epoch; the previous one is retired.
+    pub fn finish_handshake(&mut self, server_public_key: &[u8]) -> Result<(), String> {
+        let handshake = self
+            .pending
+            .take()
+            .ok_or_else(|| "no handshake in progress".to_string())?;
+        let secret = handshake.complete(server_public_key)?;
+
+        let now = Instant::now();
+        if let Some(current) = self.secrets.get_mut(&self.epoch) {
+            current.retired_at = Some(now);
+        }
+        self.evict_retired(now);
+
+        self.epoch += 1;
+        self.secrets.insert(self.epoch, EpochSecret { secret, retired_at: None });
         Ok(())
     }
 
     pub fn is_ready(&self) -> bool {
-        self.ecdh_secret.is_some()
+        self.secrets.contains_key(&self.epoch)
     }
 
-    /// Derive channel key from password using PBKDF2.
-    pub fn set_channel_key(&mut self, channel: &str, password: Option<&str>) {
-        if let Some(pwd) = password {
-            let salt = format!("darkrelay-channel-{}", channel);
-            let key = pbkdf2_hmac_array::<Sha256, 32>(pwd.as_bytes(), salt.as_bytes(), 100_000);
-            self.channel_keys.insert(channel.to_string(), key);
-        }
+    /// Epoch of the secret `encrypt` uses; sent with each message.
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Drop secrets retired more than `EPOCH_GRACE` before `now`.
+    pub fn evict_retired(&mut self, now: Instant) {
+        self.secrets.retain(|_, s| {
+            s.retired_at
+                .is_none_or(|retired| now.saturating_duration_since(retired) <= EPOCH_GRACE)
+        });
     }
 
     /// Encrypt plaintext with ECDH shared secret + optional channel key.
     /// Returns (ciphertext, nonce).
     pub fn encrypt(&mut self, plaintext: &[u8], channel: Option<&str>) -> io::Result<(Vec<u8>, Vec<u8>)> {
-        // Add padding
-        let padded = darkrelayprotocol::crypto::add_padding(plaintext);
+        // Add padding; the buffer holds the plaintext, so wipe it afterwards
+        let padded = Zeroizing::new(darkrelayprotocol::crypto::add_padding(plaintext, self.padding));
 
         // Generate nonce first before borrowing
         let nonce_bytes = self.next_nonce();
         let nonce = Nonce::from_slice(&nonce_bytes);
         
         // Now get shared secret
-        let shared_secret = self.ecdh_secret.as_ref()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "ECDH not complete"))?;
+        let shared_secret = self.secrets.get(&self.epoch)
+            .map(|s| &s.secret)
+            .ok_or_else(|| io::Error::other("ECDH not complete"))?;
         
         let cipher = Aes256Gcm::new_from_slice(shared_secret.as_bytes())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
         
         let mut ciphertext = cipher.encrypt(nonce, padded.as_slice())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("encryption failed: {:?}", e)))?;
+            .map_err(|e| io::Error::other(format!("encryption failed: {:?}", e)))?;
 
         // Second layer: if channel key exists, encrypt again
         if let Some(ch) = channel {
             if let Some(channel_key) = self.channel_keys.get(ch) {
-                let channel_cipher = Aes256Gcm::new_from_slice(channel_key)
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+                let channel_cipher = Aes256Gcm::new_from_slice(channel_key.as_slice())
+                    .map_err(|e| io::Error::other(format!("{:?}", e)))?;
                 
                 // Use a different nonce for channel encryption
                 let channel_nonce_bytes = self.next_nonce();
                 let channel_nonce = Nonce::from_slice(&channel_nonce_bytes);
                 
                 ciphertext = channel_cipher.encrypt(channel_nonce, ciphertext.as_slice())
-                    .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("channel encryption failed: {:?}", e)))?;
+                    .map_err(|e| io::Error::other(format!("channel encryption failed: {:?}", e)))?;
             }
         }
 
         Ok((ciphertext, nonce_bytes.to_vec()))
     }
 
-    /// Decrypt ciphertext with ECDH shared secret + optional channel key.
-    pub fn decrypt(&self, ciphertext: &[u8], nonce: &[u8], channel: Option<&str>) -> io::Result<Vec<u8>> {
-        let shared_secret = self.ecdh_secret.as_ref()
-            .ok_or_else(|| io::Error::new(io::ErrorKind::Other, "ECDH not complete"))?;
+    /// Decrypt ciphertext with the ECDH secret of `epoch` (the current one
+    /// if `None`) + optional channel key.
+    pub fn decrypt(&self, ciphertext: &[u8], nonce: &[u8], channel: Option<&str>, epoch: Option<u64>) -> io::Result<Vec<u8>> {
+        if !self.is_ready() {
+            return Err(io::Error::other("ECDH not complete"));
+        }
+        let shared_secret = self.secrets.get(&epoch.unwrap_or(self.epoch))
+            .map(|s| &s.secret)
+            .ok_or_else(|| io::Error::other("key epoch expired"))?;
 
         if nonce.len() != 12 {
             return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid nonce length"));
@@ -141,10 +176,10 @@ impl CryptoState {
         let nonce_array = Nonce::from_slice(nonce);
         
         let cipher = Aes256Gcm::new_from_slice(shared_secret.as_bytes())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("{:?}", e)))?;
+            .map_err(|e| io::Error::other(format!("{:?}", e)))?;
         
-        let padded = cipher.decrypt(nonce_array, data.as_slice())
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, format!("decryption failed: {:?}", e)))?;
+        let padded = Zeroizing::new(cipher.decrypt(nonce_array, data.as_slice())
+            .map_err(|e| io::Error::other(format!("decryption failed: {:?}", e)))?);
 
         // Remove padding
         darkrelayprotocol::crypto::remove_padding(&padded)
@@ -160,21 +195,40 @@ impl CryptoState {
         nonce
     }
 
+    /// Zero every channel key in place. Dropping a `Zeroizing` key wipes it
+    /// too; doing it first doesn't depend on how the map frees its entries.
+    fn wipe_channel_keys(&mut self) {
+        for key in self.channel_keys.values_mut() {
+            key.zeroize();
+        }
+    }
+
+    /// Forget all key material. Secrets are dropped here rather than left for
+    /// whenever the state itself goes away.
     pub fn reset(&mut self) {
-        self.ecdh_secret = None;
+        self.secrets.clear();
+        self.epoch = 0;
+        self.pending = None;
+        self.wipe_channel_keys();
         self.channel_keys.clear();
         self.message_counter = 0;
     }
 }
 
-/// Helper to hold the ephemeral secret until handshake completes.
-pub struct EcdhHandshake {
+impl Drop for CryptoState {
+    fn drop(&mut self) {
+        self.wipe_channel_keys();
+    }
+}
+
+/// Holds the ephemeral secret until the handshake completes.
+struct EcdhHandshake {
     secret: Option<EphemeralSecret>,
     public_key: Vec<u8>,
 }
 
 impl EcdhHandshake {
-    pub fn new() -> Self {
+    fn new() -> Self {
         let secret = EphemeralSecret::random_from_rng(OsRng);
         let public = PublicKey::from(&secret);
         let public_key = public.as_bytes().to_vec();
@@ -185,11 +239,11 @@ impl EcdhHandshake {
         }
     }
 
-    pub fn public_key(&self) -> &[u8] {
+    fn public_key(&self) -> &[u8] {
         &self.public_key
     }
 
-    pub fn complete(mut self, server_public_key: &[u8]) -> Result<SharedSecret, String> {
+    fn complete(mut self, server_public_key: &[u8]) -> Result<SharedSecret, String> {
         if server_public_key.len() != 32 {
             return Err("invalid server public key length".to_string());
         }
@@ -206,3 +260,92 @@ impl EcdhHandshake {
         Ok(secret.diffie_hellman(&server_public))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handshake_flow() {
+        let mut crypto = CryptoState::new();
+        assert!(!crypto.is_ready());
+        assert!(crypto.finish_handshake(&[0u8; 32]).is_err());
+
+        let client_public = crypto.begin_handshake();
+        assert_eq!(client_public.len(), 32);
+
+        let server_secret = EphemeralSecret::random_from_rng(OsRng);
+        let server_public = PublicKey::from(&server_secret);
+        let mut client_bytes = [0u8; 32];
+        client_bytes.copy_from_slice(&client_public);
+        let server_shared = server_secret.diffie_hellman(&PublicKey::from(client_bytes));
+
+        crypto.finish_handshake(server_public.as_bytes()).unwrap();
+        assert!(crypto.is_ready());
+        assert_eq!(crypto.secrets[&crypto.epoch].secret.as_bytes(), server_shared.as_bytes());
+
+        let (ciphertext, nonce) = crypto.encrypt(b"hello", None).unwrap();
+        assert_eq!(crypto.decrypt(&ciphertext, &nonce, None, None).unwrap(), b"hello");
+
+        assert_eq!(
+            crypto.finish_handshake(server_public.as_bytes()).unwrap_err(),
+            "no handshake in progress"
+        );
+    }
+
+    /// Run a handshake against a throwaway server key.
+    fn handshake(crypto: &mut CryptoState) {
+        crypto.begin_handshake();
+        let server_secret = EphemeralSecret::random_from_rng(OsRng);
+        crypto.finish_handshake(PublicKey::from(&server_secret).as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn test_previous_epoch_decrypts_after_rekey() {
+        let mut crypto = CryptoState::new();
+        handshake(&mut crypto);
+        let old_epoch = crypto.epoch();
+        let (old_ct, old_nonce) = crypto.encrypt(b"before", None).unwrap();
+
+        handshake(&mut crypto);
+        let new_epoch = crypto.epoch();
+        assert_ne!(old_epoch, new_epoch);
+        let (new_ct, new_nonce) = crypto.encrypt(b"after", None).unwrap();
+
+        assert_eq!(crypto.decrypt(&old_ct, &old_nonce, None, Some(old_epoch)).unwrap(), b"before");
+        assert_eq!(crypto.decrypt(&new_ct, &new_nonce, None, Some(new_epoch)).unwrap(), b"after");
+        assert_eq!(crypto.decrypt(&new_ct, &new_nonce, None, None).unwrap(), b"after", "untagged means current");
+        assert!(crypto.decrypt(&old_ct, &old_nonce, None, Some(new_epoch)).is_err());
+
+        crypto.evict_retired(Instant::now() + EPOCH_GRACE * 2);
+        assert!(crypto.decrypt(&old_ct, &old_nonce, None, Some(old_epoch)).is_err(), "retired epoch expired");
+        assert!(crypto.decrypt(&new_ct, &new_nonce, None, Some(new_epoch)).is_ok(), "current epoch never expires");
+    }
+
+    #[test]
+    fn test_bad_server_key_rejected() {
+        let mut crypto = CryptoState::new();
+        crypto.begin_handshake();
+        assert!(crypto.finish_handshake(&[1, 2, 3]).is_err());
+        assert!(!crypto.is_ready());
+    }
+
+    #[test]
+    fn test_reset_wipes_key_material() {
+        let mut crypto = CryptoState::new();
+        handshake(&mut crypto);
+        // Stand-ins for PBKDF2 output, which is slow in debug builds.
+        crypto.channel_keys.insert("staff".to_string(), Zeroizing::new([7u8; 32]));
+        crypto.channel_keys.insert("ops".to_string(), Zeroizing::new([9u8; 32]));
+
+        crypto.wipe_channel_keys();
+        assert_eq!(crypto.channel_keys.len(), 2, "wiped in place, before removal");
+        assert!(crypto.channel_keys.values().all(|key| **key == [0u8; 32]));
+
+        crypto.begin_handshake();
+        crypto.reset();
+        assert!(crypto.channel_keys.is_empty());
+        assert!(crypto.secrets.is_empty());
+        assert!(crypto.pending.is_none());
+    }
+}
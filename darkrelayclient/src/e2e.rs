@@ -0,0 +1,115 @@
+//! End-to-end encryption for DMs. Each user publishes an X25519 key as
+//! `UserInfo::public_key`; a DM is encrypted under the key both ends of the
+//! conversation derive from their own secret and the other's public key, so
+//! the server relays ciphertext it cannot read.
+
+use std::{io, path::Path};
+
+use aes_gcm::{
+    aead::{Aead, KeyInit},
+    Aes256Gcm, Nonce,
+};
+use darkrelayprotocol::crypto::{add_padding, remove_padding, PaddingScheme, NONCE_LEN};
+use rand::{rngs::OsRng, RngCore};
+use sha2::{Digest, Sha256};
+use x25519_dalek::{PublicKey, StaticSecret};
+use zeroize::Zeroizing;
+
+use crate::signing::load_or_create_seed;
+
+/// `MessageMetadata::end_to_end` of a DM encrypted by `DmKey`.
+pub const DM_SCHEME: &str = "x25519-aes256gcm";
+
+/// The user's long-term DM keypair. `StaticSecret` zeroes itself on drop.
+#[derive(Clone)]
+pub struct DmKey {
+    secret: StaticSecret,
+}
+
+impl DmKey {
+    pub fn generate() -> Self {
+        Self { secret: StaticSecret::random_from_rng(OsRng) }
+    }
+
+    /// Load the seed at `path`, or generate one and save it there.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        let seed = load_or_create_seed(path)?;
+        Ok(Self { secret: StaticSecret::from(*seed) })
+    }
+
+    /// What to publish as `UserInfo::public_key`.
+    pub fn public_key(&self) -> Vec<u8> {
+        PublicKey::from(&self.secret).to_bytes().to_vec()
+    }
+
+    /// The AES key shared with the holder of `peer_key`. Both public keys go
+    /// into it, in a fixed order, so either side derives the same one.
+    fn conversation_key(&self, peer_key: &[u8]) -> io::Result<Aes256Gcm> {
+        let peer: [u8; 32] = peer_key
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "DM key must be 32 bytes"))?;
+        let shared = self.secret.diffie_hellman(&PublicKey::from(peer));
+        if !shared.was_contributory() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "unusable DM key"));
+        }
+        let own = PublicKey::from(&self.secret).to_bytes();
+        let (first, second) = if own <= peer { (own, peer) } else { (peer, own) };
+        let key: Zeroizing<[u8; 32]> = Zeroizing::new(
+            Sha256::new()
+                .chain_update(b"darkrelay-dm-v1")
+                .chain_update(shared.as_bytes())
+                .chain_update(first)
+                .chain_update(second)
+                .finalize()
+                .into(),
+        );
+        Aes256Gcm::new_from_slice(key.as_slice()).map_err(|e| io::Error::other(format!("{:?}", e)))
+    }
+
+    /// Pad and encrypt `plaintext` for the holder of `peer_key`. Returns
+    /// (ciphertext, nonce); the nonce is random since the key is long-lived.
+    pub fn encrypt(&self, peer_key: &[u8], plaintext: &[u8], padding: PaddingScheme) -> io::Result<(Vec<u8>, Vec<u8>)> {
+        let cipher = self.conversation_key(peer_key)?;
+        let padded = Zeroizing::new(add_padding(plaintext, padding));
+        let mut nonce = [0u8; NONCE_LEN];
+        OsRng.fill_bytes(&mut nonce);
+        let ciphertext = cipher
+            .encrypt(Nonce::from_slice(&nonce), padded.as_slice())
+            .map_err(|e| io::Error::other(format!("encryption failed: {:?}", e)))?;
+        Ok((ciphertext, nonce.to_vec()))
+    }
+
+    /// Decrypt a DM exchanged with the holder of `peer_key`, in either direction.
+    pub fn decrypt(&self, peer_key: &[u8], ciphertext: &[u8], nonce: &[u8]) -> io::Result<Vec<u8>> {
+        if nonce.len() != NONCE_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid nonce length"));
+        }
+        let cipher = self.conversation_key(peer_key)?;
+        let padded = Zeroizing::new(
+            cipher
+                .decrypt(Nonce::from_slice(nonce), ciphertext)
+                .map_err(|e| io::Error::other(format!("decryption failed: {:?}", e)))?,
+        );
+        remove_padding(&padded).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_only_the_recipient_can_decrypt() {
+        let (alice, bob, mallory) = (DmKey::generate(), DmKey::generate(), DmKey::generate());
+        let (ciphertext, nonce) = alice.encrypt(&bob.public_key(), b"for bob only", PaddingScheme::default()).unwrap();
+
+        assert_eq!(bob.decrypt(&alice.public_key(), &ciphertext, &nonce).unwrap(), b"for bob only");
+        assert_eq!(alice.decrypt(&bob.public_key(), &ciphertext, &nonce).unwrap(), b"for bob only", "the sender rereads it");
+        assert!(mallory.decrypt(&alice.public_key(), &ciphertext, &nonce).is_err());
+        assert!(mallory.decrypt(&bob.public_key(), &ciphertext, &nonce).is_err());
+        assert!(bob.decrypt(&mallory.public_key(), &ciphertext, &nonce).is_err(), "a substituted sender key");
+
+        assert!(alice.encrypt(&[0; 32], b"hi", PaddingScheme::default()).is_err(), "low-order key");
+        assert!(alice.encrypt(&[1; 31], b"hi", PaddingScheme::default()).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::time::{Duration, Instant};
+
+/// How often a ping is sent while the last one has been answered.
+pub const PING_INTERVAL: Duration = Duration::from_secs(10);
+
+/// An unanswered ping older than this marks the link degraded.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Round trips slower than this mark the link degraded.
+pub const SLOW_RTT: Duration = Duration::from_millis(500);
+
+/// Connection health shown in the header.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LinkState {
+    #[default]
+    Connected,
+    Degraded,
+    /// The session was resumed on a new connection and no pong has come back yet.
+    Reconnecting,
+}
+
+/// Ping/pong bookkeeping. One ping is in flight at a time; a pong only
+/// counts if it echoes that ping's nonce.
+#[derive(Debug, Default)]
+pub struct Heartbeat {
+    next_nonce: u64,
+    in_flight: Option<(u64, Instant)>,
+    last_sent: Option<Instant>,
+    latency: Option<Duration>,
+    link: LinkState,
+}
+
+impl Heartbeat {
+    pub fn link(&self) -> LinkState {
+        self.link
+    }
+
+    /// Round trip of the last answered ping.
+    pub fn latency(&self) -> Option<Duration> {
+        self.latency
+    }
+
+    /// Called from the UI loop. Returns the nonce of a ping to send if one is
+    /// due, and flags the link degraded when the current ping is overdue.
+    pub fn poll(&mut self, now: Instant) -> Option<u64> {
+        if let Some((_, sent)) = self.in_flight {
+            if now.saturating_duration_since(sent) > PING_TIMEOUT && self.link == LinkState::Connected {
+                self.link = LinkState::Degraded;
+            }
+            return None;
+        }
+        if self.last_sent.is_some_and(|last| now.saturating_duration_since(last) < PING_INTERVAL) {
+            return None;
+        }
+
+        self.next_nonce += 1;
+        self.in_flight = Some((self.next_nonce, now));
+        self.last_sent = Some(now);
+        Some(self.next_nonce)
+    }
+
+    /// Record a pong; returns the round trip if `nonce` matches the ping in flight.
+    pub fn pong(&mut self, nonce: u64, now: Instant) -> Option<Duration> {
+        let (expected, sent) = self.in_flight?;
+        if nonce != expected {
+            return None;
+        }
+        self.in_flight = None;
+
+        let rtt = now.saturating_duration_since(sent);
+        self.latency = Some(rtt);
+        self.link = if rtt > SLOW_RTT {
+            LinkState::Degraded
+        } else {
+            LinkState::Connected
+        };
+        Some(rtt)
+    }
+
+    /// Start over on a new connection; a ping goes out on the next poll.
+    pub fn reconnecting(&mut self) {
+        *self = Self {
+            next_nonce: self.next_nonce,
+            link: LinkState::Reconnecting,
+            ..Self::default()
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_latency_from_matching_pong() {
+        let mut hb = Heartbeat::default();
+        let t0 = Instant::now();
+
+        let nonce = hb.poll(t0).expect("first poll pings");
+        assert_eq!(hb.poll(t0 + Duration::from_secs(1)), None, "one ping in flight");
+
+        assert_eq!(hb.pong(nonce + 1, t0 + Duration::from_millis(10)), None, "stale nonce is ignored");
+        assert_eq!(hb.pong(nonce, t0 + Duration::from_millis(42)), Some(Duration::from_millis(42)));
+        assert_eq!(hb.latency(), Some(Duration::from_millis(42)));
+        assert_eq!(hb.link(), LinkState::Connected);
+        assert_eq!(hb.pong(nonce, t0 + Duration::from_millis(50)), None, "answered once");
+
+        assert_eq!(hb.poll(t0 + Duration::from_secs(1)), None, "not due yet");
+        let next = hb.poll(t0 + PING_INTERVAL).expect("due again");
+        assert_ne!(next, nonce);
+        hb.pong(next, t0 + PING_INTERVAL + SLOW_RTT * 2);
+        assert_eq!(hb.link(), LinkState::Degraded);
+    }
+
+    #[test]
+    fn test_overdue_ping_and_reconnect() {
+        let mut hb = Heartbeat::default();
+        let t0 = Instant::now();
+        let nonce = hb.poll(t0).unwrap();
+        hb.poll(t0 + PING_TIMEOUT * 2);
+        assert_eq!(hb.link(), LinkState::Degraded);
+
+        hb.reconnecting();
+        assert_eq!(hb.link(), LinkState::Reconnecting);
+        assert_eq!(hb.latency(), None);
+        assert_eq!(hb.pong(nonce, t0 + PING_TIMEOUT * 3), None, "pings from the old connection don't count");
+
+        let fresh = hb.poll(t0 + PING_TIMEOUT * 3).expect("pings right away");
+        hb.pong(fresh, t0 + PING_TIMEOUT * 3 + Duration::from_millis(5));
+        assert_eq!(hb.link(), LinkState::Connected);
+    }
+}
@@ -0,0 +1,102 @@
+use std::time::{Duration, Instant};
+
+/// Idle time before we mark ourselves away, unless `DARKRELAY_AWAY_MINUTES` says otherwise.
+pub const DEFAULT_AWAY_AFTER: Duration = Duration::from_secs(10 * 60);
+
+/// Auto-away bookkeeping: no keypress for `threshold` means away, the next
+/// keypress means back. Both methods return the new away flag when it
+/// changes, i.e. when a `SetPresence` should go out.
+#[derive(Debug)]
+pub struct IdleTimer {
+    /// `None` turns auto-away off.
+    threshold: Option<Duration>,
+    last_activity: Instant,
+    away: bool,
+}
+
+impl Default for IdleTimer {
+    fn default() -> Self {
+        Self::new(Some(DEFAULT_AWAY_AFTER))
+    }
+}
+
+impl IdleTimer {
+    pub fn new(threshold: Option<Duration>) -> Self {
+        Self {
+            threshold,
+            last_activity: Instant::now(),
+            away: false,
+        }
+    }
+
+    /// Read `DARKRELAY_AWAY_MINUTES`; `0` turns auto-away off.
+    pub fn from_env() -> Self {
+        let threshold = match std::env::var("DARKRELAY_AWAY_MINUTES").ok().and_then(|v| v.trim().parse::<u64>().ok()) {
+            Some(0) => None,
+            Some(minutes) => Some(Duration::from_secs(minutes * 60)),
+            None => Some(DEFAULT_AWAY_AFTER),
+        };
+        Self::new(threshold)
+    }
+
+    pub fn is_away(&self) -> bool {
+        self.away
+    }
+
+    /// A keypress or paste.
+    pub fn activity(&mut self, now: Instant) -> Option<bool> {
+        self.last_activity = now;
+        if self.away {
+            self.away = false;
+            return Some(false);
+        }
+        None
+    }
+
+    /// Called from the UI loop.
+    pub fn poll(&mut self, now: Instant) -> Option<bool> {
+        let threshold = self.threshold?;
+        if self.away || now.saturating_duration_since(self.last_activity) < threshold {
+            return None;
+        }
+        self.away = true;
+        Some(true)
+    }
+
+    /// Start a new session as present, keeping the threshold.
+    pub fn reset(&mut self) {
+        *self = Self::new(self.threshold);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_marks_away_and_activity_clears_it() {
+        let threshold = Duration::from_secs(60);
+        let mut idle = IdleTimer::new(Some(threshold));
+        let t0 = Instant::now();
+        idle.activity(t0);
+
+        assert_eq!(idle.poll(t0 + threshold / 2), None);
+        assert_eq!(idle.activity(t0 + threshold / 2), None, "not away yet");
+        assert_eq!(idle.poll(t0 + threshold), None, "the keypress restarted the timer");
+
+        let idle_at = t0 + threshold / 2 + threshold;
+        assert_eq!(idle.poll(idle_at), Some(true));
+        assert!(idle.is_away());
+        assert_eq!(idle.poll(idle_at + threshold), None, "reported once");
+
+        assert_eq!(idle.activity(idle_at + threshold * 2), Some(false));
+        assert!(!idle.is_away());
+        assert_eq!(idle.activity(idle_at + threshold * 2), None);
+    }
+
+    #[test]
+    fn test_disabled_never_goes_away() {
+        let mut idle = IdleTimer::new(None);
+        assert_eq!(idle.poll(Instant::now() + Duration::from_secs(24 * 3600)), None);
+    }
+}
@@ -0,0 +1,8 @@
+//! The pieces of the DarkRelay client that work without the TUI, for bots and
+//! log collectors. See `Connection::connect_read_only` for the handshake.
+
+pub mod connection;
+pub mod e2e;
+#[cfg(any(test, feature = "test-support"))]
+pub mod log_capture;
+pub mod signing;
@@ -1,24 +1,46 @@
-mod connection;
+mod config;
 mod state;
 mod ui;
 mod crypto;
+mod heartbeat;
+mod idle;
 
 use std::{
+    collections::HashMap,
     env,
     io,
+    path::Path,
     time::Duration,
 };
 
 use chrono::Utc;
-use darkrelayprotocol::protocol::{ClientMessage, MessageMeta, ServerMessage};
-use tracing::{error, info};
+use darkrelayprotocol::{
+    crypto::PaddingScheme,
+    protocol::{ClientMessage, MessageMeta, ServerMessage},
+};
+use tracing::{error, info, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
+use darkrelayclient::{connection::Connection, e2e::DmKey, signing::Identity};
+
 use crate::{
-    connection::Connection,
+    config::ClientConfig,
+    idle::IdleTimer,
     state::{AuthMode, ClientState},
 };
 
+/// Load the key kept in `file` beside the server profiles, creating it on
+/// first run. Without a saved key others see a new one every session.
+fn load_key<K>(config_path: Option<&Path>, file: &str, load: fn(&Path) -> io::Result<K>, generate: fn() -> K) -> K {
+    let Some(path) = config_path.map(|p| p.with_file_name(file)) else {
+        return generate();
+    };
+    load(&path).unwrap_or_else(|e| {
+        warn!(error = %e, path = %path.display(), "using a one-off key; the key file is unreadable");
+        generate()
+    })
+}
+
 fn init_tracing() {
     let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info,darkrelayclient=debug"));
     let layer = fmt::layer().with_target(true);
@@ -31,16 +53,40 @@ async fn main() -> io::Result<()> {
 
     let special_key = env::var("DARKRELAY_SPECIAL_KEY").unwrap_or_else(|_| "darkrelay-dev-key".to_string());
 
+    let padding = match env::var("DARKRELAY_PADDING") {
+        Ok(value) => PaddingScheme::parse(&value).unwrap_or_else(|| {
+            warn!(value, "ignoring DARKRELAY_PADDING; expected random:<max> or block:<size>");
+            PaddingScheme::default()
+        }),
+        Err(_) => PaddingScheme::default(),
+    };
+
+    let config_path = ClientConfig::default_path();
+    let mut config = match config_path.as_deref().map(ClientConfig::load).transpose() {
+        Ok(config) => config.unwrap_or_default(),
+        Err(e) => {
+            warn!(error = %e, "ignoring unreadable server profiles");
+            ClientConfig::default()
+        }
+    };
+    let identity = load_key(config_path.as_deref(), "identity.key", Identity::load_or_create, Identity::generate);
+    let dm_key = load_key(config_path.as_deref(), "dm.key", DmKey::load_or_create, DmKey::generate);
+
     let mut terminal = ui::TerminalSession::new()?;
+    // Lines a session that couldn't be resumed never sent, for the next one's input.
+    let mut draft = None;
+    // Keys pinned for other users, by server, so logging in again can't reset them.
+    let mut pinned_keys = HashMap::new();
 
     loop {
-        let Some(dialog) = ui::auth_dialog::run(&mut terminal).await? else {
+        let Some(dialog) = ui::auth_dialog::run(&mut terminal, &config.servers, config.last_used_index()).await? else {
             return Ok(());
         };
 
         let server_addr = format!("{}:8080", dialog.server_ip);
+        let cert_pin = config.profile_for(&dialog.server_ip).and_then(|p| p.cert_pin.clone());
 
-        let connection = match Connection::connect(&server_addr, Duration::from_secs(5)).await {
+        let connection = match Connection::connect(&server_addr, Duration::from_secs(5), cert_pin.as_deref()).await {
             Ok(c) => c,
             Err(e) => {
                 ui::show_error_dialog(&mut terminal, &format!("Connection failed: {e}"))?;
@@ -49,8 +95,15 @@ async fn main() -> io::Result<()> {
         };
 
         let mut state = ClientState::new(server_addr.clone());
+        state.crypto.set_padding(padding);
+        state.idle = IdleTimer::from_env();
+        state.cert_pin = connection.cert_fingerprint().map(str::to_string);
+        state.identity = Some(identity.clone());
+        state.dm_key = Some(dm_key.clone());
         let mut conn = connection;
 
+        send_connect(&mut state, &conn)?;
+
         if let Err(e) = handshake_special_key(&mut terminal, &mut state, &mut conn, &special_key).await {
             error!(error = %e, "special key handshake failed");
             ui::show_error_dialog(&mut terminal, &format!("Auth failed: {e}"))?;
@@ -73,6 +126,9 @@ async fn main() -> io::Result<()> {
                     ClientMessage::RegisterUser {
                         meta,
                         username: dialog.username,
+                        password: Some(dialog.password).filter(|p| !p.is_empty()),
+                        signing_key: Some(identity.public_key()),
+                        public_key: Some(dm_key.public_key()),
                     },
                 )
                 .await
@@ -87,10 +143,16 @@ async fn main() -> io::Result<()> {
                         meta,
                         username: dialog.username,
                         password: dialog.password,
+                        signing_key: Some(identity.public_key()),
+                        public_key: Some(dm_key.public_key()),
                     },
                 )
                 .await
             }
+            AuthMode::Guest => {
+                let meta = state.next_meta();
+                authenticate_with_spinner(&mut terminal, &mut state, &mut conn, ClientMessage::GuestLogin { meta }).await
+            }
         };
 
         if let Err(e) = auth_res {
@@ -100,20 +162,93 @@ async fn main() -> io::Result<()> {
 
         info!(user = state.user.as_ref().map(|u| u.username.as_str()).unwrap_or("<none>"), "authenticated");
 
+        let remembered_user = match dialog.mode {
+            AuthMode::Guest => None,
+            _ => state.user.as_ref().map(|u| u.username.as_str()),
+        };
+        config.remember(&dialog.server_ip, remembered_user, state.cert_pin.as_deref());
+        if let Some(path) = &config_path {
+            if let Err(e) = config.save(path) {
+                warn!(error = %e, "failed to save server profiles");
+            }
+        }
+
         // Some servers already send ChannelList after auth; request one anyway.
         conn.send(ClientMessage::ListChannels {
             meta: state.next_meta(),
         })?;
 
-        if let Err(e) = ui::main_layout::run(&mut terminal, &mut state, &mut conn).await {
-            ui::show_error_dialog(&mut terminal, &format!("Runtime error: {e}"))?;
+        state.draft = draft.take();
+        if let Some(pins) = pinned_keys.remove(&server_addr) {
+            state.restore_pinned_keys(pins);
+        }
+        while let Err(e) = ui::main_layout::run(&mut terminal, &mut state, &mut conn).await {
+            error!(error = %e, "session interrupted");
+            state.heartbeat.reconnecting();
+            match resume_session(&mut terminal, &mut state, &special_key).await {
+                Some(resumed) => conn = resumed,
+                None => {
+                    draft = state.take_unsent_text();
+                    ui::show_error_dialog(&mut terminal, &format!("Runtime error: {e}"))?;
+                    break;
+                }
+            }
         }
 
         // If main layout returns, restart the auth dialog.
+        pinned_keys.insert(server_addr, state.take_pinned_keys());
         state.reset();
     }
 }
 
+fn send_connect(state: &mut ClientState, conn: &Connection) -> io::Result<()> {
+    conn.send(ClientMessage::Connect {
+        meta: state.next_meta(),
+        client_name: Some(env!("CARGO_PKG_NAME").to_string()),
+        client_version: Some(env!("CARGO_PKG_VERSION").to_string()),
+    })?;
+
+    // Opt-in: servers predating frame compression don't know this message.
+    if env::var("DARKRELAY_COMPRESSION").is_ok_and(|v| v == "1") {
+        conn.send(ClientMessage::RequestCompression {
+            meta: state.next_meta(),
+        })?;
+    }
+    Ok(())
+}
+
+/// Reconnect and present the resume token so the server restores our channels
+/// without announcing a fresh join. Returns `None` if the session can't be resumed.
+async fn resume_session(
+    terminal: &mut ui::TerminalSession,
+    state: &mut ClientState,
+    special_key: &str,
+) -> Option<Connection> {
+    let token = state.resume_token.take()?;
+
+    let mut conn = Connection::connect(&state.server_addr, Duration::from_secs(5), state.cert_pin.as_deref()).await.ok()?;
+    send_connect(state, &conn).ok()?;
+    handshake_special_key(terminal, state, &mut conn, special_key).await.ok()?;
+    handshake_ecdh(terminal, state, &mut conn).await.ok()?;
+
+    // The resumed session replays only recent history; what we had seen
+    // before the drop is where to fetch from.
+    let seen = state.seen_seqs();
+    let meta = state.next_meta();
+    match authenticate_with_spinner(terminal, state, &mut conn, ClientMessage::Resume { meta, token }).await {
+        Ok(()) => {
+            state.joined_channels.clear();
+            state.resync_since(seen);
+            let _ = ui::toast(terminal, "Reconnected", ui::ToastKind::Info);
+            Some(conn)
+        }
+        Err(e) => {
+            info!(error = %e, "session resume rejected");
+            None
+        }
+    }
+}
+
 async fn handshake_special_key(
     terminal: &mut ui::TerminalSession,
     state: &mut ClientState,
@@ -160,11 +295,10 @@ async fn handshake_ecdh(
     state: &mut ClientState,
     conn: &mut Connection,
 ) -> io::Result<()> {
-    let handshake = crypto::EcdhHandshake::new();
-    
+    let public_key = state.crypto.begin_handshake();
     conn.send(ClientMessage::EcdhPublicKey {
         meta: state.next_meta(),
-        public_key: handshake.public_key().to_vec(),
+        public_key,
     })?;
 
     let resp = tokio::time::timeout(Duration::from_secs(5), conn.recv())
@@ -173,17 +307,21 @@ async fn handshake_ecdh(
 
     match resp {
         Some(ServerMessage::EcdhAck { public_key, .. }) => {
-            let shared_secret = handshake.complete(&public_key)
-                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-            
-            // Store the shared secret in crypto state
-            state.crypto.ecdh_secret = Some(shared_secret);
-            
-            ui::toast(terminal, "🔒 Encryption enabled", ui::ToastKind::Info)?;
+            state.crypto.finish_handshake(&public_key).map_err(io::Error::other)?;
+
+            // Every handshake after the first replaces the session key.
+            if state.crypto.epoch() > 1 {
+                if let Some(channel) = state.current_channel.clone() {
+                    state.push_event(&channel, chrono::Utc::now(), "Encryption key renewed".to_string());
+                }
+                ui::toast(terminal, "🔒 Encryption key renewed", ui::ToastKind::Info)?;
+            } else {
+                ui::toast(terminal, "🔒 Encryption enabled", ui::ToastKind::Info)?;
+            }
             Ok(())
         }
         Some(ServerMessage::ProtocolError { text, .. }) => {
-            Err(io::Error::new(io::ErrorKind::Other, text))
+            Err(io::Error::other(text))
         }
         Some(other) => {
             Err(io::Error::new(
@@ -213,8 +351,9 @@ async fn authenticate_with_spinner(
         idx += 1;
 
         match tokio::time::timeout(Duration::from_millis(120), conn.recv()).await {
-            Ok(Ok(Some(ServerMessage::AuthSuccess { user, generated_password, .. }))) => {
+            Ok(Ok(Some(ServerMessage::AuthSuccess { user, generated_password, resume_token, .. }))) => {
                 state.user = Some(user);
+                state.resume_token = resume_token;
                 if let Some(pw) = generated_password {
                     state.generated_password = Some(pw.clone());
                     ui::toast(terminal, &format!("Registered. Password: {pw}"), ui::ToastKind::Info)?;
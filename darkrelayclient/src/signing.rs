@@ -0,0 +1,170 @@
+//! Ed25519 signatures over what users send, so a server that forges a
+//! message can be caught. The public half is published at login as
+//! `UserInfo::signing_key`; the signature travels in the message metadata.
+
+use std::{fs, io, path::Path};
+
+use chrono::{DateTime, Duration, Utc};
+use darkrelayprotocol::metadata::MessageMetadata;
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::{rngs::OsRng, RngCore};
+use zeroize::Zeroizing;
+
+/// How far a signature's `signed_at` may be from the time the server
+/// stamped on the message. Further apart, it was signed for another message.
+pub const MAX_SIGNATURE_SKEW_SECS: i64 = 300;
+
+/// The user's signing keypair.
+#[derive(Clone)]
+pub struct Identity {
+    key: SigningKey,
+}
+
+impl Identity {
+    pub fn generate() -> Self {
+        Self { key: SigningKey::generate(&mut OsRng) }
+    }
+
+    /// Load the seed at `path`, or generate one and save it there.
+    pub fn load_or_create(path: &Path) -> io::Result<Self> {
+        let seed = load_or_create_seed(path)?;
+        Ok(Self { key: SigningKey::from_bytes(&seed) })
+    }
+
+    /// What to publish as `UserInfo::signing_key`.
+    pub fn public_key(&self) -> Vec<u8> {
+        self.key.verifying_key().to_bytes().to_vec()
+    }
+
+    /// Sign `plaintext` and `metadata` as sent to `context` now, adding the
+    /// time and signature to `metadata`. Must come after every other
+    /// metadata change.
+    pub fn sign(&self, context: &str, plaintext: &[u8], metadata: &mut MessageMetadata) {
+        metadata.set_signed_at(Utc::now());
+        let signature = self.key.sign(&metadata.signing_payload(context, plaintext));
+        metadata.set_signature(&signature.to_bytes());
+    }
+}
+
+/// Read the hex seed at `path`, or save a random one there readable only by
+/// the owner.
+pub(crate) fn load_or_create_seed(path: &Path) -> io::Result<Zeroizing<[u8; 32]>> {
+    match fs::read_to_string(path) {
+        Ok(text) => {
+            let bytes = Zeroizing::new(hex::decode(text.trim()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?);
+            let seed: [u8; 32] = bytes
+                .as_slice()
+                .try_into()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "key seed must be 32 bytes"))?;
+            Ok(Zeroizing::new(seed))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut seed = Zeroizing::new([0u8; 32]);
+            OsRng.fill_bytes(seed.as_mut());
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir)?;
+            }
+            let text = Zeroizing::new(hex::encode(seed.as_ref()));
+            #[cfg(unix)]
+            {
+                use std::{io::Write, os::unix::fs::OpenOptionsExt};
+                let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(path)?;
+                file.write_all(text.as_bytes())?;
+            }
+            #[cfg(not(unix))]
+            fs::write(path, text.as_bytes())?;
+            Ok(seed)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// What a message's signature says about its sender.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verification {
+    /// Signed by the holder of the sender's published key.
+    Verified,
+    /// The sender publishes a key but the message carries no signature.
+    Unsigned,
+    /// The signature doesn't match, or was made for a message sent at
+    /// another time: the message was altered, forged or replayed.
+    Invalid,
+}
+
+/// Check a message the server stamped `sent_at` against its sender's
+/// published `public_key`.
+pub fn verify(public_key: &[u8], context: &str, plaintext: &[u8], metadata: &MessageMetadata, sent_at: DateTime<Utc>) -> Verification {
+    let Some(signature) = metadata.signature() else {
+        return Verification::Unsigned;
+    };
+    let Ok(public_key) = <[u8; 32]>::try_from(public_key) else {
+        return Verification::Invalid;
+    };
+    let (Ok(key), Ok(signature)) = (VerifyingKey::from_bytes(&public_key), Signature::from_slice(&signature)) else {
+        return Verification::Invalid;
+    };
+    if key.verify(&metadata.signing_payload(context, plaintext), &signature).is_err() {
+        return Verification::Invalid;
+    }
+    match metadata.signed_at() {
+        Some(at) if (sent_at - at).abs() <= Duration::seconds(MAX_SIGNATURE_SKEW_SECS) => Verification::Verified,
+        _ => Verification::Invalid,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use darkrelayprotocol::metadata::{NONCE_KEY, TYPE_KEY};
+    use std::env;
+
+    #[test]
+    fn test_sign_verify_round_trip() {
+        let alice = Identity::generate();
+        let now = Utc::now();
+        let mut metadata = MessageMetadata::new().with(TYPE_KEY, "action");
+        alice.sign("general", b"waves", &mut metadata);
+
+        assert_eq!(verify(&alice.public_key(), "general", b"waves", &metadata, now), Verification::Verified);
+        assert_eq!(verify(&Identity::generate().public_key(), "general", b"waves", &metadata, now), Verification::Invalid);
+        assert_eq!(verify(&alice.public_key(), "general", b"waves", &MessageMetadata::new(), now), Verification::Unsigned);
+    }
+
+    #[test]
+    fn test_tampering_is_detected() {
+        let alice = Identity::generate();
+        let key = alice.public_key();
+        let now = Utc::now();
+        let mut metadata = MessageMetadata::new().with(NONCE_KEY, "00ff");
+        alice.sign("general", b"meet at noon", &mut metadata);
+
+        assert_eq!(verify(&key, "general", b"meet at ten", &metadata, now), Verification::Invalid, "content");
+        assert_eq!(verify(&key, "random", b"meet at noon", &metadata, now), Verification::Invalid, "replayed elsewhere");
+        let mut changed = metadata.clone();
+        changed.set_message_type("action");
+        assert_eq!(verify(&key, "general", b"meet at noon", &changed, now), Verification::Invalid, "added metadata");
+        let mut garbled = metadata.clone();
+        garbled.set_signature(&[0; 64]);
+        assert_eq!(verify(&key, "general", b"meet at noon", &garbled, now), Verification::Invalid, "signature");
+        assert_eq!(verify(&key[..31], "general", b"meet at noon", &metadata, now), Verification::Invalid, "short key");
+
+        // The same signed message relayed again later.
+        let later = now + Duration::seconds(MAX_SIGNATURE_SKEW_SECS + 60);
+        assert_eq!(verify(&key, "general", b"meet at noon", &metadata, later), Verification::Invalid, "replayed later");
+        let mut restamped = metadata.clone();
+        restamped.set_signed_at(later);
+        assert_eq!(verify(&key, "general", b"meet at noon", &restamped, later), Verification::Invalid, "new timestamp");
+    }
+
+    #[test]
+    fn test_identity_persists() {
+        let path = env::temp_dir().join(format!("darkrelay-identity-test-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let created = Identity::load_or_create(&path).unwrap();
+        let loaded = Identity::load_or_create(&path).unwrap();
+        assert_eq!(created.public_key(), loaded.public_key());
+
+        fs::remove_file(&path).unwrap();
+    }
+}
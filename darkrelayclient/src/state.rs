@@ -1,26 +1,279 @@
-use std::collections::HashMap;
+use std::{
+    collections::{HashMap, HashSet},
+    time::{Duration, Instant},
+};
 
-use chrono::Utc;
-use darkrelayprotocol::protocol::{ChannelInfo, ChatMessage, MessageMeta, UserInfo};
-use crate::crypto::CryptoState;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use darkrelayprotocol::{
+    channel::ChannelType,
+    metadata::MessageMetadata,
+    permissions::Role,
+    protocol::{
+        features, AdminInfo, ChannelInfo, ChatMessage, ClientMessage, MessageId, MessageMeta, PollId,
+        UserInfo, DEFAULT_MAX_MESSAGE_LEN,
+    },
+};
+use darkrelayclient::{
+    e2e::DmKey,
+    signing::{self, Identity, Verification},
+};
+use crate::{
+    crypto::CryptoState,
+    heartbeat::Heartbeat,
+    idle::IdleTimer,
+};
+
+/// Metadata key carrying the `meta.id` of the `SendMessage` that produced a
+/// message. The server echoes metadata back, which lets us match the broadcast
+/// to the copy we rendered locally.
+pub const CLIENT_MSG_ID_KEY: &str = "client_msg_id";
+
+/// Message type marking an action (`/me waves`), rendered as
+/// `* alice waves`. The server relays it like any other message.
+pub const ACTION_TYPE: &str = "action";
+
+pub fn is_action(metadata: &MessageMetadata) -> bool {
+    metadata.message_type() == Some(ACTION_TYPE)
+}
+
+/// Message id of a locally rendered message the server has not echoed yet.
+pub const PENDING_MESSAGE_ID: u64 = 0;
+
+/// Most chat messages and system events kept per channel.
+const MAX_TRANSCRIPT: usize = 500;
+
+/// Shown in place of a message that can't be decrypted.
+pub const DECRYPT_FAILED: &str = "[decryption failed]";
+/// Shown in place of a message that decrypts to something other than text.
+pub const CORRUPT_MESSAGE: &str = "[binary/corrupt message]";
+/// Shown in place of an end-to-end DM until the key it needs arrives.
+pub const AWAITING_KEY: &str = "[waiting for the sender's key]";
+
+/// A chat line whose send found the connection gone, kept to send again
+/// once reconnected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsentMessage {
+    pub tab: String,
+    pub text: String,
+    pub action: bool,
+}
+
+/// The keys `ClientState` pinned for other users, handed from one login to
+/// the next on the same server.
+#[derive(Debug, Clone, Default)]
+pub struct PinnedKeys {
+    signing_keys: HashMap<String, Vec<u8>>,
+    public_keys: HashMap<String, Vec<u8>>,
+    changed_keys: HashSet<String>,
+}
+
+/// What `ClientState::learn_user` made of a `UserInfo`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LearnedUser {
+    /// It answers a lookup of ours, which the UI shouldn't show as a `/whois`.
+    pub answers_lookup: bool,
+    /// The user's keys differ from the pinned ones, reported once per user.
+    pub keys_changed: bool,
+}
+
+/// A DM waiting for its recipient's `public_key`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HeldDm {
+    pub recipient: String,
+    pub text: String,
+    pub action: bool,
+}
+
+/// A channel event ("alice was kicked") shown inline in the transcript.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SystemEvent {
+    pub timestamp: DateTime<Utc>,
+    pub text: String,
+}
+
+/// One transcript line, as returned by `ClientState::transcript`.
+#[derive(Debug, Clone, Copy)]
+pub enum TranscriptEntry<'a> {
+    Message(&'a ChatMessage),
+    Event(&'a SystemEvent),
+}
+
+/// DM conversations get a tab named `@<username>`; channel names can't
+/// start with `@`, so the two never collide.
+pub const DM_TAB_PREFIX: char = '@';
+
+pub fn dm_tab(username: &str) -> String {
+    format!("{DM_TAB_PREFIX}{username}")
+}
+
+/// What a message sent to `tab` is signed for: the channel, or the DM
+/// recipient regardless of how their name was typed.
+fn signing_context(tab: &str) -> String {
+    match dm_peer(tab) {
+        Some(peer) => dm_tab(&peer.to_ascii_lowercase()),
+        None => tab.to_string(),
+    }
+}
+
+/// What users read to each other to check they see the same keys: the
+/// start of a SHA-256 over both published keys, in groups of four digits.
+pub fn key_fingerprint(user: &UserInfo) -> Option<String> {
+    if user.signing_key.is_none() && user.public_key.is_none() {
+        return None;
+    }
+    let mut hasher = Sha256::new();
+    for key in [&user.signing_key, &user.public_key] {
+        let key = key.as_deref().unwrap_or_default();
+        hasher.update((key.len() as u32).to_be_bytes());
+        hasher.update(key);
+    }
+    let digest = hex::encode(&hasher.finalize()[..16]);
+    let groups: Vec<&str> = (0..digest.len()).step_by(4).map(|i| &digest[i..i + 4]).collect();
+    Some(groups.join(" "))
+}
+
+/// The other user of a DM tab, or `None` for a channel.
+pub fn dm_peer(tab: &str) -> Option<&str> {
+    tab.strip_prefix(DM_TAB_PREFIX)
+}
+
+fn is_username_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.')
+}
+
+/// Whether `text` mentions `@username` as a whole word, ignoring case. A
+/// trailing `.` still counts (`thanks @alice.`), `@alice.b` does not.
+pub fn mentions(text: &str, username: &str) -> bool {
+    let text = text.to_ascii_lowercase();
+    let needle = format!("@{}", username.to_ascii_lowercase());
+
+    text.match_indices(&needle).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let mut after = text[start + needle.len()..].chars();
+        let starts_word = !before.is_some_and(|c| c.is_ascii_alphanumeric() || c == '_');
+        let ends_word = match after.next() {
+            None => true,
+            Some('.') => !after.next().is_some_and(is_username_char),
+            Some(c) => !is_username_char(c),
+        };
+        starts_word && ends_word
+    })
+}
+
+fn client_msg_id(msg: &ChatMessage) -> Option<&str> {
+    msg.metadata.get(CLIENT_MSG_ID_KEY)
+}
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum AuthMode {
     Login,
     Register,
+    Guest,
+}
+
+/// A channel's most recent poll, shown in the info pane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Poll {
+    pub id: PollId,
+    pub question: String,
+    pub options: Vec<String>,
+    pub tallies: Vec<u32>,
+}
+
+/// What the server said this session may use, from `ServerCapabilities`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Capabilities {
+    pub features: HashSet<String>,
+    pub max_message_len: usize,
 }
 
 pub struct ClientState {
     pub server_addr: String,
+    /// Fingerprint of the server's certificate; reconnects must present the same one.
+    pub cert_pin: Option<String>,
     pub user: Option<UserInfo>,
     pub generated_password: Option<String>,
 
+    /// Token from the last `AuthSuccess`, used to resume after a dropped connection.
+    pub resume_token: Option<String>,
+    /// `None` until the server sends `ServerCapabilities`; servers that
+    /// never do are assumed to support everything.
+    pub capabilities: Option<Capabilities>,
+
     pub channels: Vec<ChannelInfo>,
     pub current_channel: Option<String>,
 
+    /// Channels we are a member of, in tab order.
+    pub joined_channels: Vec<String>,
+    unread: HashMap<String, usize>,
+    /// Unseen messages mentioning us, per channel.
+    mentions: HashMap<String, usize>,
+    /// Set when a mention arrives; the UI rings the terminal bell once.
+    bell: bool,
+
+    /// Posting rules reported on join, shown in the info pane.
+    pub channel_rules: HashMap<String, String>,
+    /// Known roles per channel by username, from `AdminList` and role
+    /// changes. A missing entry means unknown, drawn without a badge.
+    member_roles: HashMap<String, HashMap<String, Role>>,
+    /// Latest poll per channel; a newer poll replaces it.
+    pub polls: HashMap<String, Poll>,
+
+    /// Per-channel instant until which the server will reject our sends.
+    cooldowns: HashMap<String, Instant>,
+
     pub messages_by_channel: HashMap<String, Vec<ChatMessage>>,
+    /// Highest `ChatMessage::seq` seen per channel; a `Resync` asks for
+    /// everything after it.
+    last_seq: HashMap<String, u64>,
+    /// System events per channel, ordered by timestamp.
+    events_by_channel: HashMap<String, Vec<SystemEvent>>,
+
+    /// DMs we sent that the server is holding until the recipient connects.
+    pub undelivered_dms: HashSet<MessageId>,
+    /// Lines typed as the connection dropped; sent again after a resume.
+    pub unsent: Vec<UnsentMessage>,
+    /// Text to put back in the input line when the UI starts, such as the
+    /// unsent lines of a session that couldn't be resumed.
+    pub draft: Option<String>,
+
+    /// Prefix transcript lines with their message id (`/ids`).
+    pub show_message_ids: bool,
 
     pub crypto: CryptoState,
+    /// Signs what we send; kept across `reset`.
+    pub identity: Option<Identity>,
+    /// Encrypts DMs end to end; kept across `reset`.
+    pub dm_key: Option<DmKey>,
+    /// Signing keys by lowercased username, pinned the first time we see
+    /// them (user ids are reassigned when the server restarts). Kept across
+    /// `reset` so a reconnect can't swap them.
+    signing_keys: HashMap<String, Vec<u8>>,
+    /// Lowercased names whose published keys no longer match the pinned ones.
+    changed_keys: HashSet<String>,
+    /// DM keys by lowercased username, since DMs go by name; pinned and
+    /// kept like `signing_keys`.
+    public_keys: HashMap<String, Vec<u8>>,
+    /// Lowercased names we asked the server about; `true` once answered.
+    key_lookups: HashMap<String, bool>,
+    held_dms: Vec<HeldDm>,
+
+    pub heartbeat: Heartbeat,
+    pub idle: IdleTimer,
+    /// Users the server reported away, by username.
+    pub away_users: HashSet<String>,
+    /// The server's message of the day, shown in a panel until Esc dismisses it.
+    pub motd: Option<String>,
+    /// Channel whose password the input line is asking for, after a join
+    /// was refused for want of one. The next line submitted is the password.
+    pub password_prompt: Option<String>,
+
+    /// Set once `Disconnect` is sent; the UI loop then waits for the ack and exits.
+    pub disconnecting: bool,
+
+    /// Requests made while handling server messages; the UI loop sends them.
+    outbox: Vec<ClientMessage>,
 
     next_msg_id: u64,
 }
@@ -29,12 +282,43 @@ impl ClientState {
     pub fn new(server_addr: String) -> Self {
         Self {
             server_addr,
+            cert_pin: None,
             user: None,
             generated_password: None,
+            resume_token: None,
+            capabilities: None,
             channels: Vec::new(),
             current_channel: None,
+            joined_channels: Vec::new(),
+            unread: HashMap::new(),
+            mentions: HashMap::new(),
+            bell: false,
+            channel_rules: HashMap::new(),
+            member_roles: HashMap::new(),
+            polls: HashMap::new(),
+            cooldowns: HashMap::new(),
             messages_by_channel: HashMap::new(),
+            last_seq: HashMap::new(),
+            events_by_channel: HashMap::new(),
+            undelivered_dms: HashSet::new(),
+            unsent: Vec::new(),
+            draft: None,
+            show_message_ids: false,
             crypto: CryptoState::new(),
+            identity: None,
+            dm_key: None,
+            signing_keys: HashMap::new(),
+            changed_keys: HashSet::new(),
+            public_keys: HashMap::new(),
+            key_lookups: HashMap::new(),
+            held_dms: Vec::new(),
+            heartbeat: Heartbeat::default(),
+            idle: IdleTimer::default(),
+            away_users: HashSet::new(),
+            motd: None,
+            password_prompt: None,
+            disconnecting: false,
+            outbox: Vec::new(),
             next_msg_id: 1,
         }
     }
@@ -42,45 +326,1194 @@ impl ClientState {
     pub fn reset(&mut self) {
         self.user = None;
         self.generated_password = None;
+        self.resume_token = None;
+        self.capabilities = None;
         self.channels.clear();
         self.current_channel = None;
+        self.joined_channels.clear();
+        self.unread.clear();
+        self.mentions.clear();
+        self.bell = false;
+        self.channel_rules.clear();
+        self.member_roles.clear();
+        self.polls.clear();
+        self.cooldowns.clear();
         self.messages_by_channel.clear();
+        self.last_seq.clear();
+        self.events_by_channel.clear();
+        self.undelivered_dms.clear();
+        self.unsent.clear();
+        self.draft = None;
         self.crypto.reset();
+        self.key_lookups.clear();
+        self.held_dms.clear();
+        self.heartbeat = Heartbeat::default();
+        self.idle.reset();
+        self.away_users.clear();
+        self.disconnecting = false;
+        self.outbox.clear();
         self.next_msg_id = 1;
     }
 
+    pub fn is_me(&self, username: &str) -> bool {
+        self.user.as_ref().is_some_and(|u| u.username == username)
+    }
+
+    pub fn supports(&self, feature: &str) -> bool {
+        self.capabilities.as_ref().is_none_or(|c| c.features.contains(feature))
+    }
+
+    pub fn max_message_len(&self) -> usize {
+        self.capabilities.as_ref().map_or(DEFAULT_MAX_MESSAGE_LEN, |c| c.max_message_len)
+    }
+
     pub fn next_meta(&mut self) -> MessageMeta {
         let id = self.next_msg_id;
         self.next_msg_id += 1;
         MessageMeta::new(id, Utc::now())
     }
 
+    /// Append `msg` unless the channel already has it: a message sent as we
+    /// join can arrive both in the `HistoryChunk` and live. Pending messages
+    /// all share `PENDING_MESSAGE_ID`, so they are always appended.
     pub fn push_message(&mut self, channel: &str, msg: ChatMessage) {
+        if msg.id != PENDING_MESSAGE_ID && self.has_message(channel, msg.id) {
+            return;
+        }
         let entry = self
             .messages_by_channel
             .entry(channel.to_string())
             .or_default();
         entry.push(msg);
-        if entry.len() > 500 {
-            let overflow = entry.len() - 500;
+        if entry.len() > MAX_TRANSCRIPT {
+            let overflow = entry.len() - MAX_TRANSCRIPT;
             entry.drain(0..overflow);
         }
     }
 
-    pub fn messages_for_current(&self) -> Vec<ChatMessage> {
-        let Some(ch) = &self.current_channel else {
-            return Vec::new();
+    /// Record a system event for `channel`'s transcript.
+    pub fn push_event(&mut self, channel: &str, timestamp: DateTime<Utc>, text: String) {
+        let events = self.events_by_channel.entry(channel.to_string()).or_default();
+        let at = events.partition_point(|e| e.timestamp <= timestamp);
+        events.insert(at, SystemEvent { timestamp, text });
+        if events.len() > MAX_TRANSCRIPT {
+            events.remove(0);
+        }
+    }
+
+    /// Forget the cached transcript of `channel`.
+    pub fn clear_messages(&mut self, channel: &str) {
+        self.messages_by_channel.remove(channel);
+        self.events_by_channel.remove(channel);
+    }
+
+    /// Show one of our own messages immediately, marked pending until the
+    /// server's echo replaces it (see `receive_message`).
+    pub fn push_pending(&mut self, channel: &str, mut msg: ChatMessage) {
+        msg.id = PENDING_MESSAGE_ID;
+        self.push_message(channel, msg);
+    }
+
+    /// The server refused the send with meta id `request_id`: drop its
+    /// pending copy and return the text it was showing. `None` when that
+    /// wasn't one of our pending messages.
+    pub fn reject_pending(&mut self, request_id: u64) -> Option<String> {
+        let request_id = request_id.to_string();
+        let (channel, index) = self.messages_by_channel.iter().find_map(|(channel, msgs)| {
+            msgs.iter()
+                .position(|m| m.id == PENDING_MESSAGE_ID && client_msg_id(m) == Some(request_id.as_str()))
+                .map(|index| (channel.clone(), index))
+        })?;
+        let msg = self.messages_by_channel.get_mut(&channel)?.remove(index);
+        self.message_text(&channel, &msg).ok()
+    }
+
+    /// Have the input line take `text` back as soon as it is empty, after
+    /// any draft already waiting.
+    pub fn return_to_input(&mut self, text: String) {
+        self.draft = Some(match self.draft.take() {
+            Some(draft) => format!("{draft}\n{text}"),
+            None => text,
+        });
+    }
+
+    /// Record a live message; counts as unread unless its channel is in view.
+    /// The echo of one of our pending messages takes its place instead.
+    pub fn receive_message(&mut self, channel: &str, msg: ChatMessage) {
+        let own = self.user.as_ref().is_some_and(|u| u.id == msg.user_id);
+        if let Some(client_id) = client_msg_id(&msg).filter(|_| own) {
+            let pending = self.messages_by_channel.get_mut(channel).and_then(|msgs| {
+                msgs.iter_mut()
+                    .find(|m| m.id == PENDING_MESSAGE_ID && client_msg_id(m) == Some(client_id))
+            });
+            if let Some(pending) = pending {
+                *pending = msg;
+                return;
+            }
+        }
+        // Already shown; don't count it as unread twice.
+        if msg.id != PENDING_MESSAGE_ID && self.has_message(channel, msg.id) {
+            return;
+        }
+
+        self.look_up_keys(channel, &msg);
+        let text = self.message_text(channel, &msg);
+        if text == Err(CORRUPT_MESSAGE) {
+            tracing::warn!(channel, message_id = msg.id, user_id = msg.user_id, "message is not valid UTF-8 after decryption");
+        }
+
+        if self.current_channel.as_deref() != Some(channel) {
+            *self.unread.entry(channel.to_string()).or_default() += 1;
+            if !own && text.is_ok_and(|text| self.mentions_me(&text)) {
+                *self.mentions.entry(channel.to_string()).or_default() += 1;
+                self.bell = true;
+            }
+        }
+        self.push_message(channel, msg);
+    }
+
+    /// Checked on the decrypted text, the same way it will be rendered.
+    fn mentions_me(&self, text: &str) -> bool {
+        self.user.as_ref().is_some_and(|user| mentions(text, &user.username))
+    }
+
+    /// `msg`'s text, decrypted if it was sent encrypted, or the marker to
+    /// show in its place. Clients only ever send text, so bytes that aren't
+    /// UTF-8 once decrypted mean a key or encryption bug, not odd content.
+    pub fn message_text(&self, channel: &str, msg: &ChatMessage) -> Result<String, &'static str> {
+        let bytes = match &msg.nonce {
+            Some(nonce) if msg.metadata.end_to_end().is_some() => {
+                let peer_key = self.dm_peer_key(channel, msg).ok_or(AWAITING_KEY)?;
+                let dm_key = self.dm_key.as_ref().ok_or(DECRYPT_FAILED)?;
+                dm_key.decrypt(peer_key, &msg.content, nonce).map_err(|_| DECRYPT_FAILED)?
+            }
+            Some(nonce) => self
+                .crypto
+                .decrypt(&msg.content, nonce, Some(channel), msg.metadata.key_epoch())
+                .map_err(|_| DECRYPT_FAILED)?,
+            None => msg.content.clone(),
         };
+        String::from_utf8(bytes).map_err(|_| CORRUPT_MESSAGE)
+    }
 
-        self.messages_by_channel
-            .get(ch)
-            .cloned()
+    /// The other end's DM key for an end-to-end DM in `tab`: the sender's,
+    /// or for our own, the recipient's.
+    fn dm_peer_key(&self, tab: &str, msg: &ChatMessage) -> Option<&[u8]> {
+        let own = self.user.as_ref().is_some_and(|u| u.id == msg.user_id);
+        let peer = if own { dm_peer(tab)? } else { &msg.username };
+        self.dm_public_key(peer)
+    }
+
+    pub fn dm_public_key(&self, username: &str) -> Option<&[u8]> {
+        self.public_keys.get(&username.to_ascii_lowercase()).map(Vec::as_slice)
+    }
+
+    /// Remember `user`'s published keys, pinning the first ones seen. A
+    /// different key later is never used in their place.
+    pub fn learn_user(&mut self, user: &UserInfo) -> LearnedUser {
+        let name = user.username.to_ascii_lowercase();
+        let mut changed = false;
+        if let Some(key) = &user.signing_key {
+            changed |= self.signing_keys.entry(name.clone()).or_insert_with(|| key.clone()) != key;
+        }
+        if let Some(key) = &user.public_key {
+            changed |= self.public_keys.entry(name.clone()).or_insert_with(|| key.clone()) != key;
+        }
+        LearnedUser {
+            answers_lookup: self.key_lookups.get_mut(&name).is_some_and(|answered| !std::mem::replace(answered, true)),
+            keys_changed: changed && self.changed_keys.insert(name),
+        }
+    }
+
+
+    /// Hand the pinned keys over to the next login on this server.
+    pub fn take_pinned_keys(&mut self) -> PinnedKeys {
+        PinnedKeys {
+            signing_keys: std::mem::take(&mut self.signing_keys),
+            public_keys: std::mem::take(&mut self.public_keys),
+            changed_keys: std::mem::take(&mut self.changed_keys),
+        }
+    }
+
+    pub fn restore_pinned_keys(&mut self, pins: PinnedKeys) {
+        self.signing_keys = pins.signing_keys;
+        self.public_keys = pins.public_keys;
+        self.changed_keys = pins.changed_keys;
+    }
+
+    /// Whether `username` published keys other than the ones we pinned. We
+    /// don't encrypt to them until the user trusts the new ones.
+    pub fn keys_changed(&self, username: &str) -> bool {
+        self.changed_keys.contains(&username.to_ascii_lowercase())
+    }
+
+    /// Forget the keys pinned for `username` and fetch the current ones,
+    /// which are pinned in their place. For after checking the fingerprint.
+    pub fn trust_new_keys(&mut self, username: &str) {
+        let name = username.to_ascii_lowercase();
+        self.signing_keys.remove(&name);
+        self.public_keys.remove(&name);
+        self.changed_keys.remove(&name);
+        self.key_lookups.remove(&name);
+        self.look_up_user(username);
+    }
+
+    /// Ask the server about `username` unless we already have.
+    fn look_up_user(&mut self, username: &str) {
+        let name = username.to_ascii_lowercase();
+        if self.key_lookups.contains_key(&name) {
+            return;
+        }
+        self.key_lookups.insert(name, false);
+        let meta = self.next_meta();
+        self.queue(ClientMessage::GetUserInfo { meta, username: username.to_string() });
+    }
+
+    /// Ask for the keys a message from someone else needs and we haven't seen.
+    fn look_up_keys(&mut self, tab: &str, msg: &ChatMessage) {
+        if self.user.as_ref().is_some_and(|u| u.id == msg.user_id) {
+            return;
+        }
+        let unverifiable = msg.metadata.signature().is_some() && !self.signing_keys.contains_key(&msg.username.to_ascii_lowercase());
+        let undecryptable = msg.metadata.end_to_end().is_some() && self.dm_peer_key(tab, msg).is_none();
+        if unverifiable || undecryptable {
+            self.look_up_user(&msg.username);
+        }
+    }
+
+    /// Keep a DM until `recipient`'s DM key arrives, asking for it.
+    pub fn hold_dm(&mut self, recipient: &str, text: &str, action: bool) {
+        self.held_dms.push(HeldDm { recipient: recipient.to_string(), text: text.to_string(), action });
+        self.look_up_user(recipient);
+    }
+
+    /// The DMs held for `username`, in the order they were typed.
+    pub fn take_held_dms(&mut self, username: &str) -> Vec<HeldDm> {
+        let (taken, kept) = std::mem::take(&mut self.held_dms)
+            .into_iter()
+            .partition(|held| held.recipient.eq_ignore_ascii_case(username));
+        self.held_dms = kept;
+        taken
+    }
+
+    /// Sign a message we are about to send to `tab` (a channel or DM tab).
+    pub fn sign(&self, tab: &str, plaintext: &[u8], metadata: &mut MessageMetadata) {
+        if let Some(identity) = &self.identity {
+            identity.sign(&signing_context(tab), plaintext, metadata);
+        }
+    }
+
+    /// Check `msg` in `tab` against its sender's key, given its decrypted
+    /// `text`. `None` when there is nothing to check against yet.
+    pub fn verification(&self, tab: &str, msg: &ChatMessage, text: &str) -> Option<Verification> {
+        if msg.id == PENDING_MESSAGE_ID || msg.deleted {
+            return None;
+        }
+        let me = self.user.as_ref()?;
+        let (key, context) = if msg.user_id == me.id {
+            (self.identity.as_ref()?.public_key(), signing_context(tab))
+        } else {
+            let key = self.signing_keys.get(&msg.username.to_ascii_lowercase())?.clone();
+            // Sent to our DM tab, so signed for us.
+            let context = match dm_peer(tab) {
+                Some(_) => signing_context(&dm_tab(&me.username)),
+                None => tab.to_string(),
+            };
+            (key, context)
+        };
+        Some(signing::verify(&key, &context, text.as_bytes(), &msg.metadata, msg.timestamp))
+    }
+
+    /// The unsent lines, after any draft not yet put back, as input-line
+    /// text for when they can't be resent because their session is gone.
+    pub fn take_unsent_text(&mut self) -> Option<String> {
+        let unsent = std::mem::take(&mut self.unsent).into_iter().map(|line| line.text);
+        let lines: Vec<String> = self.draft.take().into_iter().chain(unsent).collect();
+        (!lines.is_empty()).then(|| lines.join("\n"))
+    }
+
+    pub fn mention_count(&self, channel: &str) -> usize {
+        self.mentions.get(channel).copied().unwrap_or(0)
+    }
+
+    /// Whether a mention arrived since the last call.
+    pub fn take_bell(&mut self) -> bool {
+        std::mem::take(&mut self.bell)
+    }
+
+    /// Record a DM under its conversation tab, adding the tab without
+    /// switching to it. `recipient` is only used for our own messages.
+    pub fn receive_dm(&mut self, recipient: &str, msg: ChatMessage) {
+        let own = self.user.as_ref().is_some_and(|u| u.id == msg.user_id);
+        let tab = dm_tab(if own { recipient } else { &msg.username });
+        if !self.joined_channels.contains(&tab) {
+            self.joined_channels.push(tab.clone());
+        }
+        self.receive_message(&tab, msg);
+    }
+
+    pub fn unread(&self, channel: &str) -> usize {
+        self.unread.get(channel).copied().unwrap_or(0)
+    }
+
+    pub fn start_cooldown(&mut self, channel: &str, retry_after: Duration) {
+        self.cooldowns.insert(channel.to_string(), Instant::now() + retry_after);
+    }
+
+    /// Time left before sends to `channel` are accepted again, if any.
+    pub fn cooldown_remaining(&self, channel: &str) -> Option<Duration> {
+        self.cooldowns
+            .get(channel)
+            .map(|until| until.saturating_duration_since(Instant::now()))
+            .filter(|left| !left.is_zero())
+    }
+
+    /// Add a tab for a newly joined channel and bring it into view.
+    pub fn open_channel(&mut self, channel: &str) {
+        if !self.joined_channels.iter().any(|c| c == channel) {
+            self.joined_channels.push(channel.to_string());
+        }
+        self.switch_channel(channel);
+    }
+
+    /// Drop a channel's tab; if it was in view, fall back to the last tab.
+    pub fn close_channel(&mut self, channel: &str) {
+        self.joined_channels.retain(|c| c != channel);
+        self.unread.remove(channel);
+        self.mentions.remove(channel);
+        if self.current_channel.as_deref() == Some(channel) {
+            self.current_channel = self.joined_channels.last().cloned();
+        }
+    }
+
+    /// Add a newly announced channel to the listing, kept sorted by name like
+    /// the server's `ChannelList`. A channel already listed is updated; listings
+    /// carry no role, so the one we learned on join is kept.
+    pub fn add_listed_channel(&mut self, mut channel: ChannelInfo) {
+        match self.channels.binary_search_by(|c| c.name.cmp(&channel.name)) {
+            Ok(i) => {
+                channel.user_role = channel.user_role.or(self.channels[i].user_role);
+                self.channels[i] = channel;
+            }
+            Err(i) => self.channels.insert(i, channel),
+        }
+    }
+
+    /// Replace the listing with a fresh `ChannelList`. Channels we're in keep
+    /// their role, and private ones the listing leaves out stay listed.
+    pub fn set_channel_list(&mut self, channels: Vec<ChannelInfo>) {
+        let previous = std::mem::replace(&mut self.channels, channels);
+        for info in previous {
+            match self.channels.binary_search_by(|c| c.name.cmp(&info.name)) {
+                Ok(i) => self.channels[i].user_role = self.channels[i].user_role.or(info.user_role),
+                Err(i) if self.joined_channels.contains(&info.name) => self.channels.insert(i, info),
+                Err(_) => {}
+            }
+        }
+    }
+
+    /// Take in `MyChannels`: list every channel and queue joins for the public
+    /// ones we have no tab for. Returns the private ones, which need their
+    /// password typed again.
+    pub fn restore_channels(&mut self, channels: Vec<ChannelInfo>) -> Vec<String> {
+        let mut locked = Vec::new();
+        for info in channels {
+            if !self.joined_channels.contains(&info.name) {
+                if info.is_public {
+                    let meta = self.next_meta();
+                    self.queue(ClientMessage::JoinChannel { meta, name: info.name.clone(), password: None });
+                } else {
+                    locked.push(info.name.clone());
+                }
+            }
+            self.add_listed_channel(info);
+        }
+        locked
+    }
+
+    pub fn queue(&mut self, msg: ClientMessage) {
+        self.outbox.push(msg);
+    }
+
+    pub fn take_outbox(&mut self) -> Vec<ClientMessage> {
+        std::mem::take(&mut self.outbox)
+    }
+
+    /// The listing entry of the channel in view, if it is a channel tab.
+    pub fn current_channel_info(&self) -> Option<&ChannelInfo> {
+        let current = self.current_channel.as_deref()?;
+        self.channels.iter().find(|c| c.name == current)
+    }
+
+    /// Our role in the channel in view; `User` until the server says otherwise.
+    pub fn current_role(&self) -> Role {
+        self.current_channel_info().and_then(|c| c.user_role).unwrap_or(Role::User)
+    }
+
+    /// Whether the server would take a message from us in the tab in view.
+    /// DM tabs and channels we know nothing about are assumed open.
+    pub fn can_send_here(&self) -> bool {
+        self.current_channel_info()
+            .is_none_or(|c| c.channel_type.allows_sending(c.user_role.unwrap_or(Role::User)))
+    }
+
+    /// Record our new role in `channel` after a promotion, demotion or handover.
+    pub fn set_channel_role(&mut self, channel: &str, role: Role) {
+        for info in self.channels.iter_mut().filter(|c| c.name == channel) {
+            info.user_role = Some(role);
+        }
+    }
+
+    /// Replace what we know of `channel`'s staff with an `AdminList`.
+    pub fn set_member_roles(&mut self, channel: &str, admins: &[AdminInfo]) {
+        let roles = admins.iter().map(|a| (a.username.clone(), a.role)).collect();
+        self.member_roles.insert(channel.to_string(), roles);
+    }
+
+    pub fn set_member_role(&mut self, channel: &str, username: &str, role: Role) {
+        self.member_roles.entry(channel.to_string()).or_default().insert(username.to_string(), role);
+        if self.is_me(username) {
+            self.set_channel_role(channel, role);
+        }
+    }
+
+    pub fn member_role(&self, channel: &str, username: &str) -> Option<Role> {
+        self.member_roles.get(channel)?.get(username).copied()
+    }
+
+    pub fn set_channel_type(&mut self, channel: &str, channel_type: ChannelType) {
+        for info in self.channels.iter_mut().filter(|c| c.name == channel) {
+            info.channel_type = channel_type;
+        }
+    }
+
+    /// Follow a server-side rename: the tab, transcript and per-channel
+    /// state move to `new`.
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        fn move_key<V>(map: &mut HashMap<String, V>, old: &str, new: &str) {
+            if let Some(value) = map.remove(old) {
+                map.insert(new.to_string(), value);
+            }
+        }
+
+        for channel in self.joined_channels.iter_mut().filter(|c| *c == old) {
+            *channel = new.to_string();
+        }
+        if self.current_channel.as_deref() == Some(old) {
+            self.current_channel = Some(new.to_string());
+        }
+        for info in self.channels.iter_mut().filter(|c| c.name == old) {
+            info.name = new.to_string();
+        }
+        move_key(&mut self.unread, old, new);
+        move_key(&mut self.mentions, old, new);
+        move_key(&mut self.channel_rules, old, new);
+        move_key(&mut self.member_roles, old, new);
+        move_key(&mut self.polls, old, new);
+        move_key(&mut self.cooldowns, old, new);
+        move_key(&mut self.messages_by_channel, old, new);
+        move_key(&mut self.last_seq, old, new);
+        move_key(&mut self.events_by_channel, old, new);
+    }
+
+    pub fn switch_channel(&mut self, channel: &str) -> bool {
+        if !self.joined_channels.iter().any(|c| c == channel) {
+            return false;
+        }
+        self.current_channel = Some(channel.to_string());
+        self.unread.remove(channel);
+        self.mentions.remove(channel);
+        true
+    }
+
+    /// Move `delta` tabs from the current one, wrapping around.
+    pub fn switch_relative(&mut self, delta: isize) {
+        let len = self.joined_channels.len();
+        if len == 0 {
+            return;
+        }
+        let current = self
+            .current_channel
+            .as_ref()
+            .and_then(|c| self.joined_channels.iter().position(|j| j == c))
+            .unwrap_or(0);
+        let next = (current as isize + delta).rem_euclid(len as isize) as usize;
+        let channel = self.joined_channels[next].clone();
+        self.switch_channel(&channel);
+    }
+
+    /// Messages and system events of `channel`, interleaved by timestamp.
+    /// Messages keep their received order; each event goes before the first
+    /// message stamped later than it.
+    pub fn transcript(&self, channel: &str) -> Vec<TranscriptEntry<'_>> {
+        let messages = self.messages_by_channel.get(channel).map(Vec::as_slice).unwrap_or_default();
+        let mut events = self
+            .events_by_channel
+            .get(channel)
+            .map(Vec::as_slice)
             .unwrap_or_default()
+            .iter()
+            .peekable();
+
+        let mut entries = Vec::with_capacity(messages.len() + events.len());
+        for msg in messages {
+            while let Some(event) = events.next_if(|e| e.timestamp < msg.timestamp) {
+                entries.push(TranscriptEntry::Event(event));
+            }
+            entries.push(TranscriptEntry::Message(msg));
+        }
+        entries.extend(events.map(TranscriptEntry::Event));
+        entries
+    }
+
+    pub fn has_message(&self, channel: &str, message_id: u64) -> bool {
+        self.messages_by_channel
+            .get(channel)
+            .is_some_and(|messages| messages.iter().any(|msg| msg.id == message_id))
+    }
+
+    /// Apply a tally update if it is for the poll being shown in `channel`.
+    pub fn update_poll(&mut self, channel: &str, poll_id: PollId, tallies: Vec<u32>) {
+        if let Some(poll) = self.polls.get_mut(channel).filter(|p| p.id == poll_id) {
+            poll.tallies = tallies;
+        }
+    }
+
+    /// Slot fetched messages (history or a resync) into the transcript by id,
+    /// skipping any already shown unless they have since been deleted.
+    /// Pending messages keep their place at the end.
+    pub fn merge_missed(&mut self, channel: &str, missed: Vec<ChatMessage>) {
+        if let Some(seq) = missed.iter().map(|m| m.seq).max() {
+            self.record_seq(channel, seq);
+        }
+        let entry = self.messages_by_channel.entry(channel.to_string()).or_default();
+        for msg in missed {
+            if let Some(known) = entry.iter_mut().find(|m| m.id == msg.id) {
+                if msg.deleted {
+                    *known = msg;
+                }
+                continue;
+            }
+            let at = entry
+                .iter()
+                .position(|m| m.id == PENDING_MESSAGE_ID || m.id > msg.id)
+                .unwrap_or(entry.len());
+            entry.insert(at, msg);
+        }
+        if entry.len() > MAX_TRANSCRIPT {
+            let overflow = entry.len() - MAX_TRANSCRIPT;
+            entry.drain(0..overflow);
+        }
+    }
+
+    /// Note the seq of a live message in `channel`. One that skips past the
+    /// next expected seq means frames were lost, so ask for the ones between.
+    pub fn track_seq(&mut self, channel: &str, seq: u64) {
+        // Pending copies and servers without seqs carry 0.
+        if seq == 0 {
+            return;
+        }
+        if let Some(&last) = self.last_seq.get(channel).filter(|&&last| seq > last + 1) {
+            self.request_resync(channel, last);
+        }
+        self.record_seq(channel, seq);
+    }
+
+    fn record_seq(&mut self, channel: &str, seq: u64) {
+        let last = self.last_seq.entry(channel.to_string()).or_default();
+        *last = (*last).max(seq);
+    }
+
+    /// The seq each channel had reached, taken before a reconnect so the
+    /// resumed session's history can't move it on first.
+    pub fn seen_seqs(&self) -> Vec<(String, u64)> {
+        self.last_seq.iter().map(|(channel, &seq)| (channel.clone(), seq)).collect()
     }
 
-    pub fn remove_message(&mut self, channel: &str, message_id: u64) {
-        if let Some(messages) = self.messages_by_channel.get_mut(channel) {
-            messages.retain(|msg| msg.id != message_id);
+    /// Ask for what each channel missed while we were away.
+    pub fn resync_since(&mut self, seen: Vec<(String, u64)>) {
+        for (channel, since) in seen {
+            self.request_resync(&channel, since);
         }
     }
+
+    /// Merge a `ResyncChunk`; while the server says it stopped short, ask
+    /// again from the last message it sent.
+    pub fn receive_resync(&mut self, channel: &str, messages: Vec<ChatMessage>, truncated: bool) {
+        let last = messages.iter().map(|m| m.seq).max();
+        self.merge_missed(channel, messages);
+        if let Some(last) = last.filter(|_| truncated) {
+            self.request_resync(channel, last);
+        }
+    }
+
+    fn request_resync(&mut self, channel: &str, since_sequence: u64) {
+        if !self.supports(features::RESYNC) {
+            return;
+        }
+        let meta = self.next_meta();
+        self.queue(ClientMessage::Resync { meta, channel: channel.to_string(), since_sequence });
+    }
+
+    /// Remove a message if we have it. Deletes can name messages we never
+    /// loaded (they scrolled out before we joined); returns whether one was removed.
+    pub fn remove_message(&mut self, channel: &str, message_id: u64) -> bool {
+        let Some(messages) = self.messages_by_channel.get_mut(channel) else {
+            return false;
+        };
+        let before = messages.len();
+        messages.retain(|msg| msg.id != message_id);
+        messages.len() != before
+    }
+
+    /// Turn a channel message into a tombstone, as the server's history now
+    /// has it. Returns whether we had the message and it wasn't deleted yet.
+    pub fn tombstone_message(&mut self, channel: &str, message_id: u64) -> bool {
+        let Some(msg) = self
+            .messages_by_channel
+            .get_mut(channel)
+            .and_then(|msgs| msgs.iter_mut().find(|msg| msg.id == message_id && !msg.deleted))
+        else {
+            return false;
+        };
+        msg.deleted = true;
+        msg.content.clear();
+        msg.nonce = None;
+        msg.metadata.clear();
+        true
+    }
+
+    /// Remove a DM from whichever conversation holds it, returning that tab.
+    pub fn remove_dm(&mut self, dm_id: u64) -> Option<String> {
+        let tab = self
+            .messages_by_channel
+            .iter()
+            .find(|(tab, messages)| dm_peer(tab).is_some() && messages.iter().any(|msg| msg.id == dm_id))
+            .map(|(tab, _)| tab.clone())?;
+        self.remove_message(&tab, dm_id);
+        Some(tab)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chat(id: u64) -> ChatMessage {
+        ChatMessage {
+            id,
+            seq: 0,
+            user_id: 1,
+            username: "bob".to_string(),
+            content: b"hi".to_vec(),
+            timestamp: Utc::now(),
+            nonce: None,
+            metadata: MessageMetadata::new(),
+            deleted: false,
+            edited: false,
+        }
+    }
+
+    #[test]
+    fn test_unread_counts_across_switches() {
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        state.open_channel("random");
+        assert_eq!(state.current_channel.as_deref(), Some("random"));
+
+        state.receive_message("general", chat(1));
+        state.receive_message("general", chat(2));
+        state.receive_message("random", chat(3));
+        assert_eq!(state.unread("general"), 2);
+        assert_eq!(state.unread("random"), 0);
+
+        assert!(state.switch_channel("general"));
+        assert_eq!(state.unread("general"), 0);
+
+        state.receive_message("random", chat(4));
+        assert_eq!(state.unread("random"), 1);
+
+        state.switch_relative(1);
+        assert_eq!(state.current_channel.as_deref(), Some("random"));
+        assert_eq!(state.unread("random"), 0);
+
+        assert!(!state.switch_channel("unknown"));
+        assert_eq!(state.current_channel.as_deref(), Some("random"));
+    }
+
+    #[test]
+    fn test_history_does_not_count_as_unread() {
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        state.open_channel("random");
+        state.push_message("general", chat(1));
+        assert_eq!(state.unread("general"), 0);
+    }
+
+    #[test]
+    fn test_resync_fills_gap_in_order() {
+        let mut state = ClientState::new("test".to_string());
+        state.push_message("general", chat(1));
+        state.push_message("general", chat(4));
+        state.push_pending("general", chat(0));
+
+        state.merge_missed("general", vec![chat(2), chat(3), chat(4)]);
+
+        let ids: Vec<_> = state.messages_by_channel["general"].iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, PENDING_MESSAGE_ID]);
+    }
+
+    #[test]
+    fn test_seq_gap_requests_resync_until_caught_up() {
+        let sequenced = |seq| ChatMessage { seq, ..chat(seq) };
+        let resyncs = |state: &mut ClientState| -> Vec<(String, u64)> {
+            state
+                .take_outbox()
+                .into_iter()
+                .filter_map(|msg| match msg {
+                    ClientMessage::Resync { channel, since_sequence, .. } => Some((channel, since_sequence)),
+                    _ => None,
+                })
+                .collect()
+        };
+        let mut state = ClientState::new("test".to_string());
+        for seq in [1, 2] {
+            state.track_seq("general", seq);
+            state.receive_message("general", sequenced(seq));
+        }
+        assert!(resyncs(&mut state).is_empty());
+
+        state.track_seq("general", 6);
+        state.receive_message("general", sequenced(6));
+        assert_eq!(resyncs(&mut state), vec![("general".to_string(), 2)]);
+
+        // The server stopped short: keep asking from where it got to.
+        state.receive_resync("general", vec![sequenced(3), sequenced(4)], true);
+        assert_eq!(resyncs(&mut state), vec![("general".to_string(), 4)]);
+        state.receive_resync("general", vec![sequenced(5)], false);
+        assert!(resyncs(&mut state).is_empty());
+
+        let ids: Vec<_> = state.messages_by_channel["general"].iter().map(|m| m.id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6]);
+
+        // After a reconnect, pick up from the newest seq seen.
+        let seen = state.seen_seqs();
+        state.resync_since(seen);
+        assert_eq!(resyncs(&mut state), vec![("general".to_string(), 6)]);
+    }
+
+    #[test]
+    fn test_fetched_tombstone_replaces_cached_message() {
+        let mut state = ClientState::new("test".to_string());
+        state.push_message("general", chat(1));
+        state.push_message("general", chat(2));
+
+        let tombstone = ChatMessage { deleted: true, content: Vec::new(), ..chat(2) };
+        state.merge_missed("general", vec![chat(1), tombstone, chat(3)]);
+
+        let msgs = &state.messages_by_channel["general"];
+        assert_eq!(msgs.iter().map(|m| (m.id, m.deleted)).collect::<Vec<_>>(), [(1, false), (2, true), (3, false)]);
+        assert!(msgs[1].content.is_empty());
+        assert!(!state.tombstone_message("general", 2), "already a tombstone");
+        assert!(state.tombstone_message("general", 3));
+    }
+
+    #[test]
+    fn test_same_message_id_is_kept_once() {
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("random");
+        state.merge_missed("general", vec![chat(1), chat(2)]);
+
+        state.receive_message("general", chat(2));
+        state.push_message("general", chat(2));
+        state.receive_message("general", chat(3));
+        state.receive_message("general", chat(3));
+        let ids: Vec<_> = state.messages_by_channel["general"].iter().map(|m| m.id).collect();
+        assert_eq!(ids, [1, 2, 3]);
+        assert_eq!(state.unread.get("general"), Some(&1), "a repeat isn't unread again");
+
+        state.push_pending("general", chat(0));
+        state.push_pending("general", chat(0));
+        assert_eq!(state.messages_by_channel["general"].len(), 5, "pending messages share id 0 and are all kept");
+    }
+
+    #[test]
+    fn test_close_channel_falls_back_to_last_tab() {
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        state.open_channel("random");
+        state.receive_message("general", chat(1));
+
+        state.close_channel("random");
+        assert_eq!(state.current_channel.as_deref(), Some("general"));
+        state.close_channel("general");
+        assert_eq!(state.current_channel, None);
+        assert_eq!(state.unread("general"), 0);
+    }
+
+    #[test]
+    fn test_cooldown_elapses() {
+        let mut state = ClientState::new("test".to_string());
+        state.start_cooldown("general", Duration::from_secs(30));
+        state.start_cooldown("random", Duration::ZERO);
+
+        assert!(state.cooldown_remaining("general").unwrap() > Duration::from_secs(29));
+        assert_eq!(state.cooldown_remaining("random"), None);
+        assert_eq!(state.cooldown_remaining("other"), None);
+    }
+
+    #[test]
+    fn test_echo_replaces_pending_message() {
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo { id: 1, username: "bob".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.open_channel("general");
+
+        let mut sent = chat(99);
+        sent.metadata.set(CLIENT_MSG_ID_KEY, "7");
+        state.push_pending("general", sent.clone());
+        state.receive_message("general", chat(10));
+        assert_eq!(state.messages_by_channel["general"][0].id, PENDING_MESSAGE_ID);
+
+        let mut echo = sent;
+        echo.id = 11;
+        state.receive_message("general", echo);
+
+        let ids: Vec<_> = state.messages_by_channel["general"].iter().map(|m| m.id).collect();
+        assert_eq!(ids, [11, 10], "echo takes the pending slot, no duplicate");
+    }
+
+    #[test]
+    fn test_missing_echo_leaves_message_pending() {
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo { id: 1, username: "bob".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.open_channel("general");
+
+        let mut first = chat(0);
+        first.metadata.set(CLIENT_MSG_ID_KEY, "7");
+        let mut second = chat(0);
+        second.metadata.set(CLIENT_MSG_ID_KEY, "8");
+        state.push_pending("general", first);
+        state.push_pending("general", second.clone());
+
+        // Only the second send is acknowledged.
+        second.id = 12;
+        state.receive_message("general", second);
+
+        let ids: Vec<_> = state.messages_by_channel["general"].iter().map(|m| m.id).collect();
+        assert_eq!(ids, [PENDING_MESSAGE_ID, 12]);
+        assert_eq!(state.unread("general"), 0);
+    }
+
+    #[test]
+    fn test_rejected_send_drops_its_pending_copy() {
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo { id: 1, username: "bob".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.open_channel("general");
+
+        let mut first = chat(0);
+        first.content = b"too fast".to_vec();
+        first.metadata.set(CLIENT_MSG_ID_KEY, "7");
+        let mut second = chat(0);
+        second.metadata.set(CLIENT_MSG_ID_KEY, "8");
+        state.push_pending("general", first);
+        state.push_pending("general", second);
+
+        assert_eq!(state.reject_pending(7).as_deref(), Some("too fast"));
+        let left: Vec<_> = state.messages_by_channel["general"].iter().map(|m| client_msg_id(m).unwrap()).collect();
+        assert_eq!(left, ["8"]);
+        assert_eq!(state.reject_pending(7), None, "already gone");
+        assert_eq!(state.reject_pending(3), None, "not a send of ours");
+
+        state.return_to_input("too fast".to_string());
+        state.return_to_input("again".to_string());
+        assert_eq!(state.draft.as_deref(), Some("too fast\nagain"));
+    }
+
+    #[test]
+    fn test_dm_opens_tab_for_the_other_user() {
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo {
+            id: 2,
+            username: "alice".to_string(),
+            joined_at: Utc::now(),
+            signing_key: None,
+            public_key: None,
+        });
+        state.open_channel("general");
+
+        state.receive_dm("alice", chat(1));
+        assert_eq!(state.joined_channels, ["general", "@bob"]);
+        assert_eq!(state.current_channel.as_deref(), Some("general"));
+        assert_eq!(state.unread("@bob"), 1);
+
+        let mut own = chat(2);
+        own.user_id = 2;
+        own.username = "alice".to_string();
+        state.receive_dm("bob", own);
+        assert_eq!(state.messages_by_channel["@bob"].len(), 2);
+        assert_eq!(dm_peer("@bob"), Some("bob"));
+        assert_eq!(dm_peer("general"), None);
+
+        assert_eq!(state.remove_dm(2).as_deref(), Some("@bob"));
+        assert_eq!(state.messages_by_channel["@bob"].len(), 1);
+        assert_eq!(state.remove_dm(2), None);
+    }
+
+    #[test]
+    fn test_mention_detection() {
+        assert!(mentions("hey @alice", "alice"));
+        assert!(mentions("@ALICE: ping", "Alice"));
+        assert!(mentions("thanks @alice.", "alice"));
+        assert!(mentions("(@alice)", "alice"));
+        assert!(!mentions("hey @alicebob", "alice"));
+        assert!(!mentions("hey @alice.b", "alice"));
+        assert!(!mentions("hey @alice_2", "alice"));
+        assert!(!mentions("mail bob@alice", "alice"));
+        assert!(!mentions("hey alice", "alice"));
+    }
+
+    #[test]
+    fn test_signed_messages_are_checked_against_published_keys() {
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo { id: 2, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.open_channel("general");
+        let bob = Identity::generate();
+        let mut signed = chat(1);
+        bob.sign("general", b"hi", &mut signed.metadata);
+
+        // Until bob's key arrives there is nothing to check against; ask once.
+        state.receive_message("general", signed.clone());
+        state.receive_message("general", ChatMessage { id: 2, ..signed.clone() });
+        assert!(matches!(state.take_outbox().as_slice(), [ClientMessage::GetUserInfo { username, .. }] if username == "bob"));
+        assert_eq!(state.verification("general", &signed, "hi"), None);
+
+        let bob_info = UserInfo { id: 1, username: "bob".to_string(), joined_at: Utc::now(), signing_key: Some(bob.public_key()), public_key: None };
+        assert!(state.learn_user(&bob_info).answers_lookup, "answers our lookup");
+        assert!(!state.learn_user(&bob_info).answers_lookup, "a later /whois is shown");
+        assert_eq!(state.verification("general", &signed, "hi"), Some(Verification::Verified));
+        assert_eq!(state.verification("general", &signed, "bye"), Some(Verification::Invalid));
+        assert_eq!(state.verification("random", &signed, "hi"), Some(Verification::Invalid));
+        assert_eq!(state.verification("general", &chat(3), "hi"), Some(Verification::Unsigned));
+
+        // A DM is signed for its recipient, whatever case the sender typed.
+        let mut dm = chat(4);
+        bob.sign(&signing_context("@Alice"), b"hi", &mut dm.metadata);
+        assert_eq!(state.verification("@bob", &dm, "hi"), Some(Verification::Verified));
+
+        let replayed = ChatMessage { id: 5, timestamp: signed.timestamp + chrono::Duration::minutes(10), ..signed.clone() };
+        assert_eq!(state.verification("general", &replayed, "hi"), Some(Verification::Invalid));
+
+        // A new key for bob is reported once and never replaces the pinned one.
+        let mallory = Identity::generate();
+        let swapped = UserInfo { signing_key: Some(mallory.public_key()), ..bob_info.clone() };
+        assert!(state.learn_user(&swapped).keys_changed);
+        assert!(!state.learn_user(&swapped).keys_changed, "warned once");
+        let mut forged = chat(6);
+        mallory.sign("general", b"hi", &mut forged.metadata);
+        assert_eq!(state.verification("general", &forged, "hi"), Some(Verification::Invalid));
+
+        state.reset();
+        state.user = Some(UserInfo { id: 9, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.learn_user(&UserInfo { id: 4, ..swapped });
+        assert_eq!(state.verification("general", &signed, "hi"), Some(Verification::Verified), "pins outlive a reconnect");
+        assert_eq!(state.verification("general", &forged, "hi"), Some(Verification::Invalid));
+
+        // And a fresh login to the same server.
+        let mut next = ClientState::new("test".to_string());
+        next.user = state.user.clone();
+        next.restore_pinned_keys(state.take_pinned_keys());
+        assert_eq!(next.verification("general", &forged, "hi"), Some(Verification::Invalid));
+        assert!(next.keys_changed("BOB"));
+    }
+
+    #[test]
+    fn test_end_to_end_dm_reads_only_with_the_recipient_key() {
+        let (alice, bob) = (DmKey::generate(), DmKey::generate());
+        let (content, nonce) = bob.encrypt(&alice.public_key(), b"psst", Default::default()).unwrap();
+        let mut dm = ChatMessage { content, nonce: Some(nonce), ..chat(1) };
+        dm.metadata.set_end_to_end(darkrelayclient::e2e::DM_SCHEME);
+        let bob_info = UserInfo { id: 1, username: "Bob".to_string(), joined_at: Utc::now(), signing_key: None, public_key: Some(bob.public_key()) };
+
+        let reader = |dm_key: DmKey| {
+            let mut state = ClientState::new("test".to_string());
+            state.user = Some(UserInfo { id: 2, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+            state.dm_key = Some(dm_key);
+            state
+        };
+        let mut state = reader(alice);
+        state.receive_dm("alice", dm.clone());
+        assert_eq!(state.message_text("@bob", &dm), Err(AWAITING_KEY));
+        assert!(matches!(state.take_outbox().as_slice(), [ClientMessage::GetUserInfo { username, .. }] if username == "bob"));
+        state.learn_user(&bob_info);
+        assert_eq!(state.message_text("@bob", &dm), Ok("psst".to_string()));
+
+        let mut carol = reader(DmKey::generate());
+        carol.learn_user(&bob_info);
+        assert_eq!(carol.message_text("@bob", &dm), Err(DECRYPT_FAILED));
+
+        let fingerprint = key_fingerprint(&bob_info).unwrap();
+        assert_eq!(fingerprint.split(' ').count(), 8);
+        let other = UserInfo { public_key: Some(DmKey::generate().public_key()), ..bob_info.clone() };
+        assert_ne!(key_fingerprint(&other), Some(fingerprint));
+        assert_eq!(key_fingerprint(&UserInfo { public_key: None, ..bob_info }), None);
+    }
+
+    #[test]
+    fn test_mentions_counted_outside_current_channel() {
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo {
+            id: 2,
+            username: "alice".to_string(),
+            joined_at: Utc::now(),
+            signing_key: None,
+            public_key: None,
+        });
+        state.open_channel("general");
+        state.open_channel("random");
+
+        let mut ping = chat(1);
+        ping.content = b"@alice look".to_vec();
+        state.receive_message("general", ping.clone());
+        state.receive_message("general", chat(2));
+        state.receive_message("random", ping);
+        assert_eq!(state.mention_count("general"), 1);
+        assert_eq!(state.unread("general"), 2);
+        assert_eq!(state.mention_count("random"), 0, "channel in view");
+        assert!(state.take_bell());
+        assert!(!state.take_bell());
+
+        state.switch_channel("general");
+        assert_eq!(state.mention_count("general"), 0);
+    }
+
+    #[test]
+    fn test_events_interleave_by_timestamp() {
+        let mut state = ClientState::new("test".to_string());
+        let t0 = Utc::now();
+        let at = |secs| t0 + chrono::Duration::seconds(secs);
+        let msg = |id, secs| ChatMessage { timestamp: at(secs), ..chat(id) };
+
+        state.push_message("general", msg(1, 10));
+        state.push_message("general", msg(2, 20));
+        state.push_message("general", msg(3, 30));
+        state.push_event("general", at(25), "alice was kicked".to_string());
+        state.push_event("general", at(5), "bob joined".to_string());
+        state.push_event("general", at(40), "slow mode enabled".to_string());
+        state.push_event("general", at(20), "carol joined".to_string());
+        state.push_event("random", at(15), "elsewhere".to_string());
+
+        let lines: Vec<_> = state
+            .transcript("general")
+            .into_iter()
+            .map(|entry| match entry {
+                TranscriptEntry::Message(m) => format!("#{}", m.id),
+                TranscriptEntry::Event(e) => e.text.clone(),
+            })
+            .collect();
+        assert_eq!(
+            lines,
+            ["bob joined", "#1", "#2", "carol joined", "alice was kicked", "#3", "slow mode enabled"],
+            "an event stamped with a message goes after it"
+        );
+
+        state.clear_messages("general");
+        assert!(state.transcript("general").is_empty());
+        assert_eq!(state.transcript("random").len(), 1);
+    }
+
+    #[test]
+    fn test_announced_channels_join_the_listing_in_order() {
+        let info = |id, name: &str| ChannelInfo {
+            id,
+            name: name.to_string(),
+            is_public: true,
+            channel_type: darkrelayprotocol::channel::ChannelType::Public,
+            user_role: None,
+            member_count: 1,
+            max_members: None,
+            topic: None,
+        };
+        let mut state = ClientState::new("test".to_string());
+        state.channels = vec![info(1, "general"), info(2, "random")];
+
+        state.add_listed_channel(info(3, "lobby"));
+        state.add_listed_channel(info(4, "zeta"));
+        state.add_listed_channel(ChannelInfo { member_count: 5, ..info(3, "lobby") });
+
+        let names: Vec<_> = state.channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["general", "lobby", "random", "zeta"]);
+        assert_eq!(state.channels[1].member_count, 5);
+    }
+
+    #[test]
+    fn test_current_channel_info_tracks_role_and_type() {
+        use darkrelayprotocol::channel::ChannelType;
+
+        let info = |id, name: &str, channel_type| ChannelInfo {
+            id,
+            name: name.to_string(),
+            is_public: true,
+            channel_type,
+            user_role: None,
+            member_count: 1,
+            max_members: None,
+            topic: None,
+        };
+        let mut state = ClientState::new("test".to_string());
+        assert!(state.current_channel_info().is_none());
+        assert!(state.can_send_here(), "nothing in view, nothing to gate");
+
+        state.set_channel_list(vec![info(1, "general", ChannelType::Public), info(2, "news", ChannelType::ReadOnly)]);
+        state.open_channel("news");
+        state.add_listed_channel(ChannelInfo { user_role: Some(Role::User), ..info(2, "news", ChannelType::ReadOnly) });
+        assert_eq!(state.current_channel_info().map(|c| c.id), Some(2));
+        assert_eq!(state.current_role(), Role::User);
+        assert!(!state.can_send_here());
+
+        state.set_channel_role("news", Role::Admin);
+        assert!(state.can_send_here());
+        state.set_channel_type("news", ChannelType::Announcement);
+        assert!(!state.can_send_here(), "announcements need SuperAdmin");
+
+        // A fresh listing carries no roles but must not forget ours.
+        state.set_channel_list(vec![info(1, "general", ChannelType::Public), info(2, "news", ChannelType::Announcement)]);
+        assert_eq!(state.current_role(), Role::Admin);
+
+        // Private channels are left out of listings; a joined one stays.
+        state.add_listed_channel(ChannelInfo { is_public: false, user_role: Some(Role::SuperAdmin), ..info(3, "ops", ChannelType::Private) });
+        state.open_channel("ops");
+        state.set_channel_list(vec![info(1, "general", ChannelType::Public)]);
+        let names: Vec<_> = state.channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["general", "news", "ops"]);
+        assert_eq!(state.current_role(), Role::SuperAdmin);
+
+        state.open_channel(&dm_tab("bob"));
+        assert!(state.current_channel_info().is_none());
+        assert!(state.can_send_here());
+    }
+
+    #[test]
+    fn test_non_utf8_plaintext_gets_the_corrupt_marker() {
+        let mut state = ClientState::new("test".to_string());
+        state.crypto.begin_handshake();
+        let server_secret = x25519_dalek::EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        state.crypto.finish_handshake(x25519_dalek::PublicKey::from(&server_secret).as_bytes()).unwrap();
+        let encrypted = |state: &mut ClientState, plaintext: &[u8]| {
+            let (content, nonce) = state.crypto.encrypt(plaintext, Some("general")).unwrap();
+            ChatMessage { content, nonce: Some(nonce), ..chat(1) }
+        };
+
+        let good = encrypted(&mut state, "héllo".as_bytes());
+        assert_eq!(state.message_text("general", &good), Ok("héllo".to_string()));
+
+        let binary = encrypted(&mut state, &[0x68, 0xff, 0xfe, 0x00]);
+        assert_eq!(state.message_text("general", &binary), Err(CORRUPT_MESSAGE));
+        assert_eq!(state.message_text("general", &ChatMessage { content: vec![0xc3], ..chat(2) }), Err(CORRUPT_MESSAGE));
+
+        let tampered = ChatMessage { content: vec![0; 40], ..binary.clone() };
+        assert_eq!(state.message_text("general", &tampered), Err(DECRYPT_FAILED));
+
+        state.receive_message("general", binary);
+        assert_eq!(state.messages_by_channel["general"].len(), 1, "still listed, just unreadable");
+    }
 }
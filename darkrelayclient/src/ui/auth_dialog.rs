@@ -13,6 +13,7 @@ use crossterm::{
 };
 
 use crate::{
+    config::ServerProfile,
     state::AuthMode,
     ui::{clear, TerminalSession},
 };
@@ -36,23 +37,46 @@ enum Field {
 enum Button {
     Login,
     Register,
+    Guest,
     Exit,
 }
 
-pub async fn run(terminal: &mut TerminalSession) -> io::Result<Option<AuthDialogOutput>> {
+/// `profiles` are the saved servers, offered with Up/Down on the server field;
+/// `selected` is preselected.
+pub async fn run(
+    terminal: &mut TerminalSession,
+    profiles: &[ServerProfile],
+    selected: Option<usize>,
+) -> io::Result<Option<AuthDialogOutput>> {
     let mut server_ip = "127.0.0.1".to_string();
     let mut username = String::new();
     let mut password = String::new();
 
+    let mut selected = selected.filter(|i| *i < profiles.len());
+    if let Some(profile) = selected.map(|i| &profiles[i]) {
+        server_ip = profile.address.clone();
+        username = profile.last_username.clone().unwrap_or_default();
+    }
+
     let mut field = Field::Server;
     let mut button = Button::Login;
 
     loop {
         draw(terminal, &server_ip, &username, &password, field, button, None)?;
+        draw_profile_hint(terminal, profiles, selected)?;
 
         if event::poll(Duration::from_millis(50))? {
             let ev = event::read()?;
             if let Event::Key(key) = ev {
+                if matches!(field, Field::Server) && matches!(key.code, KeyCode::Up | KeyCode::Down) {
+                    if let Some(i) = cycle_profile(profiles.len(), selected, key.code == KeyCode::Down) {
+                        selected = Some(i);
+                        server_ip = profiles[i].address.clone();
+                        username = profiles[i].last_username.clone().unwrap_or_default();
+                    }
+                    continue;
+                }
+
                 if handle_key(
                     key,
                     &mut server_ip,
@@ -75,10 +99,18 @@ pub async fn run(terminal: &mut TerminalSession) -> io::Result<Option<AuthDialog
                             return Ok(Some(AuthDialogOutput {
                                 server_ip,
                                 username,
-                                password: String::new(),
+                                password,
                                 mode: AuthMode::Register,
                             }));
                         }
+                        Button::Guest => {
+                            return Ok(Some(AuthDialogOutput {
+                                server_ip,
+                                username: String::new(),
+                                password: String::new(),
+                                mode: AuthMode::Guest,
+                            }));
+                        }
                     }
                 }
             }
@@ -86,6 +118,35 @@ pub async fn run(terminal: &mut TerminalSession) -> io::Result<Option<AuthDialog
     }
 }
 
+fn cycle_profile(len: usize, selected: Option<usize>, forward: bool) -> Option<usize> {
+    if len == 0 {
+        return None;
+    }
+    Some(match (selected, forward) {
+        (None, true) => 0,
+        (None, false) => len - 1,
+        (Some(i), true) => (i + 1) % len,
+        (Some(i), false) => (i + len - 1) % len,
+    })
+}
+
+fn draw_profile_hint(terminal: &mut TerminalSession, profiles: &[ServerProfile], selected: Option<usize>) -> io::Result<()> {
+    if profiles.is_empty() {
+        return Ok(());
+    }
+
+    let hint = match selected {
+        Some(i) => format!("saved: {} ({}/{}), Up/Down to switch", profiles[i].name, i + 1, profiles.len()),
+        None => format!("{} saved servers, Up/Down to pick", profiles.len()),
+    };
+    execute!(
+        terminal.stdout(),
+        cursor::MoveTo(14, 4),
+        Print(hint.with(Color::DarkGrey)),
+    )?;
+    terminal.stdout().flush()
+}
+
 pub fn draw_processing(terminal: &mut TerminalSession, spinner: &str) -> io::Result<()> {
     clear(terminal)?;
 
@@ -138,7 +199,8 @@ fn handle_key(
                 *button = match button {
                     Button::Login => Button::Exit,
                     Button::Register => Button::Login,
-                    Button::Exit => Button::Register,
+                    Button::Guest => Button::Register,
+                    Button::Exit => Button::Guest,
                 };
             }
         }
@@ -146,7 +208,8 @@ fn handle_key(
             if matches!(field, Field::Buttons) {
                 *button = match button {
                     Button::Login => Button::Register,
-                    Button::Register => Button::Exit,
+                    Button::Register => Button::Guest,
+                    Button::Guest => Button::Exit,
                     Button::Exit => Button::Login,
                 };
             }
@@ -244,6 +307,7 @@ fn draw(
         button == Button::Register,
         matches!(field, Field::Buttons),
     );
+    let guest = style_button("Guest", button == Button::Guest, matches!(field, Field::Buttons));
     let exit = style_button("Exit", button == Button::Exit, matches!(field, Field::Buttons));
 
     execute!(
@@ -253,6 +317,8 @@ fn draw(
         cursor::MoveTo(12, 10),
         Print(register),
         cursor::MoveTo(25, 10),
+        Print(guest),
+        cursor::MoveTo(35, 10),
         Print(exit),
     )?;
 
@@ -288,3 +354,4 @@ fn style_button(label: &str, selected: bool, active: bool) -> String {
         format!(" {} ", label)
     }
 }
+
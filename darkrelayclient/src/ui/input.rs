@@ -0,0 +1,157 @@
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Editable input line. Holds literal newlines so multi-line pastes and
+/// Shift+Enter are submitted as a single message.
+#[derive(Debug, Default)]
+pub struct InputBuffer {
+    text: String,
+    /// Longest buffer accepted, in bytes; further typing or pasting is dropped.
+    max_len: Option<usize>,
+}
+
+impl InputBuffer {
+    pub fn new() -> Self {
+        Self { text: String::new(), max_len: None }
+    }
+
+    pub fn set_max_len(&mut self, max_len: usize) {
+        self.max_len = Some(max_len);
+    }
+
+    fn push(&mut self, ch: char) -> bool {
+        if self.max_len.is_some_and(|max| self.text.len() + ch.len_utf8() > max) {
+            return false;
+        }
+        self.text.push(ch);
+        true
+    }
+
+    #[cfg(test)]
+    pub fn as_str(&self) -> &str {
+        &self.text
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.text.clear();
+    }
+
+    /// Whether the buffer holds a `/command` rather than a chat message.
+    pub fn is_command(&self) -> bool {
+        self.text.trim_start().starts_with('/')
+    }
+
+    /// Insert a bracketed paste as one block, normalizing line endings.
+    pub fn paste(&mut self, text: &str) {
+        let normalized = text.replace("\r\n", "\n").replace('\r', "\n");
+        for ch in normalized.chars().filter(|c| *c == '\n' || *c == '\t' || !c.is_control()) {
+            if !self.push(ch) {
+                break;
+            }
+        }
+    }
+
+    /// Apply a key press. Enter submits and returns the trimmed buffer (if not
+    /// blank); Shift+Enter or Alt+Enter inserts a newline instead.
+    pub fn handle_key(&mut self, key: KeyEvent) -> Option<String> {
+        match key.code {
+            KeyCode::Enter if key.modifiers.intersects(KeyModifiers::SHIFT | KeyModifiers::ALT) => {
+                self.push('\n');
+                None
+            }
+            KeyCode::Enter => {
+                let line = self.text.trim().to_string();
+                self.text.clear();
+                if line.is_empty() {
+                    None
+                } else {
+                    Some(line)
+                }
+            }
+            KeyCode::Backspace => {
+                self.text.pop();
+                None
+            }
+            KeyCode::Char(ch) if !ch.is_control() => {
+                self.push(ch);
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Single-row rendering: newlines are shown as `↵`.
+    pub fn display(&self) -> String {
+        self.text.replace('\n', "↵")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(code: KeyCode, modifiers: KeyModifiers) -> KeyEvent {
+        KeyEvent::new(code, modifiers)
+    }
+
+    #[test]
+    fn test_paste_is_one_block() {
+        let mut input = InputBuffer::new();
+        input.handle_key(key(KeyCode::Char('>'), KeyModifiers::NONE));
+        input.paste("line one\r\nline two\rline three\n");
+        assert_eq!(input.as_str(), ">line one\nline two\nline three\n");
+        assert_eq!(input.display(), ">line one↵line two↵line three↵");
+
+        let submitted = input.handle_key(key(KeyCode::Enter, KeyModifiers::NONE));
+        assert_eq!(submitted.as_deref(), Some(">line one\nline two\nline three"));
+        assert_eq!(input.as_str(), "");
+    }
+
+    #[test]
+    fn test_paste_drops_control_chars() {
+        let mut input = InputBuffer::new();
+        input.paste("a\u{1b}[31mb\tc");
+        assert_eq!(input.as_str(), "a[31mb\tc");
+    }
+
+    #[test]
+    fn test_enter_submits_shift_enter_inserts_newline() {
+        let mut input = InputBuffer::new();
+        for ch in "hi".chars() {
+            input.handle_key(key(KeyCode::Char(ch), KeyModifiers::NONE));
+        }
+
+        assert_eq!(input.handle_key(key(KeyCode::Enter, KeyModifiers::SHIFT)), None);
+        assert_eq!(input.as_str(), "hi\n");
+
+        input.handle_key(key(KeyCode::Char('x'), KeyModifiers::NONE));
+        assert_eq!(
+            input.handle_key(key(KeyCode::Enter, KeyModifiers::NONE)).as_deref(),
+            Some("hi\nx")
+        );
+    }
+
+    #[test]
+    fn test_enter_on_blank_input_submits_nothing() {
+        let mut input = InputBuffer::new();
+        input.paste("  \n ");
+        assert_eq!(input.handle_key(key(KeyCode::Enter, KeyModifiers::NONE)), None);
+        assert_eq!(input.as_str(), "");
+    }
+
+    #[test]
+    fn test_max_len_caps_typing_and_paste() {
+        let mut input = InputBuffer::new();
+        input.set_max_len(6);
+        input.paste("abcé");
+        assert_eq!(input.as_str(), "abcé");
+        input.handle_key(key(KeyCode::Char('é'), KeyModifiers::NONE));
+        assert_eq!(input.as_str(), "abcé", "a two-byte char doesn't fit in one byte");
+        input.handle_key(key(KeyCode::Char('x'), KeyModifiers::NONE));
+        input.paste("yz");
+        assert_eq!(input.as_str(), "abcéx");
+    }
+}
@@ -1,9 +1,9 @@
 use std::{
     io,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-use chrono::Local;
+use chrono::{DateTime, Local, Utc};
 use crossterm::{
     cursor,
     event::{self, Event, KeyCode, KeyModifiers},
@@ -12,12 +12,26 @@ use crossterm::{
     terminal,
 };
 
-use darkrelayprotocol::protocol::{ClientMessage, ServerMessage};
+use darkrelayprotocol::{
+    channel::ChannelType,
+    metadata::MessageMetadata,
+    permissions::{has_permission, Permission, Role},
+    protocol::{
+        features, max_content_len, ChannelInfo, ChatMessage, ClientMessage, MessageId, ServerMessage, UserInfo, CHANNEL_NAME_MAX_LEN,
+        JOIN_INVALID_PASSWORD, JOIN_PASSWORD_REQUIRED, MAX_HISTORY_LEN, MOTD_PREFIX,
+    },
+};
+
+use darkrelayclient::{connection::Connection, e2e::DM_SCHEME, signing::Verification};
 
 use crate::{
-    connection::Connection,
-    state::ClientState,
-    ui::{clear, toast, TerminalSession, ToastKind},
+    heartbeat::LinkState,
+    state::{
+        dm_peer, dm_tab, is_action, key_fingerprint, Capabilities, ClientState, LearnedUser, Poll, SystemEvent, TranscriptEntry, UnsentMessage, ACTION_TYPE,
+        CLIENT_MSG_ID_KEY,
+        PENDING_MESSAGE_ID,
+    },
+    ui::{clear, input::InputBuffer, reset_screen, toast, TerminalSession, ToastKind},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -32,13 +46,46 @@ pub async fn run(
     conn: &mut Connection,
 ) -> io::Result<()> {
     let mut focus = Focus::Input;
-    let mut input = String::new();
+    let mut input = InputBuffer::new();
     let mut selected_channel_idx: usize = 0;
 
+    resend_unsent(terminal, state, conn)?;
+    restore_draft(terminal, state, &mut input)?;
+
     loop {
+        if state.disconnecting {
+            return await_disconnect_ack(terminal, state, conn).await;
+        }
         while let Some(msg) = conn.try_recv() {
             handle_server_message(terminal, state, msg)?;
         }
+        for msg in state.take_outbox() {
+            conn.send(msg)?;
+        }
+        if state.take_bell() {
+            execute!(terminal.stdout(), Print('\x07'))?;
+        }
+        state.crypto.evict_retired(Instant::now());
+        if let Some(away) = state.idle.poll(Instant::now()) {
+            send_presence(state, conn, away)?;
+        }
+        if let Some(nonce) = state.heartbeat.poll(Instant::now()) {
+            conn.send(ClientMessage::Ping { meta: state.next_meta(), nonce })?;
+        }
+        if let Some(reason) = conn.lost() {
+            return Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                format!("connection lost: {reason}"),
+            ));
+        }
+
+        input.set_max_len(state.max_message_len());
+        // A refused send comes back once the line is free, not over what's being typed.
+        if input.is_empty() && state.password_prompt.is_none() {
+            if let Some(draft) = state.draft.take() {
+                input.paste(&draft);
+            }
+        }
 
         if state.channels.is_empty() {
             selected_channel_idx = 0;
@@ -48,56 +95,103 @@ pub async fn run(
 
         if event::poll(Duration::from_millis(25))? {
             let ev = event::read()?;
+            if matches!(ev, Event::Key(_) | Event::Paste(_)) {
+                if let Some(away) = state.idle.activity(Instant::now()) {
+                    send_presence(state, conn, away)?;
+                }
+            }
+            if let Event::Paste(text) = &ev {
+                if focus == Focus::Input {
+                    input.paste(text);
+                }
+            }
             if let Event::Key(key) = ev {
                 if key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL) {
                     request_disconnect(state, conn)?;
-                    return Ok(());
+                    continue;
+                }
+
+                if key.code == KeyCode::Char('l') && key.modifiers.contains(KeyModifiers::CONTROL) {
+                    reset_screen(terminal)?;
+                    draw(terminal, state, focus, &input.display(), selected_channel_idx)?;
+                    continue;
+                }
+
+                if key.modifiers.contains(KeyModifiers::ALT)
+                    && matches!(key.code, KeyCode::Left | KeyCode::Right | KeyCode::Char('1'..='9'))
+                {
+                    match key.code {
+                        KeyCode::Left => state.switch_relative(-1),
+                        KeyCode::Right => state.switch_relative(1),
+                        KeyCode::Char(d @ '1'..='9') => {
+                            let idx = d as usize - '1' as usize;
+                            if let Some(ch) = state.joined_channels.get(idx).cloned() {
+                                state.switch_channel(&ch);
+                            }
+                        }
+                        _ => {}
+                    }
+                    draw(terminal, state, focus, &input.display(), selected_channel_idx)?;
+                    continue;
+                }
+
+                // Hold chat messages in the buffer while the server has us on cooldown.
+                if focus == Focus::Input
+                    && key.code == KeyCode::Enter
+                    && key.modifiers.is_empty()
+                    && !input.is_command()
+                    && state.password_prompt.is_none()
+                {
+                    let cooling = state
+                        .current_channel
+                        .as_deref()
+                        .and_then(|ch| state.cooldown_remaining(ch));
+                    if let Some(left) = cooling {
+                        toast(terminal, &format!("Slow down: wait {}s", left.as_secs() + 1), ToastKind::Error)?;
+                        draw(terminal, state, focus, &input.display(), selected_channel_idx)?;
+                        continue;
+                    }
+                }
+
+                if focus == Focus::Input && matches!(key.code, KeyCode::Enter | KeyCode::Backspace | KeyCode::Char(_)) {
+                    if let Some(line) = input.handle_key(key) {
+                        handle_input_line(terminal, state, conn, &line)?;
+                    }
+                    draw(terminal, state, focus, &input.display(), selected_channel_idx)?;
+                    continue;
                 }
 
                 match key.code {
+                    KeyCode::Esc if state.password_prompt.is_some() => {
+                        state.password_prompt = None;
+                        input.clear();
+                    }
+                    KeyCode::Esc if state.motd.is_some() => state.motd = None,
                     KeyCode::Esc => {
                         request_disconnect(state, conn)?;
-                        return Ok(());
+                        continue;
                     }
                     KeyCode::Left => focus = Focus::Channels,
                     KeyCode::Right => focus = Focus::Input,
-                    KeyCode::Up => {
-                        if focus == Focus::Channels {
-                            selected_channel_idx = selected_channel_idx.saturating_sub(1);
-                        }
+                    KeyCode::Up if focus == Focus::Channels => {
+                        selected_channel_idx = selected_channel_idx.saturating_sub(1);
                     }
-                    KeyCode::Down => {
-                        if focus == Focus::Channels && selected_channel_idx + 1 < state.channels.len() {
-                            selected_channel_idx += 1;
-                        }
+                    KeyCode::Down
+                        if focus == Focus::Channels && selected_channel_idx + 1 < state.channels.len() =>
+                    {
+                        selected_channel_idx += 1;
                     }
-                    KeyCode::Enter => match focus {
-                        Focus::Input => {
-                            let line = input.trim().to_string();
-                            input.clear();
-                            if !line.is_empty() {
-                                handle_input_line(terminal, state, conn, &line)?;
-                            }
-                        }
-                        Focus::Channels => {
-                            if let Some(ch) = state.channels.get(selected_channel_idx).cloned() {
-                                let meta = state.next_meta();
-                                conn.send(ClientMessage::JoinChannel {
-                                    meta,
-                                    name: ch.name.clone(),
-                                    password: None,
-                                })?;
+                    KeyCode::Enter => {
+                        if let Some(ch) = state.channels.get(selected_channel_idx).cloned() {
+                            if state.switch_channel(&ch.name) {
+                                continue;
                             }
-                        }
-                    },
-                    KeyCode::Backspace => {
-                        if focus == Focus::Input {
-                            input.pop();
-                        }
-                    }
-                    KeyCode::Char(ch) => {
-                        if focus == Focus::Input {
-                            input.push(ch);
+                            let meta = state.next_meta();
+                            conn.send(ClientMessage::JoinChannel {
+                                meta,
+                                name: ch.name.clone(),
+                                password: None,
+                            })?;
                         }
                     }
                     _ => {}
@@ -105,26 +199,73 @@ pub async fn run(
             }
         }
 
-        draw(terminal, state, focus, &input, selected_channel_idx)?;
+        draw(terminal, state, focus, &input.display(), selected_channel_idx)?;
         tokio::time::sleep(Duration::from_millis(33)).await;
     }
 }
 
+fn send_presence(state: &mut ClientState, conn: &mut Connection, away: bool) -> io::Result<()> {
+    conn.send(ClientMessage::SetPresence {
+        meta: state.next_meta(),
+        away,
+    })
+}
+
+/// How long to wait for the server's `DisconnectAck` before closing anyway.
+const DISCONNECT_ACK_TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Ask the server to end the session. `run` then waits for the ack and returns.
 fn request_disconnect(state: &mut ClientState, conn: &mut Connection) -> io::Result<()> {
     let _ = conn.send(ClientMessage::Disconnect {
         meta: state.next_meta(),
     });
+    state.disconnecting = true;
     Ok(())
 }
 
+/// Keep handling what the server sends until it acknowledges the disconnect,
+/// so messages already in flight aren't dropped. Gives up after
+/// `DISCONNECT_ACK_TIMEOUT`, or when the connection closes first (servers
+/// that predate the ack just close).
+async fn await_disconnect_ack(
+    terminal: &mut TerminalSession,
+    state: &mut ClientState,
+    conn: &mut Connection,
+) -> io::Result<()> {
+    state.disconnecting = false;
+    let deadline = tokio::time::Instant::now() + DISCONNECT_ACK_TIMEOUT;
+    loop {
+        match tokio::time::timeout_at(deadline, conn.recv()).await {
+            Ok(Ok(Some(ServerMessage::DisconnectAck { .. }))) => return Ok(()),
+            Ok(Ok(Some(msg))) => handle_server_message(terminal, state, msg)?,
+            Ok(Ok(None)) | Ok(Err(_)) => return Ok(()),
+            Err(_) => {
+                tracing::debug!("no disconnect ack from server");
+                return Ok(());
+            }
+        }
+    }
+}
+
 fn handle_input_line(
     terminal: &mut TerminalSession,
     state: &mut ClientState,
     conn: &mut Connection,
     line: &str,
 ) -> io::Result<()> {
-    if line.starts_with('/') {
-        return handle_command(terminal, state, conn, line);
+    if let Some(channel) = state.password_prompt.take() {
+        let meta = state.next_meta();
+        return conn.send(ClientMessage::JoinChannel { meta, name: channel, password: Some(line.to_string()) });
+    }
+
+    let (text, action) = match line.strip_prefix("/me ") {
+        Some(rest) => (rest.trim_start(), true),
+        None if line.starts_with('/') => return handle_command(terminal, state, conn, line),
+        None => (line, false),
+    };
+    if text.trim().is_empty() {
+        toast(terminal, "Usage: /me <action>", ToastKind::Error)?;
+        return Ok(());
     }
 
     let Some(channel) = state.current_channel.clone() else {
@@ -132,25 +273,261 @@ fn handle_input_line(
         return Ok(());
     };
 
+    if dm_peer(&channel).is_none() && !state.can_send_here() {
+        toast(terminal, &format!("Only admins can post in #{channel}"), ToastKind::Error)?;
+        return Ok(());
+    }
+    send_text(terminal, state, conn, &channel, text, action)
+}
+
+/// Send what the last connection dropped. A resumed session is back in its
+/// channels by now. Whatever isn't sent stays queued, in order.
+fn resend_unsent(terminal: &mut TerminalSession, state: &mut ClientState, conn: &mut Connection) -> io::Result<()> {
+    let mut unsent = std::mem::take(&mut state.unsent).into_iter();
+    while let Some(line) = unsent.next() {
+        if let Err(e) = send_text(terminal, state, conn, &line.tab, &line.text, line.action) {
+            state.unsent.extend(unsent);
+            return Err(e);
+        }
+    }
+    Ok(())
+}
+
+/// Put back what the previous session couldn't send, saying so.
+fn restore_draft(terminal: &mut TerminalSession, state: &mut ClientState, input: &mut InputBuffer) -> io::Result<()> {
+    let Some(draft) = state.draft.take() else {
+        return Ok(());
+    };
+    input.paste(&draft);
+    toast(terminal, "The session couldn't be resumed, so your last message wasn't sent; it is back in the input line", ToastKind::Error)
+}
+
+/// Send a chat line to `tab`. If the connection turns out to be gone the
+/// line is kept for `run` to send again after reconnecting, and the session
+/// ends so that it does.
+fn send_text(
+    terminal: &mut TerminalSession,
+    state: &mut ClientState,
+    conn: &mut Connection,
+    tab: &str,
+    text: &str,
+    action: bool,
+) -> io::Result<()> {
+    let sent = match dm_peer(tab) {
+        Some(peer) => send_dm(terminal, state, conn, peer, text, action),
+        None => send_to_channel(terminal, state, conn, tab, text, action),
+    };
+    match sent {
+        Err(e) if e.kind() == io::ErrorKind::BrokenPipe => {
+            state.unsent.push(UnsentMessage { tab: tab.to_string(), text: text.to_string(), action });
+            toast(terminal, "Connection lost; your message will be sent after reconnecting", ToastKind::Error)?;
+            Err(io::Error::new(io::ErrorKind::ConnectionAborted, "connection lost while sending"))
+        }
+        other => other,
+    }
+}
+
+fn send_to_channel(
+    terminal: &mut TerminalSession,
+    state: &mut ClientState,
+    conn: &mut Connection,
+    channel: &str,
+    text: &str,
+    action: bool,
+) -> io::Result<()> {
+    if !fits_message_limit(terminal, state, text.len())? {
+        return Ok(());
+    }
+
     // Encrypt the message if ECDH is complete
-    let (content, metadata) = if state.crypto.is_ready() {
-        let (ciphertext, nonce) = state.crypto.encrypt(line.as_bytes(), Some(&channel))?;
-        let nonce_hex = hex::encode(&nonce);
-        (ciphertext, vec![("nonce".to_string(), nonce_hex)])
+    let mut metadata = MessageMetadata::new();
+    let content = if state.crypto.is_ready() {
+        let (ciphertext, nonce) = state.crypto.encrypt(text.as_bytes(), Some(channel))?;
+        metadata.set_nonce(&nonce);
+        metadata.set_key_epoch(state.crypto.epoch());
+        ciphertext
     } else {
-        (line.as_bytes().to_vec(), Vec::new())
+        text.as_bytes().to_vec()
     };
+    if content.len() > max_content_len(state.max_message_len()) {
+        toast(terminal, "Message too long once padded; try a smaller DARKRELAY_PADDING", ToastKind::Error)?;
+        return Ok(());
+    }
+
+    let meta = state.next_meta();
+    metadata.set(CLIENT_MSG_ID_KEY, meta.id.to_string());
+    if action {
+        metadata.set_message_type(ACTION_TYPE);
+    }
+    state.sign(channel, text.as_bytes(), &mut metadata);
 
     conn.send(ClientMessage::SendMessage {
-        meta: state.next_meta(),
-        channel,
-        content,
-        metadata,
+        meta,
+        channel: channel.to_string(),
+        content: content.clone(),
+        metadata: metadata.clone(),
     })?;
 
+    push_local_copy(state, channel, content, metadata);
+    Ok(())
+}
+
+/// Toast and return `false` when `len` plaintext bytes are over the server's
+/// limit. The input stops there already, but the limit can shrink on reconnect.
+fn fits_message_limit(terminal: &mut TerminalSession, state: &ClientState, len: usize) -> io::Result<bool> {
+    let max = state.max_message_len();
+    if len <= max {
+        return Ok(true);
+    }
+    toast(terminal, &format!("Message too long: the server accepts up to {max} bytes"), ToastKind::Error)?;
+    Ok(false)
+}
+
+/// DMs are encrypted end to end to the recipient's published key. Until we
+/// have that key they are held, and sent once it arrives.
+fn send_dm(
+    terminal: &mut TerminalSession,
+    state: &mut ClientState,
+    conn: &mut Connection,
+    recipient: &str,
+    text: &str,
+    action: bool,
+) -> io::Result<()> {
+    if !state.supports(features::DMS) {
+        toast(terminal, "This server doesn't offer DMs", ToastKind::Error)?;
+        return Ok(());
+    }
+    // The server refuses blank messages too; don't leave a local copy that never arrives.
+    if text.trim().is_empty() {
+        return Ok(());
+    }
+    if !fits_message_limit(terminal, state, text.len())? {
+        return Ok(());
+    }
+    if state.dm_key.is_none() {
+        toast(terminal, "No DM key to encrypt with; DMs are unavailable", ToastKind::Error)?;
+        return Ok(());
+    }
+    if state.keys_changed(recipient) {
+        toast(terminal, &format!("{recipient}'s keys changed, so the DM wasn't sent; /trust {recipient} once you've checked them"), ToastKind::Error)?;
+        return Ok(());
+    }
+    if state.dm_public_key(recipient).is_none() {
+        state.hold_dm(recipient, text, action);
+        return Ok(());
+    }
+    seal_dm(state, recipient, text, action, |_, msg| conn.send(msg))
+}
+
+/// Encrypt, sign and `deliver` a DM to `recipient`, whose key we have, then
+/// show our copy.
+fn seal_dm(
+    state: &mut ClientState,
+    recipient: &str,
+    text: &str,
+    action: bool,
+    deliver: impl FnOnce(&mut ClientState, ClientMessage) -> io::Result<()>,
+) -> io::Result<()> {
+    let (Some(dm_key), Some(peer_key)) = (&state.dm_key, state.dm_public_key(recipient)) else {
+        return Err(io::Error::other("no DM key"));
+    };
+    let (content, nonce) = dm_key.encrypt(peer_key, text.as_bytes(), state.crypto.padding())?;
+
+    let meta = state.next_meta();
+    let mut metadata = MessageMetadata::new().with(CLIENT_MSG_ID_KEY, meta.id.to_string());
+    metadata.set_nonce(&nonce);
+    metadata.set_end_to_end(DM_SCHEME);
+    if action {
+        metadata.set_message_type(ACTION_TYPE);
+    }
+    state.sign(&dm_tab(recipient), text.as_bytes(), &mut metadata);
+
+    deliver(
+        state,
+        ClientMessage::SendDM {
+            meta,
+            recipient: recipient.to_string(),
+            content: content.clone(),
+            metadata: metadata.clone(),
+        },
+    )?;
+
+    push_local_copy(state, &dm_tab(recipient), content, metadata);
+    Ok(())
+}
+
+/// Take in `user`'s published keys, warning once if they stop matching the
+/// ones we pinned.
+fn learn_user(terminal: &mut TerminalSession, state: &mut ClientState, user: &UserInfo) -> io::Result<LearnedUser> {
+    let learned = state.learn_user(user);
+    if learned.keys_changed {
+        let fingerprint = key_fingerprint(user).unwrap_or_else(|| "none".to_string());
+        let text = format!(
+            "{name}'s keys changed (now {fingerprint})! Their messages won't verify and DMs to them are blocked. Check the fingerprint with them, then /trust {name}",
+            name = user.username,
+        );
+        toast(terminal, &text, ToastKind::Error)?;
+    }
+    Ok(learned)
+}
+
+/// Send or give up on the DMs held for `user`, now that we know its keys.
+fn release_held_dms(terminal: &mut TerminalSession, state: &mut ClientState, user: &UserInfo) -> io::Result<()> {
+    let held = state.take_held_dms(&user.username);
+    if held.is_empty() {
+        return Ok(());
+    }
+    if user.public_key.is_none() {
+        toast(terminal, &format!("{} has no DM key, so the DM wasn't sent", user.username), ToastKind::Error)?;
+        return Ok(());
+    }
+    if state.keys_changed(&user.username) {
+        toast(terminal, &format!("{}'s keys changed, so the DM wasn't sent", user.username), ToastKind::Error)?;
+        return Ok(());
+    }
+    for dm in held {
+        seal_dm(state, &dm.recipient, &dm.text, dm.action, |state, msg| {
+            state.queue(msg);
+            Ok(())
+        })?;
+    }
     Ok(())
 }
 
+/// When the server refused one of our sends, take its pending copy out of
+/// the transcript and put the text back in the input line. Returns whether
+/// `request_id` was such a send.
+fn return_rejected_send(state: &mut ClientState, request_id: Option<u64>) -> bool {
+    match request_id.and_then(|id| state.reject_pending(id)) {
+        Some(text) => {
+            state.return_to_input(text);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Render a message we just sent right away; the server's echo replaces it.
+fn push_local_copy(state: &mut ClientState, tab: &str, content: Vec<u8>, metadata: MessageMetadata) {
+    let Some(user) = &state.user else {
+        return;
+    };
+    let nonce = metadata.nonce();
+    let local = ChatMessage {
+        id: PENDING_MESSAGE_ID,
+        seq: 0,
+        user_id: user.id,
+        username: user.username.clone(),
+        content,
+        timestamp: Utc::now(),
+        nonce,
+        metadata,
+        deleted: false,
+        edited: false,
+    };
+    state.push_pending(tab, local);
+}
+
 fn handle_command(
     terminal: &mut TerminalSession,
     state: &mut ClientState,
@@ -166,7 +543,7 @@ fn handle_command(
         ["/help"] => {
             toast(
                 terminal,
-                "Commands: /list, /join <name> [password], /create <name> [password], /quit",
+                "Commands: /list, /join <name> [password], /create <name> [password] [type=<type>] [| topic], /leave [name], /nick <name>, /dm <user> [text], /whois <user>, /trust <user>, /history <n>, /ids, /clear, /delete <id>, /poll <q> | <a> | <b>, /vote <poll> <n>, /quit (Alt+←/→ or Alt+1-9 switch tabs)",
                 ToastKind::Info,
             )?;
         }
@@ -175,18 +552,238 @@ fn handle_command(
                 meta: state.next_meta(),
             })?;
         }
-        ["/join", name] | ["/create", name] => {
-            conn.send(ClientMessage::JoinChannel {
+        ["/ids"] => {
+            state.show_message_ids = !state.show_message_ids;
+            let text = if state.show_message_ids { "Message ids shown" } else { "Message ids hidden" };
+            toast(terminal, text, ToastKind::Info)?;
+        }
+        ["/delete", id] => {
+            let Some(channel) = state.current_channel.clone() else {
+                toast(terminal, "Join a channel first (/join general)", ToastKind::Error)?;
+                return Ok(());
+            };
+            let Some(message_id) = parse_message_id(id) else {
+                toast(terminal, "Usage: /delete <id> (toggle ids with /ids)", ToastKind::Error)?;
+                return Ok(());
+            };
+            if !state.has_message(&channel, message_id) {
+                toast(terminal, &format!("Message #{message_id} not found in #{channel}"), ToastKind::Error)?;
+                return Ok(());
+            }
+            if dm_peer(&channel).is_some() {
+                conn.send(ClientMessage::DeleteDM {
+                    meta: state.next_meta(),
+                    dm_id: message_id,
+                })?;
+                return Ok(());
+            }
+            conn.send(ClientMessage::DeleteMessage {
                 meta: state.next_meta(),
-                name: (*name).to_string(),
-                password: None,
+                channel,
+                message_id,
+            })?;
+        }
+        ["/leave"] | ["/part"] => {
+            let Some(channel) = state.current_channel.clone() else {
+                toast(terminal, "Not in a channel", ToastKind::Error)?;
+                return Ok(());
+            };
+            if dm_peer(&channel).is_some() {
+                // DM tabs are local; there is nothing to leave on the server.
+                state.close_channel(&channel);
+                return Ok(());
+            }
+            conn.send(ClientMessage::LeaveChannel {
+                meta: state.next_meta(),
+                channel,
+            })?;
+        }
+        ["/leave", name] | ["/part", name] => {
+            conn.send(ClientMessage::LeaveChannel {
+                meta: state.next_meta(),
+                channel: (*name).to_string(),
+            })?;
+        }
+        ["/nick", name] => {
+            conn.send(ClientMessage::Rename {
+                meta: state.next_meta(),
+                new_username: (*name).to_string(),
+            })?;
+        }
+        ["/dm", ..] if !state.supports(features::DMS) => {
+            toast(terminal, "This server doesn't offer DMs", ToastKind::Error)?;
+        }
+        ["/poll", ..] | ["/vote", ..] if !state.supports(features::POLLS) => {
+            toast(terminal, "This server doesn't offer polls", ToastKind::Error)?;
+        }
+        ["/dm", recipient] => {
+            state.open_channel(&dm_tab(recipient));
+        }
+        ["/dm", recipient, ..] => {
+            let text = line
+                .trim_start()
+                .splitn(3, char::is_whitespace)
+                .nth(2)
+                .unwrap_or_default()
+                .trim_start();
+            let tab = dm_tab(recipient);
+            state.open_channel(&tab);
+            send_text(terminal, state, conn, &tab, text, false)?;
+        }
+        ["/whois", username] => {
+            conn.send(ClientMessage::GetUserInfo {
+                meta: state.next_meta(),
+                username: (*username).to_string(),
+            })?;
+        }
+        ["/trust", username] => {
+            state.trust_new_keys(username);
+            toast(terminal, &format!("Trusting the keys {username} publishes now"), ToastKind::Info)?;
+        }
+        ["/createuser", username, password @ ..] if password.len() <= 1 => {
+            conn.send(ClientMessage::CreateUser {
+                meta: state.next_meta(),
+                username: (*username).to_string(),
+                password: password.first().map(|p| (*p).to_string()),
+            })?;
+        }
+        ["/disconnect", username, ..] => {
+            let reason = line
+                .trim_start()
+                .splitn(3, char::is_whitespace)
+                .nth(2)
+                .map(str::trim)
+                .filter(|r| !r.is_empty())
+                .map(str::to_string);
+            conn.send(ClientMessage::ForceDisconnect {
+                meta: state.next_meta(),
+                username: (*username).to_string(),
+                reason,
+            })?;
+        }
+        ["/motd", ..] => {
+            let motd = match line.trim_start().strip_prefix("/motd").unwrap_or_default().trim() {
+                "" => {
+                    toast(terminal, "Usage: /motd <text> | reload | clear", ToastKind::Error)?;
+                    return Ok(());
+                }
+                "reload" => None,
+                "clear" => Some(String::new()),
+                text => Some(text.to_string()),
+            };
+            conn.send(ClientMessage::SetMotd { meta: state.next_meta(), motd })?;
+        }
+        ["/rotatekey", key] => {
+            conn.send(ClientMessage::RotateSpecialKey {
+                meta: state.next_meta(),
+                new_key: (*key).to_string(),
+            })?;
+        }
+        ["/clear"] => {
+            // Local view only; the server keeps the history.
+            if let Some(channel) = state.current_channel.clone() {
+                state.clear_messages(&channel);
+                toast(terminal, &format!("Cleared #{} (history is kept on the server)", channel), ToastKind::Info)?;
+            }
+        }
+        ["/history", n] => {
+            let Some(n) = n.parse::<usize>().ok().filter(|&n| n > 0) else {
+                toast(terminal, "Usage: /history <count>", ToastKind::Error)?;
+                return Ok(());
+            };
+            let Some(channel) = state.current_channel.clone().filter(|c| dm_peer(c).is_none()) else {
+                toast(terminal, "History is only kept for channels", ToastKind::Error)?;
+                return Ok(());
+            };
+            if n > MAX_HISTORY_LEN {
+                toast(terminal, &format!("The server keeps the last {MAX_HISTORY_LEN} messages; fetching those"), ToastKind::Info)?;
+            }
+            conn.send(ClientMessage::GetHistory {
+                meta: state.next_meta(),
+                channel,
+                limit: n.min(MAX_HISTORY_LEN) as u16,
+            })?;
+        }
+        ["/listall"] => {
+            conn.send(ClientMessage::ListAllChannels {
+                meta: state.next_meta(),
+            })?;
+        }
+        ["/connections"] => {
+            conn.send(ClientMessage::ListConnections {
+                meta: state.next_meta(),
+            })?;
+        }
+        ["/poll", ..] => {
+            let Some(channel) = state.current_channel.clone().filter(|c| dm_peer(c).is_none()) else {
+                toast(terminal, "Polls can only be started in a channel", ToastKind::Error)?;
+                return Ok(());
+            };
+            let Some((question, options)) = parse_poll(line) else {
+                toast(terminal, "Usage: /poll <question> | <option> | <option> ...", ToastKind::Error)?;
+                return Ok(());
+            };
+            conn.send(ClientMessage::CreatePoll {
+                meta: state.next_meta(),
+                channel,
+                question,
+                options,
+            })?;
+        }
+        ["/welcome", ..] => {
+            let Some(channel) = state.current_channel.clone().filter(|c| dm_peer(c).is_none()) else {
+                toast(terminal, "Welcome messages are set per channel", ToastKind::Error)?;
+                return Ok(());
+            };
+            let text = line.trim_start().strip_prefix("/welcome").unwrap_or_default().trim();
+            conn.send(ClientMessage::SetWelcome {
+                meta: state.next_meta(),
+                channel,
+                welcome: (!text.is_empty()).then(|| text.to_string()),
             })?;
         }
-        ["/join", name, password] | ["/create", name, password] => {
+        ["/vote", poll_id, option] => {
+            let Some(channel) = state.current_channel.clone() else {
+                toast(terminal, "Join a channel first (/join general)", ToastKind::Error)?;
+                return Ok(());
+            };
+            // Options are numbered from 1 on screen.
+            let (Ok(poll_id), Some(option_index)) =
+                (poll_id.trim_start_matches('#').parse(), option.parse::<u32>().ok().and_then(|n| n.checked_sub(1)))
+            else {
+                toast(terminal, "Usage: /vote <poll id> <option number>", ToastKind::Error)?;
+                return Ok(());
+            };
+            conn.send(ClientMessage::Vote {
+                meta: state.next_meta(),
+                channel,
+                poll_id,
+                option_index,
+            })?;
+        }
+        ["/join", name, ..] | ["/create", name, ..]
+            if name.trim_start_matches('#').chars().count() > CHANNEL_NAME_MAX_LEN =>
+        {
+            toast(terminal, &format!("Channel names are at most {CHANNEL_NAME_MAX_LEN} characters"), ToastKind::Error)?;
+        }
+        ["/join", name] | ["/join", name, _] => {
             conn.send(ClientMessage::JoinChannel {
                 meta: state.next_meta(),
                 name: (*name).to_string(),
-                password: Some((*password).to_string()),
+                password: parts.get(2).map(|pw| pw.to_string()),
+            })?;
+        }
+        ["/create", _, ..] => {
+            let Some(create) = parse_create(line) else {
+                toast(terminal, "Usage: /create <name> [password] [type=<type>] [| topic]", ToastKind::Error)?;
+                return Ok(());
+            };
+            conn.send(ClientMessage::CreateChannel {
+                meta: state.next_meta(),
+                name: create.name,
+                password: create.password,
+                channel_type: create.channel_type,
+                topic: create.topic,
             })?;
         }
         _ => {
@@ -197,6 +794,23 @@ fn handle_command(
     Ok(())
 }
 
+/// Show a channel event inline in its transcript, or as a toast if we no
+/// longer have a tab for the channel.
+fn channel_event(
+    terminal: &mut TerminalSession,
+    state: &mut ClientState,
+    channel: &str,
+    timestamp: DateTime<Utc>,
+    text: String,
+) -> io::Result<()> {
+    if state.joined_channels.iter().any(|c| c == channel) {
+        state.push_event(channel, timestamp, text);
+        Ok(())
+    } else {
+        toast(terminal, &text, ToastKind::Info)
+    }
+}
+
 fn handle_server_message(
     terminal: &mut TerminalSession,
     state: &mut ClientState,
@@ -204,59 +818,186 @@ fn handle_server_message(
 ) -> io::Result<()> {
     match msg {
         ServerMessage::ChannelList { channels, .. } => {
-            state.channels = channels;
+            state.set_channel_list(channels);
+        }
+        ServerMessage::ChannelCreated { channel, .. } => {
+            state.add_listed_channel(channel);
+        }
+        ServerMessage::AllChannelList { channels, .. } => {
+            let names: Vec<_> = channels
+                .iter()
+                .map(|c| {
+                    let sigil = if c.is_public { "#" } else { "*" };
+                    match c.max_members {
+                        Some(max) => format!("{}{} ({}/{})", sigil, c.name, c.member_count, max),
+                        None => format!("{}{} ({})", sigil, c.name, c.member_count),
+                    }
+                })
+                .collect();
+            toast(terminal, &format!("All channels: {}", names.join(", ")), ToastKind::Info)?;
+        }
+        ServerMessage::ConnectionList { connections, .. } => {
+            let entries: Vec<_> = connections
+                .iter()
+                .map(|c| {
+                    let who = c.username.as_deref().unwrap_or("(not logged in)");
+                    let addr = c.peer_addr.map(|a| a.to_string()).unwrap_or_else(|| "?".to_string());
+                    let mut entry = match &c.current_channel {
+                        Some(ch) => format!("{}:{}@{} in #{}", c.client_id, who, addr, ch),
+                        None => format!("{}:{}@{}", c.client_id, who, addr),
+                    };
+                    if let Some(name) = &c.client_name {
+                        entry.push_str(&format!(" ({} {})", name, c.client_version.as_deref().unwrap_or("?")));
+                    }
+                    entry
+                })
+                .collect();
+            toast(terminal, &format!("Connections: {}", entries.join(", ")), ToastKind::Info)?;
+        }
+        ServerMessage::UserDetails { user, last_seen, online, .. } => {
+            // Our own lookup of a key, not a `/whois`.
+            let learned = learn_user(terminal, state, &user)?;
+            release_held_dms(terminal, state, &user)?;
+            if learned.answers_lookup {
+                return Ok(());
+            }
+            let seen = match (online, last_seen) {
+                (true, _) => "online".to_string(),
+                (false, Some(at)) => format!("last seen {}", at.with_timezone(&Local).format("%Y-%m-%d %H:%M")),
+                (false, None) => "not seen since the server started".to_string(),
+            };
+            let joined = user.joined_at.with_timezone(&Local).format("%Y-%m-%d");
+            let keys = key_fingerprint(&user).map(|fp| format!(", keys {fp}")).unwrap_or_default();
+            toast(terminal, &format!("{}: {}, joined {}{}", user.username, seen, joined, keys), ToastKind::Info)?;
         }
-        ServerMessage::JoinSuccess { channel, .. } => {
-            state.current_channel = Some(channel.name.clone());
-            toast(terminal, &format!("Joined #{}", channel.name), ToastKind::Info)?;
+        ServerMessage::JoinSuccess { channel, rules, .. } => {
+            state.open_channel(&channel.name);
+            toast(terminal, &format!("Joined #{} — {}", channel.name, rules), ToastKind::Info)?;
+            state.channel_rules.insert(channel.name.clone(), rules);
+            // Staff badges in the transcript come from the admin list.
+            let meta = state.next_meta();
+            state.queue(ClientMessage::ListAdmins { meta, channel: channel.name.clone() });
+            state.add_listed_channel(channel);
         }
         ServerMessage::JoinFailure { channel, reason, .. } => {
-            toast(terminal, &format!("Join #{channel} failed: {reason}"), ToastKind::Error)?;
+            let prompt = match reason.as_str() {
+                JOIN_PASSWORD_REQUIRED => format!("#{channel} needs a password: type it and press Enter (Esc cancels)"),
+                JOIN_INVALID_PASSWORD => format!("Wrong password for #{channel}: try again (Esc cancels)"),
+                _ => {
+                    toast(terminal, &format!("Join #{channel} failed: {reason}"), ToastKind::Error)?;
+                    return Ok(());
+                }
+            };
+            state.password_prompt = Some(channel);
+            toast(terminal, &prompt, ToastKind::Info)?;
         }
         ServerMessage::HistoryChunk { channel, messages, .. } => {
-            for m in messages {
-                state.push_message(&channel, m);
-            }
+            // Rejoins and `/history` overlap what we already show.
+            state.merge_missed(&channel, messages);
+        }
+        ServerMessage::PollCreated { meta, channel, poll_id, question, options, created_by, .. } => {
+            let text = format!("{} started poll #{}: {} (/vote {} <1-{}>)", created_by, poll_id, question, poll_id, options.len());
+            let tallies = vec![0; options.len()];
+            state.polls.insert(channel.clone(), Poll { id: poll_id, question, options, tallies });
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
+        }
+        ServerMessage::PollUpdated { channel, poll_id, tallies, .. } => {
+            state.update_poll(&channel, poll_id, tallies);
+        }
+        ServerMessage::ResyncChunk { channel, messages, truncated, .. } => {
+            state.receive_resync(&channel, messages, truncated);
         }
         ServerMessage::MessageReceived { channel, message, .. } => {
-            state.push_message(&channel, message);
+            state.track_seq(&channel, message.seq);
+            state.receive_message(&channel, message);
+        }
+        ServerMessage::DMReceived { recipient, message, .. } => {
+            state.receive_dm(&recipient, message);
         }
-        ServerMessage::UserJoined { channel, user, .. } => {
-            toast(terminal, &format!("{} joined #{}", user.username, channel), ToastKind::Info)?;
+        ServerMessage::DMDeliveryStatus { dm_id, delivered, .. } => {
+            if !delivered {
+                state.undelivered_dms.insert(dm_id);
+                toast(terminal, "Recipient is offline; the DM will be delivered when they connect", ToastKind::Info)?;
+            } else if state.undelivered_dms.remove(&dm_id) {
+                toast(terminal, "A stored DM was delivered", ToastKind::Info)?;
+            }
+        }
+        ServerMessage::DMDeleted { meta, dm_id, deleted_by, .. } => {
+            if let Some(tab) = state.remove_dm(dm_id) {
+                state.push_event(&tab, meta.timestamp, format!("A message was deleted by {}", deleted_by));
+            }
         }
-        ServerMessage::UserLeft { channel, user, .. } => {
-            toast(terminal, &format!("{} left #{}", user.username, channel), ToastKind::Info)?;
+        ServerMessage::UserJoined { meta, channel, user, .. } => {
+            learn_user(terminal, state, &user)?;
+            channel_event(terminal, state, &channel, meta.timestamp, format!("{} joined", user.username))?;
         }
-        ServerMessage::SystemMessage { text, .. } => {
-            toast(terminal, &text, ToastKind::Info)?;
+        ServerMessage::UserLeft { meta, channel, user, .. } => {
+            if state.user.as_ref().map(|u| u.id) == Some(user.id) {
+                state.close_channel(&channel);
+                toast(terminal, &format!("Left #{}", channel), ToastKind::Info)?;
+                return Ok(());
+            }
+            state.away_users.remove(&user.username);
+            channel_event(terminal, state, &channel, meta.timestamp, format!("{} left", user.username))?;
+        }
+        ServerMessage::UserRenamed { user_id, old_username, new_username, .. } => {
+            if let Some(user) = state.user.as_mut().filter(|u| u.id == user_id) {
+                user.username = new_username.clone();
+                toast(terminal, &format!("You are now known as {}", new_username), ToastKind::Info)?;
+                return Ok(());
+            }
+            toast(terminal, &format!("{} is now known as {}", old_username, new_username), ToastKind::Info)?;
+        }
+        ServerMessage::SystemMessage { text, .. } if text.starts_with(MOTD_PREFIX) => {
+            state.motd = Some(text[MOTD_PREFIX.len()..].to_string());
         }
-        ServerMessage::ProtocolError { text, .. } => {
+        ServerMessage::SystemMessage { meta, text, .. } => match state.current_channel.clone() {
+            Some(channel) => state.push_event(&channel, meta.timestamp, text),
+            None => toast(terminal, &text, ToastKind::Info)?,
+        },
+        ServerMessage::ProtocolError { text, request_id, .. } => {
+            return_rejected_send(state, request_id);
             toast(terminal, &text, ToastKind::Error)?;
         }
-        ServerMessage::MessageDeleted { channel, message_id, deleted_by, .. } => {
-            state.remove_message(&channel, message_id);
-            toast(terminal, &format!("Message deleted by {}", deleted_by), ToastKind::Info)?;
+        ServerMessage::MessageDeleted { meta, channel, message_id, deleted_by, .. } => {
+            // Nothing to show for a message we never had.
+            if state.tombstone_message(&channel, message_id) {
+                channel_event(terminal, state, &channel, meta.timestamp, format!("A message was deleted by {}", deleted_by))?;
+            }
+        }
+        ServerMessage::UserPromoted { meta, channel, username, new_role, promoted_by, .. } => {
+            state.set_member_role(&channel, &username, new_role);
+            let text = format!("{} promoted to {:?} by {} in #{}", username, new_role, promoted_by, channel);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
-        ServerMessage::UserPromoted { channel, username, new_role, promoted_by, .. } => {
-            toast(terminal, &format!("{} promoted to {:?} by {} in #{}", username, new_role, promoted_by, channel), ToastKind::Info)?;
+        ServerMessage::UserDemoted { meta, channel, username, demoted_by, .. } => {
+            state.set_member_role(&channel, &username, Role::User);
+            let text = format!("{} demoted to User by {} in #{}", username, demoted_by, channel);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
-        ServerMessage::UserDemoted { channel, username, demoted_by, .. } => {
-            toast(terminal, &format!("{} demoted to User by {} in #{}", username, demoted_by, channel), ToastKind::Info)?;
+        ServerMessage::OwnershipTransferred { meta, channel, previous_owner, new_owner, .. } => {
+            // Mirrors the server: the new owner is SuperAdmin, the old one stays on as Admin.
+            state.set_member_role(&channel, &new_owner, Role::SuperAdmin);
+            state.set_member_role(&channel, &previous_owner, Role::Admin);
+            let text = format!("{} handed ownership of #{} to {}", previous_owner, channel, new_owner);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
-        ServerMessage::UserBanned { channel, username, banned_by, reason, .. } => {
+        ServerMessage::UserBanned { meta, channel, username, banned_by, reason, .. } => {
             let reason_text = reason.unwrap_or_default();
-            toast(terminal, &format!("{} banned from #{} by {}: {}", username, channel, banned_by, reason_text), ToastKind::Info)?;
+            let text = format!("{} banned from #{} by {}: {}", username, channel, banned_by, reason_text);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
-        ServerMessage::UserUnbanned { channel, username, unbanned_by, .. } => {
-            toast(terminal, &format!("{} unbanned from #{} by {}", username, channel, unbanned_by), ToastKind::Info)?;
+        ServerMessage::UserUnbanned { meta, channel, username, unbanned_by, .. } => {
+            let text = format!("{} unbanned from #{} by {}", username, channel, unbanned_by);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
-        ServerMessage::UserKicked { channel, username, kicked_by, reason, .. } => {
+        ServerMessage::UserKicked { meta, channel, username, kicked_by, reason, .. } => {
             let reason_text = reason.unwrap_or_default();
-            toast(terminal, &format!("{} kicked from #{} by {}: {}", username, channel, kicked_by, reason_text), ToastKind::Info)?;
+            let text = format!("{} kicked from #{} by {}: {}", username, channel, kicked_by, reason_text);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
-        ServerMessage::AdminList { admins, .. } => {
-            let admin_names: Vec<_> = admins.iter().map(|a| format!("{} ({:?})", a.username, a.role)).collect();
-            toast(terminal, &format!("Admins: {}", admin_names.join(", ")), ToastKind::Info)?;
+        ServerMessage::AdminList { channel, admins, .. } => {
+            state.set_member_roles(&channel, &admins);
         }
         ServerMessage::BanList { bans, .. } => {
             if bans.is_empty() {
@@ -276,22 +1017,96 @@ fn handle_server_message(
                 toast(terminal, &format!("[{}] {} by {}: {}", log.timestamp.format("%H:%M:%S"), log.action, log.username, log.details), ToastKind::Info)?;
             }
         }
-        ServerMessage::ChannelTypeChanged { channel, new_type, changed_by, .. } => {
-            toast(terminal, &format!("#{} channel type changed to {:?} by {}", channel, new_type, changed_by), ToastKind::Info)?;
+        ServerMessage::ChannelTypeChanged { meta, channel, new_type, changed_by, .. } => {
+            state.channel_rules.insert(channel.clone(), new_type.description().to_string());
+            state.set_channel_type(&channel, new_type);
+            let text = format!("#{} channel type changed to {:?} by {}", channel, new_type, changed_by);
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
         }
         ServerMessage::ChannelDeleted { channel, deleted_by, .. } => {
             toast(terminal, &format!("Channel #{} deleted by {}", channel, deleted_by), ToastKind::Error)?;
-            if state.current_channel.as_deref() == Some(channel.as_str()) {
-                state.current_channel = None;
+            state.close_channel(&channel);
+        }
+        ServerMessage::RetentionChanged { meta, channel, max_age_seconds, changed_by, .. } => {
+            let text = match max_age_seconds {
+                Some(secs) => format!("#{} now keeps messages for {}s (set by {})", channel, secs, changed_by),
+                None => format!("#{} retention cleared by {}", channel, changed_by),
+            };
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
+        }
+        ServerMessage::SlowModeChanged { meta, channel, interval_seconds, changed_by, .. } => {
+            let text = match interval_seconds {
+                Some(secs) => format!("#{} slow mode: one message every {}s (set by {})", channel, secs, changed_by),
+                None => format!("#{} slow mode disabled by {}", channel, changed_by),
+            };
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
+        }
+        ServerMessage::MaxMembersChanged { meta, channel, max_members, changed_by, .. } => {
+            let text = match max_members {
+                Some(max) => format!("#{} is now limited to {} members (set by {})", channel, max, changed_by),
+                None => format!("#{} member limit removed by {}", channel, changed_by),
+            };
+            channel_event(terminal, state, &channel, meta.timestamp, text)?;
+        }
+        ServerMessage::ChannelRenamed { meta, old_name, new_name, renamed_by, .. } => {
+            state.rename_channel(&old_name, &new_name);
+            let text = format!("#{} renamed to #{} by {}", old_name, new_name, renamed_by);
+            channel_event(terminal, state, &new_name, meta.timestamp, text)?;
+        }
+        ServerMessage::Pong { nonce, .. } => {
+            state.heartbeat.pong(nonce, Instant::now());
+        }
+        ServerMessage::Cooldown { channel, retry_after_ms, request_id, .. } => {
+            state.start_cooldown(&channel, Duration::from_millis(retry_after_ms));
+            if return_rejected_send(state, request_id) {
+                toast(terminal, "Slow down: your message wasn't sent and is back in the input line", ToastKind::Error)?;
             }
         }
-        ServerMessage::AdminError { reason, .. } => {
+        ServerMessage::AdminError { reason, request_id, .. } => {
+            return_rejected_send(state, request_id);
             toast(terminal, &format!("Admin error: {}", reason), ToastKind::Error)?;
         }
+        ServerMessage::UserCreated { user, generated_password, .. } => {
+            learn_user(terminal, state, &user)?;
+            let text = match generated_password {
+                Some(pw) => format!("Created account {}. Password: {pw}", user.username),
+                None => format!("Created account {}", user.username),
+            };
+            toast(terminal, &text, ToastKind::Info)?;
+        }
+        ServerMessage::PresenceChanged { username, away, .. } => {
+            if away {
+                state.away_users.insert(username);
+            } else {
+                state.away_users.remove(&username);
+            }
+        }
+        ServerMessage::ServerCapabilities { features, max_message_len, .. } => {
+            tracing::debug!(?features, max_message_len, "server capabilities");
+            state.capabilities = Some(Capabilities {
+                features: features.into_iter().collect(),
+                max_message_len: max_message_len as usize,
+            });
+            // Join whatever our other sessions are in. Sent on every login and
+            // resume; channels we already have are skipped.
+            if state.supports(features::MY_CHANNELS) {
+                let meta = state.next_meta();
+                state.queue(ClientMessage::ListMyChannels { meta });
+            }
+        }
+        ServerMessage::MyChannels { channels, .. } => {
+            let locked = state.restore_channels(channels);
+            if !locked.is_empty() {
+                let names: Vec<_> = locked.iter().map(|c| format!("#{c}")).collect();
+                toast(terminal, &format!("Your other sessions are in {}; /join them with the password", names.join(", ")), ToastKind::Info)?;
+            }
+        }
         ServerMessage::AuthChallenge { .. }
         | ServerMessage::AuthSuccess { .. }
         | ServerMessage::AuthFailure { .. }
-        | ServerMessage::EcdhAck { .. } => {
+        | ServerMessage::EcdhAck { .. }
+        | ServerMessage::CompressionEnabled { .. }
+        | ServerMessage::DisconnectAck { .. } => {
             // handled earlier
         }
     }
@@ -299,9 +1114,33 @@ fn handle_server_message(
     Ok(())
 }
 
-fn draw(
-    terminal: &mut TerminalSession,
-    state: &ClientState,
+/// Below this the panes can't show anything useful.
+const MIN_COLS: u16 = 40;
+const MIN_ROWS: u16 = 10;
+
+#[derive(Debug, PartialEq, Eq)]
+enum Layout {
+    TooSmall,
+    /// Widths of the channel list, transcript and info pane; two separators
+    /// take up the remaining columns.
+    Panes { channels_w: usize, messages_w: usize, info_w: usize },
+}
+
+/// The side panes give up width on narrow terminals so the transcript keeps
+/// at least half of it.
+fn layout(cols: u16, rows: u16) -> Layout {
+    if cols < MIN_COLS || rows < MIN_ROWS {
+        return Layout::TooSmall;
+    }
+    let cols = cols as usize;
+    let channels_w = 20.min(cols / 4);
+    let info_w = 22.min(cols / 4);
+    Layout::Panes { channels_w, messages_w: cols - channels_w - info_w - 2, info_w }
+}
+
+fn draw(
+    terminal: &mut TerminalSession,
+    state: &ClientState,
     focus: Focus,
     input: &str,
     selected_channel_idx: usize,
@@ -312,31 +1151,39 @@ fn draw(
     let cols_usize = cols as usize;
     let rows_usize = rows as usize;
 
-    let channels_w = 20usize.min(cols_usize.saturating_sub(1));
-    let info_w = 22usize.min(cols_usize.saturating_sub(channels_w + 1));
-    let messages_w = cols_usize.saturating_sub(channels_w + info_w + 2);
-
-    let encryption_indicator = if state.crypto.is_ready() {
-        "🔒"
-    } else {
-        ""
+    let Layout::Panes { channels_w, messages_w, info_w } = layout(cols, rows) else {
+        // Redrawn on every resize, so the normal layout comes back by itself.
+        let text = truncate(&format!("Terminal too small (need ≥{MIN_COLS}×{MIN_ROWS})"), cols_usize);
+        let x = cols_usize.saturating_sub(text.chars().count()) / 2;
+        return execute!(
+            terminal.stdout(),
+            cursor::MoveTo(x as u16, rows / 2),
+            Print(text.with(Color::Yellow))
+        );
     };
-    
+
     let header = format!(
-        "DarkRelay {} | Connected: {} @ {}",
-        encryption_indicator,
+        "DarkRelay | {}{} @ {}",
         state
             .user
             .as_ref()
             .map(|u| u.username.as_str())
             .unwrap_or("<guest>"),
+        if state.idle.is_away() { " (away)" } else { "" },
         state.server_addr
     );
+    let (lock, lock_color) = encryption_indicator(state.crypto.is_ready(), !input.is_empty());
+    let (link, link_color) = link_indicator(state.heartbeat.link(), state.heartbeat.latency());
+    let link_w = link.chars().count().min(cols_usize);
+    let lock_w = lock.chars().count().min(cols_usize - link_w);
+    let header_w = cols_usize - link_w - lock_w;
 
     execute!(
         terminal.stdout(),
         cursor::MoveTo(0, 0),
-        Print(pad(&header, cols_usize).with(Color::White).on(Color::DarkBlue)),
+        Print(pad(&header, header_w).with(Color::White).on(Color::DarkBlue)),
+        Print(truncate(&lock, lock_w).with(lock_color).on(Color::DarkBlue)),
+        Print(truncate(&link, link_w).with(link_color).on(Color::DarkBlue)),
     )?;
 
     // Vertical separators
@@ -371,14 +1218,27 @@ fn draw(
         let y = 3 + i;
         let prefix = if Some(&ch.name) == state.current_channel.as_ref() {
             "#"
+        } else if state.joined_channels.contains(&ch.name) {
+            "+"
         } else {
             " "
         };
 
-        let label = pad(&format!("{prefix} {}", ch.name), channels_w.saturating_sub(2));
+        let unread = state.unread(&ch.name);
+        let mentioned = state.mention_count(&ch.name);
+        let label = if mentioned > 0 {
+            format!("{prefix} {} ({unread}, @{mentioned})", ch.name)
+        } else if unread > 0 {
+            format!("{prefix} {} ({unread})", ch.name)
+        } else {
+            format!("{prefix} {}", ch.name)
+        };
+        let label = pad(&label, channels_w.saturating_sub(2));
 
         let styled = if i == selected_channel_idx {
             label.with(Color::Yellow)
+        } else if mentioned > 0 {
+            label.with(Color::Magenta)
         } else {
             label.with(Color::White)
         };
@@ -390,13 +1250,24 @@ fn draw(
         )?;
     }
 
-    let messages_title = format!(
-        " Messages ({}) ",
-        state
-            .current_channel
-            .as_deref()
-            .unwrap_or("no-channel")
-    );
+    let messages_title = if state.joined_channels.is_empty() {
+        " Messages (no-channel) ".to_string()
+    } else {
+        let tabs: Vec<_> = state
+            .joined_channels
+            .iter()
+            .enumerate()
+            .map(|(i, name)| {
+                if state.current_channel.as_ref() == Some(name) {
+                    format!("[{}:{}]", i + 1, name)
+                } else {
+                    format!("{}:{}", i + 1, name)
+                }
+            })
+            .collect();
+        format!(" {} ", tabs.join(" "))
+    };
+    let messages_title = truncate(&messages_title, messages_w);
 
     execute!(
         terminal.stdout(),
@@ -405,70 +1276,404 @@ fn draw(
     )?;
 
     // Messages area
-    let msgs = state.messages_for_current();
+    let transcript = state
+        .current_channel
+        .as_deref()
+        .map(|ch| state.transcript(ch))
+        .unwrap_or_default();
     let max_lines = rows_usize.saturating_sub(6);
-    let start = msgs.len().saturating_sub(max_lines);
+    let start = transcript.len().saturating_sub(max_lines);
 
-    for (i, m) in msgs.iter().skip(start).enumerate() {
+    for (i, entry) in transcript.iter().skip(start).enumerate() {
         let y = 3 + i;
-        let ts = m.timestamp.with_timezone(&Local).format("%H:%M:%S");
-        
-        // Try to decrypt the message if nonce is present
-        let content_str = if let Some(ref nonce) = m.nonce {
-            match state.crypto.decrypt(&m.content, nonce, state.current_channel.as_deref()) {
-                Ok(plaintext) => String::from_utf8_lossy(&plaintext).to_string(),
-                Err(_) => "[decryption failed]".to_string(),
+        let m = match entry {
+            TranscriptEntry::Message(m) => m,
+            TranscriptEntry::Event(event) => {
+                execute!(
+                    terminal.stdout(),
+                    cursor::MoveTo((channels_w + 2) as u16, y as u16),
+                    Print(format_event_line(event, messages_w).with(Color::DarkGrey).dim())
+                )?;
+                continue;
             }
-        } else {
-            String::from_utf8_lossy(&m.content).to_string()
         };
-        
-        let line = format!("[{}] <{}>: {}", ts, m.username, content_str);
+        let channel = state.current_channel.as_deref().unwrap_or_default();
+        let (content_str, unreadable) = match state.message_text(channel, m) {
+            _ if m.deleted => (String::new(), false),
+            Ok(text) => match state.verification(channel, m, &text) {
+                Some(Verification::Invalid) => (format!("(bad signature) {text}"), true),
+                Some(Verification::Unsigned) => (format!("(unsigned) {text}"), false),
+                _ => (text, false),
+            },
+            Err(marker) => (marker.to_string(), true),
+        };
+
+        let badge = state.member_role(channel, &m.username).and_then(role_badge);
+        let line = truncate(&format_message_line(m, &content_str, state.show_message_ids, badge.map(|(b, _)| b)), messages_w);
 
         let is_self = state.user.as_ref().map(|u| u.id) == Some(m.user_id);
-        let styled = if is_self {
-            truncate(&line, messages_w).with(Color::Cyan)
+        let color = if unreadable {
+            Color::Red
+        } else if m.id == PENDING_MESSAGE_ID || m.deleted {
+            Color::DarkGrey
+        } else if is_self {
+            Color::Cyan
         } else {
-            truncate(&line, messages_w).with(Color::White)
+            Color::White
         };
+        let style = |text: &str| {
+            let styled = text.to_string().with(color);
+            if is_action(&m.metadata) { styled.italic().dim() } else { styled }
+        };
+
+        execute!(terminal.stdout(), cursor::MoveTo((channels_w + 2) as u16, y as u16))?;
+        // The badge is the first one in the line: it comes before the content.
+        match badge.and_then(|(b, badge_color)| line.find(b).map(|at| (at, b, badge_color))) {
+            Some((at, b, badge_color)) => execute!(
+                terminal.stdout(),
+                Print(style(&line[..at])),
+                Print(b.with(badge_color).bold()),
+                Print(style(&line[at + b.len_utf8()..])),
+            )?,
+            None => execute!(terminal.stdout(), Print(style(&line)))?,
+        }
+    }
+
+    if let Some(motd) = &state.motd {
+        draw_motd(terminal, motd, channels_w + 1, messages_w, rows_usize.saturating_sub(6) / 2)?;
+    }
+
+    // Info pane
+    let info_x = (channels_w + messages_w + 3) as u16;
+    execute!(terminal.stdout(), cursor::MoveTo(info_x, 1), Print(" Info ".with(Color::Grey)))?;
+    let current = state.current_channel_info();
+    let mut info_y = 3;
+    for hint in command_hints(current) {
+        execute!(terminal.stdout(), cursor::MoveTo(info_x, info_y), Print(hint.with(Color::DarkGrey)))?;
+        info_y += 1;
+    }
+    info_y += 1;
+
+    if let Some(info) = current {
+        let mut line = format!("{:?} · {:?}", state.current_role(), info.channel_type);
+        if !state.can_send_here() {
+            line.push_str(" (read-only)");
+        }
+        execute!(
+            terminal.stdout(),
+            cursor::MoveTo(info_x, info_y),
+            Print(truncate(&line, info_w.saturating_sub(1)).with(Color::Grey)),
+        )?;
+        info_y += 1;
+        for line in info.topic.iter().flat_map(|topic| wrap(topic, info_w.saturating_sub(1))).take(2) {
+            execute!(terminal.stdout(), cursor::MoveTo(info_x, info_y), Print(line.with(Color::White)))?;
+            info_y += 1;
+        }
+    }
 
+    let rules = state
+        .current_channel
+        .as_ref()
+        .and_then(|ch| state.channel_rules.get(ch));
+    if let Some(rules) = rules {
+        for line in wrap(rules, info_w.saturating_sub(1)).iter().take(4) {
+            execute!(
+                terminal.stdout(),
+                cursor::MoveTo(info_x, info_y),
+                Print(line.as_str().with(Color::Yellow)),
+            )?;
+            info_y += 1;
+        }
+    }
+
+    if !state.away_users.is_empty() {
+        let mut away: Vec<&str> = state.away_users.iter().map(String::as_str).collect();
+        away.sort_unstable();
         execute!(
             terminal.stdout(),
-            cursor::MoveTo((channels_w + 2) as u16, y as u16),
-            Print(styled)
+            cursor::MoveTo(info_x, info_y),
+            Print(truncate(&format!("Away: {}", away.join(", ")), info_w.saturating_sub(1)).with(Color::DarkGrey)),
         )?;
+        info_y += 1;
     }
 
-    // Info pane
-    execute!(
-        terminal.stdout(),
-        cursor::MoveTo((channels_w + messages_w + 3) as u16, 1),
-        Print(" Info ".with(Color::Grey)),
-        cursor::MoveTo((channels_w + messages_w + 3) as u16, 3),
-        Print("/help".with(Color::DarkGrey)),
-        cursor::MoveTo((channels_w + messages_w + 3) as u16, 4),
-        Print("/list".with(Color::DarkGrey)),
-        cursor::MoveTo((channels_w + messages_w + 3) as u16, 5),
-        Print("/join <name>".with(Color::DarkGrey)),
-        cursor::MoveTo((channels_w + messages_w + 3) as u16, 6),
-        Print("/quit".with(Color::DarkGrey)),
-    )?;
+    if let Some(poll) = state.current_channel.as_ref().and_then(|ch| state.polls.get(ch)) {
+        let width = info_w.saturating_sub(1);
+        let total: u32 = poll.tallies.iter().sum();
+        let mut lines: Vec<(String, Color)> = wrap(&format!("Poll #{}: {}", poll.id, poll.question), width)
+            .into_iter()
+            .take(2)
+            .map(|line| (line, Color::Cyan))
+            .collect();
+        for (i, option) in poll.options.iter().enumerate() {
+            let votes = poll.tallies.get(i).copied().unwrap_or(0);
+            lines.push((truncate(&format!("{} {}", i + 1, option), width), Color::White));
+            let bar = poll_bar(votes, total, width.saturating_sub(5));
+            lines.push((format!("{bar} {votes}"), Color::Green));
+        }
+        let room = rows_usize.saturating_sub(info_y as usize + 3);
+        for (i, (line, color)) in lines.iter().take(room).enumerate() {
+            execute!(
+                terminal.stdout(),
+                cursor::MoveTo(info_x, info_y + i as u16),
+                Print(line.as_str().with(*color)),
+            )?;
+        }
+    }
 
     // Input
     let input_y = rows.saturating_sub(2);
-    let input_prefix = if focus == Focus::Input { "> " } else { "  " };
+    let cooldown = state
+        .current_channel
+        .as_deref()
+        .and_then(|ch| state.cooldown_remaining(ch));
+    // A password being typed is masked.
+    let masked;
+    let input = match &state.password_prompt {
+        Some(_) => {
+            masked = "*".repeat(input.chars().count());
+            masked.as_str()
+        }
+        None => input,
+    };
+    let input_prefix = match (focus, cooldown, &state.password_prompt) {
+        (Focus::Input, _, Some(channel)) => format!("Password for #{channel}: "),
+        (Focus::Input, _, None) if !state.can_send_here() => "[read-only] ".to_string(),
+        (Focus::Input, Some(left), None) => format!("[{}s] ", left.as_secs() + 1),
+        (Focus::Input, None, None) => "> ".to_string(),
+        _ => "  ".to_string(),
+    };
     let input_line = format!("{}{}", input_prefix, input);
+    let counter = remaining_label(input.len(), state.max_message_len());
+    let is_chat = !input.is_empty() && !input.starts_with('/') && state.password_prompt.is_none();
+    let input_line = if is_chat && input_line.len() + counter.len() < cols_usize {
+        format!("{}{}", pad(&input_line, cols_usize - counter.len()), counter)
+    } else {
+        input_line
+    };
     execute!(
         terminal.stdout(),
         cursor::MoveTo(0, input_y),
         Print(pad(&input_line, cols_usize).with(Color::Black).on(Color::Grey)),
-        cursor::MoveTo((input_prefix.len() + input.len()) as u16, input_y),
+        cursor::MoveTo((input_prefix.len() + input.chars().count()) as u16, input_y),
     )?;
 
     terminal.draw_toast()?;
     Ok(())
 }
 
+/// Commands listed in the info pane. Moderation commands only show up in
+/// channels where our role can use them.
+fn command_hints(channel: Option<&ChannelInfo>) -> Vec<&'static str> {
+    let mut hints = vec!["/help", "/list", "/join <name>", "/quit"];
+    let role = channel.and_then(|c| c.user_role).unwrap_or(Role::User);
+    if has_permission(role, Permission::DeleteMessage) {
+        hints.push("/delete <id>");
+    }
+    if has_permission(role, Permission::ManageChannel) {
+        hints.push("/welcome [text]");
+    }
+    hints
+}
+
+/// Bytes left before the server's message limit, shown at the right of the
+/// input line.
+fn remaining_label(len: usize, max: usize) -> String {
+    match max.checked_sub(len) {
+        Some(left) => format!("{left} left"),
+        None => format!("{} over", len - max),
+    }
+}
+
+/// A bordered panel across the top of the messages area, at most `max_rows`
+/// high including the border.
+fn draw_motd(terminal: &mut TerminalSession, motd: &str, x: usize, width: usize, max_rows: usize) -> io::Result<()> {
+    let inner = width.saturating_sub(4);
+    if inner == 0 || max_rows < 3 {
+        return Ok(());
+    }
+    let mut lines = wrap(motd, inner);
+    if lines.len() > max_rows - 2 {
+        lines.truncate(max_rows - 2);
+        if let Some(last) = lines.last_mut() {
+            *last = truncate(&format!("{last}…"), inner);
+        }
+    }
+
+    let edge = |left: char, title: &str, right: char| {
+        let title = truncate(title, inner);
+        format!("{left}{title}{}{right}", "─".repeat(width - 2 - title.chars().count()))
+    };
+    let mut rows = vec![edge('┌', "─ Message of the day ", '┐')];
+    rows.extend(lines.iter().map(|line| format!("│ {} │", pad(line, inner))));
+    rows.push(edge('└', "─ Esc to dismiss ", '┘'));
+
+    for (i, row) in rows.iter().enumerate() {
+        execute!(
+            terminal.stdout(),
+            cursor::MoveTo(x as u16, (3 + i) as u16),
+            Print(row.as_str().with(Color::Yellow).on(Color::Black)),
+        )?;
+    }
+    Ok(())
+}
+
+/// `/poll Lunch? | pizza | sushi` into the question and its options.
+fn parse_poll(line: &str) -> Option<(String, Vec<String>)> {
+    let rest = line.trim_start().strip_prefix("/poll")?;
+    let mut parts = rest.split('|').map(str::trim);
+    let question = parts.next().filter(|q| !q.is_empty())?.to_string();
+    let options: Vec<String> = parts.filter(|o| !o.is_empty()).map(str::to_string).collect();
+    (options.len() >= 2).then_some((question, options))
+}
+
+/// Arguments of `/create`.
+#[derive(Debug, PartialEq, Eq)]
+struct CreateArgs {
+    name: String,
+    password: Option<String>,
+    channel_type: ChannelType,
+    topic: Option<String>,
+}
+
+/// `/create news type=readonly | Release notes`: a name, then an optional
+/// password and `type=`, in any order, then the topic after `|`.
+fn parse_create(line: &str) -> Option<CreateArgs> {
+    let rest = line.trim_start().strip_prefix("/create")?;
+    let (args, topic) = match rest.split_once('|') {
+        Some((args, topic)) => (args, Some(topic.trim()).filter(|t| !t.is_empty()).map(str::to_string)),
+        None => (rest, None),
+    };
+    let mut words = args.split_whitespace();
+    let name = words.next()?.to_string();
+    let mut create = CreateArgs { name, password: None, channel_type: ChannelType::Public, topic };
+    for word in words {
+        match word.strip_prefix("type=") {
+            Some(kind) => create.channel_type = parse_channel_type(kind)?,
+            None if create.password.is_none() => create.password = Some(word.to_string()),
+            None => return None,
+        }
+    }
+    Some(create)
+}
+
+fn parse_channel_type(name: &str) -> Option<ChannelType> {
+    match name.to_ascii_lowercase().replace('-', "").as_str() {
+        "public" => Some(ChannelType::Public),
+        "private" => Some(ChannelType::Private),
+        "adminonly" => Some(ChannelType::AdminOnly),
+        "readonly" => Some(ChannelType::ReadOnly),
+        "announcement" => Some(ChannelType::Announcement),
+        _ => None,
+    }
+}
+
+/// A `width`-cell bar filled in proportion to `votes` out of `total`.
+fn poll_bar(votes: u32, total: u32, width: usize) -> String {
+    let filled = if total == 0 {
+        0
+    } else {
+        ((votes as usize * width) + total as usize / 2) / total as usize
+    };
+    format!("{}{}", "█".repeat(filled), "░".repeat(width - filled))
+}
+
+/// Header text and color for the session's encryption. Typing while the
+/// session is unencrypted turns the warning red.
+fn encryption_indicator(ready: bool, typing: bool) -> (String, Color) {
+    match (ready, typing) {
+        (true, _) => ("🔒 Encrypted ".to_string(), Color::Green),
+        (false, false) => ("🔓 Not encrypted ".to_string(), Color::Yellow),
+        (false, true) => ("⚠ Not encrypted ".to_string(), Color::Red),
+    }
+}
+
+/// Header text and color for the connection state, e.g. `● Connected 42ms `.
+fn link_indicator(link: LinkState, latency: Option<Duration>) -> (String, Color) {
+    let (label, color) = match link {
+        LinkState::Connected => ("Connected", Color::Green),
+        LinkState::Degraded => ("Degraded", Color::Yellow),
+        LinkState::Reconnecting => ("Reconnecting", Color::Red),
+    };
+    let text = match latency.filter(|_| link != LinkState::Reconnecting) {
+        Some(rtt) => format!("● {} {}ms ", label, rtt.as_millis()),
+        None => format!("● {} ", label),
+    };
+    (text, color)
+}
+
+/// Render a transcript line; with `show_id` it is prefixed by `#<message_id>`
+/// so moderators can target it with `/delete`. Actions read `* alice waves`.
+fn format_message_line(m: &ChatMessage, content: &str, show_id: bool, badge: Option<char>) -> String {
+    let ts = m.timestamp.with_timezone(&Local).format("%H:%M:%S");
+    let content = if m.deleted {
+        DELETED_MESSAGE.to_string()
+    } else if m.edited {
+        format!("{} (edited)", content.replace('\n', "↵"))
+    } else {
+        content.replace('\n', "↵")
+    };
+    let name = match badge {
+        Some(badge) => format!("{badge}{}", m.username),
+        None => m.username.clone(),
+    };
+    let body = if is_action(&m.metadata) {
+        format!("* {} {}", name, content)
+    } else {
+        format!("<{}>: {}", name, content)
+    };
+    if show_id {
+        format!("#{} [{}] {}", m.id, ts, body)
+    } else {
+        format!("[{}] {}", ts, body)
+    }
+}
+
+/// Shown in place of a tombstone's content.
+const DELETED_MESSAGE: &str = "(message deleted)";
+
+/// IRC-style prefix and color for channel staff; plain members get none.
+fn role_badge(role: Role) -> Option<(char, Color)> {
+    match role {
+        Role::SuperAdmin => Some(('~', Color::Yellow)),
+        Role::Admin => Some(('@', Color::Red)),
+        Role::Moderator => Some(('%', Color::Green)),
+        Role::User => None,
+    }
+}
+
+/// Render a system event centered in `width` columns.
+fn format_event_line(event: &SystemEvent, width: usize) -> String {
+    let ts = event.timestamp.with_timezone(&Local).format("%H:%M:%S");
+    let line = truncate(&format!("── {} [{}] ──", event.text.replace('\n', " "), ts), width);
+    let indent = width.saturating_sub(line.chars().count()) / 2;
+    format!("{}{}", " ".repeat(indent), line)
+}
+
+/// Greedy word wrap to `width` columns.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        if !line.is_empty() && line.chars().count() + 1 + word.chars().count() > width {
+            lines.push(std::mem::take(&mut line));
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.push_str(word);
+    }
+    if !line.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
+/// Accepts `42` or `#42`.
+fn parse_message_id(arg: &str) -> Option<MessageId> {
+    arg.strip_prefix('#').unwrap_or(arg).parse().ok()
+}
+
 fn pad(s: &str, width: usize) -> String {
     if s.len() >= width {
         truncate(s, width)
@@ -484,3 +1689,677 @@ fn truncate(s: &str, width: usize) -> String {
         s.chars().take(width).collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+
+    fn chat(id: MessageId) -> ChatMessage {
+        ChatMessage {
+            id,
+            seq: 0,
+            user_id: 7,
+            username: "alice".to_string(),
+            content: b"hello".to_vec(),
+            timestamp: Utc::now(),
+            nonce: None,
+            metadata: MessageMetadata::new(),
+            deleted: false,
+            edited: false,
+        }
+    }
+
+    #[test]
+    fn test_parse_message_id() {
+        assert_eq!(parse_message_id("42"), Some(42));
+        assert_eq!(parse_message_id("#42"), Some(42));
+        assert_eq!(parse_message_id("abc"), None);
+        assert_eq!(parse_message_id("-1"), None);
+    }
+
+    #[test]
+    fn test_parse_create() {
+        let create = |name: &str, password: Option<&str>, channel_type, topic: Option<&str>| CreateArgs {
+            name: name.to_string(),
+            password: password.map(str::to_string),
+            channel_type,
+            topic: topic.map(str::to_string),
+        };
+        assert_eq!(parse_create("/create lobby"), Some(create("lobby", None, ChannelType::Public, None)));
+        assert_eq!(parse_create("/create staff hunter2"), Some(create("staff", Some("hunter2"), ChannelType::Public, None)));
+        assert_eq!(
+            parse_create("/create news type=Read-Only | Release notes | and more"),
+            Some(create("news", None, ChannelType::ReadOnly, Some("Release notes | and more")))
+        );
+        assert_eq!(
+            parse_create("/create ops type=private pw |  "),
+            Some(create("ops", Some("pw"), ChannelType::Private, None))
+        );
+        assert_eq!(parse_create("/create"), None);
+        assert_eq!(parse_create("/create x type=secret"), None);
+        assert_eq!(parse_create("/create x one two"), None);
+    }
+
+    #[test]
+    fn test_promotion_badges_later_messages() {
+        let mut terminal = TerminalSession::headless();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        let rendered = |state: &ClientState, username: &str| {
+            let m = ChatMessage { username: username.to_string(), ..chat(1) };
+            let badge = state.member_role("general", username).and_then(role_badge).map(|(b, _)| b);
+            format_message_line(&m, "hi", false, badge)
+        };
+        let meta = || darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now());
+
+        handle_server_message(&mut terminal, &mut state, ServerMessage::AdminList {
+            meta: meta(),
+            channel: "general".to_string(),
+            admins: vec![darkrelayprotocol::protocol::AdminInfo { user_id: 1, username: "alice".to_string(), role: Role::SuperAdmin }],
+        }).unwrap();
+        assert!(rendered(&state, "alice").ends_with("<~alice>: hi"));
+        assert!(rendered(&state, "bob").ends_with("<bob>: hi"), "unknown role stays plain");
+
+        handle_server_message(&mut terminal, &mut state, ServerMessage::UserPromoted {
+            meta: meta(),
+            channel: "general".to_string(),
+            user_id: 2,
+            username: "bob".to_string(),
+            new_role: Role::Moderator,
+            promoted_by: "alice".to_string(),
+        }).unwrap();
+        assert!(rendered(&state, "bob").ends_with("<%bob>: hi"));
+        assert_eq!(role_badge(Role::Moderator), Some(('%', Color::Green)));
+
+        handle_server_message(&mut terminal, &mut state, ServerMessage::UserDemoted {
+            meta: meta(),
+            channel: "general".to_string(),
+            user_id: 2,
+            username: "bob".to_string(),
+            demoted_by: "alice".to_string(),
+        }).unwrap();
+        assert!(rendered(&state, "bob").ends_with("<bob>: hi"));
+    }
+
+    #[test]
+    fn test_moderation_hints_follow_role() {
+        let channel = |role| ChannelInfo {
+            id: 1,
+            name: "general".to_string(),
+            is_public: true,
+            channel_type: darkrelayprotocol::channel::ChannelType::Public,
+            user_role: role,
+            member_count: 1,
+            max_members: None,
+            topic: None,
+        };
+        let base = command_hints(None);
+        assert!(!base.contains(&"/delete <id>"));
+        assert_eq!(command_hints(Some(&channel(None))), base);
+        assert_eq!(command_hints(Some(&channel(Some(Role::User)))), base);
+
+        let moderator = command_hints(Some(&channel(Some(Role::Moderator))));
+        assert!(moderator.contains(&"/delete <id>"));
+        assert!(!moderator.contains(&"/welcome [text]"));
+
+        let admin = command_hints(Some(&channel(Some(Role::Admin))));
+        assert!(admin.contains(&"/delete <id>") && admin.contains(&"/welcome [text]"));
+    }
+
+    #[test]
+    fn test_id_prefix_matches_message_id() {
+        let m = chat(1234);
+        let line = format_message_line(&m, "hello", true, None);
+        assert!(line.starts_with(&format!("#{} ", m.id)));
+        assert!(line.ends_with("<alice>: hello"));
+
+        let plain = format_message_line(&m, "hello", false, None);
+        assert!(plain.starts_with('['));
+    }
+
+    #[test]
+    fn test_motd_opens_panel_and_command_sends_it() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        let meta = darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now());
+
+        let text = format!("{MOTD_PREFIX}Maintenance Sunday");
+        handle_server_message(&mut terminal, &mut state, ServerMessage::SystemMessage { meta, text }).unwrap();
+        assert_eq!(state.motd.as_deref(), Some("Maintenance Sunday"));
+        assert!(state.transcript("general").is_empty(), "shown in the panel, not the transcript");
+
+        for (line, expected) in [("/motd  Be kind ", Some("Be kind")), ("/motd reload", None), ("/motd clear", Some(""))] {
+            handle_command(&mut terminal, &mut state, &mut conn, line).unwrap();
+            match sent.try_recv() {
+                Ok(ClientMessage::SetMotd { motd, .. }) => assert_eq!(motd.as_deref(), expected, "{line}"),
+                other => panic!("expected SetMotd for {line}, got {other:?}"),
+            }
+        }
+        handle_command(&mut terminal, &mut state, &mut conn, "/motd").unwrap();
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_password_required_join_prompts_and_retries() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        let failure = |reason: &str| ServerMessage::JoinFailure {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            channel: "staff".to_string(),
+            reason: reason.to_string(),
+        };
+        let mut retry = |state: &mut ClientState, line: &str| {
+            handle_input_line(&mut terminal, state, &mut conn, line).unwrap();
+            match sent.try_recv() {
+                Ok(ClientMessage::JoinChannel { name, password, .. }) => (name, password),
+                other => panic!("expected JoinChannel, got {other:?}"),
+            }
+        };
+
+        handle_server_message(&mut TerminalSession::headless(), &mut state, failure(JOIN_PASSWORD_REQUIRED)).unwrap();
+        assert_eq!(state.password_prompt.as_deref(), Some("staff"));
+        assert_eq!(retry(&mut state, "/not-a-command"), ("staff".to_string(), Some("/not-a-command".to_string())));
+        assert_eq!(state.password_prompt, None, "one line per prompt");
+
+        handle_server_message(&mut TerminalSession::headless(), &mut state, failure(JOIN_INVALID_PASSWORD)).unwrap();
+        assert_eq!(retry(&mut state, "hunter2"), ("staff".to_string(), Some("hunter2".to_string())));
+
+        handle_server_message(&mut TerminalSession::headless(), &mut state, failure("channel is full")).unwrap();
+        assert_eq!(state.password_prompt, None, "other reasons only get a toast");
+    }
+
+    #[test]
+    fn test_delete_command_sends_for_known_id_only() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        state.push_message("general", chat(5));
+
+        handle_command(&mut terminal, &mut state, &mut conn, "/delete 9").unwrap();
+        assert!(sent.try_recv().is_err());
+
+        handle_command(&mut terminal, &mut state, &mut conn, "/delete #5").unwrap();
+        match sent.try_recv() {
+            Ok(ClientMessage::DeleteMessage { channel, message_id, .. }) => {
+                assert_eq!(channel, "general");
+                assert_eq!(message_id, 5);
+            }
+            other => panic!("expected DeleteMessage, got {other:?}"),
+        }
+
+        state.open_channel("@bob");
+        state.push_message("@bob", chat(6));
+        handle_command(&mut terminal, &mut state, &mut conn, "/delete 6").unwrap();
+        assert!(matches!(sent.try_recv(), Ok(ClientMessage::DeleteDM { dm_id: 6, .. })));
+    }
+
+    #[test]
+    fn test_clear_only_empties_local_view() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("random");
+        state.push_message("random", chat(4));
+        state.open_channel("general");
+        state.push_message("general", chat(5));
+
+        handle_command(&mut terminal, &mut state, &mut conn, "/clear").unwrap();
+        assert!(state.transcript("general").is_empty());
+        assert_eq!(state.messages_by_channel["random"].len(), 1);
+        assert!(sent.try_recv().is_err(), "/clear must not reach the server");
+    }
+
+    #[test]
+    fn test_history_command_merges_overlapping_chunks() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        for id in [4, 5] {
+            state.receive_message("general", chat(id));
+        }
+
+        handle_command(&mut terminal, &mut state, &mut conn, "/history 500").unwrap();
+        match sent.try_recv() {
+            Ok(ClientMessage::GetHistory { channel, limit, .. }) => {
+                assert_eq!(channel, "general");
+                assert_eq!(limit as usize, MAX_HISTORY_LEN, "clamped to what the server keeps");
+            }
+            other => panic!("expected GetHistory, got {other:?}"),
+        }
+
+        let chunk = |ids: &[MessageId]| ServerMessage::HistoryChunk {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            channel: "general".to_string(),
+            messages: ids.iter().map(|&id| chat(id)).collect(),
+        };
+        handle_server_message(&mut terminal, &mut state, chunk(&[1, 2, 3, 4, 5])).unwrap();
+        handle_server_message(&mut terminal, &mut state, chunk(&[3, 4, 5, 6])).unwrap();
+        let ids: Vec<_> = state.messages_by_channel["general"].iter().map(|m| m.id).collect();
+        assert_eq!(ids, [1, 2, 3, 4, 5, 6]);
+
+        for bad in ["/history 0", "/history lots"] {
+            handle_command(&mut terminal, &mut state, &mut conn, bad).unwrap();
+        }
+        state.open_channel("@bob");
+        handle_command(&mut terminal, &mut state, &mut conn, "/history 10").unwrap();
+        assert!(sent.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_tiny_terminal_falls_back() {
+        for (cols, rows) in [(0, 0), (39, 40), (120, 9), (20, 5)] {
+            assert_eq!(layout(cols, rows), Layout::TooSmall, "{cols}x{rows}");
+        }
+        assert_eq!(layout(40, 10), Layout::Panes { channels_w: 10, messages_w: 18, info_w: 10 });
+        assert_eq!(layout(120, 40), Layout::Panes { channels_w: 20, messages_w: 76, info_w: 22 }, "wide terminals keep full side panes");
+    }
+
+    #[test]
+    fn test_dm_command_sends_by_username() {
+        use darkrelayclient::e2e::DmKey;
+
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        let alice = DmKey::generate();
+        state.dm_key = Some(alice.clone());
+        state.open_channel("general");
+
+        // Held until bob's DM key arrives.
+        handle_command(&mut terminal, &mut state, &mut conn, "/dm bob hello  there").unwrap();
+        assert_eq!(state.current_channel.as_deref(), Some("@bob"));
+        assert!(sent.try_recv().is_err());
+        assert!(matches!(state.take_outbox().as_slice(), [ClientMessage::GetUserInfo { username, .. }] if username == "bob"));
+
+        let bob = DmKey::generate();
+        let details = ServerMessage::UserDetails {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            user: UserInfo { id: 1, username: "bob".to_string(), joined_at: Utc::now(), signing_key: None, public_key: Some(bob.public_key()) },
+            last_seen: None,
+            online: true,
+        };
+        handle_server_message(&mut terminal, &mut state, details).unwrap();
+        match state.take_outbox().as_slice() {
+            [ClientMessage::SendDM { recipient, content, metadata, .. }] => {
+                assert_eq!(recipient, "bob");
+                assert_ne!(content.as_slice(), b"hello  there", "the server only sees ciphertext");
+                assert_eq!(bob.decrypt(&alice.public_key(), content, &metadata.nonce().unwrap()).unwrap(), b"hello  there");
+            }
+            other => panic!("expected SendDM, got {other:?}"),
+        }
+
+        // Plain input in a DM tab continues the conversation.
+        handle_input_line(&mut terminal, &mut state, &mut conn, "again").unwrap();
+        assert!(matches!(sent.try_recv(), Ok(ClientMessage::SendDM { recipient, .. }) if recipient == "bob"));
+    }
+
+    #[test]
+    fn test_replaced_dm_key_is_not_used_silently() {
+        use darkrelayclient::e2e::DmKey;
+
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        let alice = DmKey::generate();
+        state.dm_key = Some(alice.clone());
+        let details = |key: &DmKey| ServerMessage::UserDetails {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            user: UserInfo { id: 1, username: "bob".to_string(), joined_at: Utc::now(), signing_key: None, public_key: Some(key.public_key()) },
+            last_seen: None,
+            online: true,
+        };
+        let (bob, mallory) = (DmKey::generate(), DmKey::generate());
+        handle_server_message(&mut terminal, &mut state, details(&bob)).unwrap();
+        handle_command(&mut terminal, &mut state, &mut conn, "/dm bob one").unwrap();
+        assert!(matches!(sent.try_recv(), Ok(ClientMessage::SendDM { .. })));
+
+        // The server now hands out another key for bob: nothing is encrypted to it.
+        handle_server_message(&mut terminal, &mut state, details(&mallory)).unwrap();
+        assert!(state.keys_changed("bob"));
+        assert_eq!(state.dm_public_key("bob"), Some(bob.public_key().as_slice()), "the pinned key stays");
+        handle_command(&mut terminal, &mut state, &mut conn, "/dm bob two").unwrap();
+        assert!(sent.try_recv().is_err());
+
+        // Trusting the new key pins whatever the server answers with next.
+        handle_command(&mut terminal, &mut state, &mut conn, "/trust bob").unwrap();
+        assert!(matches!(state.take_outbox().as_slice(), [ClientMessage::GetUserInfo { username, .. }] if username == "bob"));
+        handle_server_message(&mut terminal, &mut state, details(&mallory)).unwrap();
+        assert!(!state.keys_changed("bob"));
+        handle_command(&mut terminal, &mut state, &mut conn, "/dm bob three").unwrap();
+        match sent.try_recv() {
+            Ok(ClientMessage::SendDM { content, metadata, .. }) => {
+                assert_eq!(mallory.decrypt(&alice.public_key(), &content, &metadata.nonce().unwrap()).unwrap(), b"three");
+            }
+            other => panic!("expected SendDM, got {other:?}"),
+        }
+    }
+
+    #[test]
+    fn test_send_failure_keeps_the_message_for_after_reconnect() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo { id: 2, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.open_channel("general");
+        drop(sent);
+
+        let err = handle_input_line(&mut terminal, &mut state, &mut conn, "/me is still here").unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted, "ends the session so it reconnects");
+        let kept = UnsentMessage { tab: "general".to_string(), text: "is still here".to_string(), action: true };
+        assert_eq!(state.unsent, [kept]);
+        assert!(state.messages_by_channel.get("general").is_none_or(Vec::is_empty), "no local copy of a message that never left");
+
+        // After reconnecting it goes to the channel it was typed in.
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        state.open_channel("random");
+        resend_unsent(&mut terminal, &mut state, &mut conn).unwrap();
+        match sent.try_recv() {
+            Ok(ClientMessage::SendMessage { channel, content, metadata, .. }) => {
+                assert_eq!((channel.as_str(), content.as_slice()), ("general", b"is still here".as_slice()));
+                assert!(is_action(&metadata));
+            }
+            other => panic!("expected SendMessage, got {other:?}"),
+        }
+        assert!(state.unsent.is_empty());
+        assert_eq!(state.messages_by_channel["general"].len(), 1);
+    }
+
+    #[test]
+    fn test_refused_resume_puts_unsent_lines_back_in_the_input() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.user = Some(UserInfo { id: 2, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None });
+        state.open_channel("general");
+        drop(sent);
+        handle_input_line(&mut terminal, &mut state, &mut conn, "first").unwrap_err();
+        state.unsent.push(UnsentMessage { tab: "general".to_string(), text: "second".to_string(), action: false });
+
+        // The resume was refused: what `main` does before logging in again.
+        let draft = state.take_unsent_text();
+        state.reset();
+        assert!(state.unsent.is_empty());
+        let mut state = ClientState::new("test".to_string());
+        state.draft = draft;
+
+        let mut input = InputBuffer::new();
+        restore_draft(&mut terminal, &mut state, &mut input).unwrap();
+        assert_eq!(input.as_str(), "first\nsecond");
+        assert_eq!(state.draft, None);
+        assert_eq!(state.take_unsent_text(), None);
+    }
+
+    #[test]
+    fn test_encryption_indicator_follows_crypto_state() {
+        use x25519_dalek::{EphemeralSecret, PublicKey};
+
+        let mut state = ClientState::new("test".to_string());
+        assert_eq!(encryption_indicator(state.crypto.is_ready(), false).1, Color::Yellow);
+        let (text, color) = encryption_indicator(state.crypto.is_ready(), true);
+        assert!(text.contains("Not encrypted"));
+        assert_eq!(color, Color::Red);
+
+        state.crypto.begin_handshake();
+        let server_secret = EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        state.crypto.finish_handshake(PublicKey::from(&server_secret).as_bytes()).unwrap();
+        let (text, color) = encryption_indicator(state.crypto.is_ready(), true);
+        assert!(text.starts_with("🔒"));
+        assert_eq!(color, Color::Green);
+    }
+
+    #[test]
+    fn test_me_sends_action_message() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/me waves").unwrap();
+        let mut message = chat(8);
+        match sent.try_recv() {
+            Ok(ClientMessage::SendMessage { content, metadata, .. }) => {
+                assert_eq!(content, b"waves");
+                assert!(is_action(&metadata));
+                message.metadata = metadata;
+            }
+            other => panic!("expected SendMessage, got {other:?}"),
+        }
+
+        assert!(format_message_line(&message, "waves", false, None).ends_with("] * alice waves"));
+        assert!(format_message_line(&chat(9), "waves", false, None).ends_with("] <alice>: waves"));
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/me   ").unwrap();
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/mention").unwrap();
+        assert!(sent.try_recv().is_err());
+    }
+    #[test]
+    fn test_poll_commands_and_tallies() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/poll Lunch? | pizza | sushi ").unwrap();
+        match sent.try_recv() {
+            Ok(ClientMessage::CreatePoll { channel, question, options, .. }) => {
+                assert_eq!(channel, "general");
+                assert_eq!(question, "Lunch?");
+                assert_eq!(options, vec!["pizza".to_string(), "sushi".to_string()]);
+            }
+            other => panic!("expected CreatePoll, got {other:?}"),
+        }
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/poll Lunch? | pizza").unwrap();
+        assert!(sent.try_recv().is_err(), "one option is not a poll");
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/vote 3 2").unwrap();
+        assert!(matches!(
+            sent.try_recv(),
+            Ok(ClientMessage::Vote { poll_id: 3, option_index: 1, .. })
+        ));
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/vote 3 0").unwrap();
+        assert!(sent.try_recv().is_err());
+
+        let meta = darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now());
+        handle_server_message(&mut terminal, &mut state, ServerMessage::PollCreated {
+            meta: meta.clone(),
+            channel: "general".to_string(),
+            poll_id: 3,
+            question: "Lunch?".to_string(),
+            options: vec!["pizza".to_string(), "sushi".to_string()],
+            created_by: "bob".to_string(),
+        }).unwrap();
+        handle_server_message(&mut terminal, &mut state, ServerMessage::PollUpdated {
+            meta: meta.clone(),
+            channel: "general".to_string(),
+            poll_id: 3,
+            tallies: vec![1, 3],
+        }).unwrap();
+        handle_server_message(&mut terminal, &mut state, ServerMessage::PollUpdated {
+            meta,
+            channel: "general".to_string(),
+            poll_id: 2,
+            tallies: vec![9, 9],
+        }).unwrap();
+        assert_eq!(state.polls["general"].tallies, vec![1, 3], "older polls are ignored");
+
+        assert_eq!(poll_bar(1, 4, 8), "██░░░░░░");
+        assert_eq!(poll_bar(3, 4, 8), "██████░░");
+        assert_eq!(poll_bar(0, 0, 4), "░░░░");
+    }
+
+    #[tokio::test]
+    async fn test_quit_waits_for_disconnect_ack() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/quit").unwrap();
+        assert!(matches!(sent.try_recv(), Ok(ClientMessage::Disconnect { .. })));
+        assert!(state.disconnecting);
+
+        let meta = darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now());
+        inbound.send(ServerMessage::MessageReceived {
+            meta: meta.clone(),
+            channel: "general".to_string(),
+            message: chat(7),
+        }).await.unwrap();
+        inbound.send(ServerMessage::DisconnectAck { meta }).await.unwrap();
+
+        await_disconnect_ack(&mut terminal, &mut state, &mut conn).await.unwrap();
+        assert!(!state.disconnecting);
+        assert!(state.messages_by_channel["general"].iter().any(|m| m.id == 7), "messages sent before the ack are kept");
+    }
+
+    #[test]
+    fn test_capabilities_gate_features_and_length() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        assert!(state.supports(features::DMS), "assume everything until told otherwise");
+
+        handle_server_message(&mut terminal, &mut state, ServerMessage::ServerCapabilities {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            features: vec![features::RESYNC.to_string()],
+            max_frame_len: 1024 * 1024,
+            max_message_len: 8,
+        }).unwrap();
+        assert_eq!(state.max_message_len(), 8);
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/dm bob hi").unwrap();
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/poll q | a | b").unwrap();
+        handle_input_line(&mut terminal, &mut state, &mut conn, "/vote 1 1").unwrap();
+        assert!(sent.try_recv().is_err(), "unsupported features send nothing");
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "way too long").unwrap();
+        assert!(sent.try_recv().is_err());
+        handle_input_line(&mut terminal, &mut state, &mut conn, "short").unwrap();
+        assert!(matches!(sent.try_recv(), Ok(ClientMessage::SendMessage { .. })));
+    }
+
+    #[test]
+    fn test_other_sessions_channels_are_restored() {
+        let mut terminal = TerminalSession::headless();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        let capabilities = |features: Vec<String>| ServerMessage::ServerCapabilities {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            features,
+            max_frame_len: 1024 * 1024,
+            max_message_len: 4096,
+        };
+
+        handle_server_message(&mut terminal, &mut state, capabilities(Vec::new())).unwrap();
+        assert!(state.take_outbox().is_empty(), "older servers don't know ListMyChannels");
+        handle_server_message(&mut terminal, &mut state, capabilities(vec![features::MY_CHANNELS.to_string()])).unwrap();
+        assert!(matches!(state.take_outbox()[..], [ClientMessage::ListMyChannels { .. }]));
+
+        let info = |id, name: &str, is_public| ChannelInfo {
+            id,
+            name: name.to_string(),
+            is_public,
+            channel_type: ChannelType::Public,
+            user_role: Some(Role::User),
+            member_count: 2,
+            max_members: None,
+            topic: None,
+        };
+        let mine = vec![info(1, "general", true), info(2, "project", true), info(3, "staff", false)];
+        handle_server_message(&mut terminal, &mut state, ServerMessage::MyChannels {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(2, Utc::now()),
+            channels: mine,
+        }).unwrap();
+
+        let joins: Vec<_> = state
+            .take_outbox()
+            .into_iter()
+            .map(|msg| match msg {
+                ClientMessage::JoinChannel { name, password: None, .. } => name,
+                other => panic!("expected a passwordless JoinChannel, got {other:?}"),
+            })
+            .collect();
+        assert_eq!(joins, ["project"], "already in general; staff needs its password");
+        let listed: Vec<_> = state.channels.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(listed, ["general", "project", "staff"]);
+    }
+
+    #[test]
+    fn test_limit_counts_plaintext_not_ciphertext() {
+        let mut terminal = TerminalSession::headless();
+        let (mut conn, mut sent, _inbound) = Connection::test_pair();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        state.crypto.begin_handshake();
+        let server_secret = x25519_dalek::EphemeralSecret::random_from_rng(rand::rngs::OsRng);
+        state.crypto.finish_handshake(x25519_dalek::PublicKey::from(&server_secret).as_bytes()).unwrap();
+        handle_server_message(&mut terminal, &mut state, ServerMessage::ServerCapabilities {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            features: Vec::new(),
+            max_frame_len: 1024 * 1024,
+            max_message_len: 8,
+        }).unwrap();
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "123456789").unwrap();
+        assert!(sent.try_recv().is_err(), "over the limit is blocked before encrypting");
+
+        handle_input_line(&mut terminal, &mut state, &mut conn, "12345678").unwrap();
+        match sent.try_recv() {
+            Ok(ClientMessage::SendMessage { content, .. }) => assert!(content.len() > 8),
+            other => panic!("expected SendMessage, got {other:?}"),
+        }
+
+        assert_eq!(remaining_label(5, 8), "3 left");
+        assert_eq!(remaining_label(10, 8), "2 over");
+    }
+
+    #[test]
+    fn test_delete_of_unknown_message_is_ignored() {
+        let mut terminal = TerminalSession::headless();
+        let mut state = ClientState::new("test".to_string());
+        state.open_channel("general");
+        state.receive_message("general", chat(5));
+        let deleted = |channel: &str, message_id| ServerMessage::MessageDeleted {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            channel: channel.to_string(),
+            message_id,
+            deleted_by: "mod".to_string(),
+        };
+
+        handle_server_message(&mut terminal, &mut state, deleted("general", 99)).unwrap();
+        handle_server_message(&mut terminal, &mut state, deleted("unvisited", 5)).unwrap();
+        assert_eq!(state.transcript("general").len(), 1, "no event for a message we never had");
+        assert_eq!(state.messages_by_channel["general"].len(), 1);
+
+        handle_server_message(&mut terminal, &mut state, deleted("general", 5)).unwrap();
+        let tombstone = &state.messages_by_channel["general"][0];
+        assert!(tombstone.deleted && tombstone.content.is_empty(), "kept as a tombstone, like the server's history");
+        assert_eq!(format_message_line(tombstone, "", false, None).split_once(' ').unwrap().1, "<alice>: (message deleted)");
+        assert_eq!(state.transcript("general").len(), 2, "the delete shows as an event");
+
+        handle_server_message(&mut terminal, &mut state, deleted("general", 5)).unwrap();
+        assert_eq!(state.transcript("general").len(), 2, "a repeated delete adds nothing");
+    }
+
+    #[test]
+    fn test_presence_updates_away_list() {
+        let mut terminal = TerminalSession::headless();
+        let mut state = ClientState::new("test".to_string());
+        let presence = |username: &str, away| ServerMessage::PresenceChanged {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, Utc::now()),
+            username: username.to_string(),
+            away,
+        };
+
+        handle_server_message(&mut terminal, &mut state, presence("bob", true)).unwrap();
+        handle_server_message(&mut terminal, &mut state, presence("carol", true)).unwrap();
+        handle_server_message(&mut terminal, &mut state, presence("bob", false)).unwrap();
+        assert_eq!(state.away_users, std::collections::HashSet::from(["carol".to_string()]));
+    }
+}
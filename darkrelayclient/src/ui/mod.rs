@@ -1,8 +1,9 @@
 pub mod auth_dialog;
+pub mod input;
 pub mod main_layout;
 
 use std::{
-    io::{self, Stdout, Write},
+    io::{self, Write},
     time::{Duration, Instant},
 };
 
@@ -15,8 +16,10 @@ use crossterm::{
 };
 
 pub struct TerminalSession {
-    stdout: Stdout,
+    /// The real stdout, except in `headless` sessions.
+    stdout: Box<dyn Write + Send>,
     toast: Option<ToastState>,
+    raw_mode: bool,
 }
 
 struct ToastState {
@@ -36,15 +39,32 @@ impl TerminalSession {
     pub fn new() -> io::Result<Self> {
         let mut stdout = io::stdout();
         terminal::enable_raw_mode()?;
-        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide, event::EnableBracketedPaste)?;
+        // Lets Shift+Enter be told apart from Enter; unsupported terminals just ignore it.
+        let _ = execute!(
+            stdout,
+            event::PushKeyboardEnhancementFlags(event::KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES)
+        );
 
         Ok(Self {
-            stdout,
+            stdout: Box::new(stdout),
             toast: None,
+            raw_mode: true,
         })
     }
 
-    pub fn stdout(&mut self) -> &mut Stdout {
+    /// Session that leaves the terminal mode untouched and discards whatever
+    /// is drawn, for tests.
+    #[cfg(test)]
+    pub fn headless() -> Self {
+        Self {
+            stdout: Box::new(io::sink()),
+            toast: None,
+            raw_mode: false,
+        }
+    }
+
+    pub fn stdout(&mut self) -> &mut Box<dyn Write + Send> {
         &mut self.stdout
     }
 
@@ -95,7 +115,16 @@ impl TerminalSession {
 
 impl Drop for TerminalSession {
     fn drop(&mut self) {
-        let _ = execute!(self.stdout, cursor::Show, terminal::LeaveAlternateScreen);
+        if !self.raw_mode {
+            return;
+        }
+        let _ = execute!(self.stdout, event::PopKeyboardEnhancementFlags);
+        let _ = execute!(
+            self.stdout,
+            event::DisableBracketedPaste,
+            cursor::Show,
+            terminal::LeaveAlternateScreen
+        );
         let _ = terminal::disable_raw_mode();
     }
 }
@@ -110,10 +139,22 @@ pub fn clear(terminal: &mut TerminalSession) -> io::Result<()> {
     Ok(())
 }
 
+/// Harder than `clear`: also drops scrollback, for recovering after something
+/// else wrote to the terminal (Ctrl+L).
+pub fn reset_screen(terminal: &mut TerminalSession) -> io::Result<()> {
+    execute!(
+        terminal.stdout,
+        terminal::Clear(ClearType::Purge),
+        terminal::Clear(ClearType::All),
+        cursor::MoveTo(0, 0)
+    )?;
+    Ok(())
+}
+
 pub fn show_error_dialog(terminal: &mut TerminalSession, text: &str) -> io::Result<()> {
     clear(terminal)?;
 
-    let (cols, rows) = terminal::size()?;
+    let (_, rows) = terminal::size()?;
     let y = rows / 2;
 
     execute!(terminal.stdout, cursor::MoveTo(2, y), Print(text.with(Color::Red)))?;
@@ -1,8 +1,11 @@
 use serde::{Deserialize, Serialize};
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+use crate::permissions::{has_permission, Permission, Role};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
 #[repr(u8)]
 pub enum ChannelType {
+    #[default]
     Public = 0,
     Private = 1,
     AdminOnly = 2,
@@ -10,12 +13,6 @@ pub enum ChannelType {
     Announcement = 4,
 }
 
-impl Default for ChannelType {
-    fn default() -> Self {
-        ChannelType::Public
-    }
-}
-
 impl ChannelType {
     pub fn description(&self) -> &'static str {
         match self {
@@ -26,4 +23,14 @@ impl ChannelType {
             ChannelType::Announcement => "SuperAdmin broadcasts only",
         }
     }
+
+    /// Whether a member with `role` may post here. The server enforces this;
+    /// clients use it to grey out the input.
+    pub fn allows_sending(&self, role: Role) -> bool {
+        match self {
+            ChannelType::Public | ChannelType::Private => has_permission(role, Permission::SendMessage),
+            ChannelType::AdminOnly | ChannelType::ReadOnly => role >= Role::Admin,
+            ChannelType::Announcement => role >= Role::SuperAdmin,
+        }
+    }
 }
@@ -1,17 +1,64 @@
 use rand::Rng;
 
-/// Generate random padding bytes (0-256 bytes).
-pub fn generate_padding() -> Vec<u8> {
-    let mut rng = rand::thread_rng();
-    let len = rng.gen_range(0..=256);
+/// AES-GCM nonce length carried hex-encoded in a message's `nonce` metadata.
+pub const NONCE_LEN: usize = 12;
+
+/// AES-GCM authentication tag appended to every ciphertext.
+pub const TAG_LEN: usize = 16;
+
+/// Shortest ciphertext a non-empty message can produce: the padding's
+/// length prefix, one byte of plaintext and the tag.
+pub const MIN_CIPHERTEXT_LEN: usize = 4 + 1 + TAG_LEN;
+
+/// How much padding `add_padding` appends. Either way the result starts with
+/// the plaintext length, so `remove_padding` handles both.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaddingScheme {
+    /// 0..=`max` random bytes. Hides exact lengths but costs up to `max`
+    /// bytes per message.
+    Random { max: usize },
+    /// Pad the length prefix plus plaintext up to the next multiple of
+    /// `block`, so only the length class (in blocks) is visible.
+    Block { block: usize },
+}
+
+impl Default for PaddingScheme {
+    fn default() -> Self {
+        PaddingScheme::Random { max: 256 }
+    }
+}
+
+impl PaddingScheme {
+    /// Parse `random:<max>` or `block:<size>`; a block size must be non-zero.
+    pub fn parse(s: &str) -> Option<Self> {
+        let (kind, n) = s.trim().split_once(':')?;
+        let n = n.trim().parse().ok()?;
+        match kind.trim().to_ascii_lowercase().as_str() {
+            "random" => Some(PaddingScheme::Random { max: n }),
+            "block" if n > 0 => Some(PaddingScheme::Block { block: n }),
+            _ => None,
+        }
+    }
+
+    /// Padding bytes to append after `framed_len` bytes of prefix and plaintext.
+    fn padding_len(&self, framed_len: usize) -> usize {
+        match *self {
+            PaddingScheme::Random { max } => rand::thread_rng().gen_range(0..=max),
+            PaddingScheme::Block { block } => (block - framed_len % block) % block,
+        }
+    }
+}
+
+/// Generate `len` random padding bytes.
+pub fn generate_padding(len: usize) -> Vec<u8> {
     let mut padding = vec![0u8; len];
-    rng.fill(&mut padding[..]);
+    rand::thread_rng().fill(&mut padding[..]);
     padding
 }
 
 /// Add padding to plaintext. Format: [plaintext_len: u32][plaintext][padding].
-pub fn add_padding(plaintext: &[u8]) -> Vec<u8> {
-    let padding = generate_padding();
+pub fn add_padding(plaintext: &[u8], scheme: PaddingScheme) -> Vec<u8> {
+    let padding = generate_padding(scheme.padding_len(4 + plaintext.len()));
     let plaintext_len = plaintext.len() as u32;
     
     let mut result = Vec::with_capacity(4 + plaintext.len() + padding.len());
@@ -44,7 +91,7 @@ mod tests {
     #[test]
     fn test_padding_roundtrip() {
         let plaintext = b"Hello, world!";
-        let padded = add_padding(plaintext);
+        let padded = add_padding(plaintext, PaddingScheme::default());
         let recovered = remove_padding(&padded).unwrap();
         assert_eq!(plaintext, recovered.as_slice());
     }
@@ -52,11 +99,41 @@ mod tests {
     #[test]
     fn test_padding_varies() {
         let plaintext = b"test";
-        let padded1 = add_padding(plaintext);
-        let padded2 = add_padding(plaintext);
+        let padded1 = add_padding(plaintext, PaddingScheme::default());
+        let padded2 = add_padding(plaintext, PaddingScheme::default());
         // Padding should make different sizes (most of the time)
         // At least verify they decode to same plaintext
         assert_eq!(remove_padding(&padded1).unwrap(), plaintext);
         assert_eq!(remove_padding(&padded2).unwrap(), plaintext);
     }
+
+    #[test]
+    fn test_block_padding_boundaries() {
+        let scheme = PaddingScheme::Block { block: 64 };
+        // The 4-byte length prefix counts towards the block.
+        for (len, padded_len) in [(0, 64), (59, 64), (60, 64), (61, 128), (124, 128), (125, 192)] {
+            let plaintext = vec![7u8; len];
+            let padded = add_padding(&plaintext, scheme);
+            assert_eq!(padded.len(), padded_len, "plaintext of {len} bytes");
+            assert_eq!(remove_padding(&padded).unwrap(), plaintext);
+        }
+
+        let bounded = PaddingScheme::Random { max: 8 };
+        for _ in 0..20 {
+            let padded = add_padding(b"hi", bounded);
+            assert!((6..=14).contains(&padded.len()));
+            assert_eq!(remove_padding(&padded).unwrap(), b"hi");
+        }
+        assert_eq!(add_padding(b"hi", PaddingScheme::Random { max: 0 }).len(), 6);
+    }
+
+    #[test]
+    fn test_padding_scheme_parse() {
+        assert_eq!(PaddingScheme::parse("block:256"), Some(PaddingScheme::Block { block: 256 }));
+        assert_eq!(PaddingScheme::parse(" Random: 32 "), Some(PaddingScheme::Random { max: 32 }));
+        assert_eq!(PaddingScheme::parse("random:0"), Some(PaddingScheme::Random { max: 0 }));
+        assert_eq!(PaddingScheme::parse("block:0"), None);
+        assert_eq!(PaddingScheme::parse("block"), None);
+        assert_eq!(PaddingScheme::parse("zigzag:4"), None);
+    }
 }
@@ -0,0 +1,226 @@
+//! Wire framing shared by the client and server.
+//!
+//! A plain frame is `[len: u32][bincode]`. Once both sides have agreed to
+//! compression (`RequestCompression` / `CompressionEnabled`) every frame
+//! carries a flag byte first: `[flag: u8][len: u32][body]`, where the body is
+//! deflate-compressed bincode when `flag == FLAG_DEFLATE`.
+//!
+//! Because every frame is length-delimited, a body that names an enum variant
+//! this build does not know (sent by a newer peer) can be dropped without
+//! losing sync: `decode_body` reports it as `ErrorKind::Unsupported` and the
+//! reader moves on to the next frame.
+
+use std::io::{self, Read, Write};
+
+use bincode::Options;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// Largest frame accepted on the wire, header included.
+pub const MAX_FRAME_LEN: usize = 1024 * 1024;
+
+/// Payloads at least this large are compressed when compression is on.
+pub const COMPRESSION_THRESHOLD: usize = 1024;
+
+pub const FLAG_PLAIN: u8 = 0;
+pub const FLAG_DEFLATE: u8 = 1;
+
+/// The bincode settings for every frame body. The encoding matches
+/// `bincode::serialize` (fixed-width integers, little endian), but a body must
+/// be consumed exactly and may not claim more than a frame can hold.
+fn bincode_options() -> impl Options {
+    bincode::DefaultOptions::new()
+        .with_fixint_encoding()
+        .with_limit(MAX_FRAME_LEN as u64)
+        .reject_trailing_bytes()
+}
+
+/// Bytes before the body: the length prefix, plus the flag when compressing.
+pub fn header_len(compression: bool) -> usize {
+    if compression {
+        5
+    } else {
+        4
+    }
+}
+
+/// Serialize `msg` into a complete frame ready to write.
+pub fn encode_frame<T: Serialize>(msg: &T, compression: bool) -> io::Result<Vec<u8>> {
+    let data = bincode_options().serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+    let (flag, body) = if compression && data.len() >= COMPRESSION_THRESHOLD {
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data)?;
+        let compressed = encoder.finish()?;
+        if compressed.len() < data.len() {
+            (FLAG_DEFLATE, compressed)
+        } else {
+            (FLAG_PLAIN, data)
+        }
+    } else {
+        (FLAG_PLAIN, data)
+    };
+
+    let header = header_len(compression);
+    if header + body.len() > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+
+    let mut frame = Vec::with_capacity(header + body.len());
+    if compression {
+        frame.push(flag);
+    }
+    frame.extend_from_slice(&(body.len() as u32).to_be_bytes());
+    frame.extend_from_slice(&body);
+    Ok(frame)
+}
+
+/// Parse a frame header into `(flag, body_len)`, rejecting oversized frames
+/// before anything is allocated for the body.
+pub fn parse_header(header: &[u8], compression: bool) -> io::Result<(u8, usize)> {
+    if header.len() != header_len(compression) {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "bad frame header"));
+    }
+
+    let (flag, len_bytes) = if compression {
+        (header[0], &header[1..])
+    } else {
+        (FLAG_PLAIN, header)
+    };
+    if flag != FLAG_PLAIN && flag != FLAG_DEFLATE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "unknown frame flag"));
+    }
+
+    let len = u32::from_be_bytes(len_bytes.try_into().expect("4 length bytes")) as usize;
+    if header.len() + len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "frame too large"));
+    }
+    Ok((flag, len))
+}
+
+/// Decode a frame body read after `parse_header`.
+pub fn decode_body<T: DeserializeOwned>(flag: u8, body: &[u8]) -> io::Result<T> {
+    let inflated;
+    let data = if flag == FLAG_DEFLATE {
+        let mut out = Vec::new();
+        DeflateDecoder::new(body)
+            .take(MAX_FRAME_LEN as u64 + 1)
+            .read_to_end(&mut out)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if out.len() > MAX_FRAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "decompressed frame too large"));
+        }
+        inflated = out;
+        &inflated[..]
+    } else {
+        body
+    };
+
+    bincode_options().deserialize(data).map_err(|e| match unknown_variant::<T>(data) {
+        Some(variant) => io::Error::new(io::ErrorKind::Unsupported, format!("unknown message variant {variant}")),
+        None => io::Error::new(io::ErrorKind::InvalidData, e),
+    })
+}
+
+/// The variant index at the start of `data`, if `T` has no such variant.
+///
+/// Decoding just the index of a known variant fails with an EOF while reading
+/// its fields; an unknown index fails before that, on the index itself.
+fn unknown_variant<T: DeserializeOwned>(data: &[u8]) -> Option<u32> {
+    let index = u32::from_le_bytes(data.get(..4)?.try_into().ok()?);
+    match bincode_options().deserialize::<T>(&index.to_le_bytes()) {
+        Err(e) if !matches!(*e, bincode::ErrorKind::Io(_)) => Some(index),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(msg: &String, compression: bool) -> (u8, String) {
+        let frame = encode_frame(msg, compression).unwrap();
+        let header = header_len(compression);
+        let (flag, len) = parse_header(&frame[..header], compression).unwrap();
+        assert_eq!(len, frame.len() - header);
+        (flag, decode_body(flag, &frame[header..]).unwrap())
+    }
+
+    #[test]
+    fn test_plain_frame_round_trip() {
+        let small = "hello".to_string();
+        let frame = encode_frame(&small, false).unwrap();
+        assert_eq!(&frame[..4], &((frame.len() - 4) as u32).to_be_bytes());
+        assert_eq!(round_trip(&small, false), (FLAG_PLAIN, small));
+    }
+
+    #[test]
+    fn test_compressed_frame_round_trip() {
+        let small = "hello".to_string();
+        assert_eq!(round_trip(&small, true), (FLAG_PLAIN, small));
+
+        let large = "a".repeat(COMPRESSION_THRESHOLD * 4);
+        let frame = encode_frame(&large, true).unwrap();
+        assert!(frame.len() < large.len());
+        assert_eq!(round_trip(&large, true), (FLAG_DEFLATE, large));
+    }
+
+    #[test]
+    fn test_oversized_frames_rejected() {
+        let mut header = vec![FLAG_PLAIN];
+        header.extend_from_slice(&(MAX_FRAME_LEN as u32 - 4).to_be_bytes());
+        assert!(parse_header(&header, true).is_err(), "flag byte counts towards the limit");
+        assert!(parse_header(&header[1..], false).is_ok());
+
+        assert!(parse_header(&[9, 0, 0, 0, 1], true).is_err());
+
+        let huge = vec![0u8; MAX_FRAME_LEN];
+        assert!(encode_frame(&huge, false).is_err());
+    }
+
+    #[test]
+    fn test_unknown_variant_is_skippable() {
+        #[derive(Serialize)]
+        enum Newer {
+            Known { n: u32 },
+            Other { text: String },
+            Added { flag: bool },
+        }
+        #[derive(Debug, PartialEq, serde::Deserialize)]
+        enum Older {
+            Known { n: u32 },
+            Other { text: String },
+        }
+
+        let decode = |msg: &Newer| {
+            let frame = encode_frame(msg, false).unwrap();
+            decode_body::<Older>(FLAG_PLAIN, &frame[4..])
+        };
+
+        assert_eq!(decode(&Newer::Known { n: 1 }).unwrap(), Older::Known { n: 1 });
+        assert_eq!(decode(&Newer::Added { flag: true }).unwrap_err().kind(), io::ErrorKind::Unsupported);
+        assert_eq!(
+            decode(&Newer::Other { text: "still here".to_string() }).unwrap(),
+            Older::Other { text: "still here".to_string() }
+        );
+
+        // A known variant with a broken body is still a protocol error.
+        assert_eq!(decode_body::<Older>(FLAG_PLAIN, &[0, 0, 0, 0, 1]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn test_trailing_bytes_rejected() {
+        let msg = "hello".to_string();
+        let mut frame = encode_frame(&msg, false).unwrap();
+        assert_eq!(&frame[4..], &bincode::serialize(&msg).unwrap()[..], "wire format is unchanged");
+        assert_eq!(decode_body::<String>(FLAG_PLAIN, &frame[4..]).unwrap(), msg);
+
+        frame.extend_from_slice(b"junk");
+        assert_eq!(decode_body::<String>(FLAG_PLAIN, &frame[4..]).unwrap_err().kind(), io::ErrorKind::InvalidData);
+
+        // A length prefix larger than any frame fails before allocating.
+        let mut huge = (u64::MAX / 2).to_le_bytes().to_vec();
+        huge.extend_from_slice(b"x");
+        assert!(decode_body::<Vec<u8>>(FLAG_PLAIN, &huge).is_err());
+    }
+}
@@ -2,3 +2,5 @@ pub mod protocol;
 pub mod crypto;
 pub mod permissions;
 pub mod channel;
+pub mod frame;
+pub mod metadata;
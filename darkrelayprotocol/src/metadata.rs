@@ -0,0 +1,216 @@
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+
+/// Hex-encoded AES-GCM nonce of an encrypted message.
+pub const NONCE_KEY: &str = "nonce";
+/// Key epoch the content was encrypted under.
+pub const KEY_EPOCH_KEY: &str = "key_epoch";
+/// What kind of message this is, such as `action` for `/me`.
+pub const TYPE_KEY: &str = "type";
+/// How the content was compressed before encryption, if at all.
+pub const COMPRESSION_KEY: &str = "compression";
+/// Hex-encoded signature by the sender's `UserInfo::signing_key`.
+pub const SIGNATURE_KEY: &str = "sig";
+/// When the sender signed the message, in Unix milliseconds. Signed along
+/// with everything else so a replay can't carry a fresh timestamp.
+pub const SIGNED_AT_KEY: &str = "signed_at";
+/// Set on a DM encrypted to the recipient's `UserInfo::public_key` rather
+/// than the session key; names the scheme.
+pub const END_TO_END_KEY: &str = "e2e";
+
+/// String key/value pairs sent alongside a message's content. On the wire it
+/// is the plain list of pairs older peers send, so unknown keys pass through
+/// untouched; the accessors cover the keys this crate knows about.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct MessageMetadata(Vec<(String, String)>);
+
+impl MessageMetadata {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.0.clear();
+    }
+
+    /// The first value stored under `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    pub fn contains(&self, key: &str) -> bool {
+        self.get(key).is_some()
+    }
+
+    /// Replace the value under `key`, keeping its position, or append it.
+    pub fn set(&mut self, key: &str, value: impl Into<String>) {
+        let value = value.into();
+        match self.0.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value,
+            None => self.0.push((key.to_string(), value)),
+        }
+    }
+
+    /// Builder form of `set`.
+    pub fn with(mut self, key: &str, value: impl Into<String>) -> Self {
+        self.set(key, value);
+        self
+    }
+
+    /// `None` if the nonce is missing or isn't valid hex.
+    pub fn nonce(&self) -> Option<Vec<u8>> {
+        self.get(NONCE_KEY).and_then(|v| hex::decode(v).ok())
+    }
+
+    pub fn set_nonce(&mut self, nonce: &[u8]) {
+        self.set(NONCE_KEY, hex::encode(nonce));
+    }
+
+    pub fn key_epoch(&self) -> Option<u64> {
+        self.get(KEY_EPOCH_KEY).and_then(|v| v.parse().ok())
+    }
+
+    pub fn set_key_epoch(&mut self, epoch: u64) {
+        self.set(KEY_EPOCH_KEY, epoch.to_string());
+    }
+
+    pub fn message_type(&self) -> Option<&str> {
+        self.get(TYPE_KEY)
+    }
+
+    pub fn set_message_type(&mut self, message_type: &str) {
+        self.set(TYPE_KEY, message_type);
+    }
+
+    pub fn compression(&self) -> Option<&str> {
+        self.get(COMPRESSION_KEY)
+    }
+
+    pub fn set_compression(&mut self, compression: &str) {
+        self.set(COMPRESSION_KEY, compression);
+    }
+
+    pub fn end_to_end(&self) -> Option<&str> {
+        self.get(END_TO_END_KEY)
+    }
+
+    pub fn set_end_to_end(&mut self, scheme: &str) {
+        self.set(END_TO_END_KEY, scheme);
+    }
+
+    /// `None` if unsigned or the signature isn't valid hex.
+    pub fn signature(&self) -> Option<Vec<u8>> {
+        self.get(SIGNATURE_KEY).and_then(|v| hex::decode(v).ok())
+    }
+
+    pub fn set_signature(&mut self, signature: &[u8]) {
+        self.set(SIGNATURE_KEY, hex::encode(signature));
+    }
+
+    pub fn signed_at(&self) -> Option<DateTime<Utc>> {
+        self.get(SIGNED_AT_KEY).and_then(|v| v.parse().ok()).and_then(DateTime::from_timestamp_millis)
+    }
+
+    pub fn set_signed_at(&mut self, at: DateTime<Utc>) {
+        self.set(SIGNED_AT_KEY, at.timestamp_millis().to_string());
+    }
+
+    /// The bytes a sender signs: `context` (where the message was sent),
+    /// the plaintext and every pair but the signature, each length-prefixed
+    /// so no two messages encode the same. The pairs include `signed_at`,
+    /// which is what tells a replayed message from the original.
+    pub fn signing_payload(&self, context: &str, plaintext: &[u8]) -> Vec<u8> {
+        fn field(out: &mut Vec<u8>, bytes: &[u8]) {
+            out.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+            out.extend_from_slice(bytes);
+        }
+        let mut out = b"darkrelay-signature-v1".to_vec();
+        field(&mut out, context.as_bytes());
+        field(&mut out, plaintext);
+        for (k, v) in self.0.iter().filter(|(k, _)| k != SIGNATURE_KEY) {
+            field(&mut out, k.as_bytes());
+            field(&mut out, v.as_bytes());
+        }
+        out
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v.as_str()))
+    }
+}
+
+impl From<Vec<(String, String)>> for MessageMetadata {
+    fn from(pairs: Vec<(String, String)>) -> Self {
+        Self(pairs)
+    }
+}
+
+impl From<MessageMetadata> for Vec<(String, String)> {
+    fn from(metadata: MessageMetadata) -> Self {
+        metadata.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_known_keys_round_trip() {
+        let mut metadata = MessageMetadata::new();
+        assert_eq!((metadata.nonce(), metadata.key_epoch()), (None, None));
+        assert_eq!((metadata.message_type(), metadata.compression()), (None, None));
+
+        metadata.set_nonce(&[0x00, 0xff, 0x10]);
+        metadata.set_key_epoch(3);
+        metadata.set_message_type("action");
+        metadata.set_compression("deflate");
+        assert_eq!(metadata.get(NONCE_KEY), Some("00ff10"));
+        assert_eq!(metadata.nonce(), Some(vec![0x00, 0xff, 0x10]));
+        assert_eq!(metadata.key_epoch(), Some(3));
+        assert_eq!(metadata.message_type(), Some("action"));
+        assert_eq!(metadata.compression(), Some("deflate"));
+
+        // Setting again replaces in place rather than adding a second pair.
+        metadata.set_key_epoch(4);
+        assert_eq!(metadata.key_epoch(), Some(4));
+        assert_eq!(metadata.iter().filter(|(k, _)| *k == KEY_EPOCH_KEY).count(), 1);
+
+        let garbled = MessageMetadata::new().with(NONCE_KEY, "not hex").with(KEY_EPOCH_KEY, "-1");
+        assert_eq!((garbled.nonce(), garbled.key_epoch()), (None, None));
+    }
+
+    #[test]
+    fn test_unknown_keys_pass_through_on_the_wire() {
+        let pairs = vec![
+            ("routing".to_string(), "eu-1".to_string()),
+            (NONCE_KEY.to_string(), "abcd".to_string()),
+            ("routing".to_string(), "duplicate".to_string()),
+        ];
+        let encoded = bincode::serialize(&pairs).unwrap();
+        let metadata: MessageMetadata = bincode::deserialize(&encoded).unwrap();
+        assert_eq!(metadata.get("routing"), Some("eu-1"));
+        assert_eq!(metadata.nonce(), Some(vec![0xab, 0xcd]));
+        assert_eq!(bincode::serialize(&metadata).unwrap(), encoded, "same bytes as the plain pair list");
+        assert_eq!(Vec::from(metadata), pairs);
+    }
+
+    #[test]
+    fn test_signing_payload_covers_everything_but_the_signature() {
+        let mut metadata = MessageMetadata::new().with(TYPE_KEY, "action");
+        let payload = metadata.signing_payload("general", b"hi");
+        metadata.set_signature(&[1, 2, 3]);
+        assert_eq!(metadata.signature(), Some(vec![1, 2, 3]));
+        assert_eq!(metadata.signing_payload("general", b"hi"), payload);
+
+        assert_ne!(metadata.signing_payload("general", b"hi!"), payload);
+        assert_ne!(metadata.clone().with(NONCE_KEY, "00").signing_payload("general", b"hi"), payload);
+        // Length prefixes keep the context and content from running together.
+        assert_ne!(metadata.signing_payload("gen", b"eralhi"), payload);
+    }
+}
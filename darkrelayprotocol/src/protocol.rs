@@ -1,12 +1,68 @@
+use std::net::SocketAddr;
+
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
 use crate::channel::ChannelType;
+use crate::metadata::MessageMetadata;
 use crate::permissions::Role;
 
 pub type UserId = u64;
 pub type ChannelId = u64;
 pub type MessageId = u64;
+pub type PollId = u64;
+
+/// Longest username, in characters.
+pub const USERNAME_MAX_LEN: usize = 32;
+
+/// Longest channel name, in characters, not counting a leading `#`.
+pub const CHANNEL_NAME_MAX_LEN: usize = 32;
+
+/// Longest username or channel field the server accepts on the wire, in
+/// bytes. Longer fields are rejected before any normalizing or copying; the
+/// slack over the name limits leaves room for a `#` and stray whitespace.
+pub const MAX_NAME_FIELD_LEN: usize = 64;
+
+/// Longest message a server accepts unless configured otherwise, in
+/// plaintext bytes before encryption.
+pub const DEFAULT_MAX_MESSAGE_LEN: usize = 16 * 1024;
+
+/// Messages a server keeps per channel, so the most one `GetHistory` returns.
+pub const MAX_HISTORY_LEN: usize = 100;
+
+/// Starts the `SystemMessage` that carries the message of the day, sent right
+/// after `AuthSuccess`. Clients that don't look for it show it like any other
+/// system message.
+pub const MOTD_PREFIX: &str = "Message of the day: ";
+
+/// Longest message of the day a server accepts, in bytes.
+pub const MAX_MOTD_LEN: usize = 2048;
+
+/// Longest `signing_key` or `public_key` a server accepts, in bytes.
+pub const MAX_USER_KEY_LEN: usize = 64;
+
+/// `JoinFailure` reasons for a protected channel joined without a password,
+/// and with the wrong one. Clients can ask for the password and retry.
+pub const JOIN_PASSWORD_REQUIRED: &str = "channel requires a password";
+pub const JOIN_INVALID_PASSWORD: &str = "invalid channel password";
+
+/// What encryption may add on top of `max_message_len`: the length prefix,
+/// padding and an AES-GCM tag per layer.
+pub const MESSAGE_OVERHEAD_ALLOWANCE: usize = 1024;
+
+/// Largest `content` a server with `max_message_len` accepts, in bytes as sent.
+pub fn max_content_len(max_message_len: usize) -> usize {
+    max_message_len + MESSAGE_OVERHEAD_ALLOWANCE
+}
+
+/// Feature names listed in `ServerCapabilities`.
+pub mod features {
+    pub const DMS: &str = "dms";
+    pub const COMPRESSION: &str = "compression";
+    pub const POLLS: &str = "polls";
+    pub const RESYNC: &str = "resync";
+    pub const MY_CHANNELS: &str = "my_channels";
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageMeta {
@@ -25,6 +81,12 @@ pub struct UserInfo {
     pub id: UserId,
     pub username: String,
     pub joined_at: DateTime<Utc>,
+    /// Ed25519 public key the user's messages are signed with, as supplied at
+    /// login. Opaque to the server.
+    pub signing_key: Option<Vec<u8>>,
+    /// X25519 public key others encrypt DMs to, as supplied at login. Opaque
+    /// to the server.
+    pub public_key: Option<Vec<u8>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -34,11 +96,21 @@ pub struct ChannelInfo {
     pub is_public: bool,
     pub channel_type: ChannelType,
     pub user_role: Option<Role>,
+    /// Distinct users in the channel, however many sessions each has.
+    pub member_count: u32,
+    /// Member cap, counted in users like `member_count`; `None` is unlimited.
+    pub max_members: Option<u32>,
+    /// Set by the creator.
+    pub topic: Option<String>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
     pub id: MessageId,
+    /// Position in the channel's own sequence, starting at 1 with no gaps, so
+    /// a client can spot messages it missed. `id` stays unique across channels.
+    /// Always 0 for DMs.
+    pub seq: u64,
     pub user_id: UserId,
     pub username: String,
     pub content: Vec<u8>,
@@ -48,7 +120,13 @@ pub struct ChatMessage {
     pub nonce: Option<Vec<u8>>,
 
     /// Extensible map for future phases (encryption headers, routing hints, etc.).
-    pub metadata: Vec<(String, String)>,
+    pub metadata: MessageMetadata,
+
+    /// A tombstone: the message was deleted and its content, nonce and
+    /// metadata dropped. Kept in history so every client shows the same gap.
+    pub deleted: bool,
+    /// The content was changed after sending. Nothing edits messages yet.
+    pub edited: bool,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -76,6 +154,19 @@ pub struct LogEntry {
     pub details: String,
 }
 
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ConnectionInfo {
+    pub client_id: u64,
+    /// `None` until the connection has logged in.
+    pub username: Option<String>,
+    /// The channel the client joined most recently.
+    pub current_channel: Option<String>,
+    pub peer_addr: Option<SocketAddr>,
+    /// As reported in the client's `Connect`, if it sent one.
+    pub client_name: Option<String>,
+    pub client_version: Option<String>,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum ClientMessage {
     Connect {
@@ -96,23 +187,49 @@ pub enum ClientMessage {
         public_key: Vec<u8>,
     },
 
+    /// Create an account. Without `password` the server generates one and
+    /// returns it in `AuthSuccess::generated_password`.
     RegisterUser {
         meta: MessageMeta,
         username: String,
+        password: Option<String>,
+        /// Published as `UserInfo::signing_key` and `UserInfo::public_key`.
+        signing_key: Option<Vec<u8>>,
+        public_key: Option<Vec<u8>>,
     },
 
+    /// Each key replaces the published one; `None` keeps the old one.
     Login {
         meta: MessageMeta,
         username: String,
         password: String,
+        signing_key: Option<Vec<u8>>,
+        public_key: Option<Vec<u8>>,
+    },
+
+    /// Re-attach to a recently dropped session using the token from `AuthSuccess`.
+    Resume {
+        meta: MessageMeta,
+        token: String,
     },
 
+    Rename {
+        meta: MessageMeta,
+        new_username: String,
+    },
+
+    /// Join an existing channel; a missing one is refused, not created.
     JoinChannel {
         meta: MessageMeta,
         name: String,
         password: Option<String>,
     },
 
+    LeaveChannel {
+        meta: MessageMeta,
+        channel: String,
+    },
+
     SendMessage {
         meta: MessageMeta,
         channel: String,
@@ -120,14 +237,22 @@ pub enum ClientMessage {
         /// Opaque blob. In Phase 1, the client sends plaintext bytes for testing.
         content: Vec<u8>,
 
-        /// Additional message metadata (reserved for Phase 2+).
-        metadata: Vec<(String, String)>,
+        /// The nonce and key epoch of encrypted content, plus anything the
+        /// server should relay untouched.
+        metadata: MessageMetadata,
     },
 
     ListChannels {
         meta: MessageMeta,
     },
 
+    /// Every channel regardless of visibility (server SuperAdmin only).
+    ListAllChannels {
+        meta: MessageMeta,
+    },
+
+    /// Needs membership, except for public `ReadOnly` and `Announcement`
+    /// channels without a password, which anyone may read.
     GetHistory {
         meta: MessageMeta,
         channel: String,
@@ -201,9 +326,225 @@ pub enum ClientMessage {
         channel: String,
     },
 
+    /// Prune messages older than `max_age_seconds`; `None` keeps only the count cap.
+    SetRetention {
+        meta: MessageMeta,
+        channel: String,
+        max_age_seconds: Option<u64>,
+    },
+
+    /// Minimum seconds between a user's messages in `channel`; `None` turns it off.
+    SetSlowMode {
+        meta: MessageMeta,
+        channel: String,
+        interval_seconds: Option<u64>,
+    },
+
     Disconnect {
         meta: MessageMeta,
     },
+
+    /// Ask to switch to flagged, optionally compressed frames (see `frame`).
+    /// Frames sent after this one carry the flag byte.
+    RequestCompression {
+        meta: MessageMeta,
+    },
+
+    /// Read-only session without an account: public channels only, no sending.
+    GuestLogin {
+        meta: MessageMeta,
+    },
+
+    /// Replace the server's special auth key (server SuperAdmin only). Only
+    /// new connections are affected.
+    RotateSpecialKey {
+        meta: MessageMeta,
+        new_key: String,
+    },
+
+    /// Make another member the channel's SuperAdmin; the sender becomes Admin.
+    TransferOwnership {
+        meta: MessageMeta,
+        channel: String,
+        username: String,
+    },
+
+    /// Direct message to a user by name; the server resolves the recipient.
+    SendDM {
+        meta: MessageMeta,
+        recipient: String,
+        content: Vec<u8>,
+        metadata: MessageMetadata,
+    },
+
+    /// Every open connection (server SuperAdmin only).
+    ListConnections {
+        meta: MessageMeta,
+    },
+
+    /// Cap the number of members in `channel`; `None` removes the cap.
+    SetMaxMembers {
+        meta: MessageMeta,
+        channel: String,
+        max_members: Option<u32>,
+    },
+
+    /// Rename `channel` to `new_name`, keeping its members, history and roles.
+    RenameChannel {
+        meta: MessageMeta,
+        channel: String,
+        new_name: String,
+    },
+
+    /// Keepalive; answered with a `Pong` carrying the same nonce.
+    Ping {
+        meta: MessageMeta,
+        nonce: u64,
+    },
+
+    /// Delete a stored DM; allowed for its sender and its recipient.
+    DeleteDM {
+        meta: MessageMeta,
+        dm_id: MessageId,
+    },
+
+    /// Fetch the channel messages after `since_sequence` (a `ChatMessage::seq`),
+    /// e.g. to fill a gap after a brief disconnect.
+    Resync {
+        meta: MessageMeta,
+        channel: String,
+        since_sequence: u64,
+    },
+
+    /// Open a poll in a channel (requires `ManageChannel`).
+    CreatePoll {
+        meta: MessageMeta,
+        channel: String,
+        question: String,
+        options: Vec<String>,
+    },
+
+    /// Vote in an open poll; voting again changes the earlier vote.
+    Vote {
+        meta: MessageMeta,
+        channel: String,
+        poll_id: PollId,
+        option_index: u32,
+    },
+
+    /// Greeting sent privately to everyone who joins `channel`; `None` or
+    /// blank removes it.
+    SetWelcome {
+        meta: MessageMeta,
+        channel: String,
+        welcome: Option<String>,
+    },
+
+    /// Mark ourselves away (idle) or back.
+    SetPresence {
+        meta: MessageMeta,
+        away: bool,
+    },
+
+    /// Create an account for someone else, reserved names included (server
+    /// SuperAdmin only). Without `password` one is generated.
+    CreateUser {
+        meta: MessageMeta,
+        username: String,
+        password: Option<String>,
+    },
+
+    /// Close every connection of `username` (server SuperAdmin only). Unlike
+    /// `KickUser` it isn't about one channel, and they may reconnect.
+    ForceDisconnect {
+        meta: MessageMeta,
+        username: String,
+        reason: Option<String>,
+    },
+
+    /// Create `name` and join it as its SuperAdmin, refused if it already
+    /// exists. A password or `ChannelType::Private` makes it private; otherwise
+    /// it is public and announced.
+    CreateChannel {
+        meta: MessageMeta,
+        name: String,
+        password: Option<String>,
+        channel_type: ChannelType,
+        topic: Option<String>,
+    },
+
+    /// Ask for the channels our user is in across all of its sessions.
+    ListMyChannels {
+        meta: MessageMeta,
+    },
+
+    /// Look up an account by name; answered with `UserDetails`.
+    GetUserInfo {
+        meta: MessageMeta,
+        username: String,
+    },
+
+    /// Replace the message of the day (server SuperAdmin only). `None`
+    /// re-reads `DARKRELAY_MOTD_FILE`; blank text clears it.
+    SetMotd {
+        meta: MessageMeta,
+        motd: Option<String>,
+    },
+}
+
+impl ClientMessage {
+    pub fn meta(&self) -> &MessageMeta {
+        match self {
+            ClientMessage::Connect { meta, .. }
+            | ClientMessage::Auth { meta, .. }
+            | ClientMessage::EcdhPublicKey { meta, .. }
+            | ClientMessage::RegisterUser { meta, .. }
+            | ClientMessage::Login { meta, .. }
+            | ClientMessage::Resume { meta, .. }
+            | ClientMessage::Rename { meta, .. }
+            | ClientMessage::JoinChannel { meta, .. }
+            | ClientMessage::LeaveChannel { meta, .. }
+            | ClientMessage::SendMessage { meta, .. }
+            | ClientMessage::ListChannels { meta, .. }
+            | ClientMessage::ListAllChannels { meta, .. }
+            | ClientMessage::GetHistory { meta, .. }
+            | ClientMessage::DeleteMessage { meta, .. }
+            | ClientMessage::PromoteUser { meta, .. }
+            | ClientMessage::DemoteUser { meta, .. }
+            | ClientMessage::BanUser { meta, .. }
+            | ClientMessage::UnbanUser { meta, .. }
+            | ClientMessage::KickUser { meta, .. }
+            | ClientMessage::ListAdmins { meta, .. }
+            | ClientMessage::ListBans { meta, .. }
+            | ClientMessage::ViewLogs { meta, .. }
+            | ClientMessage::ChangeChannelType { meta, .. }
+            | ClientMessage::DeleteChannel { meta, .. }
+            | ClientMessage::SetRetention { meta, .. }
+            | ClientMessage::SetSlowMode { meta, .. }
+            | ClientMessage::Disconnect { meta, .. }
+            | ClientMessage::RequestCompression { meta, .. }
+            | ClientMessage::GuestLogin { meta, .. }
+            | ClientMessage::RotateSpecialKey { meta, .. }
+            | ClientMessage::TransferOwnership { meta, .. }
+            | ClientMessage::SendDM { meta, .. }
+            | ClientMessage::ListConnections { meta, .. }
+            | ClientMessage::SetMaxMembers { meta, .. }
+            | ClientMessage::RenameChannel { meta, .. }
+            | ClientMessage::Ping { meta, .. }
+            | ClientMessage::DeleteDM { meta, .. }
+            | ClientMessage::Resync { meta, .. }
+            | ClientMessage::CreatePoll { meta, .. }
+            | ClientMessage::Vote { meta, .. }
+            | ClientMessage::SetWelcome { meta, .. }
+            | ClientMessage::SetPresence { meta, .. }
+            | ClientMessage::CreateUser { meta, .. }
+            | ClientMessage::ForceDisconnect { meta, .. }
+            | ClientMessage::CreateChannel { meta, .. }
+            | ClientMessage::ListMyChannels { meta, .. }
+            | ClientMessage::GetUserInfo { meta, .. }
+            | ClientMessage::SetMotd { meta, .. } => meta,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -219,6 +560,9 @@ pub enum ServerMessage {
 
         /// Only present for registration.
         generated_password: Option<String>,
+
+        /// Presented via `ClientMessage::Resume` to restore this session after a drop.
+        resume_token: Option<String>,
     },
 
     AuthFailure {
@@ -237,9 +581,18 @@ pub enum ServerMessage {
         channels: Vec<ChannelInfo>,
     },
 
+    /// Response to `ListAllChannels`, includes private channels.
+    AllChannelList {
+        meta: MessageMeta,
+        channels: Vec<ChannelInfo>,
+    },
+
     JoinSuccess {
         meta: MessageMeta,
         channel: ChannelInfo,
+
+        /// `ChannelType::description()` of the channel's current type.
+        rules: String,
     },
 
     JoinFailure {
@@ -272,6 +625,13 @@ pub enum ServerMessage {
         user: UserInfo,
     },
 
+    UserRenamed {
+        meta: MessageMeta,
+        user_id: UserId,
+        old_username: String,
+        new_username: String,
+    },
+
     SystemMessage {
         meta: MessageMeta,
         text: String,
@@ -280,6 +640,8 @@ pub enum ServerMessage {
     ProtocolError {
         meta: MessageMeta,
         text: String,
+        /// `meta.id` of the client message refused, when the error answers one.
+        request_id: Option<u64>,
     },
 
     MessageDeleted {
@@ -363,8 +725,178 @@ pub enum ServerMessage {
         deleted_by: String,
     },
 
+    /// A public channel was created; sent to every logged-in
+    /// client so channel lists stay current. Private channels aren't announced.
+    ChannelCreated {
+        meta: MessageMeta,
+        channel: ChannelInfo,
+        created_by: String,
+    },
+
+    RetentionChanged {
+        meta: MessageMeta,
+        channel: String,
+        max_age_seconds: Option<u64>,
+        changed_by: String,
+    },
+
+    SlowModeChanged {
+        meta: MessageMeta,
+        channel: String,
+        interval_seconds: Option<u64>,
+        changed_by: String,
+    },
+
+    /// A send was rejected by the rate limit or slow mode; retry after the delay.
+    Cooldown {
+        meta: MessageMeta,
+        channel: String,
+        retry_after_ms: u64,
+        /// `meta.id` of the refused send.
+        request_id: Option<u64>,
+    },
+
     AdminError {
         meta: MessageMeta,
         reason: String,
+        /// `meta.id` of the client message refused, when the error answers one.
+        request_id: Option<u64>,
+    },
+
+    /// Reply to `RequestCompression`; frames after this one carry the flag byte.
+    CompressionEnabled {
+        meta: MessageMeta,
+    },
+
+    OwnershipTransferred {
+        meta: MessageMeta,
+        channel: String,
+        previous_owner: String,
+        new_owner: String,
+    },
+
+    /// Sent to the recipient and echoed to the sender. `message.username` is
+    /// the sender; `recipient` names the other side for the sender's copy.
+    DMReceived {
+        meta: MessageMeta,
+        recipient: String,
+        message: ChatMessage,
+    },
+
+    /// Tells the sender whether a DM reached a live session (`true`) or was
+    /// stored until the recipient connects (`false`). A stored DM gets a
+    /// second status with `delivered: true` once it is handed over.
+    DMDeliveryStatus {
+        meta: MessageMeta,
+        dm_id: MessageId,
+        delivered: bool,
+    },
+
+    /// Response to `ListConnections`, ordered by client id.
+    ConnectionList {
+        meta: MessageMeta,
+        connections: Vec<ConnectionInfo>,
+    },
+
+    MaxMembersChanged {
+        meta: MessageMeta,
+        channel: String,
+        max_members: Option<u32>,
+        changed_by: String,
+    },
+
+    Pong {
+        meta: MessageMeta,
+        nonce: u64,
+    },
+
+    ChannelRenamed {
+        meta: MessageMeta,
+        old_name: String,
+        new_name: String,
+        renamed_by: String,
+    },
+
+    /// Sent to every session of both participants when a DM is deleted.
+    DMDeleted {
+        meta: MessageMeta,
+        dm_id: MessageId,
+        deleted_by: String,
+    },
+
+    /// Response to `Resync`, oldest first. `truncated` means more messages
+    /// follow; ask again from the last `seq` returned. Messages already pruned
+    /// from history can't be resent, so the first `seq` may skip ahead.
+    ResyncChunk {
+        meta: MessageMeta,
+        channel: String,
+        messages: Vec<ChatMessage>,
+        truncated: bool,
+    },
+
+    /// Broadcast to channel members when a poll opens.
+    PollCreated {
+        meta: MessageMeta,
+        channel: String,
+        poll_id: PollId,
+        question: String,
+        options: Vec<String>,
+        created_by: String,
+    },
+
+    /// Vote counts per option, broadcast after every vote.
+    PollUpdated {
+        meta: MessageMeta,
+        channel: String,
+        poll_id: PollId,
+        tallies: Vec<u32>,
+    },
+
+    /// Last frame before the server closes in response to `Disconnect`;
+    /// everything sent before it has been delivered.
+    DisconnectAck {
+        meta: MessageMeta,
+    },
+
+    /// Sent right after `AuthSuccess`: the optional features this session
+    /// may use (see `features`) and the server's size limits in bytes.
+    /// `max_message_len` counts plaintext; see `max_content_len`.
+    ServerCapabilities {
+        meta: MessageMeta,
+        features: Vec<String>,
+        max_frame_len: u32,
+        max_message_len: u32,
+    },
+
+    /// `username` went away or came back; sent to everyone sharing a channel
+    /// with them.
+    PresenceChanged {
+        meta: MessageMeta,
+        username: String,
+        away: bool,
+    },
+
+    /// Response to `CreateUser`. `generated_password` is set when the admin
+    /// didn't choose one and must be passed on to the user.
+    UserCreated {
+        meta: MessageMeta,
+        user: UserInfo,
+        generated_password: Option<String>,
+    },
+
+    /// Response to `ListMyChannels`, sorted by name. `user_role` is the best
+    /// role any of the user's sessions holds in the channel.
+    MyChannels {
+        meta: MessageMeta,
+        channels: Vec<ChannelInfo>,
+    },
+
+    /// Response to `GetUserInfo`. `last_seen` is when the server last got a
+    /// frame from any of the user's sessions; `None` if never since startup.
+    UserDetails {
+        meta: MessageMeta,
+        user: UserInfo,
+        last_seen: Option<DateTime<Utc>>,
+        online: bool,
     },
 }
@@ -0,0 +1,129 @@
+//! One `MessageReceived` with a 4 KiB payload sent to a 1000-member channel:
+//!
+//! ```text
+//! cargo bench -p darkrelayserver --bench broadcast
+//! ```
+//!
+//! `shared` is `Registry::send_many`, which queues one `Arc` for everyone.
+//! `cloned_per_member` is what it did before, a full copy of the message per
+//! recipient. Each prints its allocations per broadcast before timing.
+
+use std::{
+    alloc::{GlobalAlloc, Layout, System},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, Criterion};
+use darkrelayprotocol::{
+    metadata::MessageMetadata,
+    protocol::{ChatMessage, MessageMeta, ServerMessage},
+};
+use darkrelayserver::registry::Registry;
+use tokio::sync::mpsc;
+
+const MEMBERS: u64 = 1000;
+const PAYLOAD_LEN: usize = 4096;
+
+/// Counts every allocation, so the two strategies can be compared.
+struct CountingAlloc;
+
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+type Outbox = mpsc::Receiver<Arc<ServerMessage>>;
+
+fn channel_of_members() -> (Registry, Vec<u64>, Vec<Outbox>) {
+    let mut reg = Registry::new();
+    let ids: Vec<u64> = (1..=MEMBERS).collect();
+    let outboxes = ids
+        .iter()
+        .map(|id| {
+            let (tx, rx) = mpsc::channel(4);
+            reg.register(*id, tx);
+            rx
+        })
+        .collect();
+    (reg, ids, outboxes)
+}
+
+fn message() -> ServerMessage {
+    ServerMessage::MessageReceived {
+        meta: MessageMeta::new(1, Utc::now()),
+        channel: "general".to_string(),
+        message: ChatMessage {
+            id: 1,
+            seq: 1,
+            user_id: 1,
+            username: "alice".to_string(),
+            content: vec![b'x'; PAYLOAD_LEN],
+            timestamp: Utc::now(),
+            nonce: None,
+            metadata: MessageMetadata::new(),
+            deleted: false,
+            edited: false,
+        },
+    }
+}
+
+fn bench_strategy(c: &mut Criterion, name: &str, broadcast: impl Fn(&Registry, &[u64], &ServerMessage)) {
+    let (reg, ids, mut outboxes) = channel_of_members();
+    let msg = message();
+    let mut drain = || {
+        for rx in &mut outboxes {
+            while rx.try_recv().is_ok() {}
+        }
+    };
+
+    let before = ALLOCATIONS.load(Ordering::Relaxed);
+    broadcast(&reg, &ids, &msg);
+    println!("{name}: {} allocations per broadcast", ALLOCATIONS.load(Ordering::Relaxed) - before);
+    drain();
+
+    c.bench_function(name, |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for _ in 0..iters {
+                let start = Instant::now();
+                broadcast(&reg, &ids, &msg);
+                elapsed += start.elapsed();
+                drain();
+            }
+            elapsed
+        })
+    });
+}
+
+fn broadcast(c: &mut Criterion) {
+    bench_strategy(c, "shared", |reg, ids, msg| reg.send_many(ids, msg));
+    bench_strategy(c, "cloned_per_member", |reg, ids, msg| {
+        for id in ids {
+            reg.send(*id, msg.clone());
+        }
+    });
+}
+
+criterion_group!(benches, broadcast);
+criterion_main!(benches);
@@ -0,0 +1,150 @@
+//! Time and lock acquisitions per `SendMessage` and `JoinChannel`, through
+//! the real handlers:
+//!
+//! ```text
+//! cargo bench -p darkrelayserver --features lock-stats --bench hot_paths
+//! ```
+//!
+//! Each benchmark first prints how many `AppState` locks one message takes.
+//! Before the two paths gathered their reads under shared guards, a plain
+//! send took 8-9 (without the spam check, which has added one since) and a
+//! join of an existing channel 14. Both now take 7.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use criterion::{criterion_group, criterion_main, Criterion};
+use darkrelayprotocol::{
+    metadata::MessageMetadata,
+    protocol::{ServerMessage, UserInfo},
+};
+use darkrelayserver::{config::ServerConfig, handler, lock_stats, spam::SpamPolicy, AppState};
+use tokio::{runtime::Runtime, sync::mpsc};
+
+const CHANNEL: &str = "general";
+/// Members already in the channel, each with an outbound queue to fill.
+const MEMBERS: u64 = 100;
+/// How often the queues are emptied, outside the timing, so nobody lags out.
+const DRAIN_EVERY: u64 = 64;
+
+type Outbox = mpsc::Receiver<Arc<ServerMessage>>;
+
+/// A channel of `MEMBERS` users, all connected.
+struct Room {
+    state: Arc<AppState>,
+    outboxes: Vec<Outbox>,
+}
+
+impl Room {
+    async fn new() -> Self {
+        // Nothing to trip over: no rate limit, no spam check.
+        let config = ServerConfig {
+            rate_limit: (usize::MAX, 1),
+            spam: SpamPolicy { repeats: 0, ..SpamPolicy::default() },
+            ..ServerConfig::default()
+        };
+        let state = Arc::new(AppState::new(&config));
+        state.open_startup_channels().await;
+        let mut outboxes = Vec::new();
+        for id in 1..=MEMBERS {
+            outboxes.push(connect(&state, id, DRAIN_EVERY as usize * 2).await);
+            handler::handle_join_channel(&state, id, true, CHANNEL.to_string(), None, None).await.unwrap();
+        }
+        let mut room = Self { state, outboxes };
+        room.drain();
+        room
+    }
+
+    fn drain(&mut self) {
+        for rx in &mut self.outboxes {
+            while rx.try_recv().is_ok() {}
+        }
+    }
+
+    /// One send from a plain member: elapsed time and locks taken.
+    async fn send(&self) -> (Duration, u64) {
+        let before = lock_stats::acquisitions();
+        let start = Instant::now();
+        handler::handle_send_message(&self.state, 2, true, false, CHANNEL, b"hello".to_vec(), MessageMetadata::new())
+            .await
+            .unwrap();
+        (start.elapsed(), lock_stats::acquisitions() - before)
+    }
+
+    /// One newcomer joining, let go again outside the timing.
+    async fn join(&self, id: u64) -> (Duration, u64) {
+        let _outbox = connect(&self.state, id, 16).await;
+        let before = lock_stats::acquisitions();
+        let start = Instant::now();
+        handler::handle_join_channel(&self.state, id, true, CHANNEL.to_string(), None, None).await.unwrap();
+        let taken = (start.elapsed(), lock_stats::acquisitions() - before);
+        self.state.channels.write().await.leave(id, CHANNEL);
+        self.state.registry.write().await.remove(id);
+        taken
+    }
+}
+
+async fn connect(state: &AppState, id: u64, capacity: usize) -> Outbox {
+    let (tx, rx) = mpsc::channel(capacity);
+    let mut reg = state.registry.write().await;
+    reg.register(id, tx);
+    reg.set_user(id, UserInfo {
+        id,
+        username: format!("user{id}"),
+        joined_at: Utc::now(),
+        signing_key: None,
+        public_key: None,
+    });
+    rx
+}
+
+fn send_message(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut room = rt.block_on(Room::new());
+    let (_, locks) = rt.block_on(room.send());
+    println!("send_message: {locks} lock acquisitions per message");
+
+    c.bench_function("send_message", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for i in 1..=iters {
+                elapsed += rt.block_on(room.send()).0;
+                if i % DRAIN_EVERY == 0 {
+                    room.drain();
+                }
+            }
+            room.drain();
+            elapsed
+        })
+    });
+}
+
+fn join_channel(c: &mut Criterion) {
+    let rt = Runtime::new().unwrap();
+    let mut room = rt.block_on(Room::new());
+    let mut next_id = MEMBERS + 1;
+    let (_, locks) = rt.block_on(room.join(next_id));
+    room.drain();
+    println!("join_channel: {locks} lock acquisitions per join");
+
+    c.bench_function("join_channel", |b| {
+        b.iter_custom(|iters| {
+            let mut elapsed = Duration::ZERO;
+            for i in 1..=iters {
+                next_id += 1;
+                elapsed += rt.block_on(room.join(next_id)).0;
+                if i % DRAIN_EVERY == 0 {
+                    room.drain();
+                }
+            }
+            room.drain();
+            elapsed
+        })
+    });
+}
+
+criterion_group!(benches, send_message, join_channel);
+criterion_main!(benches);
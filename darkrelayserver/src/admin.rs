@@ -2,102 +2,221 @@ use chrono::Utc;
 use darkrelayprotocol::{
     channel::ChannelType,
     permissions::{has_permission, Permission, Role},
-    protocol::{AdminInfo, ChannelId, LogEntry, UserId},
+    protocol::{ChannelId, LogEntry, UserId},
 };
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use tracing::warn;
+
+use crate::{
+    admin_log::AdminLogStore,
+    auth::{is_guest_name, normalize_username},
+    role_store::{RoleStore, StoredChannel},
+};
+
+/// Log entries kept in memory per channel; older ones are only on disk.
+const MAX_LOGS_IN_MEMORY: usize = 1000;
+
+/// Most log entries one `ViewLogs` returns, whatever limit it asks for.
+pub const MAX_LOGS_PER_REQUEST: usize = 500;
+
+/// A log read prepared under the admin lock. `run` may touch the disk, so
+/// callers run it after releasing the lock, off the async runtime.
+#[derive(Debug)]
+pub struct LogQuery {
+    channel: String,
+    limit: usize,
+    cached: Vec<LogEntry>,
+    store: Option<AdminLogStore>,
+}
+
+impl LogQuery {
+    /// Newest first. Read from disk, which also covers entries written
+    /// before a restart, when memory holds fewer than asked for.
+    pub fn run(self) -> Vec<LogEntry> {
+        let Some(store) = self.store.filter(|_| self.cached.len() < self.limit) else {
+            return self.cached;
+        };
+        match store.read_recent(&self.channel, self.limit) {
+            // The file may have moved with a rename since the query was made.
+            Ok(entries) if entries.len() >= self.cached.len() => entries,
+            Ok(_) => self.cached,
+            Err(e) => {
+                warn!(channel = self.channel, error = %e, "failed to read admin log, using memory");
+                self.cached
+            }
+        }
+    }
+}
+
+/// A channel the manager persists roles for, as `open_channel` registered it.
+#[derive(Debug)]
+struct OpenChannel {
+    name: String,
+    channel_type: ChannelType,
+}
 
 #[derive(Debug, Default)]
 pub struct AdminManager {
-    channel_roles: HashMap<ChannelId, HashMap<UserId, Role>>,
-    channel_types: HashMap<ChannelId, ChannelType>,
+    /// Roles belong to accounts, so every session of a user shares them.
+    /// Keyed by `normalize_username`, which is also what survives a restart.
+    channel_roles: HashMap<ChannelId, HashMap<String, Role>>,
+    channels: HashMap<ChannelId, OpenChannel>,
+    /// Usernames that hold server-wide SuperAdmin (from `DARKRELAY_SUPERADMINS`).
+    server_super_admins: HashSet<String>,
     logs: HashMap<ChannelId, Vec<LogEntry>>,
+    /// Durable copy of every log entry, if configured.
+    log_store: Option<AdminLogStore>,
+    /// Durable channel types and roles, if configured.
+    role_store: Option<RoleStore>,
 }
 
 impl AdminManager {
     pub fn new() -> Self {
         Self {
             channel_roles: HashMap::new(),
-            channel_types: HashMap::new(),
+            channels: HashMap::new(),
+            server_super_admins: HashSet::new(),
             logs: HashMap::new(),
+            log_store: None,
+            role_store: None,
         }
     }
 
-    pub fn set_channel_creator(&mut self, channel_id: ChannelId, user_id: UserId) {
-        self.channel_roles
-            .entry(channel_id)
-            .or_insert_with(HashMap::new)
-            .insert(user_id, Role::Admin);
+    pub fn set_log_store(&mut self, store: AdminLogStore) {
+        self.log_store = Some(store);
     }
 
-    pub fn get_role(&self, channel_id: ChannelId, user_id: UserId) -> Role {
+    pub fn set_role_store(&mut self, store: RoleStore) {
+        self.role_store = Some(store);
+    }
+
+    /// The type a channel of this name had before a restart. A re-created
+    /// channel takes it over so it can't reopen with weaker settings.
+    pub fn stored_channel_type(&self, name: &str) -> Option<ChannelType> {
+        self.role_store.as_ref()?.get(name).map(|stored| stored.channel_type)
+    }
+
+    /// Start tracking a new channel, restoring the roles it had before a
+    /// restart. The creator owns it (`SuperAdmin` is the only role that can
+    /// delete it) unless a restored owner already does.
+    pub fn open_channel(&mut self, channel_id: ChannelId, name: &str, channel_type: ChannelType, creator: Option<&str>) {
+        let mut roles: HashMap<String, Role> = self
+            .role_store
+            .as_ref()
+            .and_then(|store| store.get(name))
+            .map(|stored| stored.roles.clone().into_iter().collect())
+            .unwrap_or_default();
+        if let Some(creator) = creator.filter(|_| !roles.values().any(|r| *r == Role::SuperAdmin)) {
+            roles.insert(normalize_username(creator), Role::SuperAdmin);
+        }
+        self.channel_roles.insert(channel_id, roles);
+        self.channels.insert(channel_id, OpenChannel { name: name.to_string(), channel_type });
+        self.persist(channel_id);
+    }
+
+    /// Write `channel_id`'s type and staff to the role store. Guests are
+    /// skipped: their names are handed out again after a restart.
+    fn persist(&mut self, channel_id: ChannelId) {
+        let (Some(store), Some(channel)) = (self.role_store.as_mut(), self.channels.get(&channel_id)) else {
+            return;
+        };
+        let roles: BTreeMap<String, Role> = self
+            .channel_roles
+            .get(&channel_id)
+            .into_iter()
+            .flatten()
+            .filter(|(name, role)| **role > Role::User && !is_guest_name(name))
+            .map(|(name, role)| (name.clone(), *role))
+            .collect();
+        let stored = StoredChannel { channel_type: channel.channel_type, roles };
+        if let Err(e) = store.put(&channel.name, stored) {
+            warn!(channel = channel.name, error = %e, "failed to persist channel roles");
+        }
+    }
+
+    /// `channel_type` lives in the `ChannelManager`; this keeps the stored copy current.
+    pub fn set_channel_type(&mut self, channel_id: ChannelId, channel_type: ChannelType) {
+        if let Some(channel) = self.channels.get_mut(&channel_id) {
+            channel.channel_type = channel_type;
+            self.persist(channel_id);
+        }
+    }
+
+    /// Hand the channel to `new_owner`; the previous owner stays on as `Admin`.
+    pub fn transfer_ownership(&mut self, channel_id: ChannelId, old_owner: &str, new_owner: &str) {
+        let roles = self.channel_roles.entry(channel_id).or_default();
+        roles.insert(normalize_username(new_owner), Role::SuperAdmin);
+        roles.insert(normalize_username(old_owner), Role::Admin);
+        self.persist(channel_id);
+    }
+
+    pub fn get_role(&self, channel_id: ChannelId, username: &str) -> Role {
         self.channel_roles
             .get(&channel_id)
-            .and_then(|roles| roles.get(&user_id))
+            .and_then(|roles| roles.get(&normalize_username(username)))
             .copied()
             .unwrap_or(Role::User)
     }
 
-    pub fn set_role(&mut self, channel_id: ChannelId, user_id: UserId, role: Role) {
+    pub fn set_role(&mut self, channel_id: ChannelId, username: &str, role: Role) {
         self.channel_roles
             .entry(channel_id)
-            .or_insert_with(HashMap::new)
-            .insert(user_id, role);
+            .or_default()
+            .insert(normalize_username(username), role);
+        self.persist(channel_id);
     }
 
-    pub fn has_permission(&self, channel_id: ChannelId, user_id: UserId, permission: Permission) -> bool {
-        let role = self.get_role(channel_id, user_id);
-        has_permission(role, permission)
+    /// Follow a user's rename in every channel they hold a role in.
+    pub fn rename_user(&mut self, old: &str, new: &str) {
+        let (old, new) = (normalize_username(old), normalize_username(new));
+        if old == new {
+            return;
+        }
+        let moved: Vec<ChannelId> = self
+            .channel_roles
+            .iter_mut()
+            .filter_map(|(id, roles)| roles.remove(&old).map(|role| (*id, roles, role)))
+            .map(|(id, roles, role)| {
+                roles.insert(new.clone(), role);
+                id
+            })
+            .collect();
+        for channel_id in moved {
+            self.persist(channel_id);
+        }
     }
 
-    pub fn can_send_message(&self, channel_id: ChannelId, user_id: UserId) -> bool {
-        let role = self.get_role(channel_id, user_id);
-        let channel_type = self.get_channel_type(channel_id);
-
-        match channel_type {
-            ChannelType::Public | ChannelType::Private => {
-                has_permission(role, Permission::SendMessage)
-            }
-            ChannelType::AdminOnly | ChannelType::ReadOnly => {
-                role >= Role::Admin
-            }
-            ChannelType::Announcement => {
-                role >= Role::SuperAdmin
-            }
-        }
+    /// Matched like account names, so `Root` in the config is the `root`
+    /// account and nobody else.
+    pub fn set_server_super_admins(&mut self, usernames: HashSet<String>) {
+        self.server_super_admins = usernames.iter().map(|n| normalize_username(n)).collect();
     }
 
-    pub fn list_admins(&self, channel_id: ChannelId, user_map: &HashMap<UserId, String>) -> Vec<AdminInfo> {
-        if let Some(roles) = self.channel_roles.get(&channel_id) {
-            roles
-                .iter()
-                .filter(|(_, role)| **role >= Role::Moderator)
-                .filter_map(|(user_id, role)| {
-                    user_map.get(user_id).map(|username| AdminInfo {
-                        user_id: *user_id,
-                        username: username.clone(),
-                        role: *role,
-                    })
-                })
-                .collect()
-        } else {
-            Vec::new()
-        }
+    pub fn is_server_super_admin(&self, username: &str) -> bool {
+        self.server_super_admins.contains(&normalize_username(username))
     }
 
-    pub fn set_channel_type(&mut self, channel_id: ChannelId, channel_type: ChannelType) {
-        self.channel_types.insert(channel_id, channel_type);
+    pub fn has_permission(&self, channel_id: ChannelId, username: &str, permission: Permission) -> bool {
+        let role = self.get_role(channel_id, username);
+        has_permission(role, permission)
     }
 
-    pub fn get_channel_type(&self, channel_id: ChannelId) -> ChannelType {
-        self.channel_types
+    /// Moderators and up, by normalized username.
+    pub fn list_admins(&self, channel_id: ChannelId) -> Vec<(String, Role)> {
+        self.channel_roles
             .get(&channel_id)
-            .copied()
-            .unwrap_or(ChannelType::Public)
+            .into_iter()
+            .flatten()
+            .filter(|(_, role)| **role >= Role::Moderator)
+            .map(|(name, role)| (name.clone(), *role))
+            .collect()
     }
 
+    #[allow(clippy::too_many_arguments)]
     pub fn log_action(
         &mut self,
         channel_id: ChannelId,
+        channel: &str,
         user_id: UserId,
         username: String,
         action: String,
@@ -113,29 +232,148 @@ impl AdminManager {
             details,
         };
 
+        if let Some(store) = &self.log_store {
+            if let Err(e) = store.append(channel, &entry) {
+                warn!(channel, error = %e, "failed to persist admin log entry");
+            }
+        }
+
         self.logs
             .entry(channel_id)
-            .or_insert_with(Vec::new)
+            .or_default()
             .push(entry);
 
         if let Some(logs) = self.logs.get_mut(&channel_id) {
-            if logs.len() > 1000 {
-                logs.drain(0..(logs.len() - 1000));
+            if logs.len() > MAX_LOGS_IN_MEMORY {
+                logs.drain(0..(logs.len() - MAX_LOGS_IN_MEMORY));
             }
         }
     }
 
-    pub fn get_logs(&self, channel_id: ChannelId, limit: usize) -> Vec<LogEntry> {
-        if let Some(logs) = self.logs.get(&channel_id) {
-            logs.iter().rev().take(limit).cloned().collect()
-        } else {
-            Vec::new()
+    /// The newest `limit` entries (at most `MAX_LOGS_PER_REQUEST`) of a
+    /// channel, to be fetched with `LogQuery::run`.
+    pub fn get_logs(&self, channel_id: ChannelId, channel: &str, limit: usize) -> LogQuery {
+        let limit = limit.min(MAX_LOGS_PER_REQUEST);
+        let cached = self
+            .logs
+            .get(&channel_id)
+            .map(|logs| logs.iter().rev().take(limit).cloned().collect())
+            .unwrap_or_default();
+        LogQuery { channel: channel.to_string(), limit, cached, store: self.log_store.clone() }
+    }
+
+    /// Roles and in-memory logs are keyed by channel id; only the files follow the name.
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        if let Some(store) = &self.log_store {
+            if let Err(e) = store.rename(old, new) {
+                warn!(old, new, error = %e, "failed to rename admin log");
+            }
+        }
+        if let Some(store) = &mut self.role_store {
+            if let Err(e) = store.rename(old, new) {
+                warn!(old, new, error = %e, "failed to rename stored channel roles");
+            }
+        }
+        if let Some(channel) = self.channels.values_mut().find(|c| c.name == old) {
+            channel.name = new.to_string();
         }
     }
 
-    pub fn remove_channel(&mut self, channel_id: ChannelId) {
+    pub fn remove_channel(&mut self, channel_id: ChannelId, channel: &str) {
         self.channel_roles.remove(&channel_id);
-        self.channel_types.remove(&channel_id);
+        self.channels.remove(&channel_id);
         self.logs.remove(&channel_id);
+        if let Some(store) = &mut self.role_store {
+            if let Err(e) = store.remove(channel) {
+                warn!(channel, error = %e, "failed to remove stored channel roles");
+            }
+        }
+
+        if let Some(store) = &self.log_store {
+            if let Err(e) = store.archive(channel) {
+                warn!(channel, error = %e, "failed to archive admin log");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{env, fs};
+
+    #[test]
+    fn test_logs_survive_restart_in_order() {
+        let dir = env::temp_dir().join(format!("darkrelay-admin-restart-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        {
+            let mut admin = AdminManager::new();
+            admin.set_log_store(AdminLogStore::open(&dir).unwrap());
+            for action in ["promote_user", "kick_user", "ban_user"] {
+                admin.log_action(2, "dev", 1, "alice".to_string(), action.to_string(), "bob".to_string(), String::new());
+            }
+        }
+
+        // After a restart the channel may get a different id.
+        let mut admin = AdminManager::new();
+        admin.set_log_store(AdminLogStore::open(&dir).unwrap());
+        let actions = |admin: &AdminManager, limit| -> Vec<String> {
+            admin.get_logs(5, "dev", limit).run().into_iter().map(|e| e.action).collect()
+        };
+        assert_eq!(actions(&admin, 10), ["ban_user", "kick_user", "promote_user"]);
+
+        admin.log_action(5, "dev", 1, "alice".to_string(), "unban_user".to_string(), "bob".to_string(), String::new());
+        assert_eq!(actions(&admin, 1), ["unban_user"]);
+        assert_eq!(actions(&admin, 2), ["unban_user", "ban_user"]);
+
+        admin.remove_channel(5, "dev");
+        assert!(admin.get_logs(6, "dev", 10).run().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_roles_survive_restart() {
+        let dir = env::temp_dir().join(format!("darkrelay-roles-restart-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let file = dir.join("channel_roles.json");
+
+        {
+            let mut admin = AdminManager::new();
+            admin.set_role_store(RoleStore::open(&file).unwrap());
+            admin.open_channel(2, "dev", ChannelType::Public, Some("Alice"));
+            admin.set_role(2, "bob", Role::Moderator);
+            admin.set_role(2, "guest-7", Role::Moderator);
+            admin.set_channel_type(2, ChannelType::Private);
+            admin.open_channel(3, "old", ChannelType::Public, Some("alice"));
+            admin.rename_channel("old", "new");
+            admin.open_channel(4, "gone", ChannelType::Public, Some("alice"));
+            admin.remove_channel(4, "gone");
+        }
+
+        // After a restart the channels get different ids and are re-created
+        // by someone else; the stored owner and type win.
+        let mut admin = AdminManager::new();
+        admin.set_role_store(RoleStore::open(&file).unwrap());
+        assert_eq!(admin.stored_channel_type("dev"), Some(ChannelType::Private));
+        assert_eq!(admin.stored_channel_type("gone"), None);
+        admin.open_channel(7, "dev", ChannelType::Private, Some("carol"));
+        assert_eq!(admin.get_role(7, "alice"), Role::SuperAdmin);
+        assert_eq!(admin.get_role(7, "BOB"), Role::Moderator);
+        assert_eq!(admin.get_role(7, "guest-7"), Role::User, "guest names are handed out again");
+        assert_eq!(admin.get_role(7, "carol"), Role::User);
+        admin.open_channel(8, "new", ChannelType::Public, None);
+        assert_eq!(admin.get_role(8, "alice"), Role::SuperAdmin);
+        admin.open_channel(9, "gone", ChannelType::Public, Some("carol"));
+        assert_eq!(admin.get_role(9, "carol"), Role::SuperAdmin);
+
+        admin.rename_user("bob", "robert");
+        admin.set_role(7, "alice", Role::User);
+        let mut staff = admin.list_admins(7);
+        staff.sort();
+        assert_eq!(staff, [("robert".to_string(), Role::Moderator)]);
+
+        let _ = fs::remove_dir_all(&dir);
     }
 }
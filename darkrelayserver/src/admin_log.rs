@@ -0,0 +1,190 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use chrono::Utc;
+use darkrelayprotocol::protocol::LogEntry;
+use tracing::warn;
+
+/// Bytes read per step when scanning a log file backwards.
+const TAIL_CHUNK: usize = 8 * 1024;
+
+/// Append-only JSONL files of admin actions, one per channel.
+///
+/// Files are keyed by channel name because channel ids are reassigned on
+/// restart. Each entry is written as a single line with one `write_all` on a
+/// file opened in append mode, and callers serialize through the
+/// `AdminManager` lock, so concurrent actions never interleave. Reads can run
+/// on a clone without that lock.
+#[derive(Debug, Clone)]
+pub struct AdminLogStore {
+    dir: PathBuf,
+}
+
+impl AdminLogStore {
+    pub fn open(dir: impl Into<PathBuf>) -> io::Result<Self> {
+        let dir = dir.into();
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Hex-encoded so logs written before channel names were restricted keep
+    /// their file names.
+    fn path(&self, channel: &str) -> PathBuf {
+        self.dir.join(format!("channel-{}.jsonl", hex::encode(channel)))
+    }
+
+    pub fn append(&self, channel: &str, entry: &LogEntry) -> io::Result<()> {
+        let mut line = serde_json::to_vec(entry)?;
+        line.push(b'\n');
+
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path(channel))?;
+        file.write_all(&line)
+    }
+
+    /// The newest `limit` entries, newest first. The file is read backwards
+    /// from its end, so a long history costs no more than the lines returned.
+    /// Unreadable lines (e.g. a write cut short by a crash) are skipped.
+    pub fn read_recent(&self, channel: &str, limit: usize) -> io::Result<Vec<LogEntry>> {
+        let mut file = match File::open(self.path(channel)) {
+            Ok(f) => f,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+
+        let mut entries = Vec::new();
+        let mut pos = file.seek(SeekFrom::End(0))?;
+        // The start of a line whose end was in the chunk read before.
+        let mut carry = Vec::new();
+        while pos > 0 && entries.len() < limit {
+            let len = TAIL_CHUNK.min(pos as usize);
+            pos -= len as u64;
+            let mut chunk = vec![0; len];
+            file.seek(SeekFrom::Start(pos))?;
+            file.read_exact(&mut chunk)?;
+            chunk.extend_from_slice(&carry);
+
+            // Only the file's first byte is known to start a line.
+            let start = match chunk.iter().position(|b| *b == b'\n') {
+                _ if pos == 0 => 0,
+                Some(newline) => newline + 1,
+                None => {
+                    carry = chunk;
+                    continue;
+                }
+            };
+            for line in chunk[start..].split(|b| *b == b'\n').rev() {
+                if line.is_empty() || entries.len() == limit {
+                    continue;
+                }
+                match serde_json::from_slice::<LogEntry>(line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => warn!(channel, error = %e, "skipping corrupt admin log line"),
+                }
+            }
+            chunk.truncate(start);
+            carry = chunk;
+        }
+
+        Ok(entries)
+    }
+
+    /// Carry a renamed channel's log over to its new name.
+    pub fn rename(&self, old: &str, new: &str) -> io::Result<()> {
+        let path = self.path(old);
+        if !path.exists() {
+            return Ok(());
+        }
+        fs::rename(path, self.path(new))
+    }
+
+    /// Move a deleted channel's log aside so a new channel with the same name
+    /// starts clean while the history is kept on disk.
+    pub fn archive(&self, channel: &str) -> io::Result<()> {
+        let path = self.path(channel);
+        if !path.exists() {
+            return Ok(());
+        }
+        let archived = self.dir.join(format!(
+            "channel-{}.deleted-{}.jsonl",
+            hex::encode(channel),
+            Utc::now().timestamp()
+        ));
+        fs::rename(path, archived)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    #[test]
+    fn test_corrupt_lines_are_skipped() {
+        let dir = env::temp_dir().join(format!("darkrelay-admin-log-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = AdminLogStore::open(&dir).unwrap();
+
+        let entry = |action: &str| LogEntry {
+            timestamp: Utc::now(),
+            user_id: 1,
+            username: "alice".to_string(),
+            action: action.to_string(),
+            target: "bob".to_string(),
+            details: String::new(),
+        };
+        store.append("dev/../x", &entry("kick_user")).unwrap();
+        OpenOptions::new()
+            .append(true)
+            .open(store.path("dev/../x"))
+            .unwrap()
+            .write_all(b"{\"timestamp\":\n")
+            .unwrap();
+        store.append("dev/../x", &entry("ban_user")).unwrap();
+
+        let actions: Vec<_> = store
+            .read_recent("dev/../x", 10)
+            .unwrap()
+            .into_iter()
+            .map(|e| e.action)
+            .collect();
+        assert_eq!(actions, ["ban_user", "kick_user"]);
+        assert!(store.read_recent("other", 10).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_read_recent_from_the_tail() {
+        let dir = env::temp_dir().join(format!("darkrelay-admin-log-tail-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let store = AdminLogStore::open(&dir).unwrap();
+
+        // Enough entries, with details long enough, that lines straddle chunks.
+        for i in 0..300 {
+            let entry = LogEntry {
+                timestamp: Utc::now(),
+                user_id: i,
+                username: "alice".to_string(),
+                action: format!("action_{i}"),
+                target: "bob".to_string(),
+                details: "x".repeat(i as usize * 7 % 500),
+            };
+            store.append("dev", &entry).unwrap();
+        }
+
+        let recent = store.read_recent("dev", 5).unwrap();
+        assert_eq!(recent.iter().map(|e| e.user_id).collect::<Vec<_>>(), [299, 298, 297, 296, 295]);
+        let all = store.read_recent("dev", 1000).unwrap();
+        assert_eq!(all.len(), 300);
+        assert!(all.iter().rev().enumerate().all(|(i, e)| e.user_id == i as u64));
+        assert!(store.read_recent("dev", 0).unwrap().is_empty());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
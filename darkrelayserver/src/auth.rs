@@ -1,24 +1,183 @@
-use std::{
-    collections::HashMap,
-    time::{SystemTime, UNIX_EPOCH},
-};
+use std::collections::{HashMap, HashSet};
 
+use argon2::{
+    password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+    Argon2,
+};
 use chrono::{DateTime, Utc};
+use rand::{distributions::Alphanumeric, Rng};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+use tracing::warn;
+
+use darkrelayprotocol::protocol::{UserId, UserInfo, MAX_USER_KEY_LEN, USERNAME_MAX_LEN};
+
+use crate::user_store::{StoredUser, UserStore};
+
+/// Guest ids are allocated from here up so they never collide with accounts.
+pub const GUEST_ID_BASE: UserId = 1 << 62;
+
+const GUEST_PREFIX: &str = "guest-";
+
+pub fn is_guest(user_id: UserId) -> bool {
+    user_id >= GUEST_ID_BASE
+}
+
+/// Guest names can't be registered, so this holds for guests only.
+pub fn is_guest_name(username: &str) -> bool {
+    normalize_username(username).starts_with(GUEST_PREFIX)
+}
+
+pub const USERNAME_MIN_LEN: usize = 3;
+
+/// Keys a client publishes for its user at register or login. The server
+/// only stores and hands them out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PublishedKeys {
+    pub signing_key: Option<Vec<u8>>,
+    pub public_key: Option<Vec<u8>>,
+}
+
+impl PublishedKeys {
+    /// Keys are opaque, but bounded like any other field.
+    pub fn check(&self) -> Result<(), String> {
+        for (name, key) in [("signing key", &self.signing_key), ("public key", &self.public_key)] {
+            if key.as_ref().is_some_and(|key| key.is_empty() || key.len() > MAX_USER_KEY_LEN) {
+                return Err(format!("{name} must be 1 to {MAX_USER_KEY_LEN} bytes"));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Names only a SuperAdmin can hand out, so nobody can pose as staff or the
+/// server. Override with `DARKRELAY_RESERVED_USERNAMES`.
+pub const DEFAULT_RESERVED_USERNAMES: &[&str] = &["admin", "system", "server", "moderator"];
 
-use darkrelayprotocol::protocol::{UserId, UserInfo};
+/// Check a requested username and return it trimmed. Only ASCII letters,
+/// digits, `_`, `-` and `.` are allowed, which rules out control characters
+/// and look-alike Unicode.
+pub fn validate_username(username: &str) -> Result<String, String> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err("username cannot be empty".to_string());
+    }
+
+    let len = username.chars().count();
+    if !(USERNAME_MIN_LEN..=USERNAME_MAX_LEN).contains(&len) {
+        return Err(format!(
+            "username must be {}-{} characters",
+            USERNAME_MIN_LEN, USERNAME_MAX_LEN
+        ));
+    }
+
+    if !username
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err("username may only contain letters, digits, '_', '-' and '.'".to_string());
+    }
+
+    if normalize_username(username).starts_with(GUEST_PREFIX) {
+        return Err("usernames starting with 'guest-' are reserved".to_string());
+    }
+
+    Ok(username.to_string())
+}
+
+/// Key used for uniqueness and lookups; display case is kept on `UserInfo`.
+pub fn normalize_username(username: &str) -> String {
+    username.trim().to_ascii_lowercase()
+}
+
+pub const DEFAULT_PASSWORD_MIN_LEN: usize = 10;
+/// Upper bound so a huge password can't make hashing expensive.
+pub const PASSWORD_MAX_LEN: usize = 128;
+
+/// Rules for passwords chosen at registration. Generated passwords always pass.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PasswordPolicy {
+    pub min_len: usize,
+    /// Require at least one letter and one digit.
+    pub require_mixed: bool,
+}
+
+impl Default for PasswordPolicy {
+    fn default() -> Self {
+        Self {
+            min_len: DEFAULT_PASSWORD_MIN_LEN,
+            require_mixed: true,
+        }
+    }
+}
+
+impl PasswordPolicy {
+    pub fn check(&self, password: &str) -> Result<(), String> {
+        let len = password.chars().count();
+        if len < self.min_len {
+            return Err(format!("password must be at least {} characters", self.min_len));
+        }
+        if len > PASSWORD_MAX_LEN {
+            return Err(format!("password must be at most {PASSWORD_MAX_LEN} characters"));
+        }
+        if password.chars().any(|c| c.is_control()) {
+            return Err("password may not contain control characters".to_string());
+        }
+        if self.require_mixed
+            && !(password.chars().any(|c| c.is_alphabetic()) && password.chars().any(|c| c.is_numeric()))
+        {
+            return Err("password must contain both letters and digits".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Length of a generated password: 24 alphanumerics is about 142 bits.
+pub const GENERATED_PASSWORD_LEN: usize = 24;
+
+/// A password for an account created without one, drawn from the OS RNG.
+pub fn generate_password() -> String {
+    OsRng.sample_iter(&Alphanumeric).take(GENERATED_PASSWORD_LEN).map(char::from).collect()
+}
+
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    let argon2 = Argon2::default();
+    argon2
+        .hash_password(password.as_bytes(), &salt)
+        .expect("hash password")
+        .to_string()
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    let argon2 = Argon2::default();
+    let Ok(parsed) = PasswordHash::new(hash) else {
+        return false;
+    };
+    argon2.verify_password(password.as_bytes(), &parsed).is_ok()
+}
 
 #[derive(Debug, Clone)]
 pub struct UserRecord {
     pub user: UserInfo,
 
-    /// Phase 1: stored in-memory as a string.
-    pub password: String,
+    /// Argon2 hash; the plain password is only ever held by the client.
+    pub password_hash: String,
 }
 
 #[derive(Debug, Default)]
 pub struct AuthService {
+    /// Keyed by `normalize_username`.
     users_by_name: HashMap<String, UserRecord>,
     next_user_id: UserId,
+    next_guest: u64,
+    password_policy: PasswordPolicy,
+    /// Keyed by `normalize_username`.
+    reserved: HashSet<String>,
+    /// When each account last sent us a frame. Kept beside the accounts so it
+    /// lives exactly as long as they do; guests are not tracked.
+    last_seen: HashMap<UserId, DateTime<Utc>>,
+    store: Option<UserStore>,
 }
 
 impl AuthService {
@@ -26,22 +185,113 @@ impl AuthService {
         Self {
             users_by_name: HashMap::new(),
             next_user_id: 1,
+            next_guest: 1,
+            password_policy: PasswordPolicy::default(),
+            reserved: DEFAULT_RESERVED_USERNAMES.iter().map(|n| n.to_string()).collect(),
+            last_seen: HashMap::new(),
+            store: None,
+        }
+    }
+
+    /// Load the accounts saved in `store` and keep it up to date from now on.
+    pub fn set_user_store(&mut self, store: UserStore) {
+        for (key, stored) in store.users() {
+            self.next_user_id = self.next_user_id.max(stored.user.id + 1);
+            if let Some(at) = stored.last_seen {
+                self.last_seen.insert(stored.user.id, at);
+            }
+            self.users_by_name.insert(
+                key.clone(),
+                UserRecord { user: stored.user.clone(), password_hash: stored.password_hash.clone() },
+            );
         }
+        self.store = Some(store);
     }
 
+    fn stored(&self, key: &str) -> Option<StoredUser> {
+        let rec = self.users_by_name.get(key)?;
+        Some(StoredUser {
+            user: rec.user.clone(),
+            password_hash: rec.password_hash.clone(),
+            last_seen: self.last_seen.get(&rec.user.id).copied(),
+        })
+    }
+
+    /// Write the account under `key` to the user store.
+    fn persist(&mut self, key: &str) {
+        let Some(stored) = self.stored(key) else {
+            return;
+        };
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        if let Err(e) = store.put(key, stored) {
+            warn!(user = key, error = %e, "failed to save account");
+        }
+    }
+
+    /// Save every account's `last_seen`. `touch` runs for every frame, so the
+    /// stamps are written in batches by the retention sweep and at shutdown
+    /// rather than as they change.
+    pub fn flush_last_seen(&mut self) {
+        let Some(store) = self.store.as_mut() else {
+            return;
+        };
+        let seen = self
+            .users_by_name
+            .iter()
+            .filter_map(|(key, rec)| Some((key.clone(), *self.last_seen.get(&rec.user.id)?)));
+        if let Err(e) = store.record_last_seen(seen) {
+            warn!(error = %e, "failed to save last seen times");
+        }
+    }
+
+    pub fn set_password_policy(&mut self, policy: PasswordPolicy) {
+        self.password_policy = policy;
+    }
+
+    pub fn set_reserved_usernames(&mut self, names: impl IntoIterator<Item = String>) {
+        self.reserved = names.into_iter().map(|n| normalize_username(&n)).collect();
+    }
+
+    /// Add to the reserved names without replacing the configured ones.
+    pub fn reserve(&mut self, names: impl IntoIterator<Item = String>) {
+        self.reserved.extend(names.into_iter().map(|n| normalize_username(&n)));
+    }
+
+    pub fn is_reserved(&self, username: &str) -> bool {
+        self.reserved.contains(&normalize_username(username))
+    }
+
+    /// Compare in constant time. Both sides are hashed first so the
+    /// comparison is over equal-length digests and doesn't reveal the key's length.
     pub fn verify_special_key(&self, expected: &str, candidate: &str) -> bool {
-        expected == candidate
+        let expected = Sha256::digest(expected.as_bytes());
+        let candidate = Sha256::digest(candidate.as_bytes());
+        expected.ct_eq(&candidate).into()
     }
 
-    pub fn register(&mut self, username: String) -> Result<(UserInfo, String), String> {
-        let username = username.trim().to_string();
-        if username.is_empty() {
-            return Err("username cannot be empty".to_string());
+    /// Self-service registration: `provision` minus the reserved names.
+    pub fn register(&mut self, username: String, password: Option<String>) -> Result<(UserInfo, Option<String>), String> {
+        if self.is_reserved(&username) {
+            return Err("username is reserved".to_string());
         }
+        self.provision(username, password)
+    }
+
+    /// Create an account. `password` is checked against the policy; without
+    /// one a password is generated and returned, since the user has never
+    /// seen it.
+    pub fn provision(&mut self, username: String, password: Option<String>) -> Result<(UserInfo, Option<String>), String> {
+        let username = validate_username(&username)?;
+        let key = normalize_username(&username);
 
-        if self.users_by_name.contains_key(&username) {
+        if self.users_by_name.contains_key(&key) {
             return Err("username already exists".to_string());
         }
+        if let Some(password) = &password {
+            self.password_policy.check(password)?;
+        }
 
         let user_id = self.next_user_id;
         self.next_user_id += 1;
@@ -51,47 +301,269 @@ impl AuthService {
             id: user_id,
             username: username.clone(),
             joined_at,
+            signing_key: None,
+            public_key: None,
         };
 
-        let nanos = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .map(|d| d.as_nanos())
-            .unwrap_or(0);
-
-        let password = format!("dr-{}-{}", nanos, user_id);
+        let (password, generated) = match password {
+            Some(password) => (password, None),
+            None => {
+                let password = generate_password();
+                (password.clone(), Some(password))
+            }
+        };
 
         self.users_by_name.insert(
-            username,
+            key.clone(),
             UserRecord {
                 user: user.clone(),
-                password: password.clone(),
+                password_hash: hash_password(&password),
             },
         );
+        self.persist(&key);
+
+        Ok((user, generated))
+    }
 
-        Ok((user, password))
+    /// Allocate a synthetic identity for a read-only guest session. Guests
+    /// are not stored; `guest-` names are reserved by `validate_username`.
+    pub fn guest(&mut self) -> UserInfo {
+        let n = self.next_guest;
+        self.next_guest += 1;
+        UserInfo {
+            id: GUEST_ID_BASE + n,
+            username: format!("{GUEST_PREFIX}{n}"),
+            joined_at: Utc::now(),
+            signing_key: None,
+            public_key: None,
+        }
+    }
+
+    /// Change a user's display name. A case-only change of one's own name is allowed.
+    pub fn rename(&mut self, user_id: UserId, new_username: &str) -> Result<UserInfo, String> {
+        let new_username = validate_username(new_username)?;
+        let new_key = normalize_username(&new_username);
+
+        let old_key = self
+            .users_by_name
+            .iter()
+            .find(|(_, rec)| rec.user.id == user_id)
+            .map(|(key, _)| key.clone())
+            .ok_or_else(|| "user not found".to_string())?;
+
+        if new_key != old_key && self.users_by_name.contains_key(&new_key) {
+            return Err("username already exists".to_string());
+        }
+        if new_key != old_key && self.reserved.contains(&new_key) {
+            return Err("username is reserved".to_string());
+        }
+
+        let mut rec = self.users_by_name.remove(&old_key).expect("record present");
+        rec.user.username = new_username;
+        let user = rec.user.clone();
+        self.users_by_name.insert(new_key.clone(), rec);
+        if let (Some(stored), Some(store)) = (self.stored(&new_key), self.store.as_mut()) {
+            if let Err(e) = store.rename(&old_key, &new_key, stored) {
+                warn!(old = old_key, new = new_key, error = %e, "failed to save renamed account");
+            }
+        }
+        Ok(user)
     }
 
     pub fn login(&self, username: &str, password: &str) -> Result<UserInfo, String> {
         let rec = self
             .users_by_name
-            .get(username)
+            .get(&normalize_username(username))
             .ok_or_else(|| "user not found".to_string())?;
 
-        if rec.password != password {
+        if !verify_password(password, &rec.password_hash) {
             return Err("invalid password".to_string());
         }
 
         Ok(rec.user.clone())
     }
 
-    pub fn find_user_by_username(&self, username: &str) -> Option<UserInfo> {
-        self.users_by_name.get(username).map(|rec| rec.user.clone())
+    /// Replace whichever of the user's keys `keys` carries, returning the
+    /// updated info.
+    pub fn publish_keys(&mut self, user_id: UserId, keys: PublishedKeys) -> Option<UserInfo> {
+        let (name, rec) = self.users_by_name.iter_mut().find(|(_, rec)| rec.user.id == user_id)?;
+        let changed = keys.signing_key.is_some() || keys.public_key.is_some();
+        if let Some(key) = keys.signing_key {
+            rec.user.signing_key = Some(key);
+        }
+        if let Some(key) = keys.public_key {
+            rec.user.public_key = Some(key);
+        }
+        let (name, user) = (name.clone(), rec.user.clone());
+        if changed {
+            self.persist(&name);
+        }
+        Some(user)
     }
 
-    pub fn get_all_users_map(&self) -> HashMap<UserId, String> {
+    pub fn find_user_by_username(&self, username: &str) -> Option<UserInfo> {
         self.users_by_name
-            .values()
-            .map(|rec| (rec.user.id, rec.user.username.clone()))
-            .collect()
+            .get(&normalize_username(username))
+            .map(|rec| rec.user.clone())
+    }
+
+    /// Record activity from `user_id`. Called for every frame, so it's a single map insert.
+    pub fn touch(&mut self, user_id: UserId, now: DateTime<Utc>) {
+        if !is_guest(user_id) {
+            self.last_seen.insert(user_id, now);
+        }
+    }
+
+    pub fn last_seen(&self, user_id: UserId) -> Option<DateTime<Utc>> {
+        self.last_seen.get(&user_id).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_valid_usernames_accepted() {
+        let mut auth = AuthService::new();
+        for name in ["alice", "Bob_99", "  carol.d-x  ", "abc"] {
+            assert!(auth.register(name.to_string(), None).is_ok(), "{name} should be accepted");
+        }
+        assert_eq!(auth.find_user_by_username("carol.d-x").unwrap().username, "carol.d-x");
+    }
+
+    #[test]
+    fn test_invalid_usernames_rejected() {
+        let mut auth = AuthService::new();
+        for name in ["", "ab", "Guest-1", "bad\u{7}name", "tab\tname", "two words", "аlice", &"x".repeat(33)] {
+            assert!(auth.register(name.to_string(), None).is_err(), "{name:?} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_usernames_are_case_insensitive() {
+        let mut auth = AuthService::new();
+        let (user, password) = auth.register("Alice".to_string(), None).unwrap();
+        let password = password.unwrap();
+        assert_eq!(user.username, "Alice");
+
+        assert_eq!(auth.register("alice".to_string(), None).unwrap_err(), "username already exists");
+        assert_eq!(auth.login("ALICE", &password).unwrap().id, user.id);
+        assert_eq!(auth.find_user_by_username("alice").unwrap().username, "Alice");
+    }
+
+    #[test]
+    fn test_rename() {
+        let mut auth = AuthService::new();
+        let (alice, password) = auth.register("alice".to_string(), None).unwrap();
+        let password = password.unwrap();
+        auth.register("bob".to_string(), None).unwrap();
+
+        assert_eq!(auth.rename(alice.id, "BOB").unwrap_err(), "username already exists");
+        assert!(auth.rename(alice.id, "no spaces").is_err());
+
+        assert_eq!(auth.rename(alice.id, "Alice").unwrap().username, "Alice");
+        let renamed = auth.rename(alice.id, "carol").unwrap();
+        assert_eq!(renamed.id, alice.id);
+        assert!(auth.find_user_by_username("alice").is_none());
+        assert_eq!(auth.login("carol", &password).unwrap().username, "carol");
+    }
+
+    #[test]
+    fn test_chosen_password() {
+        let mut auth = AuthService::new();
+        let (alice, generated) = auth.register("alice".to_string(), Some("correct horse 42".to_string())).unwrap();
+        assert_eq!(generated, None);
+        assert_eq!(auth.login("alice", "correct horse 42").unwrap().id, alice.id);
+        assert_eq!(auth.login("alice", "correct horse 43").unwrap_err(), "invalid password");
+    }
+
+    #[test]
+    fn test_password_policy_violations() {
+        let mut auth = AuthService::new();
+        for (password, reason) in [
+            ("short1", "password must be at least 10 characters"),
+            ("onlyletters", "password must contain both letters and digits"),
+            ("1234567890", "password must contain both letters and digits"),
+            ("tab\there123", "password may not contain control characters"),
+        ] {
+            assert_eq!(auth.register("alice".to_string(), Some(password.to_string())).unwrap_err(), reason);
+        }
+        assert!(auth.find_user_by_username("alice").is_none(), "nothing is stored on failure");
+
+        auth.set_password_policy(PasswordPolicy { min_len: 4, require_mixed: false });
+        assert!(auth.register("alice".to_string(), Some("letters".to_string())).is_ok());
+    }
+
+    #[test]
+    fn test_generated_password_fallback() {
+        let mut auth = AuthService::new();
+        let (bob, generated) = auth.register("bob".to_string(), None).unwrap();
+        let generated = generated.expect("a password is generated");
+        assert_eq!(generated.len(), GENERATED_PASSWORD_LEN);
+        assert!(generated.chars().all(|c| c.is_ascii_alphanumeric()));
+        assert_ne!(auth.register("carol".to_string(), None).unwrap().1.unwrap(), generated);
+        assert_eq!(auth.login("bob", &generated).unwrap().id, bob.id);
+        assert_ne!(auth.users_by_name["bob"].password_hash, generated, "only the hash is stored");
+    }
+
+    #[test]
+    fn test_reserved_names_need_provisioning() {
+        let mut auth = AuthService::new();
+        for name in ["admin", "SYSTEM", " Server "] {
+            assert_eq!(auth.register(name.to_string(), None).unwrap_err(), "username is reserved");
+        }
+
+        let (admin, _) = auth.provision("Admin".to_string(), None).unwrap();
+        assert_eq!(auth.find_user_by_username("admin").unwrap().id, admin.id);
+        assert_eq!(auth.rename(admin.id, "ADMIN").unwrap().username, "ADMIN", "case-only change of one's own name");
+
+        let (alice, _) = auth.register("alice".to_string(), None).unwrap();
+        assert_eq!(auth.rename(alice.id, "Moderator").unwrap_err(), "username is reserved");
+
+        auth.set_reserved_usernames(["Staff".to_string()]);
+        assert!(auth.register("moderator".to_string(), None).is_ok());
+        assert_eq!(auth.register("staff".to_string(), None).unwrap_err(), "username is reserved");
+    }
+
+    #[test]
+    fn test_accounts_survive_restart() {
+        let dir = std::env::temp_dir().join(format!("darkrelay-users-restart-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let file = dir.join("users.json");
+        let seen = Utc::now();
+
+        let (alice, password) = {
+            let mut auth = AuthService::new();
+            auth.set_user_store(UserStore::open(&file).unwrap());
+            let (alice, password) = auth.register("alice".to_string(), None).unwrap();
+            auth.register("bob".to_string(), None).unwrap();
+            auth.rename(alice.id, "Alicia").unwrap();
+            auth.publish_keys(alice.id, PublishedKeys { signing_key: Some(vec![7; 32]), public_key: None });
+            auth.touch(alice.id, seen);
+            auth.flush_last_seen();
+            (alice, password.unwrap())
+        };
+
+        let mut auth = AuthService::new();
+        auth.set_user_store(UserStore::open(&file).unwrap());
+        let restored = auth.login("alicia", &password).unwrap();
+        assert_eq!(restored.id, alice.id);
+        assert_eq!(restored.signing_key, Some(vec![7; 32]));
+        assert!(auth.find_user_by_username("alice").is_none());
+        assert_eq!(auth.last_seen(alice.id), Some(seen));
+        assert_eq!(auth.register("carol".to_string(), None).unwrap().0.id, 3, "ids carry on after the stored ones");
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_special_key_must_match_exactly() {
+        let auth = AuthService::new();
+        assert!(auth.verify_special_key("s3cret-key", "s3cret-key"));
+        for wrong in ["", "s3cret", "s3cret-key ", "S3cret-key", "s3cret-kez", "s3cret-key-and-more"] {
+            assert!(!auth.verify_special_key("s3cret-key", wrong), "{wrong:?} should be refused");
+        }
+        assert!(auth.verify_special_key("", ""));
     }
 }
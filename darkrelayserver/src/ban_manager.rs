@@ -44,7 +44,7 @@ impl BanManager {
 
         self.bans
             .entry(channel_id)
-            .or_insert_with(HashMap::new)
+            .or_default()
             .insert(user_id, ban);
 
         banned_until
@@ -1,49 +1,147 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeSet, HashMap, HashSet};
 
-use argon2::{
-    password_hash::{
-        rand_core::OsRng, PasswordHash, PasswordHasher, PasswordVerifier, SaltString,
-    },
-    Argon2,
-};
-use chrono::Utc;
+use chrono::{DateTime, Duration, Utc};
 
 use darkrelayprotocol::{
     channel::ChannelType,
-    protocol::{ChannelId, ChannelInfo, ChatMessage, MessageId},
+    protocol::{
+        ChannelId, ChannelInfo, ChatMessage, MessageId, UserId, CHANNEL_NAME_MAX_LEN, JOIN_INVALID_PASSWORD, JOIN_PASSWORD_REQUIRED,
+        MAX_HISTORY_LEN,
+    },
     permissions::Role,
 };
 
+use crate::auth::{hash_password, verify_password};
+
 pub type ClientId = u64;
 
+/// Most messages returned by one resync.
+pub const MAX_RESYNC_MESSAGES: usize = 50;
+
+/// Channels the server will hold before refusing to create more.
+pub const DEFAULT_MAX_CHANNELS: usize = 1000;
+
+/// Longest channel welcome message, in bytes.
+pub const MAX_WELCOME_LEN: usize = 1000;
+
+/// Why a name outside `DARKRELAY_ALLOWED_CHANNELS` is refused.
+pub const CHANNEL_NOT_ALLOWED: &str = "this server only has a fixed set of channels";
+
+/// Longest channel topic, in bytes.
+pub const MAX_TOPIC_LEN: usize = 200;
+
+/// Who may create a channel by joining a name that doesn't exist yet.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ChannelCreation {
+    #[default]
+    Anyone,
+    /// Only server SuperAdmins (`DARKRELAY_SUPERADMINS`).
+    Admins,
+}
+
+impl ChannelCreation {
+    /// Parse `DARKRELAY_CHANNEL_CREATION` (`anyone` or `admins`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "anyone" => Some(Self::Anyone),
+            "admins" => Some(Self::Admins),
+            _ => None,
+        }
+    }
+}
+
+/// Canonical form of a channel name: trimmed, without a leading `#`, and
+/// lowercased, so `#General` and `general` are the same channel. Only ASCII
+/// letters, digits, `_`, `-` and `.` are allowed.
+pub fn normalize_channel_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    let name = name.strip_prefix('#').unwrap_or(name);
+    if name.is_empty() {
+        return Err("channel name cannot be empty".to_string());
+    }
+
+    if name.len() > CHANNEL_NAME_MAX_LEN {
+        return Err(format!("channel name must be at most {} characters", CHANNEL_NAME_MAX_LEN));
+    }
+
+    if !name
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '.'))
+    {
+        return Err("channel name may only contain letters, digits, '_', '-' and '.'".to_string());
+    }
+
+    Ok(name.to_ascii_lowercase())
+}
+
+/// Channel names separated by commas or whitespace, normalized. Names that
+/// aren't valid are left out.
+pub fn parse_channel_list(text: &str) -> BTreeSet<String> {
+    text.split(|c: char| c == ',' || c.is_whitespace())
+        .filter_map(|name| normalize_channel_name(name).ok())
+        .collect()
+}
+
 #[derive(Debug, Clone)]
 pub struct Channel {
     pub id: ChannelId,
     pub name: String,
     pub is_public: bool,
     pub password_hash: Option<String>,
+    pub channel_type: ChannelType,
     pub messages: Vec<ChatMessage>,
-    pub members: HashSet<ClientId>,
+    /// Each member session and the user it belongs to.
+    pub members: HashMap<ClientId, UserId>,
     pub created_by: Option<ClientId>,
+    /// Maximum message age; older messages are pruned on top of the count cap.
+    pub retention: Option<Duration>,
+    /// Minimum interval between one user's messages.
+    pub slow_mode: Option<Duration>,
+    /// New members are refused once this many distinct users have joined.
+    pub max_members: Option<u32>,
+    /// `seq` of the last message posted here.
+    pub last_seq: u64,
+    /// Shown only to each member as they join.
+    pub welcome: Option<String>,
+    /// Shown with the channel in listings.
+    pub topic: Option<String>,
 }
 
 impl Channel {
-    pub fn info(&self, user_role: Option<Role>, channel_type: ChannelType) -> ChannelInfo {
+    /// Distinct users among the members; one user's sessions count once.
+    pub fn user_count(&self) -> usize {
+        self.members.values().collect::<HashSet<_>>().len()
+    }
+
+    pub fn info(&self, user_role: Option<Role>) -> ChannelInfo {
         ChannelInfo {
             id: self.id,
             name: self.name.clone(),
             is_public: self.is_public,
-            channel_type,
+            channel_type: self.channel_type,
             user_role,
+            member_count: self.user_count() as u32,
+            max_members: self.max_members,
+            topic: self.topic.clone(),
         }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct ChannelManager {
     channels_by_name: HashMap<String, Channel>,
     next_channel_id: ChannelId,
     next_message_id: MessageId,
+    max_channels: usize,
+    creation: ChannelCreation,
+    /// When set, the only names a channel may have.
+    allowed: Option<BTreeSet<String>>,
+}
+
+impl Default for ChannelManager {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ChannelManager {
@@ -52,12 +150,58 @@ impl ChannelManager {
             channels_by_name: HashMap::new(),
             next_channel_id: 1,
             next_message_id: 1,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            creation: ChannelCreation::default(),
+            allowed: None,
         }
     }
 
-    pub fn ensure_channel(&mut self, name: &str, is_public: bool, password: Option<String>, creator: Option<ClientId>) -> ChannelId {
-        if let Some(ch) = self.channels_by_name.get(name) {
-            return ch.id;
+    pub fn set_max_channels(&mut self, max: usize) {
+        self.max_channels = max.max(1);
+    }
+
+    pub fn set_creation_policy(&mut self, policy: ChannelCreation) {
+        self.creation = policy;
+    }
+
+    pub fn creation_policy(&self) -> ChannelCreation {
+        self.creation
+    }
+
+    /// Lock the namespace to `names` (normalized); `None` allows any name.
+    pub fn set_allowed_names(&mut self, names: Option<BTreeSet<String>>) {
+        self.allowed = names;
+    }
+
+    pub fn allowed_names(&self) -> Option<&BTreeSet<String>> {
+        self.allowed.as_ref()
+    }
+
+    pub fn is_allowed(&self, name: &str) -> bool {
+        self.allowed.as_ref().is_none_or(|allowed| allowed.contains(name))
+    }
+
+    /// Look up `name` (normalized), creating the channel if it doesn't exist.
+    /// The first create decides visibility and password; for an existing
+    /// channel the other arguments are ignored, and `join` holds later
+    /// joiners to what was decided.
+    pub fn ensure_channel(
+        &mut self,
+        name: &str,
+        is_public: bool,
+        password: Option<String>,
+        channel_type: ChannelType,
+        creator: Option<ClientId>,
+    ) -> Result<ChannelId, String> {
+        let name = normalize_channel_name(name)?;
+        if let Some(ch) = self.channels_by_name.get(&name) {
+            return Ok(ch.id);
+        }
+        if !self.is_allowed(&name) {
+            return Err(CHANNEL_NOT_ALLOWED.to_string());
+        }
+        if self.channels_by_name.len() >= self.max_channels {
+            return Err("server channel limit reached".to_string());
         }
 
         let (is_public, password_hash) = match password {
@@ -71,14 +215,21 @@ impl ChannelManager {
             name: name.to_string(),
             is_public,
             password_hash,
+            channel_type,
             messages: Vec::new(),
-            members: HashSet::new(),
+            members: HashMap::new(),
             created_by: creator,
+            retention: None,
+            slow_mode: None,
+            max_members: None,
+            last_seq: 0,
+            welcome: None,
+            topic: None,
         };
 
         self.next_channel_id += 1;
-        self.channels_by_name.insert(name.to_string(), channel);
-        channel_id
+        self.channels_by_name.insert(name, channel);
+        Ok(channel_id)
     }
 
     pub fn list_public(&self) -> Vec<ChannelInfo> {
@@ -86,7 +237,17 @@ impl ChannelManager {
             .channels_by_name
             .values()
             .filter(|c| c.is_public)
-            .map(|c| c.info(None, ChannelType::Public))
+            .map(|c| c.info(None))
+            .collect();
+        out.sort_by(|a, b| a.name.cmp(&b.name));
+        out
+    }
+
+    pub fn list_all(&self) -> Vec<ChannelInfo> {
+        let mut out: Vec<_> = self
+            .channels_by_name
+            .values()
+            .map(|c| c.info(None))
             .collect();
         out.sort_by(|a, b| a.name.cmp(&b.name));
         out
@@ -95,28 +256,49 @@ impl ChannelManager {
     pub fn join(
         &mut self,
         client_id: ClientId,
+        user_id: UserId,
         name: &str,
         password: Option<String>,
     ) -> Result<ChannelInfo, String> {
-        if !self.channels_by_name.contains_key(name) {
-            let pw = password.clone();
-            self.ensure_channel(name, pw.is_none(), pw, Some(client_id));
-        }
-
+        let name = normalize_channel_name(name)?;
+        let password = password.filter(|pw| !pw.is_empty());
         let channel = self
             .channels_by_name
-            .get_mut(name)
+            .get_mut(&name)
             .ok_or_else(|| "channel not found".to_string())?;
 
-        if let Some(hash) = &channel.password_hash {
-            let provided = password.unwrap_or_default();
-            if !verify_password(&provided, hash) {
-                return Err("invalid channel password".to_string());
+        // A password has to match how the channel was created, so a join
+        // meant to create a private channel can't land in a public one.
+        match (&channel.password_hash, password) {
+            (None, None) => {}
+            (None, Some(_)) => return Err("channel has no password; join without one".to_string()),
+            (Some(_), None) => return Err(JOIN_PASSWORD_REQUIRED.to_string()),
+            (Some(hash), Some(provided)) => {
+                if !verify_password(&provided, hash) {
+                    return Err(JOIN_INVALID_PASSWORD.to_string());
+                }
             }
         }
 
-        channel.members.insert(client_id);
-        Ok(channel.info(None, ChannelType::Public))
+        // The cap is on users, so another session of a member always fits.
+        let full = channel
+            .max_members
+            .is_some_and(|max| channel.user_count() >= max as usize);
+        if full && !channel.members.values().any(|member| *member == user_id) {
+            return Err("channel full".to_string());
+        }
+
+        channel.members.insert(client_id, user_id);
+        Ok(channel.info(None))
+    }
+
+    /// Restore membership of an existing channel without re-checking its
+    /// password or member cap (used when resuming a session that was already
+    /// admitted).
+    pub fn rejoin(&mut self, client_id: ClientId, user_id: UserId, name: &str) -> Option<ChannelInfo> {
+        let channel = self.channels_by_name.get_mut(name)?;
+        channel.members.insert(client_id, user_id);
+        Some(channel.info(None))
     }
 
     pub fn leave(&mut self, client_id: ClientId, name: &str) {
@@ -125,13 +307,33 @@ impl ChannelManager {
         }
     }
 
+    pub fn channel_info(&self, name: &str) -> Option<ChannelInfo> {
+        self.channels_by_name.get(name).map(|c| c.info(None))
+    }
+
     pub fn members(&self, name: &str) -> Vec<ClientId> {
         self.channels_by_name
             .get(name)
-            .map(|c| c.members.iter().copied().collect())
+            .map(|c| c.members.keys().copied().collect())
             .unwrap_or_default()
     }
 
+    pub fn is_member(&self, name: &str, client_id: ClientId) -> bool {
+        self.channels_by_name
+            .get(name)
+            .is_some_and(|c| c.members.contains_key(&client_id))
+    }
+
+    /// Public, passwordless `ReadOnly` and `Announcement` channels can be read
+    /// by anyone without joining; only their admins post.
+    pub fn readable_without_joining(&self, name: &str) -> bool {
+        self.channels_by_name.get(name).is_some_and(|c| {
+            c.is_public
+                && c.password_hash.is_none()
+                && matches!(c.channel_type, ChannelType::ReadOnly | ChannelType::Announcement)
+        })
+    }
+
     pub fn add_message(&mut self, channel: &str, mut message: ChatMessage) -> Result<ChatMessage, String> {
         let ch = self
             .channels_by_name
@@ -140,11 +342,14 @@ impl ChannelManager {
 
         message.id = self.next_message_id;
         self.next_message_id += 1;
+        ch.last_seq += 1;
+        message.seq = ch.last_seq;
         message.timestamp = Utc::now();
 
         ch.messages.push(message.clone());
-        if ch.messages.len() > 100 {
-            let overflow = ch.messages.len() - 100;
+        // Kept regardless of retention.
+        if ch.messages.len() > MAX_HISTORY_LEN {
+            let overflow = ch.messages.len() - MAX_HISTORY_LEN;
             ch.messages.drain(0..overflow);
         }
 
@@ -161,19 +366,35 @@ impl ChannelManager {
         out
     }
 
+    /// Up to `limit` messages with `seq` above `since`, oldest first, and
+    /// whether more remain. `None` if the channel doesn't exist.
+    pub fn messages_since(&self, channel: &str, since: u64, limit: usize) -> Option<(Vec<ChatMessage>, bool)> {
+        let ch = self.channels_by_name.get(channel)?;
+        let mut newer = ch.messages.iter().filter(|m| m.seq > since);
+        let messages: Vec<_> = newer.by_ref().take(limit).cloned().collect();
+        Some((messages, newer.next().is_some()))
+    }
+
+    /// Replace the message with a tombstone, keeping its id, seq, sender and
+    /// time. `false` if there is no such message or it is already deleted.
     pub fn delete_message(&mut self, channel: &str, message_id: u64) -> bool {
-        if let Some(ch) = self.channels_by_name.get_mut(channel) {
-            let len_before = ch.messages.len();
-            ch.messages.retain(|msg| msg.id != message_id);
-            ch.messages.len() < len_before
-        } else {
-            false
-        }
+        let Some(msg) = self
+            .channels_by_name
+            .get_mut(channel)
+            .and_then(|ch| ch.messages.iter_mut().find(|msg| msg.id == message_id && !msg.deleted))
+        else {
+            return false;
+        };
+        msg.deleted = true;
+        msg.content.clear();
+        msg.nonce = None;
+        msg.metadata.clear();
+        true
     }
 
     pub fn delete_channel(&mut self, channel: &str) -> Option<Vec<ClientId>> {
         self.channels_by_name.remove(channel).map(|ch| {
-            ch.members.into_iter().collect()
+            ch.members.into_keys().collect()
         })
     }
 
@@ -181,24 +402,349 @@ impl ChannelManager {
         self.channels_by_name.get(name).map(|ch| ch.id)
     }
 
+    pub fn is_public(&self, name: &str) -> Option<bool> {
+        self.channels_by_name.get(name).map(|ch| ch.is_public)
+    }
+
+    pub fn channel_type(&self, name: &str) -> Option<ChannelType> {
+        self.channels_by_name.get(name).map(|ch| ch.channel_type)
+    }
+
+    pub fn set_channel_type(&mut self, name: &str, channel_type: ChannelType) -> bool {
+        if let Some(ch) = self.channels_by_name.get_mut(name) {
+            ch.channel_type = channel_type;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_retention(&mut self, name: &str, retention: Option<Duration>) -> bool {
+        if let Some(ch) = self.channels_by_name.get_mut(name) {
+            ch.retention = retention;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn slow_mode(&self, name: &str) -> Option<Duration> {
+        self.channels_by_name.get(name).and_then(|ch| ch.slow_mode)
+    }
+
+    pub fn set_slow_mode(&mut self, name: &str, interval: Option<Duration>) -> bool {
+        if let Some(ch) = self.channels_by_name.get_mut(name) {
+            ch.slow_mode = interval;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_max_members(&mut self, name: &str, max_members: Option<u32>) -> bool {
+        if let Some(ch) = self.channels_by_name.get_mut(name) {
+            ch.max_members = max_members;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_welcome(&mut self, name: &str, welcome: Option<String>) -> bool {
+        if let Some(ch) = self.channels_by_name.get_mut(name) {
+            ch.welcome = welcome;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn set_topic(&mut self, name: &str, topic: Option<String>) -> bool {
+        if let Some(ch) = self.channels_by_name.get_mut(name) {
+            ch.topic = topic;
+            true
+        } else {
+            false
+        }
+    }
+
+    pub fn welcome(&self, name: &str) -> Option<String> {
+        self.channels_by_name.get(name).and_then(|ch| ch.welcome.clone())
+    }
+
+    /// Move channel `old` to `new` (normalized). Members, history and the
+    /// channel id are kept, so roles and bans carry over.
+    pub fn rename_channel(&mut self, old: &str, new: &str) -> Result<(), String> {
+        let new = normalize_channel_name(new)?;
+        if !self.channels_by_name.contains_key(old) {
+            return Err("channel not found".to_string());
+        }
+        if self.channels_by_name.contains_key(&new) {
+            return Err("channel already exists".to_string());
+        }
+        if !self.is_allowed(&new) {
+            return Err(CHANNEL_NOT_ALLOWED.to_string());
+        }
+
+        let mut channel = self.channels_by_name.remove(old).expect("channel present");
+        channel.name = new.clone();
+        self.channels_by_name.insert(new, channel);
+        Ok(())
+    }
+
+    /// Drop messages older than each channel's retention. Returns how many were removed.
+    pub fn prune_expired(&mut self, now: DateTime<Utc>) -> usize {
+        let mut removed = 0;
+        for ch in self.channels_by_name.values_mut() {
+            let Some(retention) = ch.retention else {
+                continue;
+            };
+            let cutoff = now - retention;
+            let len_before = ch.messages.len();
+            ch.messages.retain(|msg| msg.timestamp >= cutoff);
+            removed += len_before - ch.messages.len();
+        }
+        removed
+    }
+
     pub fn get_channel_creator(&self, name: &str) -> Option<ClientId> {
         self.channels_by_name.get(name).and_then(|ch| ch.created_by)
     }
 }
 
-fn hash_password(password: &str) -> String {
-    let salt = SaltString::generate(&mut OsRng);
-    let argon2 = Argon2::default();
-    argon2
-        .hash_password(password.as_bytes(), &salt)
-        .expect("hash password")
-        .to_string()
-}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use darkrelayprotocol::metadata::MessageMetadata;
+
+    #[test]
+    fn test_listings_report_current_channel_type() {
+        let mut channels = ChannelManager::new();
+        channels.ensure_channel("news", true, None, ChannelType::Public, None).unwrap();
+        assert!(channels.set_channel_type("news", ChannelType::Announcement));
+
+        assert_eq!(channels.channel_type("news"), Some(ChannelType::Announcement));
+        assert_eq!(channels.list_public()[0].channel_type, ChannelType::Announcement);
+        assert_eq!(channels.list_all()[0].channel_type, ChannelType::Announcement);
+        assert!(!channels.set_channel_type("missing", ChannelType::ReadOnly));
+    }
 
-fn verify_password(password: &str, hash: &str) -> bool {
-    let argon2 = Argon2::default();
-    let Ok(parsed) = PasswordHash::new(hash) else {
-        return false;
-    };
-    argon2.verify_password(password.as_bytes(), &parsed).is_ok()
+    #[test]
+    fn test_private_channel_only_in_full_listing() {
+        let mut channels = ChannelManager::new();
+        channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+        channels.ensure_channel("staff", false, Some("secret".to_string()), ChannelType::Private, Some(1)).unwrap();
+        channels.join(1, 1, "staff", Some("secret".to_string())).unwrap();
+
+        let public: Vec<_> = channels.list_public().into_iter().map(|c| c.name).collect();
+        assert_eq!(public, vec!["general".to_string()]);
+
+        let all = channels.list_all();
+        let names: Vec<_> = all.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, vec!["general", "staff"]);
+
+        let staff = all.iter().find(|c| c.name == "staff").unwrap();
+        assert!(!staff.is_public);
+        assert_eq!(staff.channel_type, ChannelType::Private);
+        assert_eq!(staff.member_count, 1);
+    }
+
+    #[test]
+    fn test_retention_prunes_old_messages() {
+        let mut channels = ChannelManager::new();
+        channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+        channels.ensure_channel("archive", true, None, ChannelType::Public, None).unwrap();
+
+        let now = Utc::now();
+        for (channel, age_hours) in [("general", 48), ("general", 1), ("archive", 48)] {
+            let msg = ChatMessage {
+                id: 0,
+                seq: 0,
+                user_id: 1,
+                username: "alice".to_string(),
+                content: b"hi".to_vec(),
+                timestamp: now,
+                nonce: None,
+                metadata: MessageMetadata::new(),
+                deleted: false,
+                edited: false,
+            };
+            let stored = channels.add_message(channel, msg).unwrap();
+            let ch = channels.channels_by_name.get_mut(channel).unwrap();
+            ch.messages.iter_mut().find(|m| m.id == stored.id).unwrap().timestamp =
+                now - Duration::hours(age_hours);
+        }
+
+        assert!(channels.set_retention("general", Some(Duration::hours(24))));
+        assert!(!channels.set_retention("missing", Some(Duration::hours(24))));
+
+        assert_eq!(channels.prune_expired(now), 1);
+        assert_eq!(channels.history("general", 10).len(), 1);
+        assert_eq!(channels.history("archive", 10).len(), 1, "no retention set, only the count cap applies");
+    }
+
+    #[test]
+    fn test_sequence_numbers_are_per_channel() {
+        let mut channels = ChannelManager::new();
+        channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+        channels.ensure_channel("random", true, None, ChannelType::Public, None).unwrap();
+
+        let mut post = |channel: &str| {
+            let msg = ChatMessage {
+                id: 0,
+                seq: 0,
+                user_id: 1,
+                username: "alice".to_string(),
+                content: b"hi".to_vec(),
+                timestamp: Utc::now(),
+                nonce: None,
+                metadata: MessageMetadata::new(),
+                deleted: false,
+                edited: false,
+            };
+            let stored = channels.add_message(channel, msg).unwrap();
+            (stored.id, stored.seq)
+        };
+
+        let order = ["general", "random", "general", "general", "random", "general"];
+        let posted: Vec<_> = order.iter().map(|ch| post(ch)).collect();
+
+        let ids: Vec<_> = posted.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids, vec![1, 2, 3, 4, 5, 6], "ids stay global");
+        let seqs_in = |name| -> Vec<u64> {
+            order.iter().zip(&posted).filter(|(ch, _)| **ch == name).map(|(_, (_, seq))| *seq).collect()
+        };
+        assert_eq!(seqs_in("general"), vec![1, 2, 3, 4]);
+        assert_eq!(seqs_in("random"), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_channel_name_validation() {
+        for (raw, normalized) in [("general", "general"), ("#Dev-Ops", "dev-ops"), ("  rust_1.0 ", "rust_1.0")] {
+            assert_eq!(normalize_channel_name(raw).unwrap(), normalized);
+        }
+        for bad in ["", "#", "two words", "tab\tname", "bell\u{7}", "ünïcode", "a/b", &"x".repeat(33)] {
+            assert!(normalize_channel_name(bad).is_err(), "{bad:?} should be rejected");
+        }
+
+        let mut channels = ChannelManager::new();
+        assert!(channels.ensure_channel("bad name", true, None, ChannelType::Public, None).is_err());
+        assert!(channels.join(1, 1, "bad name", None).is_err());
+        assert!(channels.list_all().is_empty());
+    }
+
+    #[test]
+    fn test_channel_names_are_case_insensitive() {
+        let mut channels = ChannelManager::new();
+        let id = channels.ensure_channel("General", true, None, ChannelType::Public, None).unwrap();
+        assert_eq!(channels.ensure_channel("#general", true, None, ChannelType::Public, None).unwrap(), id);
+
+        let info = channels.join(1, 1, "#GENERAL", None).unwrap();
+        assert_eq!(info.name, "general");
+        assert_eq!(channels.members("general"), vec![1]);
+        assert_eq!(channels.list_all().len(), 1);
+    }
+
+    #[test]
+    fn test_max_members_enforced_on_join() {
+        let mut mgr = ChannelManager::new();
+        mgr.ensure_channel("small", true, None, ChannelType::Public, None).unwrap();
+        assert_eq!(mgr.join(1, 1, "small", None).unwrap().max_members, None);
+        assert!(mgr.set_max_members("small", Some(2)));
+
+        let info = mgr.join(2, 2, "small", None).unwrap();
+        assert_eq!((info.member_count, info.max_members), (2, Some(2)));
+        assert_eq!(mgr.join(3, 3, "small", None).unwrap_err(), "channel full");
+        assert!(mgr.join(1, 1, "small", None).is_ok(), "existing members can rejoin");
+
+        mgr.leave(2, "small");
+        assert!(mgr.join(3, 3, "small", None).is_ok());
+
+        mgr.set_max_members("small", None);
+        assert!(mgr.join(4, 4, "small", None).is_ok());
+    }
+
+    #[test]
+    fn test_max_members_counts_users_not_sessions() {
+        let mut mgr = ChannelManager::new();
+        mgr.ensure_channel("small", true, None, ChannelType::Public, None).unwrap();
+        mgr.set_max_members("small", Some(2));
+
+        mgr.join(1, 1, "small", None).unwrap();
+        let info = mgr.join(2, 1, "small", None).unwrap();
+        assert_eq!(info.member_count, 1, "a second session of the same user");
+        assert_eq!(mgr.join(3, 2, "small", None).unwrap().member_count, 2);
+        assert_eq!(mgr.join(4, 3, "small", None).unwrap_err(), "channel full");
+        assert!(mgr.join(5, 2, "small", None).is_ok(), "a member's new session still fits");
+    }
+
+    #[test]
+    fn test_rename_channel_keeps_members_and_history() {
+        let mut mgr = ChannelManager::new();
+        mgr.ensure_channel("old", true, None, ChannelType::Public, Some(1)).unwrap();
+        mgr.join(1, 1, "old", None).unwrap();
+        mgr.join(2, 2, "old", None).unwrap();
+        let id = mgr.get_channel_id("old").unwrap();
+        mgr.add_message("old", ChatMessage {
+            id: 0,
+            seq: 0,
+            user_id: 1,
+            username: "alice".to_string(),
+            content: b"hello".to_vec(),
+            timestamp: Utc::now(),
+            nonce: None,
+            metadata: MessageMetadata::new(),
+            deleted: false,
+            edited: false,
+        }).unwrap();
+
+        mgr.rename_channel("old", "#New").unwrap();
+        assert_eq!(mgr.get_channel_id("old"), None);
+        assert_eq!(mgr.get_channel_id("new"), Some(id));
+        let mut members = mgr.members("new");
+        members.sort();
+        assert_eq!(members, vec![1, 2]);
+        assert_eq!(mgr.history("new", 10)[0].content, b"hello");
+        assert_eq!(mgr.list_public()[0].name, "new");
+    }
+
+    #[test]
+    fn test_rename_channel_collision_rejected() {
+        let mut mgr = ChannelManager::new();
+        for name in ["one", "two"] {
+            mgr.ensure_channel(name, true, None, ChannelType::Public, None).unwrap();
+        }
+        mgr.join(1, 1, "one", None).unwrap();
+        mgr.join(2, 2, "two", None).unwrap();
+
+        assert_eq!(mgr.rename_channel("one", "TWO").unwrap_err(), "channel already exists");
+        assert_eq!(mgr.rename_channel("missing", "three").unwrap_err(), "channel not found");
+        assert!(mgr.rename_channel("one", "bad name").is_err());
+        assert_eq!(mgr.members("one"), vec![1]);
+        assert_eq!(mgr.members("two"), vec![2]);
+    }
+
+    #[test]
+    fn test_join_password_must_match_creation() {
+        let mut channels = ChannelManager::new();
+        assert_eq!(channels.join(1, 1, "general", None).unwrap_err(), "channel not found", "joining never creates");
+        channels.ensure_channel("general", true, None, ChannelType::Public, Some(1)).unwrap();
+        channels.ensure_channel("staff", true, Some("secret".to_string()), ChannelType::Public, Some(1)).unwrap();
+        assert_eq!(channels.is_public("staff"), Some(false), "a password makes it private");
+
+        assert_eq!(
+            channels.join(2, 2, "general", Some("secret".to_string())).unwrap_err(),
+            "channel has no password; join without one"
+        );
+        assert_eq!(channels.join(2, 2, "staff", None).unwrap_err(), "channel requires a password");
+        assert_eq!(channels.join(2, 2, "staff", Some(String::new())).unwrap_err(), "channel requires a password");
+        assert_eq!(channels.join(2, 2, "staff", Some("guess".to_string())).unwrap_err(), "invalid channel password");
+        assert!(!channels.is_member("general", 2) && !channels.is_member("staff", 2));
+
+        // A later explicit create doesn't change what the first one decided.
+        let id = channels.ensure_channel("general", false, Some("late".to_string()), ChannelType::Private, None).unwrap();
+        assert_eq!(channels.get_channel_id("general"), Some(id));
+        channels.join(2, 2, "general", None).unwrap();
+        channels.join(2, 2, "staff", Some("secret".to_string())).unwrap();
+    }
 }
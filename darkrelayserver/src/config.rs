@@ -0,0 +1,381 @@
+use std::{
+    collections::{BTreeSet, HashSet},
+    env,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use darkrelayprotocol::{frame::MAX_FRAME_LEN, protocol::{DEFAULT_MAX_MESSAGE_LEN, MESSAGE_OVERHEAD_ALLOWANCE}};
+use tracing_appender::rolling::{InitError, RollingFileAppender, Rotation};
+
+use crate::{
+    auth::{PasswordPolicy, DEFAULT_RESERVED_USERNAMES},
+    channel::{self, ChannelCreation, DEFAULT_MAX_CHANNELS},
+    ratelimit::{DEFAULT_MAX_MESSAGES, DEFAULT_WINDOW_SECS},
+    registry::{DuplicateLogin, DEFAULT_OUTBOUND_CAPACITY},
+    spam::SpamPolicy,
+};
+
+pub const DEFAULT_SPECIAL_KEY: &str = "darkrelay-dev-key";
+pub const DEFAULT_LISTEN_ADDR: &str = "0.0.0.0:8080";
+pub const DEFAULT_ADMIN_LOG_DIR: &str = "darkrelayserver/logs/admin";
+pub const DEFAULT_ROLES_FILE: &str = "darkrelayserver/data/channel_roles.json";
+pub const DEFAULT_USERS_FILE: &str = "darkrelayserver/data/users.json";
+pub const DEFAULT_LOG_DIR: &str = "darkrelayserver/logs";
+pub const DEFAULT_LOG_FILTER: &str = "info,darkrelayserver=debug";
+/// Earlier log files kept beside the live one unless `DARKRELAY_LOG_KEEP` says otherwise.
+pub const DEFAULT_LOG_KEEP: usize = 7;
+
+/// Server settings read once at startup. Unset or unparsable variables keep
+/// their defaults.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub listen_addr: String,
+    pub special_key: String,
+    pub super_admins: HashSet<String>,
+    pub admin_log_dir: PathBuf,
+    /// Channel types and roles, kept across restarts.
+    pub roles_file: PathBuf,
+    /// Accounts and when each was last seen, kept across restarts.
+    pub users_file: PathBuf,
+    /// Message of the day sent after login; wins over `motd_file`.
+    pub motd: Option<String>,
+    /// Read at startup and again on `SetMotd` without text.
+    pub motd_file: Option<PathBuf>,
+    /// Messages per window allowed by the rate limiter.
+    pub rate_limit: (usize, i64),
+    pub outbound_queue: usize,
+    pub duplicate_login: DuplicateLogin,
+    /// PEM file of CAs for client certificates; set to require mutual TLS.
+    pub client_ca: Option<PathBuf>,
+    /// Applied to passwords chosen at registration.
+    pub password_policy: PasswordPolicy,
+    /// Usernames that only a SuperAdmin can create; matched case-insensitively.
+    pub reserved_usernames: HashSet<String>,
+    /// Stored DMs older than this are pruned by the retention sweep.
+    pub dm_ttl: Option<Duration>,
+    /// Joining an unknown name creates a channel only below this count.
+    pub max_channels: usize,
+    /// The only channel names allowed, opened at startup; `None` allows any.
+    pub allowed_channels: Option<BTreeSet<String>>,
+    /// Read at startup for `allowed_channels` when that isn't set.
+    pub allowed_channels_file: Option<PathBuf>,
+    pub channel_creation: ChannelCreation,
+    /// Repeated identical-size sends that get a user muted in a channel.
+    pub spam: SpamPolicy,
+    /// Largest channel message or DM `content` accepted, in bytes.
+    pub max_message_len: usize,
+    /// `EnvFilter` directives for the server log.
+    pub log_filter: String,
+    /// How often the server log starts a new file.
+    pub log_rotation: Rotation,
+    /// Earlier log files kept; 0 keeps them all.
+    pub log_keep: usize,
+
+    pub ban_cleanup_interval: Duration,
+    pub retention_interval: Duration,
+    pub resume_sweep_interval: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: DEFAULT_LISTEN_ADDR.to_string(),
+            special_key: DEFAULT_SPECIAL_KEY.to_string(),
+            super_admins: HashSet::new(),
+            admin_log_dir: PathBuf::from(DEFAULT_ADMIN_LOG_DIR),
+            roles_file: PathBuf::from(DEFAULT_ROLES_FILE),
+            users_file: PathBuf::from(DEFAULT_USERS_FILE),
+            motd: None,
+            motd_file: None,
+            rate_limit: (DEFAULT_MAX_MESSAGES, DEFAULT_WINDOW_SECS),
+            outbound_queue: DEFAULT_OUTBOUND_CAPACITY,
+            duplicate_login: DuplicateLogin::default(),
+            client_ca: None,
+            password_policy: PasswordPolicy::default(),
+            reserved_usernames: DEFAULT_RESERVED_USERNAMES.iter().map(|n| n.to_string()).collect(),
+            dm_ttl: None,
+            max_channels: DEFAULT_MAX_CHANNELS,
+            allowed_channels: None,
+            allowed_channels_file: None,
+            channel_creation: ChannelCreation::default(),
+            spam: SpamPolicy::default(),
+            max_message_len: DEFAULT_MAX_MESSAGE_LEN,
+            log_filter: DEFAULT_LOG_FILTER.to_string(),
+            log_rotation: Rotation::DAILY,
+            log_keep: DEFAULT_LOG_KEEP,
+            ban_cleanup_interval: Duration::from_secs(60),
+            retention_interval: Duration::from_secs(60),
+            resume_sweep_interval: Duration::from_secs(10),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        Self::from_lookup(|name| env::var(name).ok())
+    }
+
+    /// Build a config from `lookup`, which maps a variable name to its value.
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Self {
+        let defaults = Self::default();
+        let secs = |name: &str, default: Duration| {
+            lookup(name)
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|s| *s > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+
+        let names = |v: String| -> HashSet<String> {
+            v.split(',')
+                .map(|s| s.trim().to_string())
+                .filter(|s| !s.is_empty())
+                .collect()
+        };
+        let super_admins = lookup("DARKRELAY_SUPERADMINS").map(names).unwrap_or_default();
+        // Set but empty means nothing is reserved.
+        let reserved_usernames = lookup("DARKRELAY_RESERVED_USERNAMES")
+            .map(names)
+            .unwrap_or(defaults.reserved_usernames);
+
+        let rate_limit = lookup("DARKRELAY_RATE_LIMIT")
+            .and_then(|v| {
+                let (count, secs) = v.split_once('/')?;
+                Some((count.trim().parse::<usize>().ok()?, secs.trim().parse::<i64>().ok()?))
+            })
+            .unwrap_or(defaults.rate_limit);
+
+        let password_policy = PasswordPolicy {
+            min_len: lookup("DARKRELAY_PASSWORD_MIN_LEN")
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(defaults.password_policy.min_len),
+            require_mixed: lookup("DARKRELAY_PASSWORD_REQUIRE_MIXED")
+                .map(|v| !matches!(v.trim().to_ascii_lowercase().as_str(), "0" | "false" | "no" | "off"))
+                .unwrap_or(defaults.password_policy.require_mixed),
+        };
+
+        let spam_secs = |name: &str, default: chrono::Duration| {
+            lookup(name)
+                .and_then(|v| v.trim().parse::<i64>().ok())
+                .filter(|s| *s > 0)
+                .map(chrono::Duration::seconds)
+                .unwrap_or(default)
+        };
+        let spam = SpamPolicy {
+            repeats: lookup("DARKRELAY_SPAM_REPEATS")
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(defaults.spam.repeats),
+            window: spam_secs("DARKRELAY_SPAM_WINDOW_SECS", defaults.spam.window),
+            mute: spam_secs("DARKRELAY_SPAM_MUTE_SECS", defaults.spam.mute),
+        };
+
+        Self {
+            listen_addr: lookup("DARKRELAY_LISTEN").unwrap_or(defaults.listen_addr),
+            special_key: lookup("DARKRELAY_SPECIAL_KEY").unwrap_or(defaults.special_key),
+            super_admins,
+            reserved_usernames,
+            admin_log_dir: lookup("DARKRELAY_ADMIN_LOG_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.admin_log_dir),
+            roles_file: lookup("DARKRELAY_ROLES_FILE")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.roles_file),
+            users_file: lookup("DARKRELAY_USERS_FILE")
+                .map(PathBuf::from)
+                .unwrap_or(defaults.users_file),
+            motd: lookup("DARKRELAY_MOTD"),
+            motd_file: lookup("DARKRELAY_MOTD_FILE").map(PathBuf::from),
+            rate_limit,
+            outbound_queue: lookup("DARKRELAY_OUTBOUND_QUEUE")
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(defaults.outbound_queue),
+            duplicate_login: lookup("DARKRELAY_DUPLICATE_LOGIN")
+                .and_then(|v| DuplicateLogin::parse(&v))
+                .unwrap_or(defaults.duplicate_login),
+            client_ca: lookup("DARKRELAY_CLIENT_CA").map(PathBuf::from),
+            password_policy,
+            dm_ttl: lookup("DARKRELAY_DM_TTL_SECS")
+                .and_then(|v| v.trim().parse::<u64>().ok())
+                .filter(|s| *s > 0)
+                .map(Duration::from_secs),
+            max_channels: lookup("DARKRELAY_MAX_CHANNELS")
+                .and_then(|v| v.trim().parse().ok())
+                .filter(|n| *n > 0)
+                .unwrap_or(defaults.max_channels),
+            allowed_channels: lookup("DARKRELAY_ALLOWED_CHANNELS").map(|v| channel::parse_channel_list(&v)),
+            allowed_channels_file: lookup("DARKRELAY_ALLOWED_CHANNELS_FILE").map(PathBuf::from),
+            channel_creation: lookup("DARKRELAY_CHANNEL_CREATION")
+                .and_then(|v| ChannelCreation::parse(&v))
+                .unwrap_or(defaults.channel_creation),
+            spam,
+            max_message_len: lookup("DARKRELAY_MAX_MESSAGE_LEN")
+                .and_then(|v| v.trim().parse::<usize>().ok())
+                .filter(|n| *n > 0)
+                .map(|n| n.min(MAX_FRAME_LEN - MESSAGE_OVERHEAD_ALLOWANCE))
+                .unwrap_or(defaults.max_message_len),
+            // `RUST_LOG` still works for anyone used to it.
+            log_filter: lookup("DARKRELAY_LOG")
+                .or_else(|| lookup("RUST_LOG"))
+                .filter(|v| !v.trim().is_empty())
+                .unwrap_or(defaults.log_filter),
+            log_rotation: lookup("DARKRELAY_LOG_ROTATION")
+                .and_then(|v| parse_rotation(&v))
+                .unwrap_or(defaults.log_rotation),
+            log_keep: lookup("DARKRELAY_LOG_KEEP")
+                .and_then(|v| v.trim().parse().ok())
+                .unwrap_or(defaults.log_keep),
+            ban_cleanup_interval: secs("DARKRELAY_BAN_CLEANUP_SECS", defaults.ban_cleanup_interval),
+            retention_interval: secs("DARKRELAY_RETENTION_SWEEP_SECS", defaults.retention_interval),
+            resume_sweep_interval: secs("DARKRELAY_RESUME_SWEEP_SECS", defaults.resume_sweep_interval),
+        }
+    }
+
+    /// The server log in `dir`: `server.<date>.log` (`server.log` when it
+    /// never rotates), with all but the newest `log_keep` earlier files
+    /// pruned whenever a new one is started.
+    pub fn log_appender(&self, dir: impl AsRef<Path>) -> Result<RollingFileAppender, InitError> {
+        // The appender's limit counts the file it is about to open.
+        let max_files = if self.log_keep == 0 { 0 } else { self.log_keep + 1 };
+        RollingFileAppender::builder()
+            .rotation(self.log_rotation.clone())
+            .filename_prefix("server")
+            .filename_suffix("log")
+            .max_log_files(max_files)
+            .build(dir)
+    }
+}
+
+/// `hourly`, `daily`, `weekly` or `never`.
+fn parse_rotation(value: &str) -> Option<Rotation> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "hourly" => Some(Rotation::HOURLY),
+        "daily" => Some(Rotation::DAILY),
+        "weekly" => Some(Rotation::WEEKLY),
+        "never" | "off" => Some(Rotation::NEVER),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_config_overrides_and_defaults() {
+        let vars: HashMap<&str, &str> = [
+            ("DARKRELAY_SPECIAL_KEY", "secret"),
+            ("DARKRELAY_SUPERADMINS", "alice, bob,"),
+            ("DARKRELAY_RESERVED_USERNAMES", "staff,root"),
+            ("DARKRELAY_RATE_LIMIT", "10/2"),
+            ("DARKRELAY_DUPLICATE_LOGIN", "reject"),
+            ("DARKRELAY_BAN_CLEANUP_SECS", "5"),
+            ("DARKRELAY_RETENTION_SWEEP_SECS", "0"),
+            ("DARKRELAY_RESUME_SWEEP_SECS", "soon"),
+            ("DARKRELAY_PASSWORD_MIN_LEN", "16"),
+            ("DARKRELAY_PASSWORD_REQUIRE_MIXED", "off"),
+            ("DARKRELAY_DM_TTL_SECS", "86400"),
+            ("DARKRELAY_MAX_CHANNELS", "50"),
+            ("DARKRELAY_CHANNEL_CREATION", "Admins"),
+            ("DARKRELAY_SPAM_REPEATS", "0"),
+            ("DARKRELAY_SPAM_MUTE_SECS", "300"),
+            ("DARKRELAY_MAX_MESSAGE_LEN", "4096"),
+            ("DARKRELAY_LOG", "warn"),
+            ("RUST_LOG", "trace"),
+            ("DARKRELAY_LOG_ROTATION", "Hourly"),
+            ("DARKRELAY_LOG_KEEP", "0"),
+            ("DARKRELAY_MOTD_FILE", "/etc/darkrelay/motd"),
+            ("DARKRELAY_ALLOWED_CHANNELS", "#General, support news bad/name"),
+        ]
+        .into_iter()
+        .collect();
+        let config = ServerConfig::from_lookup(|name| vars.get(name).map(|v| v.to_string()));
+        let defaults = ServerConfig::default();
+
+        assert_eq!(config.special_key, "secret");
+        assert_eq!(config.super_admins, HashSet::from(["alice".to_string(), "bob".to_string()]));
+        assert_eq!(config.reserved_usernames, HashSet::from(["staff".to_string(), "root".to_string()]));
+        assert_eq!(config.rate_limit, (10, 2));
+        assert_eq!(config.duplicate_login, DuplicateLogin::Reject);
+        assert_eq!(config.ban_cleanup_interval, Duration::from_secs(5));
+        assert_eq!(config.retention_interval, defaults.retention_interval, "zero is ignored");
+        assert_eq!(config.resume_sweep_interval, defaults.resume_sweep_interval);
+        assert_eq!(config.outbound_queue, DEFAULT_OUTBOUND_CAPACITY);
+        assert_eq!(config.admin_log_dir, PathBuf::from(DEFAULT_ADMIN_LOG_DIR));
+        assert_eq!(config.client_ca, None);
+        assert_eq!(config.password_policy, PasswordPolicy { min_len: 16, require_mixed: false });
+        assert_eq!(config.dm_ttl, Some(Duration::from_secs(86400)));
+        assert_eq!(config.max_channels, 50);
+        assert_eq!(config.channel_creation, ChannelCreation::Admins);
+        assert_eq!(config.max_message_len, 4096);
+        assert_eq!(config.log_filter, "warn", "DARKRELAY_LOG wins over RUST_LOG");
+        assert_eq!(config.log_rotation, Rotation::HOURLY);
+        assert_eq!(config.log_keep, 0);
+        assert_eq!(config.motd_file, Some(PathBuf::from("/etc/darkrelay/motd")));
+        assert_eq!(
+            config.allowed_channels,
+            Some(BTreeSet::from(["general".to_string(), "support".to_string(), "news".to_string()]))
+        );
+        assert_eq!(config.spam, SpamPolicy { repeats: 0, mute: chrono::Duration::seconds(300), ..SpamPolicy::default() });
+
+        let empty = ServerConfig::from_lookup(|_| None);
+        assert_eq!(empty.special_key, DEFAULT_SPECIAL_KEY);
+        assert_eq!(empty.listen_addr, DEFAULT_LISTEN_ADDR);
+        assert_eq!(empty.rate_limit, defaults.rate_limit);
+        assert_eq!(empty.duplicate_login, DuplicateLogin::KickOld);
+        assert!(empty.super_admins.is_empty());
+        assert!(empty.reserved_usernames.contains("admin"));
+        assert!(ServerConfig::from_lookup(|name| (name == "DARKRELAY_RESERVED_USERNAMES").then(String::new))
+            .reserved_usernames
+            .is_empty());
+        assert_eq!(empty.password_policy, PasswordPolicy::default());
+        assert_eq!(empty.dm_ttl, None);
+        assert_eq!(empty.max_channels, DEFAULT_MAX_CHANNELS);
+        assert_eq!(empty.channel_creation, ChannelCreation::Anyone);
+        assert_eq!(empty.spam, SpamPolicy::default());
+        assert_eq!(empty.max_message_len, DEFAULT_MAX_MESSAGE_LEN);
+        assert_eq!(empty.log_filter, DEFAULT_LOG_FILTER);
+        assert_eq!(empty.log_rotation, Rotation::DAILY);
+        assert_eq!(empty.log_keep, DEFAULT_LOG_KEEP);
+        assert_eq!(empty.roles_file, PathBuf::from(DEFAULT_ROLES_FILE));
+        assert_eq!(empty.users_file, PathBuf::from(DEFAULT_USERS_FILE));
+        assert_eq!((empty.motd, empty.motd_file), (None, None));
+        assert_eq!((empty.allowed_channels, empty.allowed_channels_file), (None, None));
+    }
+
+    #[test]
+    fn test_log_appender_keeps_the_newest_files() {
+        use std::{fs, io::Write, thread};
+
+        let dir = env::temp_dir().join(format!("darkrelay-log-rolling-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        // Oldest first: the appender prunes by creation time.
+        for name in ["server.2026-01-01.log", "server.2026-01-02.log", "server.2026-01-03.log"] {
+            fs::write(dir.join(name), b"{}\n").unwrap();
+            thread::sleep(Duration::from_millis(20));
+        }
+        fs::write(dir.join("notes.txt"), b"").unwrap();
+
+        let config = ServerConfig { log_keep: 2, ..ServerConfig::default() };
+        let mut appender = config.log_appender(&dir).unwrap();
+        appender.write_all(b"{\"msg\":\"hello\"}\n").unwrap();
+        appender.flush().unwrap();
+
+        let today = format!("server.{}.log", chrono::Utc::now().format("%Y-%m-%d"));
+        let mut names: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        names.sort();
+        assert_eq!(names, ["notes.txt", "server.2026-01-02.log", "server.2026-01-03.log", today.as_str()]);
+        assert_eq!(fs::read_to_string(dir.join(&today)).unwrap(), "{\"msg\":\"hello\"}\n");
+
+        let never = ServerConfig { log_rotation: Rotation::NEVER, ..ServerConfig::default() };
+        drop(never.log_appender(&dir).unwrap());
+        assert!(dir.join("server.log").exists(), "no date when it never rotates");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -8,6 +8,12 @@ pub struct EcdhManager {
     secrets: HashMap<ClientId, SharedSecret>,
 }
 
+impl Default for EcdhManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl EcdhManager {
     pub fn new() -> Self {
         Self {
@@ -0,0 +1,175 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+use darkrelayprotocol::protocol::{ChatMessage, MessageId, UserId};
+
+/// Oldest DMs to a user are dropped once this many are stored for them.
+pub const MAX_DMS_PER_RECIPIENT: usize = 500;
+
+#[derive(Debug)]
+struct StoredDm {
+    message: ChatMessage,
+    delivered: bool,
+}
+
+/// Direct messages keyed by recipient user id, so renames don't orphan them.
+/// The sender is `ChatMessage::user_id`. Content is stored as sent, like
+/// channel messages.
+#[derive(Debug)]
+pub struct DMManager {
+    inboxes: HashMap<UserId, VecDeque<StoredDm>>,
+    next_id: MessageId,
+    /// DMs older than this are pruned; `None` keeps them until pushed out.
+    ttl: Option<Duration>,
+}
+
+impl Default for DMManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DMManager {
+    pub fn new() -> Self {
+        Self {
+            inboxes: HashMap::new(),
+            next_id: 1,
+            ttl: None,
+        }
+    }
+
+    pub fn set_ttl(&mut self, ttl: Option<Duration>) {
+        self.ttl = ttl;
+    }
+
+    /// Store a DM for `recipient_id`, assigning its id, and return it as
+    /// stored. `delivered` says whether it was pushed to a live session.
+    pub fn store_dm(&mut self, recipient_id: UserId, mut message: ChatMessage, delivered: bool) -> ChatMessage {
+        message.id = self.next_id;
+        self.next_id += 1;
+
+        let inbox = self.inboxes.entry(recipient_id).or_default();
+        inbox.push_back(StoredDm {
+            message: message.clone(),
+            delivered,
+        });
+        if inbox.len() > MAX_DMS_PER_RECIPIENT {
+            inbox.pop_front();
+        }
+        message
+    }
+
+    /// DMs stored for `user_id` while they were offline, oldest first. They
+    /// are marked delivered, so each is returned once.
+    pub fn get_undelivered_dms(&mut self, user_id: UserId) -> Vec<ChatMessage> {
+        let Some(inbox) = self.inboxes.get_mut(&user_id) else {
+            return Vec::new();
+        };
+        inbox
+            .iter_mut()
+            .filter(|dm| !dm.delivered)
+            .map(|dm| {
+                dm.delivered = true;
+                dm.message.clone()
+            })
+            .collect()
+    }
+
+    /// `(sender, recipient)` of a stored DM.
+    pub fn participants(&self, dm_id: MessageId) -> Option<(UserId, UserId)> {
+        self.inboxes.iter().find_map(|(recipient, inbox)| {
+            inbox
+                .iter()
+                .find(|dm| dm.message.id == dm_id)
+                .map(|dm| (dm.message.user_id, *recipient))
+        })
+    }
+
+    /// Returns false if no DM has this id.
+    pub fn delete_dm(&mut self, dm_id: MessageId) -> bool {
+        for inbox in self.inboxes.values_mut() {
+            if let Some(pos) = inbox.iter().position(|dm| dm.message.id == dm_id) {
+                inbox.remove(pos);
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Drop DMs older than the TTL, delivered or not. Returns how many were removed.
+    pub fn prune_expired(&mut self, now: DateTime<Utc>) -> usize {
+        let Some(ttl) = self.ttl else {
+            return 0;
+        };
+        let cutoff = now - ttl;
+        let mut removed = 0;
+        self.inboxes.retain(|_, inbox| {
+            let before = inbox.len();
+            inbox.retain(|dm| dm.message.timestamp >= cutoff);
+            removed += before - inbox.len();
+            !inbox.is_empty()
+        });
+        removed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Utc;
+    use darkrelayprotocol::metadata::MessageMetadata;
+
+    fn dm(from: UserId) -> ChatMessage {
+        ChatMessage {
+            id: 0,
+            seq: 0,
+            user_id: from,
+            username: "alice".to_string(),
+            content: b"hi".to_vec(),
+            timestamp: Utc::now(),
+            nonce: None,
+            metadata: MessageMetadata::new(),
+            deleted: false,
+            edited: false,
+        }
+    }
+
+    #[test]
+    fn test_undelivered_dms_are_returned_once() {
+        let mut dms = DMManager::new();
+        let live = dms.store_dm(2, dm(1), true);
+        let first = dms.store_dm(2, dm(1), false);
+        let second = dms.store_dm(2, dm(3), false);
+        dms.store_dm(4, dm(1), false);
+        assert!(live.id < first.id && first.id < second.id);
+
+        let ids: Vec<_> = dms.get_undelivered_dms(2).iter().map(|m| m.id).collect();
+        assert_eq!(ids, [first.id, second.id]);
+        assert!(dms.get_undelivered_dms(2).is_empty());
+        assert!(dms.get_undelivered_dms(9).is_empty());
+    }
+
+    #[test]
+    fn test_delete_and_expire_dms() {
+        let mut dms = DMManager::new();
+        let kept = dms.store_dm(2, dm(1), false);
+        let deleted = dms.store_dm(2, dm(1), false);
+        assert_eq!(dms.participants(deleted.id), Some((1, 2)));
+
+        assert!(dms.delete_dm(deleted.id));
+        assert!(!dms.delete_dm(deleted.id));
+        assert_eq!(dms.participants(deleted.id), None);
+        let ids: Vec<_> = dms.get_undelivered_dms(2).iter().map(|m| m.id).collect();
+        assert_eq!(ids, [kept.id]);
+
+        let mut old = dm(3);
+        old.timestamp = Utc::now() - Duration::hours(2);
+        let old = dms.store_dm(2, old, true);
+        assert_eq!(dms.prune_expired(Utc::now()), 0, "no TTL keeps everything");
+
+        dms.set_ttl(Some(Duration::hours(1)));
+        assert_eq!(dms.prune_expired(Utc::now()), 1);
+        assert_eq!(dms.participants(old.id), None);
+        assert!(dms.participants(kept.id).is_some());
+    }
+}
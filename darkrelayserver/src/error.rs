@@ -0,0 +1,143 @@
+use chrono::{DateTime, Utc};
+use thiserror::Error;
+
+use darkrelayprotocol::{
+    permissions::Permission,
+    protocol::{MessageMeta, ServerMessage},
+};
+
+/// Why a request failed. Handlers return it and `into_message` turns it into
+/// what the client sees, so each kind of failure always reads the same way.
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum ServerError {
+    #[error("login/register required")]
+    NotAuthenticated,
+
+    /// Login, registration or resume refused.
+    #[error("{0}")]
+    AuthFailed(String),
+
+    #[error("You lack permission: {0:?}")]
+    MissingPermission(Permission),
+
+    /// Refused by a rule other than a channel permission (guests, server roles, ownership).
+    #[error("{0}")]
+    PermissionDenied(String),
+
+    /// What was looked up: `"Channel"`, `"User"`, `"Message"`.
+    #[error("{0} not found")]
+    NotFound(&'static str),
+
+    #[error("{}", match .until {
+        Some(until) => format!("Banned until {}", until.format("%Y-%m-%d %H:%M:%S UTC")),
+        None => "Permanently banned from channel".to_string(),
+    })]
+    Banned { channel: String, until: Option<DateTime<Utc>> },
+
+    #[error("{reason}")]
+    JoinRefused { channel: String, reason: String },
+
+    /// Sent too fast; `channel` is the channel or `@name` conversation.
+    #[error("slow down: retry in {retry_after_ms}ms")]
+    RateLimited { channel: String, retry_after_ms: u64 },
+
+    /// A well-formed admin request with an unacceptable argument.
+    #[error("{0}")]
+    Rejected(String),
+
+    /// Message content over the limit; `max` is the advertised plaintext limit.
+    #[error("message too long: the limit is {max} bytes")]
+    MessageTooLong { max: usize },
+
+    /// A request that doesn't make sense in the current session state.
+    #[error("{0}")]
+    InvalidRequest(String),
+
+    /// A server-side fault. The detail is logged, not sent.
+    #[error("internal error")]
+    Internal(String),
+}
+
+impl ServerError {
+    pub fn into_message(self, meta: MessageMeta, request_id: Option<u64>) -> ServerMessage {
+        let text = self.to_string();
+        match self {
+            ServerError::AuthFailed(_) => ServerMessage::AuthFailure { meta, reason: text },
+            ServerError::MissingPermission(_)
+            | ServerError::PermissionDenied(_)
+            | ServerError::NotFound(_)
+            | ServerError::Rejected(_) => ServerMessage::AdminError { meta, reason: text, request_id },
+            ServerError::Banned { channel, .. } | ServerError::JoinRefused { channel, .. } => {
+                ServerMessage::JoinFailure { meta, channel, reason: text }
+            }
+            ServerError::RateLimited { channel, retry_after_ms } => {
+                ServerMessage::Cooldown { meta, channel, retry_after_ms, request_id }
+            }
+            ServerError::NotAuthenticated
+            | ServerError::MessageTooLong { .. }
+            | ServerError::InvalidRequest(_)
+            | ServerError::Internal(_) => {
+                ServerMessage::ProtocolError { meta, text, request_id }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn message(err: ServerError) -> ServerMessage {
+        err.into_message(MessageMeta::new(1, Utc::now()), Some(9))
+    }
+
+    #[test]
+    fn test_errors_map_to_protocol_messages() {
+        let protocol_error = |err| match message(err) {
+            ServerMessage::ProtocolError { text, request_id: Some(9), .. } => text,
+            other => panic!("expected ProtocolError, got {other:?}"),
+        };
+        assert_eq!(protocol_error(ServerError::NotAuthenticated), "login/register required");
+        assert_eq!(protocol_error(ServerError::InvalidRequest("not joined to channel".into())), "not joined to channel");
+        assert_eq!(protocol_error(ServerError::MessageTooLong { max: 4096 }), "message too long: the limit is 4096 bytes");
+        assert_eq!(protocol_error(ServerError::Internal("user missing".into())), "internal error");
+
+        let admin_error = |err| match message(err) {
+            ServerMessage::AdminError { reason, request_id: Some(9), .. } => reason,
+            other => panic!("expected AdminError, got {other:?}"),
+        };
+        assert_eq!(admin_error(ServerError::MissingPermission(Permission::ManageChannel)), "You lack permission: ManageChannel");
+        assert_eq!(admin_error(ServerError::PermissionDenied("Permission denied: guests are read-only".into())), "Permission denied: guests are read-only");
+        assert_eq!(admin_error(ServerError::NotFound("Channel")), "Channel not found");
+        assert_eq!(admin_error(ServerError::Rejected("Retention too long".into())), "Retention too long");
+
+        assert!(matches!(
+            message(ServerError::AuthFailed("invalid password".into())),
+            ServerMessage::AuthFailure { reason, .. } if reason == "invalid password"
+        ));
+
+        let until = Utc.with_ymd_and_hms(2030, 1, 2, 3, 4, 5).unwrap();
+        let join_failure = |err| match message(err) {
+            ServerMessage::JoinFailure { channel, reason, .. } => (channel, reason),
+            other => panic!("expected JoinFailure, got {other:?}"),
+        };
+        assert_eq!(
+            join_failure(ServerError::Banned { channel: "general".into(), until: Some(until) }),
+            ("general".to_string(), "Banned until 2030-01-02 03:04:05 UTC".to_string())
+        );
+        assert_eq!(
+            join_failure(ServerError::Banned { channel: "general".into(), until: None }).1,
+            "Permanently banned from channel"
+        );
+        assert_eq!(
+            join_failure(ServerError::JoinRefused { channel: "x".into(), reason: "channel full".into() }),
+            ("x".to_string(), "channel full".to_string())
+        );
+
+        assert!(matches!(
+            message(ServerError::RateLimited { channel: "@bob".into(), retry_after_ms: 250 }),
+            ServerMessage::Cooldown { channel, retry_after_ms: 250, request_id: Some(9), .. } if channel == "@bob"
+        ));
+    }
+}
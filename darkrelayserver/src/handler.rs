@@ -1,15 +1,23 @@
 use std::{
     io,
-    sync::Arc,
-    time::Duration,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
 };
 
-use bincode;
 use chrono::Utc;
 use darkrelayprotocol::{
-    permissions::Permission,
+    channel::ChannelType,
+    crypto::{MIN_CIPHERTEXT_LEN, NONCE_LEN},
+    frame,
+    metadata::{MessageMetadata, NONCE_KEY},
+    permissions::{has_permission, Permission, Role},
     protocol::{
-        ChatMessage, ClientMessage, MessageMeta, ServerMessage,
+        features, max_content_len, AdminInfo, ChannelId, ChannelInfo, ChatMessage, ClientMessage, MessageId, MessageMeta, PollId,
+        ServerMessage, UserInfo, MAX_NAME_FIELD_LEN, MOTD_PREFIX,
     },
 };
 use serde::{de::DeserializeOwned, Serialize};
@@ -21,30 +29,67 @@ use tokio::{
 use tokio_rustls::server::TlsStream;
 use tracing::{debug, info, warn};
 
-use crate::{AppState, channel::ClientId};
+use crate::{AppState, auth::{self, PublishedKeys}, channel::{self, ChannelCreation, ClientId}, error::ServerError, motd, registry::DuplicateLogin, spam::SpamVerdict, tls};
 
 pub async fn handle_client(
     state: Arc<AppState>,
     client_id: ClientId,
+    peer_addr: SocketAddr,
     socket: TlsStream<tokio::net::TcpStream>,
     shutdown_rx: &mut broadcast::Receiver<()>,
 ) -> io::Result<()> {
-    let (mut reader, mut writer) = tokio::io::split(socket);
+    let cert_subject = socket
+        .get_ref()
+        .1
+        .peer_certificates()
+        .and_then(|certs| certs.first())
+        .and_then(|cert| tls::cert_common_name(&cert.0));
+
+    serve_client(state, client_id, peer_addr, cert_subject, socket, shutdown_rx).await
+}
 
-    let (out_tx, mut out_rx) = mpsc::unbounded_channel::<ServerMessage>();
+/// The per-connection loop once TLS is up. Runs until the client leaves, the
+/// server shuts down, or `Registry::terminate` fires for this client.
+async fn serve_client<S>(
+    state: Arc<AppState>,
+    client_id: ClientId,
+    peer_addr: SocketAddr,
+    cert_subject: Option<String>,
+    socket: S,
+    shutdown_rx: &mut broadcast::Receiver<()>,
+) -> io::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Send + 'static,
+{
+    let connected_at = Instant::now();
+    let stats = Arc::new(ConnectionStats::default());
 
-    {
+    let (mut reader, mut writer) = tokio::io::split(socket);
+
+    let (disconnect, mut out_rx) = {
         let mut reg = state.registry.write().await;
-        reg.register(client_id, out_tx);
-    }
+        let (out_tx, out_rx) = mpsc::channel::<Arc<ServerMessage>>(reg.outbound_capacity());
+        let disconnect = reg.register(client_id, out_tx);
+        reg.set_peer_addr(client_id, peer_addr);
+        if let Some(subject) = cert_subject {
+            reg.set_cert_subject(client_id, subject);
+        }
+        (disconnect, out_rx)
+    };
 
     let writer_state = Arc::clone(&state);
+    let writer_stats = Arc::clone(&stats);
     let writer_task = tokio::spawn(async move {
+        let mut compression = false;
         while let Some(msg) = out_rx.recv().await {
-            if let Err(e) = write_frame(&mut writer, &msg).await {
+            if let Err(e) = write_frame(&mut writer, &*msg, compression).await {
                 debug!(client_id, error = %e, "writer task exiting");
                 break;
             }
+            writer_stats.frames_written.fetch_add(1, Ordering::Relaxed);
+            if matches!(*msg, ServerMessage::CompressionEnabled { .. }) {
+                compression = true;
+            }
         }
         let mut reg = writer_state.registry.write().await;
         reg.remove(client_id);
@@ -62,6 +107,7 @@ pub async fn handle_client(
     let mut special_authed = false;
     let mut user_authed = false;
     let mut ecdh_complete = false;
+    let mut compression = false;
 
     loop {
         tokio::select! {
@@ -69,29 +115,67 @@ pub async fn handle_client(
                 info!(client_id, "shutdown requested");
                 break;
             }
-            msg_res = read_frame::<ClientMessage, _>(&mut reader) => {
+            _ = disconnect.notified() => {
+                info!(client_id, "server closed the connection");
+                break;
+            }
+            msg_res = read_frame::<ClientMessage, _>(&mut reader, compression) => {
                 let msg = match msg_res {
-                    Ok(m) => m,
+                    Ok(m) => {
+                        stats.frames_read.fetch_add(1, Ordering::Relaxed);
+                        m
+                    }
+                    // A newer client; the frame was consumed, so the stream is still in sync.
+                    Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                        stats.frames_read.fetch_add(1, Ordering::Relaxed);
+                        warn!(client_id, error = %e, "skipping unsupported message");
+                        send_error(&state, client_id, None, ServerError::InvalidRequest("unsupported message".to_string())).await;
+                        continue;
+                    }
                     Err(e) => {
                         warn!(client_id, error = %e, "read failed, disconnecting");
                         break;
                     }
                 };
 
-                match msg {
-                    ClientMessage::Connect{..} => {
-                        // no-op for now
+                let request_id = msg.meta().id;
+                if let Err(reason) = check_name_fields(&msg) {
+                    warn!(client_id, reason, "rejecting oversized field");
+                    send_error(&state, client_id, Some(request_id), ServerError::InvalidRequest(reason)).await;
+                    continue;
+                }
+                if user_authed {
+                    touch_last_seen(&state, client_id).await;
+                }
+
+                let result = match msg {
+                    ClientMessage::Connect { client_name, client_version, .. } => {
+                        info!(client_id, ?client_name, ?client_version, "client identified");
+                        let mut reg = state.registry.write().await;
+                        reg.set_client_info(client_id, client_name, client_version);
+                        Ok(())
+                    }
+                    ClientMessage::Ping { nonce, .. } => {
+                        let reg = state.registry.read().await;
+                        reg.send(client_id, ServerMessage::Pong { meta: server_meta(&state), nonce });
+                        Ok(())
+                    }
+                    ClientMessage::RequestCompression { .. } => {
+                        debug!(client_id, "frame compression enabled");
+                        compression = true;
+                        let reg = state.registry.read().await;
+                        reg.send(client_id, ServerMessage::CompressionEnabled { meta: server_meta(&state) });
+                        Ok(())
                     }
                     ClientMessage::Auth{ key, .. } => {
                         let ok = {
+                            let expected = state.special_key.read().await;
                             let auth = state.auth.read().await;
-                            auth.verify_special_key(&state.special_key, &key)
+                            auth.verify_special_key(&expected, &key)
                         };
 
                         if !ok {
-                            let failure = ServerMessage::AuthFailure { meta: server_meta(&state), reason: "invalid special key".to_string() };
-                            let reg = state.registry.read().await;
-                            reg.send(client_id, failure);
+                            send_error(&state, client_id, Some(request_id), ServerError::AuthFailed("invalid special key".to_string())).await;
                             break;
                         }
 
@@ -99,14 +183,26 @@ pub async fn handle_client(
                         let sys = ServerMessage::SystemMessage { meta: server_meta(&state), text: "special key accepted; send ECDH public key".to_string() };
                         let reg = state.registry.read().await;
                         reg.send(client_id, sys);
+                        Ok(())
                     }
 
-                    ClientMessage::EcdhPublicKey { public_key, .. } => {
-                        if !special_authed {
-                            send_protocol_error(&state, client_id, "special auth required").await;
-                            continue;
-                        }
+                    ClientMessage::EcdhPublicKey { .. }
+                    | ClientMessage::RegisterUser { .. }
+                    | ClientMessage::Login { .. }
+                    | ClientMessage::GuestLogin { .. }
+                    | ClientMessage::Resume { .. } if !special_authed => {
+                        Err(ServerError::InvalidRequest("special auth required".to_string()))
+                    }
+
+                    // One identity per connection; switching needs a new one.
+                    ClientMessage::RegisterUser { .. }
+                    | ClientMessage::Login { .. }
+                    | ClientMessage::GuestLogin { .. }
+                    | ClientMessage::Resume { .. } if user_authed => {
+                        Err(ServerError::InvalidRequest("already logged in".to_string()))
+                    }
 
+                    ClientMessage::EcdhPublicKey { public_key, .. } => {
                         let server_public_key = {
                             let mut ecdh = state.ecdh.write().await;
                             ecdh.generate_keypair(client_id, &public_key)
@@ -120,378 +216,279 @@ pub async fn handle_client(
                                 reg.send(client_id, ack);
 
                                 let sys = ServerMessage::SystemMessage { meta: server_meta(&state), text: "encryption enabled; please login or register".to_string() };
-                                let reg = state.registry.read().await;
                                 reg.send(client_id, sys);
+                                Ok(())
                             }
-                            Err(reason) => {
-                                send_protocol_error(&state, client_id, &reason).await;
-                            }
+                            Err(reason) => Err(ServerError::InvalidRequest(reason)),
                         }
                     }
 
-                    ClientMessage::RegisterUser { username, .. } => {
-                        if !special_authed {
-                            send_protocol_error(&state, client_id, "special auth required").await;
-                            continue;
-                        }
-
-                        let res = {
-                            let mut auth = state.auth.write().await;
-                            auth.register(username)
-                        };
-
-                        match res {
-                            Ok((user, pw)) => {
-                                {
-                                    let mut reg = state.registry.write().await;
-                                    reg.set_user(client_id, user.clone());
-                                }
-                                user_authed = true;
-
-                                let msg = ServerMessage::AuthSuccess { meta: server_meta(&state), user, generated_password: Some(pw) };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, msg);
-
-                                send_channel_list(&state, client_id).await;
-                            }
-                            Err(reason) => {
-                                let msg = ServerMessage::AuthFailure { meta: server_meta(&state), reason };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, msg);
-                            }
-                        }
+                    ClientMessage::RegisterUser { username, password, signing_key, public_key, .. } => {
+                        let keys = PublishedKeys { signing_key, public_key };
+                        let res = handle_register(&state, client_id, username, password, keys).await;
+                        user_authed |= res.is_ok();
+                        res
                     }
 
-                    ClientMessage::Login { username, password, .. } => {
-                        if !special_authed {
-                            send_protocol_error(&state, client_id, "special auth required").await;
-                            continue;
-                        }
-
-                        let res = {
-                            let auth = state.auth.read().await;
-                            auth.login(&username, &password)
-                        };
+                    ClientMessage::Login { username, password, signing_key, public_key, .. } => {
+                        let keys = PublishedKeys { signing_key, public_key };
+                        let res = handle_login(&state, client_id, &username, &password, keys).await;
+                        user_authed |= res.is_ok();
+                        res
+                    }
 
-                        match res {
-                            Ok(user) => {
-                                {
-                                    let mut reg = state.registry.write().await;
-                                    reg.set_user(client_id, user.clone());
-                                }
-                                user_authed = true;
+                    ClientMessage::GuestLogin { .. } => {
+                        handle_guest_login(&state, client_id).await;
+                        user_authed = true;
+                        Ok(())
+                    }
 
-                                let msg = ServerMessage::AuthSuccess { meta: server_meta(&state), user, generated_password: None };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, msg);
+                    ClientMessage::Resume { token, .. } => {
+                        let res = handle_resume(&state, client_id, &token).await;
+                        user_authed |= res.is_ok();
+                        res
+                    }
 
-                                send_channel_list(&state, client_id).await;
-                            }
-                            Err(reason) => {
-                                let msg = ServerMessage::AuthFailure { meta: server_meta(&state), reason };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, msg);
-                            }
-                        }
+                    ClientMessage::Rename { new_username, .. } => {
+                        handle_rename(&state, client_id, user_authed, &new_username).await
                     }
 
                     ClientMessage::ListChannels{..} => {
-                        if !user_authed {
-                            send_protocol_error(&state, client_id, "login/register required").await;
-                            continue;
+                        if user_authed {
+                            send_channel_list(&state, client_id).await;
+                            Ok(())
+                        } else {
+                            Err(ServerError::NotAuthenticated)
                         }
-
-                        send_channel_list(&state, client_id).await;
                     }
 
-                    ClientMessage::JoinChannel { name, password, .. } => {
-                        if !user_authed {
-                            send_protocol_error(&state, client_id, "login/register required").await;
-                            continue;
-                        }
-
-                        let prev_channel = {
-                            let reg = state.registry.read().await;
-                            reg.channel(client_id)
-                        };
-
-                        if let Some(prev) = prev_channel {
-                            {
-                                let mut channels = state.channels.write().await;
-                                channels.leave(client_id, &prev);
-                            }
-
-                            if let Some(user) = {
-                                let reg = state.registry.read().await;
-                                reg.user(client_id)
-                            } {
-                                broadcast_user_left(&state, client_id, &prev, user).await;
-                            }
-                        }
+                    ClientMessage::ListAllChannels{..} => {
+                        handle_list_all_channels(&state, client_id, user_authed).await
+                    }
 
-                        let channel_exists = {
-                            let channels = state.channels.read().await;
-                            channels.get_channel_id(&name).is_some()
-                        };
+                    ClientMessage::ListMyChannels { .. } => {
+                        handle_list_my_channels(&state, client_id, user_authed).await
+                    }
 
-                        let channel_id = if !channel_exists {
-                            let channel_id = {
-                                let mut channels = state.channels.write().await;
-                                channels.ensure_channel(&name, password.is_none(), password.clone(), Some(client_id))
-                            };
+                    ClientMessage::GetUserInfo { username, .. } => {
+                        handle_get_user_info(&state, client_id, user_authed, &username).await
+                    }
 
-                            {
-                                let mut admin = state.admin.write().await;
-                                admin.set_channel_creator(channel_id, client_id);
-                            }
-                            channel_id
-                        } else {
-                            let channels = state.channels.read().await;
-                            channels.get_channel_id(&name).unwrap()
-                        };
+                    ClientMessage::ListConnections{..} => {
+                        handle_list_connections(&state, client_id, user_authed).await
+                    }
 
-                        let is_banned = {
-                            let bans = state.bans.read().await;
-                            bans.is_banned(channel_id, client_id)
-                        };
+                    ClientMessage::RotateSpecialKey { new_key, .. } => {
+                        handle_rotate_special_key(&state, client_id, user_authed, new_key).await
+                    }
 
-                        if is_banned {
-                            let reason = {
-                                let bans = state.bans.read().await;
-                                let ban_info = bans.get_ban_info(channel_id, client_id);
-                                match ban_info.and_then(|b| b.banned_until) {
-                                    Some(until) => format!("Banned until {}", until.format("%Y-%m-%d %H:%M:%S UTC")),
-                                    None => "Permanently banned from channel".to_string(),
-                                }
-                            };
-
-                            let msg = ServerMessage::JoinFailure { meta: server_meta(&state), channel: name, reason };
-                            let reg = state.registry.read().await;
-                            reg.send(client_id, msg);
-                            continue;
-                        }
+                    ClientMessage::SetMotd { motd, .. } => {
+                        handle_set_motd(&state, client_id, user_authed, motd).await
+                    }
 
-                        let join_res = {
-                            let mut channels = state.channels.write().await;
-                            channels.join(client_id, &name, password)
-                        };
+                    ClientMessage::CreateUser { username, password, .. } => {
+                        handle_create_user(&state, client_id, user_authed, username, password).await
+                    }
 
-                        match join_res {
-                            Ok(channel_info_base) => {
-                                let (role, channel_type) = {
-                                    let admin = state.admin.read().await;
-                                    (admin.get_role(channel_id, client_id), admin.get_channel_type(channel_id))
-                                };
-
-                                let channel_info = {
-                                    let channels = state.channels.read().await;
-                                    let ch = channels.get_channel_id(&name);
-                                    if let Some(ch_id) = ch {
-                                        darkrelayprotocol::protocol::ChannelInfo {
-                                            id: ch_id,
-                                            name: name.clone(),
-                                            is_public: channel_info_base.is_public,
-                                            channel_type,
-                                            user_role: Some(role),
-                                        }
-                                    } else {
-                                        channel_info_base
-                                    }
-                                };
-
-                                {
-                                    let mut reg = state.registry.write().await;
-                                    reg.set_channel(client_id, Some(channel_info.name.clone()));
-                                }
-
-                                let msg = ServerMessage::JoinSuccess { meta: server_meta(&state), channel: channel_info.clone() };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, msg);
+                    ClientMessage::ForceDisconnect { username, reason, .. } => {
+                        handle_force_disconnect(&state, client_id, user_authed, &username, reason).await
+                    }
 
-                                let history = {
-                                    let channels = state.channels.read().await;
-                                    channels.history(&channel_info.name, 50)
-                                };
+                    ClientMessage::JoinChannel { name, password, .. } => {
+                        handle_join_channel(&state, client_id, user_authed, name, password, None).await
+                    }
 
-                                let hist_msg = ServerMessage::HistoryChunk { meta: server_meta(&state), channel: channel_info.name.clone(), messages: history };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, hist_msg);
+                    ClientMessage::CreateChannel { name, password, channel_type, topic, .. } => {
+                        let options = ChannelOptions { channel_type, topic };
+                        handle_join_channel(&state, client_id, user_authed, name, password, Some(options)).await
+                    }
 
-                                broadcast_user_joined(&state, client_id, &channel_info.name).await;
-                            }
-                            Err(reason) => {
-                                let msg = ServerMessage::JoinFailure { meta: server_meta(&state), channel: name, reason };
-                                let reg = state.registry.read().await;
-                                reg.send(client_id, msg);
-                            }
-                        }
+                    ClientMessage::LeaveChannel { channel, .. } => {
+                        handle_leave_channel(&state, client_id, user_authed, &channel).await
                     }
 
                     ClientMessage::SendMessage { channel, content, metadata, .. } => {
-                        if !user_authed {
-                            send_protocol_error(&state, client_id, "login/register required").await;
-                            continue;
-                        }
-
-                        let (user, current_channel) = {
-                            let reg = state.registry.read().await;
-                            (reg.user(client_id), reg.channel(client_id))
-                        };
-
-                        let Some(user) = user else {
-                            send_protocol_error(&state, client_id, "user missing").await;
-                            continue;
-                        };
-
-                        if current_channel.as_deref() != Some(channel.as_str()) {
-                            send_protocol_error(&state, client_id, "not joined to channel").await;
-                            continue;
-                        }
-
-                        let channel_id = {
-                            let channels = state.channels.read().await;
-                            channels.get_channel_id(&channel)
-                        };
-
-                        if let Some(ch_id) = channel_id {
-                            let can_send = {
-                                let admin = state.admin.read().await;
-                                admin.can_send_message(ch_id, client_id)
-                            };
-
-                            if !can_send {
-                                send_admin_error(&state, client_id, "You lack permission to send messages in this channel").await;
-                                continue;
-                            }
-                        }
-
-                        // Extract nonce from metadata if present
-                        let nonce = metadata.iter()
-                            .find(|(k, _)| k == "nonce")
-                            .and_then(|(_, v)| hex::decode(v).ok());
-
-                        // Server stores encrypted content as-is, never attempts to decrypt
-                        info!(
-                            client_id,
-                            user = user.username,
-                            channel = &channel,
-                            size = content.len(),
-                            encrypted = ecdh_complete,
-                            "message received (content encrypted, not logged)"
-                        );
-
-                        let msg = ChatMessage {
-                            id: 0,
-                            user_id: user.id,
-                            username: user.username.clone(),
-                            content,
-                            timestamp: Utc::now(),
-                            nonce,
-                            metadata,
-                        };
+                        handle_send_message(&state, client_id, user_authed, ecdh_complete, &channel, content, metadata).await
+                    }
 
-                        let stored = {
-                            let mut channels = state.channels.write().await;
-                            channels.add_message(&channel, msg)
-                        };
+                    ClientMessage::SendDM { recipient, content, metadata, .. } => {
+                        handle_send_dm(&state, client_id, user_authed, &recipient, content, metadata).await
+                    }
 
-                        match stored {
-                            Ok(stored) => {
-                                broadcast_message(&state, &channel, stored).await;
-                            }
-                            Err(reason) => {
-                                send_protocol_error(&state, client_id, &reason).await;
-                            }
-                        }
+                    ClientMessage::DeleteDM { dm_id, .. } => {
+                        handle_delete_dm(&state, client_id, user_authed, dm_id).await
                     }
 
                     ClientMessage::GetHistory { channel, limit, .. } => {
-                        if !user_authed {
-                            send_protocol_error(&state, client_id, "login/register required").await;
-                            continue;
-                        }
+                        handle_get_history(&state, client_id, user_authed, channel, limit).await
+                    }
 
-                        let messages = {
-                            let channels = state.channels.read().await;
-                            channels.history(&channel, limit as usize)
-                        };
+                    ClientMessage::Resync { channel, since_sequence, .. } => {
+                        handle_resync(&state, client_id, user_authed, channel, since_sequence).await
+                    }
 
-                        let msg = ServerMessage::HistoryChunk { meta: server_meta(&state), channel, messages };
-                        let reg = state.registry.read().await;
-                        reg.send(client_id, msg);
+                    ClientMessage::CreatePoll { channel, question, options, .. } => {
+                        handle_create_poll(&state, client_id, user_authed, &channel, question, options).await
+                    }
+
+                    ClientMessage::Vote { channel, poll_id, option_index, .. } => {
+                        handle_vote(&state, client_id, user_authed, &channel, poll_id, option_index).await
                     }
 
                     ClientMessage::DeleteMessage { channel, message_id, .. } => {
-                        handle_delete_message(&state, client_id, user_authed, &channel, message_id).await;
+                        handle_delete_message(&state, client_id, user_authed, &channel, message_id).await
                     }
 
                     ClientMessage::PromoteUser { channel, username, role, .. } => {
-                        handle_promote_user(&state, client_id, user_authed, &channel, &username, role).await;
+                        handle_promote_user(&state, client_id, user_authed, &channel, &username, role).await
                     }
 
                     ClientMessage::DemoteUser { channel, username, .. } => {
-                        handle_demote_user(&state, client_id, user_authed, &channel, &username).await;
+                        handle_demote_user(&state, client_id, user_authed, &channel, &username).await
                     }
 
                     ClientMessage::BanUser { channel, username, duration_seconds, reason, .. } => {
-                        handle_ban_user(&state, client_id, user_authed, &channel, &username, duration_seconds, reason).await;
+                        handle_ban_user(&state, client_id, user_authed, &channel, &username, duration_seconds, reason).await
                     }
 
                     ClientMessage::UnbanUser { channel, username, .. } => {
-                        handle_unban_user(&state, client_id, user_authed, &channel, &username).await;
+                        handle_unban_user(&state, client_id, user_authed, &channel, &username).await
                     }
 
                     ClientMessage::KickUser { channel, username, reason, .. } => {
-                        handle_kick_user(&state, client_id, user_authed, &channel, &username, reason).await;
+                        handle_kick_user(&state, client_id, user_authed, &channel, &username, reason).await
                     }
 
                     ClientMessage::ListAdmins { channel, .. } => {
-                        handle_list_admins(&state, client_id, user_authed, &channel).await;
+                        handle_list_admins(&state, client_id, user_authed, &channel).await
                     }
 
                     ClientMessage::ListBans { channel, .. } => {
-                        handle_list_bans(&state, client_id, user_authed, &channel).await;
+                        handle_list_bans(&state, client_id, user_authed, &channel).await
                     }
 
                     ClientMessage::ViewLogs { channel, limit, .. } => {
-                        handle_view_logs(&state, client_id, user_authed, &channel, limit).await;
+                        handle_view_logs(&state, client_id, user_authed, &channel, limit).await
                     }
 
                     ClientMessage::ChangeChannelType { channel, channel_type, .. } => {
-                        handle_change_channel_type(&state, client_id, user_authed, &channel, channel_type).await;
+                        handle_change_channel_type(&state, client_id, user_authed, &channel, channel_type).await
                     }
 
                     ClientMessage::DeleteChannel { channel, .. } => {
-                        handle_delete_channel(&state, client_id, user_authed, &channel).await;
+                        handle_delete_channel(&state, client_id, user_authed, &channel).await
+                    }
+
+                    ClientMessage::TransferOwnership { channel, username, .. } => {
+                        handle_transfer_ownership(&state, client_id, user_authed, &channel, &username).await
+                    }
+
+                    ClientMessage::SetRetention { channel, max_age_seconds, .. } => {
+                        handle_set_retention(&state, client_id, user_authed, &channel, max_age_seconds).await
+                    }
+
+                    ClientMessage::SetSlowMode { channel, interval_seconds, .. } => {
+                        handle_set_slow_mode(&state, client_id, user_authed, &channel, interval_seconds).await
+                    }
+
+                    ClientMessage::SetMaxMembers { channel, max_members, .. } => {
+                        handle_set_max_members(&state, client_id, user_authed, &channel, max_members).await
+                    }
+
+                    ClientMessage::RenameChannel { channel, new_name, .. } => {
+                        handle_rename_channel(&state, client_id, user_authed, &channel, &new_name).await
+                    }
+
+                    ClientMessage::SetWelcome { channel, welcome, .. } => {
+                        handle_set_welcome(&state, client_id, user_authed, &channel, welcome).await
+                    }
+
+                    ClientMessage::SetPresence { away, .. } => {
+                        handle_set_presence(&state, client_id, user_authed, away).await
                     }
 
                     ClientMessage::Disconnect{..} => {
                         info!(client_id, "client disconnect requested");
+                        // Leaving on purpose ends the session; only a lost
+                        // connection is parked for resume.
+                        state.resume.write().await.revoke(client_id);
+                        acknowledge_disconnect(&state, client_id).await;
                         break;
                     }
+                };
+
+                if let Err(err) = result {
+                    send_error(&state, client_id, Some(request_id), err).await;
                 }
             }
         }
     }
 
-    cleanup_disconnect(&state, client_id).await;
+    let user = cleanup_disconnect(&state, client_id).await;
 
     let _ = time::timeout(Duration::from_secs(2), writer_task).await;
+    log_disconnect(client_id, peer_addr, connected_at.elapsed(), &stats, user.as_ref());
     Ok(())
 }
 
-async fn cleanup_disconnect(state: &Arc<AppState>, client_id: ClientId) {
-    let (user, channel) = {
+/// Frames moved over one connection, for the disconnect log.
+#[derive(Debug, Default)]
+struct ConnectionStats {
+    frames_read: AtomicU64,
+    frames_written: AtomicU64,
+}
+
+/// One structured record per connection, for tracing a session back to its
+/// address after the fact.
+fn log_disconnect(
+    client_id: ClientId,
+    peer_addr: SocketAddr,
+    duration: Duration,
+    stats: &ConnectionStats,
+    user: Option<&UserInfo>,
+) {
+    info!(
+        client_id,
+        %peer_addr,
+        duration_ms = duration.as_millis() as u64,
+        frames_read = stats.frames_read.load(Ordering::Relaxed),
+        frames_written = stats.frames_written.load(Ordering::Relaxed),
+        user_id = user.map(|u| u.id),
+        username = user.map(|u| u.username.as_str()),
+        "client disconnected"
+    );
+}
+
+/// Queue the ack behind everything already sent; the writer task flushes the
+/// queue before the connection closes.
+async fn acknowledge_disconnect(state: &Arc<AppState>, client_id: ClientId) {
+    let reg = state.registry.read().await;
+    reg.send(client_id, ServerMessage::DisconnectAck { meta: server_meta(state) });
+}
+
+/// Returns the user the connection was logged in as, if any.
+async fn cleanup_disconnect(state: &Arc<AppState>, client_id: ClientId) -> Option<UserInfo> {
+    let (user, joined) = {
         let reg = state.registry.read().await;
-        (reg.user(client_id), reg.channel(client_id))
+        (reg.user(client_id), reg.channels(client_id))
     };
 
-    if let Some(ch) = &channel {
+    // A session holding a resume token is parked instead: its departure is
+    // announced only if it isn't resumed within the grace period.
+    let parked = {
+        let mut resume = state.resume.write().await;
+        resume.detach(client_id, joined.clone(), Utc::now())
+    };
+
+    for ch in &joined {
         {
             let mut channels = state.channels.write().await;
             channels.leave(client_id, ch);
         }
-        if let Some(user) = user {
+        if parked {
+            continue;
+        }
+        if let Some(user) = user.clone() {
             broadcast_user_left(state, client_id, ch, user).await;
         }
     }
@@ -503,963 +500,5218 @@ async fn cleanup_disconnect(state: &Arc<AppState>, client_id: ClientId) {
 
     let mut reg = state.registry.write().await;
     reg.remove(client_id);
-
-    info!(client_id, "client disconnected");
+    user
 }
 
-async fn send_channel_list(state: &Arc<AppState>, client_id: ClientId) {
-    let channels = {
-        let channels = state.channels.read().await;
-        channels.list_public()
-    };
-
-    let msg = ServerMessage::ChannelList {
-        meta: server_meta(state),
-        channels,
+/// Create an account and log the client into it. `Ok` means the client is
+/// now authenticated.
+async fn handle_register(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    username: String,
+    password: Option<String>,
+    keys: PublishedKeys,
+) -> Result<(), ServerError> {
+    cert_matches_user(state, client_id, &username).await?;
+    keys.check().map_err(ServerError::AuthFailed)?;
+
+    let (user, generated_password) = {
+        let mut auth = state.auth.write().await;
+        let (user, generated_password) = auth.register(username, password).map_err(ServerError::AuthFailed)?;
+        let user = auth.publish_keys(user.id, keys).unwrap_or(user);
+        (user, generated_password)
     };
 
-    let reg = state.registry.read().await;
-    reg.send(client_id, msg);
-}
+    {
+        let mut reg = state.registry.write().await;
+        reg.set_user(client_id, user.clone());
+    }
 
-async fn broadcast_message(state: &Arc<AppState>, channel: &str, message: ChatMessage) {
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
+    let resume_token = {
+        let mut resume = state.resume.write().await;
+        resume.issue(client_id, user.clone())
     };
 
-    let msg = ServerMessage::MessageReceived {
-        meta: server_meta(state),
-        channel: channel.to_string(),
-        message,
-    };
+    let capabilities = capabilities(state, &user);
+    let msg = ServerMessage::AuthSuccess { meta: server_meta(state), user, generated_password, resume_token: Some(resume_token) };
+    {
+        let reg = state.registry.read().await;
+        reg.send(client_id, msg);
+        reg.send(client_id, capabilities);
+    }
 
-    let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    send_motd(state, client_id).await;
+    send_channel_list(state, client_id).await;
+    Ok(())
 }
 
-async fn broadcast_user_joined(state: &Arc<AppState>, client_id: ClientId, channel: &str) {
+/// `Ok` means the client is now authenticated.
+async fn handle_login(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    username: &str,
+    password: &str,
+    keys: PublishedKeys,
+) -> Result<(), ServerError> {
+    keys.check().map_err(ServerError::AuthFailed)?;
     let user = {
-        let reg = state.registry.read().await;
-        reg.user(client_id)
+        let auth = state.auth.read().await;
+        auth.login(username, password).map_err(ServerError::AuthFailed)?
     };
 
-    let Some(user) = user else {
-        return;
-    };
+    admit_session(state, client_id, &user).await?;
 
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
-    };
+    // Published only once the session is admitted, so a refused duplicate
+    // login can't swap the keys under the session that stays.
+    let user = state.auth.write().await.publish_keys(user.id, keys).unwrap_or(user);
 
-    let msg = ServerMessage::UserJoined {
-        meta: server_meta(state),
-        channel: channel.to_string(),
-        user,
+    {
+        let mut reg = state.registry.write().await;
+        reg.set_user(client_id, user.clone());
+    }
+
+    let resume_token = {
+        let mut resume = state.resume.write().await;
+        resume.issue(client_id, user.clone())
     };
 
-    let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    let msg = ServerMessage::AuthSuccess { meta: server_meta(state), user: user.clone(), generated_password: None, resume_token: Some(resume_token) };
+    {
+        let reg = state.registry.read().await;
+        reg.send(client_id, msg);
+        reg.send(client_id, capabilities(state, &user));
+    }
+
+    send_motd(state, client_id).await;
+    send_channel_list(state, client_id).await;
+    flush_undelivered_dms(state, client_id, &user).await;
+    Ok(())
 }
 
-async fn broadcast_user_left(state: &Arc<AppState>, client_id: ClientId, channel: &str, user: darkrelayprotocol::protocol::UserInfo) {
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
+/// With mutual TLS the certificate's common name must name the account being
+/// used. When certificates are mandatory one without a readable common name
+/// matches no account.
+async fn cert_matches_user(state: &Arc<AppState>, client_id: ClientId, username: &str) -> Result<(), ServerError> {
+    let reg = state.registry.read().await;
+    let Some(subject) = reg.cert_subject(client_id) else {
+        if !state.require_client_cert {
+            return Ok(());
+        }
+        warn!(client_id, username, "client certificate has no common name");
+        return Err(ServerError::AuthFailed("client certificate does not name a user".to_string()));
     };
 
-    let msg = ServerMessage::UserLeft {
-        meta: server_meta(state),
-        channel: channel.to_string(),
-        user,
+    if auth::normalize_username(&subject) == auth::normalize_username(username) {
+        return Ok(());
+    }
+
+    warn!(client_id, subject, username, "client certificate does not match user");
+    Err(ServerError::AuthFailed("client certificate does not match this user".to_string()))
+}
+
+/// Apply the duplicate-login policy before `user` is bound to `client_id`.
+async fn admit_session(state: &Arc<AppState>, client_id: ClientId, user: &UserInfo) -> Result<(), ServerError> {
+    cert_matches_user(state, client_id, &user.username).await?;
+
+    let (policy, existing) = {
+        let reg = state.registry.read().await;
+        let existing: Vec<ClientId> = reg
+            .find_clients_by_user_id(user.id)
+            .into_iter()
+            .filter(|id| *id != client_id)
+            .collect();
+        (reg.duplicate_login(), existing)
     };
 
-    let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    if existing.is_empty() {
+        return Ok(());
+    }
 
-    debug!(client_id, channel, "broadcast user left");
+    match policy {
+        DuplicateLogin::Reject => {
+            info!(client_id, user = user.username, "duplicate login rejected");
+            Err(ServerError::AuthFailed("already logged in".to_string()))
+        }
+        DuplicateLogin::KickOld => {
+            // The replaced session must not be resumable, or it could kick back.
+            {
+                let mut resume = state.resume.write().await;
+                for id in &existing {
+                    resume.revoke(*id);
+                }
+            }
+
+            info!(client_id, user = user.username, replaced = ?existing, "duplicate login, closing older session");
+            let msg = ServerMessage::SystemMessage {
+                meta: server_meta(state),
+                text: "logged in from another location; disconnecting".to_string(),
+            };
+            let mut reg = state.registry.write().await;
+            reg.hand_over_channels(client_id, &existing);
+            for id in existing {
+                reg.send(id, msg.clone());
+                reg.terminate(id);
+            }
+            Ok(())
+        }
+    }
 }
 
-async fn send_protocol_error(state: &Arc<AppState>, client_id: ClientId, text: &str) {
-    let msg = ServerMessage::ProtocolError {
-        meta: server_meta(state),
-        text: text.to_string(),
+/// Restore a parked session onto `client_id` without announcing a fresh join.
+/// `Ok` means the client is now authenticated.
+async fn handle_resume(state: &Arc<AppState>, client_id: ClientId, token: &str) -> Result<(), ServerError> {
+    let (user, joined) = {
+        let mut resume = state.resume.write().await;
+        resume.redeem(token, Utc::now()).map_err(ServerError::AuthFailed)?
     };
 
-    let reg = state.registry.read().await;
-    reg.send(client_id, msg);
-}
+    admit_session(state, client_id, &user).await?;
 
-async fn send_admin_error(state: &Arc<AppState>, client_id: ClientId, reason: &str) {
-    let msg = ServerMessage::AdminError {
-        meta: server_meta(state),
-        reason: reason.to_string(),
+    {
+        let mut reg = state.registry.write().await;
+        reg.set_user(client_id, user.clone());
+    }
+
+    let resume_token = {
+        let mut resume = state.resume.write().await;
+        resume.issue(client_id, user.clone())
     };
 
-    let reg = state.registry.read().await;
-    reg.send(client_id, msg);
-}
+    info!(client_id, user = user.username, channels = joined.len(), "session resumed");
 
-fn server_meta(state: &Arc<AppState>) -> MessageMeta {
-    MessageMeta::new(state.next_server_msg_id(), Utc::now())
-}
+    let msg = ServerMessage::AuthSuccess {
+        meta: server_meta(state),
+        user: user.clone(),
+        generated_password: None,
+        resume_token: Some(resume_token),
+    };
+    {
+        let reg = state.registry.read().await;
+        reg.send(client_id, msg);
+        reg.send(client_id, capabilities(state, &user));
+    }
 
-async fn handle_delete_message(
-    state: &Arc<AppState>,
-    client_id: ClientId,
-    user_authed: bool,
-    channel: &str,
-    message_id: u64,
-) {
-    if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
-    }
+    send_channel_list(state, client_id).await;
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
-    };
+    for name in joined {
+        // As in `handle_join_channel`, the ban table stays locked until the
+        // client is a member again, so a concurrent ban either keeps it out
+        // or finds the member to remove.
+        let (info, history) = {
+            let bans = state.bans.read().await;
+            let mut channels = state.channels.write().await;
+            // The channel may have been deleted while the session was parked.
+            let Some(channel_id) = channels.get_channel_id(&name) else {
+                continue;
+            };
+            // A ban issued while the session was parked keeps it out.
+            if bans.is_banned(channel_id, user.id) {
+                continue;
+            }
+            let Some(info) = channels.rejoin(client_id, user.id, &name) else {
+                continue;
+            };
+            let history = channels.history(&name, 50);
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
-    };
+            let mut reg = state.registry.write().await;
+            reg.join_channel(client_id, &name);
+            (info, history)
+        };
 
-    let has_permission = {
-        let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::DeleteMessage)
-    };
+        let role = channel_role(state, info.id, client_id).await;
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: DeleteMessage").await;
-        return;
+        let reg = state.registry.read().await;
+        reg.send(client_id, ServerMessage::JoinSuccess {
+            meta: server_meta(state),
+            rules: info.channel_type.description().to_string(),
+            channel: ChannelInfo { user_role: Some(role), ..info },
+        });
+        reg.send(client_id, ServerMessage::HistoryChunk {
+            meta: server_meta(state),
+            channel: name,
+            messages: history,
+        });
     }
 
-    let deleted = {
-        let mut channels = state.channels.write().await;
-        channels.delete_message(channel, message_id)
-    };
-
-    if !deleted {
-        send_admin_error(state, client_id, "Message not found").await;
-        return;
-    }
+    flush_undelivered_dms(state, client_id, &user).await;
+    Ok(())
+}
 
-    let admin_username = {
-        let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+/// Announce departures for parked sessions that were never resumed.
+pub async fn expire_resume_tokens(state: &Arc<AppState>) {
+    let expired = {
+        let mut resume = state.resume.write().await;
+        resume.expire(Utc::now())
     };
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "delete_message".to_string(),
-            format!("message_{}", message_id),
-            "Message deleted".to_string(),
-        );
+    for (user, joined) in expired {
+        for ch in joined {
+            broadcast_user_left(state, 0, &ch, user.clone()).await;
+        }
     }
+}
 
-    let members = {
+async fn send_channel_list(state: &Arc<AppState>, client_id: ClientId) {
+    let channels = {
         let channels = state.channels.read().await;
-        channels.members(channel)
+        channels.list_public()
     };
 
-    let msg = ServerMessage::MessageDeleted {
+    let msg = ServerMessage::ChannelList {
         meta: server_meta(state),
-        channel: channel.to_string(),
-        message_id,
-        deleted_by: admin_username,
+        channels,
     };
 
     let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    reg.send(client_id, msg);
 }
 
-async fn handle_promote_user(
-    state: &Arc<AppState>,
-    client_id: ClientId,
-    user_authed: bool,
-    channel: &str,
-    username: &str,
-    role: darkrelayprotocol::permissions::Role,
-) {
+/// Membership is per connection; this gathers it per user, so a new session
+/// can pick up where the user's other sessions are, or were until this login
+/// replaced them.
+async fn handle_list_my_channels(state: &Arc<AppState>, client_id: ClientId, user_authed: bool) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
+    let (names, username) = {
+        let mut reg = state.registry.write().await;
+        let Some(user) = reg.user(client_id) else {
+            return Err(ServerError::Internal("user missing".to_string()));
+        };
+        (reg.take_user_channels(client_id, user.id), user.username)
     };
-
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let infos: Vec<ChannelInfo> = {
+        let channels = state.channels.read().await;
+        names.iter().filter_map(|name| channels.channel_info(name)).collect()
     };
-
-    let has_permission = {
+    let channels = {
         let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::PromoteUser)
+        infos
+            .into_iter()
+            .map(|info| {
+                let role = admin.get_role(info.id, &username);
+                ChannelInfo { user_role: Some(role), ..info }
+            })
+            .collect()
     };
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: PromoteUser").await;
-        return;
+    let reg = state.registry.read().await;
+    reg.send(client_id, ServerMessage::MyChannels { meta: server_meta(state), channels });
+    Ok(())
+}
+
+/// Stamp the sender's `last_seen`; the frame loop calls this once the session is logged in.
+async fn touch_last_seen(state: &Arc<AppState>, client_id: ClientId) {
+    let user = state.registry.read().await.user(client_id);
+    if let Some(user) = user {
+        state.auth.write().await.touch(user.id, Utc::now());
     }
+}
 
-    let target_id = {
+async fn handle_get_user_info(state: &Arc<AppState>, client_id: ClientId, user_authed: bool, username: &str) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let (user, last_seen) = {
         let auth = state.auth.read().await;
-        auth.find_user_by_username(username).map(|u| u.id)
+        let user = auth
+            .find_user_by_username(username)
+            .ok_or(ServerError::NotFound("User"))?;
+        let last_seen = auth.last_seen(user.id);
+        (user, last_seen)
     };
 
-    let Some(target_user_id) = target_id else {
-        send_admin_error(state, client_id, "User not found").await;
-        return;
-    };
+    let reg = state.registry.read().await;
+    let online = !reg.find_clients_by_user_id(user.id).is_empty();
+    reg.send(client_id, ServerMessage::UserDetails { meta: server_meta(state), user, last_seen, online });
+    Ok(())
+}
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.set_role(ch_id, target_user_id, role);
+async fn handle_rename(state: &Arc<AppState>, client_id: ClientId, user_authed: bool, new_username: &str) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let admin_username = {
+    let user = {
         let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+        reg.user(client_id)
+    };
+
+    let Some(user) = user else {
+        return Err(ServerError::Internal("user missing".to_string()));
     };
 
+    if auth::is_guest(user.id) {
+        return Err(ServerError::PermissionDenied("guests cannot rename".to_string()));
+    }
+
+    let renamed = {
+        let mut auth = state.auth.write().await;
+        auth.rename(user.id, new_username)
+    };
+
+    let renamed = renamed.map_err(ServerError::InvalidRequest)?;
+
+    {
+        let mut resume = state.resume.write().await;
+        resume.rename(&renamed);
+    }
     {
         let mut admin = state.admin.write().await;
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "promote_user".to_string(),
-            username.to_string(),
-            format!("Promoted to {:?}", role),
-        );
+        admin.rename_user(&user.username, &renamed.username);
     }
 
-    let members = {
+    // Every session of this user, plus everyone sharing a channel with one.
+    let recipients = {
+        let mut reg = state.registry.write().await;
+        let sessions = reg.find_clients_by_user_id(user.id);
         let channels = state.channels.read().await;
-        channels.members(channel)
+        let mut recipients = sessions.clone();
+        for session in &sessions {
+            reg.set_user(*session, renamed.clone());
+            for ch in reg.channels(*session) {
+                recipients.extend(channels.members(&ch));
+            }
+        }
+        recipients.sort_unstable();
+        recipients.dedup();
+        recipients
     };
 
-    let msg = ServerMessage::UserPromoted {
+    info!(client_id, old = user.username, new = renamed.username, "user renamed");
+
+    let msg = ServerMessage::UserRenamed {
         meta: server_meta(state),
-        channel: channel.to_string(),
-        user_id: target_user_id,
-        username: username.to_string(),
-        new_role: role,
-        promoted_by: admin_username,
+        user_id: user.id,
+        old_username: user.username,
+        new_username: renamed.username,
     };
 
     let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    reg.send_many(&recipients, &msg);
+    Ok(())
 }
 
-async fn handle_demote_user(
-    state: &Arc<AppState>,
-    client_id: ClientId,
-    user_authed: bool,
-    channel: &str,
-    username: &str,
-) {
+async fn handle_list_all_channels(state: &Arc<AppState>, client_id: ClientId, user_authed: bool) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
+    let username = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| u.username).unwrap_or_default()
     };
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let allowed = {
+        let admin = state.admin.read().await;
+        admin.is_server_super_admin(&username)
     };
 
-    let has_permission = {
-        let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::PromoteUser)
+    if !allowed {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can list all channels".to_string()));
+    }
+
+    let channels = {
+        let channels = state.channels.read().await;
+        channels.list_all()
     };
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: PromoteUser").await;
-        return;
+    let msg = ServerMessage::AllChannelList {
+        meta: server_meta(state),
+        channels,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send(client_id, msg);
+    Ok(())
+}
+
+async fn handle_list_connections(state: &Arc<AppState>, client_id: ClientId, user_authed: bool) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let target_id = {
-        let auth = state.auth.read().await;
-        auth.find_user_by_username(username).map(|u| u.id)
+    let username = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| u.username).unwrap_or_default()
     };
 
-    let Some(target_user_id) = target_id else {
-        send_admin_error(state, client_id, "User not found").await;
-        return;
+    let allowed = {
+        let admin = state.admin.read().await;
+        admin.is_server_super_admin(&username)
     };
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.set_role(ch_id, target_user_id, darkrelayprotocol::permissions::Role::User);
+    if !allowed {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can list connections".to_string()));
     }
 
-    let admin_username = {
+    let reg = state.registry.read().await;
+    let msg = ServerMessage::ConnectionList {
+        meta: server_meta(state),
+        connections: reg.connections(),
+    };
+    reg.send(client_id, msg);
+    Ok(())
+}
+
+/// Provision an account on someone's behalf. Unlike registration this may
+/// use a reserved name, and the client stays logged in as the admin.
+async fn handle_create_user(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    username: String,
+    password: Option<String>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let admin_name = {
         let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+        reg.user(client_id).map(|u| u.username).unwrap_or_default()
     };
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "demote_user".to_string(),
-            username.to_string(),
-            "Demoted to User".to_string(),
-        );
+    let allowed = {
+        let admin = state.admin.read().await;
+        admin.is_server_super_admin(&admin_name)
+    };
+
+    if !allowed {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can create users".to_string()));
     }
 
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
+    let (user, generated_password) = {
+        let mut auth = state.auth.write().await;
+        auth.provision(username, password).map_err(ServerError::Rejected)?
     };
 
-    let msg = ServerMessage::UserDemoted {
+    info!(client_id, admin = admin_name, user = user.username, user_id = user.id, "user created by admin");
+
+    let msg = ServerMessage::UserCreated {
         meta: server_meta(state),
-        channel: channel.to_string(),
-        user_id: target_user_id,
-        username: username.to_string(),
-        demoted_by: admin_username,
+        user,
+        generated_password,
     };
-
     let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    reg.send(client_id, msg);
+    Ok(())
 }
 
-async fn handle_ban_user(
+/// Drop all of a user's connections; each handler loop exits on its
+/// disconnect signal and cleans up as if the client had gone away.
+async fn handle_force_disconnect(
     state: &Arc<AppState>,
     client_id: ClientId,
     user_authed: bool,
-    channel: &str,
     username: &str,
-    duration_seconds: Option<u64>,
     reason: Option<String>,
-) {
+) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
+    let admin_user = {
+        let reg = state.registry.read().await;
+        reg.user(client_id)
     };
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let Some(admin_user) = admin_user else {
+        return Err(ServerError::Internal("user missing".to_string()));
     };
 
-    let has_permission = {
+    let allowed = {
         let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::BanUser)
+        admin.is_server_super_admin(&admin_user.username)
     };
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: BanUser").await;
-        return;
+    if !allowed {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can force a disconnect".to_string()));
     }
 
-    let target_user = {
+    let target = {
         let auth = state.auth.read().await;
         auth.find_user_by_username(username)
     };
 
-    let Some(target) = target_user else {
-        send_admin_error(state, client_id, "User not found").await;
-        return;
+    let Some(target) = target else {
+        return Err(ServerError::NotFound("User"));
     };
 
-    let admin_username = {
-        let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
-    };
+    if target.id == admin_user.id {
+        return Err(ServerError::Rejected("Use /quit to disconnect yourself".to_string()));
+    }
 
-    let banned_until = {
-        let mut bans = state.bans.write().await;
-        bans.ban_user(
-            ch_id,
-            target.id,
-            target.username.clone(),
-            admin_username.clone(),
-            duration_seconds,
-            reason.clone(),
-        )
-    };
+    let reg = state.registry.read().await;
+    let sessions = reg.find_clients_by_user_id(target.id);
+    if sessions.is_empty() {
+        return Err(ServerError::Rejected(format!("{} is not connected", target.username)));
+    }
 
-    {
-        let mut admin = state.admin.write().await;
-        let details = match duration_seconds {
-            Some(secs) => format!("Banned for {} seconds", secs),
-            None => "Permanently banned".to_string(),
-        };
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "ban_user".to_string(),
-            username.to_string(),
-            details,
-        );
+    info!(client_id, admin = admin_user.username, user = target.username, sessions = sessions.len(), "forced disconnect");
+
+    let text = match &reason {
+        Some(reason) => format!("Disconnected by an administrator. Reason: {reason}"),
+        None => "Disconnected by an administrator".to_string(),
+    };
+    let notice = ServerMessage::SystemMessage { meta: server_meta(state), text };
+    for &id in &sessions {
+        reg.send(id, notice.clone());
+        reg.terminate(id);
     }
 
-    let target_client_ids: Vec<ClientId> = {
+    reg.send(client_id, ServerMessage::SystemMessage {
+        meta: server_meta(state),
+        text: format!("Disconnected {} ({} session(s))", target.username, sessions.len()),
+    });
+    Ok(())
+}
+
+async fn handle_rotate_special_key(state: &Arc<AppState>, client_id: ClientId, user_authed: bool, new_key: String) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let username = {
         let reg = state.registry.read().await;
-        reg.find_clients_by_user_id(target.id)
+        reg.user(client_id).map(|u| u.username).unwrap_or_default()
     };
 
-    for target_client_id in target_client_ids {
-        let current_channel = {
-            let reg = state.registry.read().await;
-            reg.channel(target_client_id)
-        };
+    let allowed = {
+        let admin = state.admin.read().await;
+        admin.is_server_super_admin(&username)
+    };
 
-        if current_channel.as_deref() == Some(channel) {
-            {
-                let mut channels = state.channels.write().await;
-                channels.leave(target_client_id, channel);
-            }
+    if !allowed {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can rotate the special key".to_string()));
+    }
 
-            let kick_msg = ServerMessage::SystemMessage {
-                meta: server_meta(state),
-                text: format!("You have been banned from this channel. Reason: {}", reason.clone().unwrap_or_default()),
-            };
+    if new_key.trim().is_empty() {
+        return Err(ServerError::Rejected("special key cannot be empty".to_string()));
+    }
 
-            {
-                let reg = state.registry.read().await;
-                reg.send(target_client_id, kick_msg);
-            }
-        }
+    {
+        let mut key = state.special_key.write().await;
+        *key = new_key;
     }
 
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
-    };
+    // Never log the key itself.
+    info!(client_id, user = username, "special key rotated");
 
-    let msg = ServerMessage::UserBanned {
+    let msg = ServerMessage::SystemMessage {
         meta: server_meta(state),
-        channel: channel.to_string(),
-        user_id: target.id,
-        username: username.to_string(),
-        banned_until,
-        banned_by: admin_username,
-        reason,
+        text: "special key rotated; existing sessions are unaffected".to_string(),
     };
-
     let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    reg.send(client_id, msg);
+    Ok(())
 }
 
-async fn handle_unban_user(
-    state: &Arc<AppState>,
-    client_id: ClientId,
-    user_authed: bool,
-    channel: &str,
-    username: &str,
-) {
+async fn handle_set_motd(state: &Arc<AppState>, client_id: ClientId, user_authed: bool, motd: Option<String>) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
-    };
-
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let username = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| u.username).unwrap_or_default()
     };
 
-    let has_permission = {
+    let allowed = {
         let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::BanUser)
+        admin.is_server_super_admin(&username)
     };
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: BanUser").await;
-        return;
+    if !allowed {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can set the message of the day".to_string()));
     }
 
-    let target_id = {
-        let auth = state.auth.read().await;
-        auth.find_user_by_username(username).map(|u| u.id)
+    let motd = match (motd, &state.motd_file) {
+        (Some(text), _) => motd::normalize(&text).map_err(ServerError::Rejected)?,
+        (None, Some(path)) => motd::read_file(path).map_err(|e| ServerError::Rejected(format!("cannot read {}: {e}", path.display())))?,
+        (None, None) => return Err(ServerError::Rejected("no DARKRELAY_MOTD_FILE to reload".to_string())),
     };
 
-    let Some(target_user_id) = target_id else {
-        send_admin_error(state, client_id, "User not found").await;
-        return;
+    let text = match &motd {
+        Some(_) => "message of the day updated; shown from the next login",
+        None => "message of the day cleared",
     };
+    *state.motd.write().await = motd;
 
-    let unbanned = {
-        let mut bans = state.bans.write().await;
-        bans.unban_user(ch_id, target_user_id)
-    };
+    info!(client_id, user = username, "message of the day changed");
 
-    if !unbanned {
-        send_admin_error(state, client_id, "User is not banned").await;
-        return;
-    }
+    let reg = state.registry.read().await;
+    reg.send(client_id, ServerMessage::SystemMessage { meta: server_meta(state), text: text.to_string() });
+    Ok(())
+}
 
-    let admin_username = {
-        let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+/// Follows `AuthSuccess` on login, registration and guest login. Resumed
+/// sessions have seen it already.
+async fn send_motd(state: &Arc<AppState>, client_id: ClientId) {
+    let Some(motd) = state.motd.read().await.clone() else {
+        return;
     };
+    let reg = state.registry.read().await;
+    reg.send(client_id, ServerMessage::SystemMessage { meta: server_meta(state), text: format!("{MOTD_PREFIX}{motd}") });
+}
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "unban_user".to_string(),
-            username.to_string(),
-            "Unbanned".to_string(),
-        );
-    }
-
+async fn broadcast_user_left(state: &Arc<AppState>, client_id: ClientId, channel: &str, user: darkrelayprotocol::protocol::UserInfo) {
     let members = {
         let channels = state.channels.read().await;
         channels.members(channel)
     };
 
-    let msg = ServerMessage::UserUnbanned {
+    let msg = ServerMessage::UserLeft {
         meta: server_meta(state),
         channel: channel.to_string(),
-        username: username.to_string(),
-        unbanned_by: admin_username,
+        user,
     };
 
     let reg = state.registry.read().await;
     reg.send_many(&members, &msg);
-}
-
-async fn handle_kick_user(
-    state: &Arc<AppState>,
-    client_id: ClientId,
-    user_authed: bool,
-    channel: &str,
-    username: &str,
-    reason: Option<String>,
-) {
-    if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
-    }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
-    };
+    debug!(client_id, channel, "broadcast user left");
+}
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+/// Reject username and channel fields longer than `MAX_NAME_FIELD_LEN`
+/// before any handler clones or broadcasts them.
+fn check_name_fields(msg: &ClientMessage) -> Result<(), String> {
+    let (channel, username) = match msg {
+        ClientMessage::RenameChannel { new_name, .. } if new_name.len() > MAX_NAME_FIELD_LEN => {
+            return Err("channel name too long".to_string());
+        }
+        ClientMessage::RegisterUser { username, .. } | ClientMessage::Login { username, .. } => (None, Some(username)),
+        ClientMessage::Rename { new_username, .. } => (None, Some(new_username)),
+        ClientMessage::CreateUser { username, .. } | ClientMessage::ForceDisconnect { username, .. } => {
+            (None, Some(username))
+        }
+        ClientMessage::SendDM { recipient, .. } => (None, Some(recipient)),
+        ClientMessage::GetUserInfo { username, .. } => (None, Some(username)),
+        ClientMessage::JoinChannel { name, .. } | ClientMessage::CreateChannel { name, .. } => (Some(name), None),
+        ClientMessage::LeaveChannel { channel, .. }
+        | ClientMessage::SendMessage { channel, .. }
+        | ClientMessage::GetHistory { channel, .. }
+        | ClientMessage::Resync { channel, .. }
+        | ClientMessage::CreatePoll { channel, .. }
+        | ClientMessage::Vote { channel, .. }
+        | ClientMessage::DeleteMessage { channel, .. }
+        | ClientMessage::ListAdmins { channel, .. }
+        | ClientMessage::ListBans { channel, .. }
+        | ClientMessage::ViewLogs { channel, .. }
+        | ClientMessage::ChangeChannelType { channel, .. }
+        | ClientMessage::DeleteChannel { channel, .. }
+        | ClientMessage::SetRetention { channel, .. }
+        | ClientMessage::SetSlowMode { channel, .. }
+        | ClientMessage::SetMaxMembers { channel, .. }
+        | ClientMessage::RenameChannel { channel, .. }
+        | ClientMessage::SetWelcome { channel, .. } => (Some(channel), None),
+        ClientMessage::PromoteUser { channel, username, .. }
+        | ClientMessage::DemoteUser { channel, username, .. }
+        | ClientMessage::BanUser { channel, username, .. }
+        | ClientMessage::UnbanUser { channel, username, .. }
+        | ClientMessage::KickUser { channel, username, .. }
+        | ClientMessage::TransferOwnership { channel, username, .. } => (Some(channel), Some(username)),
+        ClientMessage::Connect { .. }
+        | ClientMessage::Auth { .. }
+        | ClientMessage::EcdhPublicKey { .. }
+        | ClientMessage::Resume { .. }
+        | ClientMessage::ListChannels { .. }
+        | ClientMessage::ListAllChannels { .. }
+        | ClientMessage::ListMyChannels { .. }
+        | ClientMessage::Disconnect { .. }
+        | ClientMessage::RequestCompression { .. }
+        | ClientMessage::GuestLogin { .. }
+        | ClientMessage::RotateSpecialKey { .. }
+        | ClientMessage::SetMotd { .. }
+        | ClientMessage::ListConnections { .. }
+        | ClientMessage::Ping { .. }
+        | ClientMessage::SetPresence { .. }
+        | ClientMessage::DeleteDM { .. } => (None, None),
     };
 
-    let has_permission = {
-        let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::KickUser)
-    };
+    if channel.is_some_and(|c| c.len() > MAX_NAME_FIELD_LEN) {
+        return Err("channel name too long".to_string());
+    }
+    if username.is_some_and(|u| u.len() > MAX_NAME_FIELD_LEN) {
+        return Err("username too long".to_string());
+    }
+    Ok(())
+}
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: KickUser").await;
-        return;
+/// Report a failed request to the client that made it.
+/// `request_id` is the `meta.id` of the client message being refused, so the
+/// client can tell which of its requests failed.
+async fn send_error(state: &Arc<AppState>, client_id: ClientId, request_id: Option<u64>, err: ServerError) {
+    if let ServerError::Internal(detail) = &err {
+        warn!(client_id, detail, "internal error");
     }
 
-    let target_user = {
-        let auth = state.auth.read().await;
-        auth.find_user_by_username(username)
-    };
+    let reg = state.registry.read().await;
+    reg.send(client_id, err.into_message(server_meta(state), request_id));
+}
 
-    let Some(target) = target_user else {
-        send_admin_error(state, client_id, "User not found").await;
-        return;
-    };
+/// What `user`'s session may use. Guests are read-only, so DMs and polls are
+/// left out for them, and each guest is a user of its own, with no other
+/// sessions to list channels from.
+fn capabilities(state: &Arc<AppState>, user: &UserInfo) -> ServerMessage {
+    let mut enabled = vec![features::COMPRESSION, features::RESYNC];
+    if !auth::is_guest(user.id) {
+        enabled.extend([features::DMS, features::POLLS, features::MY_CHANNELS]);
+    }
+    ServerMessage::ServerCapabilities {
+        meta: server_meta(state),
+        features: enabled.into_iter().map(String::from).collect(),
+        max_frame_len: frame::MAX_FRAME_LEN as u32,
+        max_message_len: state.max_message_len as u32,
+    }
+}
 
-    let admin_username = {
-        let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+fn server_meta(state: &Arc<AppState>) -> MessageMeta {
+    MessageMeta::new(state.next_server_msg_id(), Utc::now())
+}
+
+async fn handle_guest_login(state: &Arc<AppState>, client_id: ClientId) {
+    let user = {
+        let mut auth = state.auth.write().await;
+        auth.guest()
     };
 
     {
-        let mut admin = state.admin.write().await;
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "kick_user".to_string(),
-            username.to_string(),
-            reason.clone().unwrap_or_default(),
-        );
+        let mut reg = state.registry.write().await;
+        reg.set_user(client_id, user.clone());
     }
 
-    let target_client_ids: Vec<ClientId> = {
-        let reg = state.registry.read().await;
-        reg.find_clients_by_user_id(target.id)
-    };
-
-    for target_client_id in target_client_ids {
-        let current_channel = {
-            let reg = state.registry.read().await;
-            reg.channel(target_client_id)
-        };
-
-        if current_channel.as_deref() == Some(channel) {
-            {
-                let mut channels = state.channels.write().await;
-                channels.leave(target_client_id, channel);
-            }
-
-            let kick_msg = ServerMessage::SystemMessage {
-                meta: server_meta(state),
-                text: format!("You have been kicked from this channel. Reason: {}", reason.clone().unwrap_or_default()),
-            };
+    info!(client_id, user = user.username, "guest session started");
 
-            {
-                let reg = state.registry.read().await;
-                reg.send(target_client_id, kick_msg);
-            }
-        }
+    // No resume token: a guest has nothing worth restoring beyond a rejoin.
+    let capabilities = capabilities(state, &user);
+    let msg = ServerMessage::AuthSuccess { meta: server_meta(state), user, generated_password: None, resume_token: None };
+    {
+        let reg = state.registry.read().await;
+        reg.send(client_id, msg);
+        reg.send(client_id, capabilities);
     }
 
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
-    };
+    send_motd(state, client_id).await;
+    send_channel_list(state, client_id).await;
+}
 
-    let msg = ServerMessage::UserKicked {
-        meta: server_meta(state),
-        channel: channel.to_string(),
-        user_id: target.id,
-        username: username.to_string(),
-        kicked_by: admin_username,
-        reason,
+/// Roles belong to the account, so every session of a user shares them.
+async fn channel_role(state: &Arc<AppState>, channel_id: ChannelId, client_id: ClientId) -> Role {
+    let Some(username) = state.registry.read().await.user(client_id).map(|u| u.username.clone()) else {
+        return Role::User;
     };
+    state.admin.read().await.get_role(channel_id, &username)
+}
+
+async fn channel_permission(state: &Arc<AppState>, channel_id: ChannelId, client_id: ClientId, permission: Permission) -> bool {
+    has_permission(channel_role(state, channel_id, client_id).await, permission)
+}
 
+/// Whether this client is a read-only guest session.
+async fn is_guest_client(state: &Arc<AppState>, client_id: ClientId) -> bool {
     let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+    reg.user(client_id).is_some_and(|u| auth::is_guest(u.id))
 }
 
-async fn handle_list_admins(
+/// Guests may only see channels that are both listed and of a non-private type.
+async fn guest_can_read(state: &Arc<AppState>, channel: &str) -> bool {
+    let channels = state.channels.read().await;
+    channels.is_public(channel) == Some(true) && channels.channel_type(channel) != Some(ChannelType::Private)
+}
+
+async fn handle_get_history(
     state: &Arc<AppState>,
     client_id: ClientId,
     user_authed: bool,
-    channel: &str,
-) {
+    channel: String,
+    limit: u16,
+) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
-    };
-
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
-    };
-
-    let user_map = {
-        let auth = state.auth.read().await;
-        auth.get_all_users_map()
-    };
-
-    let admins = {
-        let admin = state.admin.read().await;
-        admin.list_admins(ch_id, &user_map)
-    };
+    if is_guest_client(state, client_id).await && !guest_can_read(state, &channel).await {
+        return Err(ServerError::PermissionDenied("guests can only read public channels".to_string()));
+    }
 
-    let msg = ServerMessage::AdminList {
-        meta: server_meta(state),
-        channel: channel.to_string(),
-        admins,
+    let messages = {
+        let channels = state.channels.read().await;
+        if channels.get_channel_id(&channel).is_none() {
+            return Err(ServerError::NotFound("Channel"));
+        }
+        if !channels.is_member(&channel, client_id) && !channels.readable_without_joining(&channel) {
+            return Err(ServerError::PermissionDenied("join the channel to read its history".to_string()));
+        }
+        channels.history(&channel, limit as usize)
     };
 
+    let msg = ServerMessage::HistoryChunk { meta: server_meta(state), channel, messages };
     let reg = state.registry.read().await;
     reg.send(client_id, msg);
+    Ok(())
 }
 
-async fn handle_list_bans(
+async fn handle_resync(
     state: &Arc<AppState>,
     client_id: ClientId,
     user_authed: bool,
-    channel: &str,
-) {
+    channel: String,
+    since_sequence: u64,
+) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
+    if is_guest_client(state, client_id).await && !guest_can_read(state, &channel).await {
+        return Err(ServerError::PermissionDenied("guests can only read public channels".to_string()));
+    }
+
+    let (messages, truncated) = {
         let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
+        if channels.get_channel_id(&channel).is_none() {
+            return Err(ServerError::NotFound("Channel"));
+        }
+        if !channels.is_member(&channel, client_id) && !channels.readable_without_joining(&channel) {
+            return Err(ServerError::PermissionDenied("join the channel to read its history".to_string()));
+        }
+        channels
+            .messages_since(&channel, since_sequence, channel::MAX_RESYNC_MESSAGES)
+            .ok_or(ServerError::NotFound("Channel"))?
     };
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let msg = ServerMessage::ResyncChunk { meta: server_meta(state), channel, messages, truncated };
+    let reg = state.registry.read().await;
+    reg.send(client_id, msg);
+    Ok(())
+}
+
+/// What a `CreateChannel` sets up front besides the name and password.
+#[derive(Debug, Default)]
+pub struct ChannelOptions {
+    pub channel_type: ChannelType,
+    pub topic: Option<String>,
+}
+
+/// `JoinChannel`, or `CreateChannel` when `create` is set. Keeping the two
+/// apart means a typo'd join can't leave a stray channel behind.
+pub async fn handle_join_channel(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    name: String,
+    password: Option<String>,
+    create: Option<ChannelOptions>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let name = match channel::normalize_channel_name(&name) {
+        Ok(normalized) => normalized,
+        Err(reason) => return Err(ServerError::JoinRefused { channel: name, reason }),
     };
 
-    let has_permission = {
-        let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::ViewLogs)
+    // One read of each lock gathers everything the checks below need.
+    let Some(user) = state.registry.read().await.user(client_id) else {
+        return Err(ServerError::Internal("user missing".to_string()));
+    };
+    let (existing_id, creation_policy, guest_readable, allowed) = {
+        let channels = state.channels.read().await;
+        let guest_readable = channels.is_public(&name) == Some(true)
+            && channels.channel_type(&name) != Some(ChannelType::Private);
+        (channels.get_channel_id(&name), channels.creation_policy(), guest_readable, channels.is_allowed(&name))
     };
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: ViewLogs").await;
-        return;
+    let refuse = |reason: &str| ServerError::JoinRefused { channel: name.clone(), reason: reason.to_string() };
+    if !allowed {
+        return Err(refuse(channel::CHANNEL_NOT_ALLOWED));
+    }
+    match (existing_id, &create) {
+        (Some(_), Some(_)) => return Err(refuse("channel already exists")),
+        (None, None) => return Err(refuse("channel not found")),
+        _ => {}
     }
+    let create = match create {
+        Some(options) => {
+            let topic = options.topic.map(|t| t.trim().to_string()).filter(|t| !t.is_empty());
+            if topic.as_ref().is_some_and(|t| t.len() > channel::MAX_TOPIC_LEN) {
+                return Err(refuse(&format!("topic is limited to {} bytes", channel::MAX_TOPIC_LEN)));
+            }
+            Some(ChannelOptions { topic, ..options })
+        }
+        None => None,
+    };
 
-    let bans = {
+    if create.is_some()
+        && creation_policy == ChannelCreation::Admins
+        && !state.admin.read().await.is_server_super_admin(&user.username)
+    {
+        return Err(ServerError::JoinRefused {
+            channel: name,
+            reason: "only server admins can create channels".to_string(),
+        });
+    }
+
+    if auth::is_guest(user.id) {
+        let reason = if create.is_some() {
+            Some("guests cannot create channels")
+        } else if password.is_some() || !guest_readable {
+            Some("guests can only join public channels")
+        } else {
+            None
+        };
+        if let Some(reason) = reason {
+            return Err(ServerError::JoinRefused { channel: name, reason: reason.to_string() });
+        }
+    }
+
+    let (channel_id, created) = match (existing_id, create) {
+        (Some(id), _) => (id, false),
+        (None, options) => {
+            let ChannelOptions { channel_type, topic } = options.unwrap_or_default();
+            // A channel re-created after a restart keeps the type it had.
+            let channel_type = state.admin.read().await.stored_channel_type(&name).unwrap_or(channel_type);
+            let is_public = password.is_none() && channel_type != ChannelType::Private;
+            // Someone may have created it since we looked; then this create
+            // lost the race.
+            let created = {
+                let mut channels = state.channels.write().await;
+                match channels.get_channel_id(&name) {
+                    Some(_) => Err("channel already exists".to_string()),
+                    None => {
+                        let id = channels.ensure_channel(&name, is_public, password.clone(), channel_type, Some(client_id));
+                        if id.is_ok() {
+                            channels.set_topic(&name, topic);
+                        }
+                        id
+                    }
+                }
+            };
+            let channel_id = match created {
+                Ok(id) => id,
+                Err(reason) => return Err(ServerError::JoinRefused { channel: name, reason }),
+            };
+
+            let mut admin = state.admin.write().await;
+            admin.open_channel(channel_id, &name, channel_type, Some(&user.username));
+            (channel_id, true)
+        }
+    };
+
+    // The ban table stays locked until the client is a member everywhere, so
+    // a concurrent ban either refuses this join or finds the member to remove.
+    // History, the member list and the welcome are read under the same
+    // channels guard as the join itself.
+    let (channel_info_base, history, members, welcome) = {
         let bans = state.bans.read().await;
-        bans.list_bans(ch_id)
+        if bans.is_banned(channel_id, user.id) {
+            let until = bans.get_ban_info(channel_id, user.id).and_then(|b| b.banned_until);
+            return Err(ServerError::Banned { channel: name, until });
+        }
+
+        let joined = {
+            let mut channels = state.channels.write().await;
+            channels.join(client_id, user.id, &name, password).map(|info| {
+                let history = channels.history(&info.name, 50);
+                let members = channels.members(&info.name);
+                let welcome = channels.welcome(&info.name);
+                (info, history, members, welcome)
+            })
+        };
+
+        let (info, history, members, welcome) = match joined {
+            Ok(joined) => joined,
+            Err(reason) => return Err(ServerError::JoinRefused { channel: name, reason }),
+        };
+
+        let mut reg = state.registry.write().await;
+        reg.join_channel(client_id, &info.name);
+        (info, history, members, welcome)
     };
 
-    let msg = ServerMessage::BanList {
+    // `channel_role` would read the registry again for the name we have.
+    let role = state.admin.read().await.get_role(channel_id, &user.username);
+    // Everyone's channel list gains a new public channel; private ones stay unlisted.
+    let announcement = (created && channel_info_base.is_public).then(|| ServerMessage::ChannelCreated {
         meta: server_meta(state),
-        channel: channel.to_string(),
-        bans,
-    };
+        channel: channel_info_base.clone(),
+        created_by: user.username.clone(),
+    });
+    let channel_info = ChannelInfo { user_role: Some(role), ..channel_info_base };
+    let channel = channel_info.name.clone();
 
     let reg = state.registry.read().await;
-    reg.send(client_id, msg);
+    reg.send(client_id, ServerMessage::JoinSuccess {
+        meta: server_meta(state),
+        rules: channel_info.channel_type.description().to_string(),
+        channel: channel_info,
+    });
+    reg.send(client_id, ServerMessage::HistoryChunk { meta: server_meta(state), channel: channel.clone(), messages: history });
+    if let Some(text) = welcome {
+        reg.send(client_id, ServerMessage::SystemMessage { meta: server_meta(state), text });
+    }
+    // The joiner already has JoinSuccess.
+    let joined_msg = ServerMessage::UserJoined { meta: server_meta(state), channel, user };
+    reg.send_many_except(&members, client_id, &joined_msg);
+    if let Some(msg) = announcement {
+        reg.send_many(&reg.authenticated(), &msg);
+    }
+    Ok(())
 }
 
-async fn handle_view_logs(
+async fn handle_leave_channel(
     state: &Arc<AppState>,
     client_id: ClientId,
     user_authed: bool,
     channel: &str,
-    limit: u32,
-) {
+) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
-    };
-
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let (was_member, user) = {
+        let mut reg = state.registry.write().await;
+        (reg.leave_channel(client_id, channel), reg.user(client_id))
     };
 
-    let has_permission = {
-        let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::ViewLogs)
-    };
+    if !was_member {
+        return Err(ServerError::InvalidRequest("not joined to channel".to_string()));
+    }
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: ViewLogs").await;
-        return;
+    {
+        let mut channels = state.channels.write().await;
+        channels.leave(client_id, channel);
     }
 
-    let logs = {
-        let admin = state.admin.read().await;
-        admin.get_logs(ch_id, limit as usize)
+    let Some(user) = user else {
+        return Ok(());
     };
 
-    let msg = ServerMessage::LogList {
+    // The leaver is no longer a member, so tell them directly as well.
+    let msg = ServerMessage::UserLeft {
         meta: server_meta(state),
         channel: channel.to_string(),
-        logs,
+        user: user.clone(),
     };
+    {
+        let reg = state.registry.read().await;
+        reg.send(client_id, msg);
+    }
 
-    let reg = state.registry.read().await;
-    reg.send(client_id, msg);
+    broadcast_user_left(state, client_id, channel, user).await;
+    Ok(())
 }
 
-async fn handle_change_channel_type(
+/// Relay an away/back change to the user's channel peers. Repeats of the
+/// current state are dropped.
+async fn handle_set_presence(state: &Arc<AppState>, client_id: ClientId, user_authed: bool, away: bool) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let mut reg = state.registry.write().await;
+    let Some(user) = reg.user(client_id) else {
+        return Err(ServerError::Internal("user missing".to_string()));
+    };
+    if !reg.set_away(client_id, away) {
+        return Ok(());
+    }
+
+    debug!(client_id, user = user.username, away, "presence changed");
+    let msg = ServerMessage::PresenceChanged { meta: server_meta(state), username: user.username, away };
+    reg.send_many(&reg.channel_peers(client_id), &msg);
+    Ok(())
+}
+
+pub async fn handle_send_message(
     state: &Arc<AppState>,
     client_id: ClientId,
     user_authed: bool,
+    ecdh_complete: bool,
     channel: &str,
-    channel_type: darkrelayprotocol::channel::ChannelType,
-) {
+    content: Vec<u8>,
+    metadata: MessageMetadata,
+) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
+    let (user, joined) = {
+        let reg = state.registry.read().await;
+        (reg.user(client_id), reg.is_in_channel(client_id, channel))
     };
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let Some(user) = user else {
+        return Err(ServerError::Internal("user missing".to_string()));
     };
 
-    let has_permission = {
-        let admin = state.admin.read().await;
-        admin.has_permission(ch_id, client_id, Permission::ManageChannel)
-    };
+    if !joined {
+        return Err(ServerError::InvalidRequest("not joined to channel".to_string()));
+    }
 
-    if !has_permission {
-        send_admin_error(state, client_id, "You lack permission: ManageChannel").await;
-        return;
+    if auth::is_guest(user.id) {
+        return Err(ServerError::PermissionDenied("Permission denied: guests are read-only".to_string()));
     }
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.set_channel_type(ch_id, channel_type);
+    let nonce = metadata.nonce();
+
+    check_message_content(state, &content, nonce.is_some())?;
+
+    // Once the session is encrypted, plaintext would be stored and broadcast
+    // as if it were ciphertext and fail to decrypt for everyone else.
+    if ecdh_complete && nonce.as_ref().map(Vec::len) != Some(NONCE_LEN) {
+        return Err(ServerError::InvalidRequest("encrypted session requires a 12-byte nonce".to_string()));
     }
 
-    let admin_username = {
-        let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+    let channel_state = {
+        let channels = state.channels.read().await;
+        channels
+            .get_channel_id(channel)
+            .zip(channels.channel_type(channel))
+            .map(|(id, channel_type)| (id, channel_type, channels.slow_mode(channel)))
     };
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.log_action(
-            ch_id,
-            client_id,
-            admin_username.clone(),
-            "change_channel_type".to_string(),
-            channel.to_string(),
-            format!("Changed to {:?}", channel_type),
-        );
+    let Some((ch_id, channel_type, slow_mode)) = channel_state else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let role = state.admin.read().await.get_role(ch_id, &user.username);
+    let (can_send, exempt_from_slow_mode) = (channel_type.allows_sending(role), has_permission(role, Permission::ManageChannel));
+
+    if !can_send {
+        return Err(ServerError::PermissionDenied("You lack permission to send messages in this channel".to_string()));
     }
+    let slow_mode = slow_mode.filter(|_| !exempt_from_slow_mode);
 
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
+    let verdict = {
+        let mut limiter = state.rate_limiter.write().await;
+        limiter.check(user.id, channel, slow_mode, Utc::now())
     };
 
-    let msg = ServerMessage::ChannelTypeChanged {
+    if let Err(retry_after) = verdict {
+        return Err(ServerError::RateLimited {
+            channel: channel.to_string(),
+            retry_after_ms: retry_after.num_milliseconds().max(1) as u64,
+        });
+    }
+
+    // Channel managers are trusted not to spam, as with slow mode.
+    if !exempt_from_slow_mode {
+        let (verdict, mute) = {
+            let mut spam = state.spam.write().await;
+            (spam.check(user.id, channel, content.len(), Utc::now()), spam.policy().mute)
+        };
+        let muted_for = match verdict {
+            SpamVerdict::Allowed => None,
+            SpamVerdict::Flagged => {
+                warn!(client_id, user = user.username, channel, "repeated identical messages, muting");
+                let reg = state.registry.read().await;
+                reg.send(client_id, ServerMessage::SystemMessage {
+                    meta: server_meta(state),
+                    text: format!(
+                        "You have been muted in #{channel} for {}s for sending the same message repeatedly",
+                        mute.num_seconds()
+                    ),
+                });
+                Some(mute)
+            }
+            SpamVerdict::Muted { remaining } => Some(remaining),
+        };
+        if let Some(muted_for) = muted_for {
+            return Err(ServerError::RateLimited {
+                channel: channel.to_string(),
+                retry_after_ms: muted_for.num_milliseconds().max(1) as u64,
+            });
+        }
+    }
+
+    // Server stores encrypted content as-is, never attempts to decrypt
+    info!(
+        client_id,
+        user = user.username,
+        channel,
+        size = content.len(),
+        encrypted = ecdh_complete,
+        "message received (content encrypted, not logged)"
+    );
+
+    let msg = ChatMessage {
+        id: 0,
+        seq: 0,
+        user_id: user.id,
+        username: user.username.clone(),
+        content,
+        timestamp: Utc::now(),
+        nonce,
+        metadata,
+        deleted: false,
+        edited: false,
+    };
+
+    // Checked again under the locks `handle_delete_channel` holds, and
+    // broadcast before letting go, so nobody sees this after `ChannelDeleted`.
+    let reg = state.registry.read().await;
+    let mut channels = state.channels.write().await;
+    if channels.get_channel_id(channel) != Some(ch_id) {
+        return Err(ServerError::InvalidRequest(format!("#{channel} was deleted")));
+    }
+    if !reg.is_in_channel(client_id, channel) {
+        return Err(ServerError::InvalidRequest("not joined to channel".to_string()));
+    }
+    let stored = channels.add_message(channel, msg).map_err(ServerError::InvalidRequest)?;
+
+    let msg = ServerMessage::MessageReceived {
         meta: server_meta(state),
         channel: channel.to_string(),
-        new_type: channel_type,
-        changed_by: admin_username,
+        message: stored,
     };
+    reg.send_many(&channels.members(channel), &msg);
+    Ok(())
+}
 
-    let reg = state.registry.read().await;
-    reg.send_many(&members, &msg);
+/// Bound `content` from both sides. The upper bound is the `max_message_len`
+/// advertised in `ServerCapabilities`; it is on plaintext, which we never
+/// see, so ciphertext gets `MESSAGE_OVERHEAD_ALLOWANCE` on top. The lower
+/// bound: plaintext must have something besides whitespace, and ciphertext,
+/// which can't be inspected, only has to be long enough to hold a non-empty
+/// message.
+fn check_message_content(state: &Arc<AppState>, content: &[u8], encrypted: bool) -> Result<(), ServerError> {
+    if content.len() > max_content_len(state.max_message_len) {
+        return Err(ServerError::MessageTooLong { max: state.max_message_len });
+    }
+    let empty = if encrypted {
+        content.len() < MIN_CIPHERTEXT_LEN
+    } else {
+        std::str::from_utf8(content).map_or(content.is_empty(), |text| text.trim().is_empty())
+    };
+    if empty {
+        return Err(ServerError::InvalidRequest("message is empty".to_string()));
+    }
+    Ok(())
 }
 
-async fn handle_delete_channel(
+async fn handle_send_dm(
     state: &Arc<AppState>,
     client_id: ClientId,
     user_authed: bool,
-    channel: &str,
-) {
+    recipient: &str,
+    content: Vec<u8>,
+    metadata: MessageMetadata,
+) -> Result<(), ServerError> {
     if !user_authed {
-        send_protocol_error(state, client_id, "login/register required").await;
-        return;
+        return Err(ServerError::NotAuthenticated);
     }
 
-    let channel_id = {
-        let channels = state.channels.read().await;
-        channels.get_channel_id(channel)
+    let user = {
+        let reg = state.registry.read().await;
+        reg.user(client_id)
     };
 
-    let Some(ch_id) = channel_id else {
-        send_admin_error(state, client_id, "Channel not found").await;
-        return;
+    let Some(user) = user else {
+        return Err(ServerError::Internal("user missing".to_string()));
     };
 
-    let role = {
-        let admin = state.admin.read().await;
-        admin.get_role(ch_id, client_id)
+    if auth::is_guest(user.id) {
+        return Err(ServerError::PermissionDenied("Permission denied: guests are read-only".to_string()));
+    }
+
+    check_message_content(state, &content, metadata.contains(NONCE_KEY))?;
+
+    let target = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(recipient)
     };
 
-    if role != darkrelayprotocol::permissions::Role::SuperAdmin {
-        send_admin_error(state, client_id, "Only SuperAdmin can delete channels").await;
-        return;
+    let Some(target) = target else {
+        return Err(ServerError::InvalidRequest(format!("unknown user: {}", recipient)));
+    };
+
+    if target.id == user.id {
+        return Err(ServerError::InvalidRequest("cannot send a DM to yourself".to_string()));
     }
 
-    let admin_username = {
-        let reg = state.registry.read().await;
-        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+    // DMs share the sender's message budget; the cooldown is reported
+    // against the conversation's `@name` tab.
+    let conversation = format!("@{}", target.username);
+    let verdict = {
+        let mut limiter = state.rate_limiter.write().await;
+        limiter.check(user.id, &conversation, None, Utc::now())
     };
 
-    let members = {
-        let channels = state.channels.read().await;
-        channels.members(channel)
+    if let Err(retry_after) = verdict {
+        return Err(ServerError::RateLimited {
+            channel: conversation,
+            retry_after_ms: retry_after.num_milliseconds().max(1) as u64,
+        });
+    }
+
+    let nonce = metadata.nonce();
+
+    let msg = ChatMessage {
+        id: 0,
+        seq: 0,
+        user_id: user.id,
+        username: user.username.clone(),
+        content,
+        timestamp: Utc::now(),
+        nonce,
+        metadata,
+        deleted: false,
+        edited: false,
     };
 
-    let msg = ServerMessage::ChannelDeleted {
+    // Decide live vs. stored under the DM lock: a login that lands in between
+    // would otherwise flush before the DM is stored and never see it.
+    let (recipient_clients, stored) = {
+        let mut dms = state.dms.write().await;
+        let recipient_clients = state.registry.read().await.find_clients_by_user_id(target.id);
+        let stored = dms.store_dm(target.id, msg, !recipient_clients.is_empty());
+        (recipient_clients, stored)
+    };
+    let delivered = !recipient_clients.is_empty();
+
+    info!(client_id, user = user.username, recipient_id = target.id, dm_id = stored.id, delivered, "direct message stored (content not logged)");
+
+    let status = ServerMessage::DMDeliveryStatus {
         meta: server_meta(state),
-        channel: channel.to_string(),
-        deleted_by: admin_username.clone(),
+        dm_id: stored.id,
+        delivered,
+    };
+    let reg = state.registry.read().await;
+    let mut clients = recipient_clients;
+    clients.extend(reg.find_clients_by_user_id(user.id));
+    let msg = ServerMessage::DMReceived {
+        meta: server_meta(state),
+        recipient: target.username,
+        message: stored,
     };
+    reg.send_many(&clients, &msg);
+    reg.send(client_id, status);
+    Ok(())
+}
 
-    {
-        let reg = state.registry.read().await;
-        reg.send_many(&members, &msg);
+async fn handle_delete_dm(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    dm_id: MessageId,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
     }
 
-    for member_id in &members {
+    let user = {
         let reg = state.registry.read().await;
-        if let Some(ch) = reg.channel(*member_id) {
-            if ch == channel {
-                drop(reg);
-                let mut reg = state.registry.write().await;
-                reg.set_channel(*member_id, None);
-            }
+        reg.user(client_id)
+    };
+
+    let Some(user) = user else {
+        return Err(ServerError::Internal("user missing".to_string()));
+    };
+
+    let participants = {
+        let mut dms = state.dms.write().await;
+        let participants = dms.participants(dm_id);
+        if participants.is_some_and(|(sender, recipient)| user.id == sender || user.id == recipient) {
+            dms.delete_dm(dm_id);
         }
-    }
+        participants
+    };
 
-    {
-        let mut channels = state.channels.write().await;
-        channels.delete_channel(channel);
-    }
+    let Some((sender, recipient)) = participants else {
+        return Err(ServerError::NotFound("DM"));
+    };
 
-    {
-        let mut admin = state.admin.write().await;
-        admin.remove_channel(ch_id);
+    if user.id != sender && user.id != recipient {
+        return Err(ServerError::PermissionDenied("Only the sender or recipient can delete a DM".to_string()));
     }
 
-    info!(client_id, channel, deleted_by = admin_username, "channel deleted");
+    info!(client_id, user = user.username, dm_id, "direct message deleted");
+
+    let msg = ServerMessage::DMDeleted {
+        meta: server_meta(state),
+        dm_id,
+        deleted_by: user.username,
+    };
+    let reg = state.registry.read().await;
+    let mut clients = reg.find_clients_by_user_id(sender);
+    clients.extend(reg.find_clients_by_user_id(recipient));
+    reg.send_many(&clients, &msg);
+    Ok(())
 }
 
-async fn read_frame<T: DeserializeOwned, R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<T> {
-    let len = reader.read_u32().await?;
-    let mut buf = vec![0u8; len as usize];
-    reader.read_exact(&mut buf).await?;
+/// Hand DMs that arrived while `user` was offline to their new session and
+/// let the senders know they have been delivered.
+async fn flush_undelivered_dms(state: &Arc<AppState>, client_id: ClientId, user: &UserInfo) {
+    let pending = {
+        let mut dms = state.dms.write().await;
+        dms.get_undelivered_dms(user.id)
+    };
 
-    bincode::deserialize::<T>(&buf)
-        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
-}
+    if pending.is_empty() {
+        return;
+    }
 
-async fn write_frame<T: Serialize, W: AsyncWrite + Unpin>(writer: &mut W, msg: &T) -> io::Result<()> {
-    let data = bincode::serialize(msg).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    info!(client_id, user = user.username, count = pending.len(), "delivering stored direct messages");
 
-    let len: u32 = data
-        .len()
-        .try_into()
-        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "frame too large"))?;
+    let reg = state.registry.read().await;
+    for message in pending {
+        let status = ServerMessage::DMDeliveryStatus {
+            meta: server_meta(state),
+            dm_id: message.id,
+            delivered: true,
+        };
+        reg.send_many(&reg.find_clients_by_user_id(message.user_id), &status);
 
-    writer.write_u32(len).await?;
-    writer.write_all(&data).await?;
-    writer.flush().await?;
-    Ok(())
+        let msg = ServerMessage::DMReceived {
+            meta: server_meta(state),
+            recipient: user.username.clone(),
+            message,
+        };
+        reg.send(client_id, msg);
+    }
+}
+
+async fn handle_delete_message(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    message_id: u64,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::DeleteMessage).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::DeleteMessage));
+    }
+
+    let deleted = {
+        let mut channels = state.channels.write().await;
+        channels.delete_message(channel, message_id)
+    };
+
+    if !deleted {
+        return Err(ServerError::NotFound("Message"));
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "delete_message".to_string(),
+            format!("message_{}", message_id),
+            "Message deleted".to_string(),
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::MessageDeleted {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        message_id,
+        deleted_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_promote_user(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    username: &str,
+    role: darkrelayprotocol::permissions::Role,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::PromoteUser).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::PromoteUser));
+    }
+
+    let target_id = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(username).map(|u| u.id)
+    };
+
+    let Some(target_user_id) = target_id else {
+        return Err(ServerError::NotFound("User"));
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.set_role(ch_id, username, role);
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "promote_user".to_string(),
+            username.to_string(),
+            format!("Promoted to {:?}", role),
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::UserPromoted {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        user_id: target_user_id,
+        username: username.to_string(),
+        new_role: role,
+        promoted_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_demote_user(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    username: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::PromoteUser).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::PromoteUser));
+    }
+
+    let target_id = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(username).map(|u| u.id)
+    };
+
+    let Some(target_user_id) = target_id else {
+        return Err(ServerError::NotFound("User"));
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.set_role(ch_id, username, darkrelayprotocol::permissions::Role::User);
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "demote_user".to_string(),
+            username.to_string(),
+            "Demoted to User".to_string(),
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::UserDemoted {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        user_id: target_user_id,
+        username: username.to_string(),
+        demoted_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_ban_user(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    username: &str,
+    duration_seconds: Option<u64>,
+    reason: Option<String>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::BanUser).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::BanUser));
+    }
+
+    let target_user = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(username)
+    };
+
+    let Some(target) = target_user else {
+        return Err(ServerError::NotFound("User"));
+    };
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    // Record the ban and remove the target's sessions under one write lock, so
+    // no join can slip in between (joins check the ban under the same lock).
+    let (banned_until, removed) = {
+        let mut bans = state.bans.write().await;
+        let banned_until = bans.ban_user(
+            ch_id,
+            target.id,
+            target.username.clone(),
+            admin_username.clone(),
+            duration_seconds,
+            reason.clone(),
+        );
+
+        let target_client_ids: Vec<ClientId> = {
+            let reg = state.registry.read().await;
+            reg.find_clients_by_user_id(target.id)
+        };
+
+        let mut removed = Vec::new();
+        for target_client_id in target_client_ids {
+            let was_member = {
+                let mut reg = state.registry.write().await;
+                reg.leave_channel(target_client_id, channel)
+            };
+            if was_member {
+                let mut channels = state.channels.write().await;
+                channels.leave(target_client_id, channel);
+                removed.push(target_client_id);
+            }
+        }
+        (banned_until, removed)
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        let details = match duration_seconds {
+            Some(secs) => format!("Banned for {} seconds", secs),
+            None => "Permanently banned".to_string(),
+        };
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "ban_user".to_string(),
+            username.to_string(),
+            details,
+        );
+    }
+
+    let kick_msg = ServerMessage::SystemMessage {
+        meta: server_meta(state),
+        text: format!("You have been banned from this channel. Reason: {}", reason.clone().unwrap_or_default()),
+    };
+    {
+        let reg = state.registry.read().await;
+        reg.send_many(&removed, &kick_msg);
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::UserBanned {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        user_id: target.id,
+        username: username.to_string(),
+        banned_until,
+        banned_by: admin_username,
+        reason,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_unban_user(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    username: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::BanUser).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::BanUser));
+    }
+
+    let target_id = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(username).map(|u| u.id)
+    };
+
+    let Some(target_user_id) = target_id else {
+        return Err(ServerError::NotFound("User"));
+    };
+
+    let unbanned = {
+        let mut bans = state.bans.write().await;
+        bans.unban_user(ch_id, target_user_id)
+    };
+
+    if !unbanned {
+        return Err(ServerError::Rejected("User is not banned".to_string()));
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "unban_user".to_string(),
+            username.to_string(),
+            "Unbanned".to_string(),
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::UserUnbanned {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        username: username.to_string(),
+        unbanned_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_kick_user(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    username: &str,
+    reason: Option<String>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::KickUser).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::KickUser));
+    }
+
+    let target_user = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(username)
+    };
+
+    let Some(target) = target_user else {
+        return Err(ServerError::NotFound("User"));
+    };
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "kick_user".to_string(),
+            username.to_string(),
+            reason.clone().unwrap_or_default(),
+        );
+    }
+
+    let target_client_ids: Vec<ClientId> = {
+        let reg = state.registry.read().await;
+        reg.find_clients_by_user_id(target.id)
+    };
+
+    for target_client_id in target_client_ids {
+        let was_member = {
+            let mut reg = state.registry.write().await;
+            reg.leave_channel(target_client_id, channel)
+        };
+
+        if was_member {
+            {
+                let mut channels = state.channels.write().await;
+                channels.leave(target_client_id, channel);
+            }
+
+            let kick_msg = ServerMessage::SystemMessage {
+                meta: server_meta(state),
+                text: format!("You have been kicked from this channel. Reason: {}", reason.clone().unwrap_or_default()),
+            };
+
+            {
+                let reg = state.registry.read().await;
+                reg.send(target_client_id, kick_msg);
+            }
+        }
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::UserKicked {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        user_id: target.id,
+        username: username.to_string(),
+        kicked_by: admin_username,
+        reason,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_list_admins(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let staff = {
+        let admin = state.admin.read().await;
+        admin.list_admins(ch_id)
+    };
+
+    let admins = {
+        let auth = state.auth.read().await;
+        staff
+            .into_iter()
+            .filter_map(|(username, role)| {
+                auth.find_user_by_username(&username)
+                    .map(|user| AdminInfo { user_id: user.id, username: user.username.clone(), role })
+            })
+            .collect()
+    };
+
+    let msg = ServerMessage::AdminList {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        admins,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send(client_id, msg);
+    Ok(())
+}
+
+async fn handle_list_bans(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ViewLogs).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ViewLogs));
+    }
+
+    let bans = {
+        let bans = state.bans.read().await;
+        bans.list_bans(ch_id)
+    };
+
+    let msg = ServerMessage::BanList {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        bans,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send(client_id, msg);
+    Ok(())
+}
+
+async fn handle_view_logs(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    limit: u32,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ViewLogs).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ViewLogs));
+    }
+
+    let query = {
+        let admin = state.admin.read().await;
+        admin.get_logs(ch_id, channel, limit as usize)
+    };
+    let logs = tokio::task::spawn_blocking(move || query.run())
+        .await
+        .map_err(|e| ServerError::Internal(format!("reading admin log: {e}")))?;
+
+    let msg = ServerMessage::LogList {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        logs,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send(client_id, msg);
+    Ok(())
+}
+
+async fn handle_change_channel_type(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    channel_type: ChannelType,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    {
+        let mut channels = state.channels.write().await;
+        channels.set_channel_type(channel, channel_type);
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.set_channel_type(ch_id, channel_type);
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "change_channel_type".to_string(),
+            channel.to_string(),
+            format!("Changed to {:?}", channel_type),
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::ChannelTypeChanged {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        new_type: channel_type,
+        changed_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_set_retention(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    max_age_seconds: Option<u64>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    let retention = match max_age_seconds {
+        Some(0) => {
+            return Err(ServerError::Rejected("Retention must be at least one second".to_string()));
+        }
+        Some(secs) => match i64::try_from(secs).ok().and_then(chrono::Duration::try_seconds) {
+            Some(d) => Some(d),
+            None => {
+                return Err(ServerError::Rejected("Retention too long".to_string()));
+            }
+        },
+        None => None,
+    };
+
+    {
+        let mut channels = state.channels.write().await;
+        channels.set_retention(channel, retention);
+        channels.prune_expired(Utc::now());
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "set_retention".to_string(),
+            channel.to_string(),
+            match max_age_seconds {
+                Some(secs) => format!("Retention set to {}s", secs),
+                None => "Retention cleared".to_string(),
+            },
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::RetentionChanged {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        max_age_seconds,
+        changed_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_set_slow_mode(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    interval_seconds: Option<u64>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    let interval = match interval_seconds {
+        Some(0) | None => None,
+        Some(secs) => match i64::try_from(secs).ok().and_then(chrono::Duration::try_seconds) {
+            Some(d) => Some(d),
+            None => {
+                return Err(ServerError::Rejected("Slow mode interval too long".to_string()));
+            }
+        },
+    };
+    let interval_seconds = interval.map(|d| d.num_seconds() as u64);
+
+    {
+        let mut channels = state.channels.write().await;
+        channels.set_slow_mode(channel, interval);
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "set_slow_mode".to_string(),
+            channel.to_string(),
+            match interval_seconds {
+                Some(secs) => format!("Slow mode set to {}s", secs),
+                None => "Slow mode disabled".to_string(),
+            },
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::SlowModeChanged {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        interval_seconds,
+        changed_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_set_max_members(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    max_members: Option<u32>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    // Lowering the cap never removes anyone; it only stops new joins.
+    let max_members = max_members.filter(|max| *max > 0);
+
+    {
+        let mut channels = state.channels.write().await;
+        channels.set_max_members(channel, max_members);
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "set_max_members".to_string(),
+            channel.to_string(),
+            match max_members {
+                Some(max) => format!("Member limit set to {}", max),
+                None => "Member limit removed".to_string(),
+            },
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::MaxMembersChanged {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        max_members,
+        changed_by: admin_username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_set_welcome(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    welcome: Option<String>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    let welcome = welcome.map(|w| w.trim().to_string()).filter(|w| !w.is_empty());
+    if welcome.as_ref().is_some_and(|w| w.len() > channel::MAX_WELCOME_LEN) {
+        return Err(ServerError::Rejected(format!("Welcome message is limited to {} bytes", channel::MAX_WELCOME_LEN)));
+    }
+
+    {
+        let mut channels = state.channels.write().await;
+        channels.set_welcome(channel, welcome.clone());
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username,
+            "set_welcome".to_string(),
+            channel.to_string(),
+            match welcome {
+                Some(_) => "Welcome message set".to_string(),
+                None => "Welcome message removed".to_string(),
+            },
+        );
+    }
+
+    // Only the admin hears about it; members see the welcome when they join.
+    let reg = state.registry.read().await;
+    reg.send(client_id, ServerMessage::SystemMessage {
+        meta: server_meta(state),
+        text: format!("Welcome message for #{channel} updated"),
+    });
+    Ok(())
+}
+
+async fn handle_rename_channel(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    new_name: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    let new_name = channel::normalize_channel_name(new_name).map_err(ServerError::Rejected)?;
+
+    let res = {
+        let mut channels = state.channels.write().await;
+        channels.rename_channel(channel, &new_name)
+    };
+    res.map_err(ServerError::Rejected)?;
+
+    {
+        let mut reg = state.registry.write().await;
+        reg.rename_channel(channel, &new_name);
+    }
+    {
+        let mut resume = state.resume.write().await;
+        resume.rename_channel(channel, &new_name);
+    }
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.rename_channel(channel, &new_name);
+        admin.log_action(
+            ch_id,
+            &new_name,
+            admin_id,
+            admin_username.clone(),
+            "rename_channel".to_string(),
+            channel.to_string(),
+            format!("Renamed #{} to #{}", channel, new_name),
+        );
+    }
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(&new_name)
+    };
+
+    let msg = ServerMessage::ChannelRenamed {
+        meta: server_meta(state),
+        old_name: channel.to_string(),
+        new_name: new_name.clone(),
+        renamed_by: admin_username.clone(),
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+
+    info!(client_id, channel, new_name, renamed_by = admin_username, "channel renamed");
+    Ok(())
+}
+
+async fn handle_transfer_ownership(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    username: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let role = channel_role(state, ch_id, client_id).await;
+
+    if role != darkrelayprotocol::permissions::Role::SuperAdmin {
+        return Err(ServerError::PermissionDenied("Only the channel SuperAdmin can transfer ownership".to_string()));
+    }
+
+    let target = {
+        let auth = state.auth.read().await;
+        auth.find_user_by_username(username)
+    };
+
+    let Some(target) = target else {
+        return Err(ServerError::NotFound("User"));
+    };
+
+    let (admin_id, admin_username) = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| (u.id, u.username.clone())).unwrap_or_default()
+    };
+
+    if auth::normalize_username(&target.username) == auth::normalize_username(&admin_username) {
+        return Err(ServerError::Rejected("You already own this channel".to_string()));
+    }
+
+    let is_member = {
+        let members = {
+            let channels = state.channels.read().await;
+            channels.members(channel)
+        };
+        let reg = state.registry.read().await;
+        reg.find_clients_by_user_id(target.id).iter().any(|id| members.contains(id))
+    };
+
+    if !is_member {
+        return Err(ServerError::Rejected("New owner must be a member of the channel".to_string()));
+    }
+
+    {
+        let mut admin = state.admin.write().await;
+        admin.transfer_ownership(ch_id, &admin_username, &target.username);
+        admin.log_action(
+            ch_id,
+            channel,
+            admin_id,
+            admin_username.clone(),
+            "transfer_ownership".to_string(),
+            target.username.clone(),
+            format!("Ownership transferred from {} to {}", admin_username, target.username),
+        );
+    }
+
+    info!(client_id, channel, new_owner = target.username, "channel ownership transferred");
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    let msg = ServerMessage::OwnershipTransferred {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        previous_owner: admin_username,
+        new_owner: target.username,
+    };
+
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_delete_channel(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let channel_id = {
+        let channels = state.channels.read().await;
+        channels.get_channel_id(channel)
+    };
+
+    let Some(ch_id) = channel_id else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let role = channel_role(state, ch_id, client_id).await;
+
+    if role != darkrelayprotocol::permissions::Role::SuperAdmin {
+        return Err(ServerError::PermissionDenied("Only SuperAdmin can delete channels".to_string()));
+    }
+
+    let admin_username = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+    };
+
+    let msg = ServerMessage::ChannelDeleted {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        deleted_by: admin_username.clone(),
+    };
+
+    // All at once, registry before channels as everywhere else: a send either
+    // lands and is broadcast before `ChannelDeleted`, or finds the channel
+    // gone. Roles go under the same locks so a channel re-created under this
+    // name can't pick up the deleted one's staff.
+    {
+        let mut reg = state.registry.write().await;
+        let mut channels = state.channels.write().await;
+        // Deleted, or deleted and re-created, since the permission check.
+        if channels.get_channel_id(channel) != Some(ch_id) {
+            return Err(ServerError::NotFound("Channel"));
+        }
+        let members = channels.delete_channel(channel).unwrap_or_default();
+        reg.send_many(&members, &msg);
+        for member_id in &members {
+            reg.leave_channel(*member_id, channel);
+        }
+
+        state.admin.write().await.remove_channel(ch_id, channel);
+        state.polls.write().await.remove_channel(ch_id);
+    }
+
+    info!(client_id, channel, deleted_by = admin_username, "channel deleted");
+    Ok(())
+}
+
+async fn handle_create_poll(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    question: String,
+    options: Vec<String>,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let Some(ch_id) = state.channels.read().await.get_channel_id(channel) else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    let has_permission = channel_permission(state, ch_id, client_id, Permission::ManageChannel).await;
+    if !has_permission {
+        return Err(ServerError::MissingPermission(Permission::ManageChannel));
+    }
+
+    let poll_id = {
+        let mut polls = state.polls.write().await;
+        polls.create(ch_id, &question, &options).map_err(ServerError::Rejected)?
+    };
+
+    let created_by = {
+        let reg = state.registry.read().await;
+        reg.user(client_id).map(|u| u.username.clone()).unwrap_or_default()
+    };
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+
+    info!(client_id, channel, poll_id, "poll created");
+
+    let msg = ServerMessage::PollCreated {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        poll_id,
+        question,
+        options,
+        created_by,
+    };
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn handle_vote(
+    state: &Arc<AppState>,
+    client_id: ClientId,
+    user_authed: bool,
+    channel: &str,
+    poll_id: PollId,
+    option_index: u32,
+) -> Result<(), ServerError> {
+    if !user_authed {
+        return Err(ServerError::NotAuthenticated);
+    }
+
+    let (user, joined) = {
+        let reg = state.registry.read().await;
+        (reg.user(client_id), reg.is_in_channel(client_id, channel))
+    };
+    let Some(user) = user else {
+        return Err(ServerError::Internal("user missing".to_string()));
+    };
+    if !joined {
+        return Err(ServerError::InvalidRequest("not joined to channel".to_string()));
+    }
+    if auth::is_guest(user.id) {
+        return Err(ServerError::PermissionDenied("Permission denied: guests are read-only".to_string()));
+    }
+
+    let Some(ch_id) = state.channels.read().await.get_channel_id(channel) else {
+        return Err(ServerError::NotFound("Channel"));
+    };
+
+    // Votes are per user, so every session of an account shares one vote.
+    let tallies = {
+        let mut polls = state.polls.write().await;
+        polls
+            .vote(ch_id, poll_id, user.id, option_index as usize)
+            .ok_or(ServerError::NotFound("Poll"))?
+            .map_err(ServerError::InvalidRequest)?
+    };
+
+    let members = {
+        let channels = state.channels.read().await;
+        channels.members(channel)
+    };
+    let msg = ServerMessage::PollUpdated {
+        meta: server_meta(state),
+        channel: channel.to_string(),
+        poll_id,
+        tallies,
+    };
+    let reg = state.registry.read().await;
+    reg.send_many(&members, &msg);
+    Ok(())
+}
+
+async fn read_frame<T: DeserializeOwned, R: AsyncRead + Unpin>(reader: &mut R, compression: bool) -> io::Result<T> {
+    let mut header = [0u8; 5];
+    let header = &mut header[..frame::header_len(compression)];
+    reader.read_exact(header).await?;
+    let (flag, len) = frame::parse_header(header, compression)?;
+
+    let mut buf = vec![0u8; len];
+    reader.read_exact(&mut buf).await?;
+    frame::decode_body(flag, &buf)
+}
+
+async fn write_frame<T: Serialize, W: AsyncWrite + Unpin>(writer: &mut W, msg: &T, compression: bool) -> io::Result<()> {
+    let data = frame::encode_frame(msg, compression)?;
+    writer.write_all(&data).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{admin::MAX_LOGS_PER_REQUEST, config::ServerConfig, role_store::RoleStore};
+    use darkrelayprotocol::{metadata::TYPE_KEY, protocol::MAX_USER_KEY_LEN};
+
+    /// `CreateChannel` with nothing but a name and password.
+    const CREATE: Option<ChannelOptions> = Some(ChannelOptions { channel_type: ChannelType::Public, topic: None });
+
+    /// A client's queue as the tests read it: each message unshared from its `Arc`.
+    struct Outbox(mpsc::Receiver<Arc<ServerMessage>>);
+
+    impl Outbox {
+        fn try_recv(&mut self) -> Result<ServerMessage, mpsc::error::TryRecvError> {
+            self.0.try_recv().map(Arc::unwrap_or_clone)
+        }
+
+        async fn recv(&mut self) -> Option<ServerMessage> {
+            self.0.recv().await.map(Arc::unwrap_or_clone)
+        }
+    }
+
+    fn outbox() -> (mpsc::Sender<Arc<ServerMessage>>, Outbox) {
+        let (tx, rx) = mpsc::channel(64);
+        (tx, Outbox(rx))
+    }
+
+    fn connect_user(
+        reg: &mut crate::registry::Registry,
+        client_id: ClientId,
+        username: &str,
+    ) -> Outbox {
+        let (tx, rx) = outbox();
+        reg.register(client_id, tx);
+        reg.set_user(
+            client_id,
+            UserInfo {
+                id: client_id,
+                username: username.to_string(),
+                joined_at: Utc::now(),
+                signing_key: None,
+                public_key: None,
+            },
+        );
+        rx
+    }
+
+    #[tokio::test]
+    async fn test_read_only_channel_rejects_user_send_after_creation() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.join_channel(1, "news");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("news", true, None, ChannelType::ReadOnly, None).unwrap();
+            channels.join(1, 1, "news", None).unwrap();
+        }
+
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "news", b"hi".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert!(rx.try_recv().is_err());
+
+        let channels = state.channels.read().await;
+        assert!(channels.history("news", 10).is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_channel_type_change_applies_to_next_send() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (_alice_rx, _bob_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice = connect_user(&mut reg, 1, "alice");
+            let bob = connect_user(&mut reg, 2, "bob");
+            reg.join_channel(1, "general");
+            reg.join_channel(2, "general");
+            (alice, bob)
+        };
+        let ch_id = {
+            let mut channels = state.channels.write().await;
+            let id = channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            channels.join(2, 2, "general", None).unwrap();
+            id
+        };
+        state.admin.write().await.set_role(ch_id, "bob", darkrelayprotocol::permissions::Role::Admin);
+
+        handle_send_message(&state, 1, true, false, "general", b"before".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        handle_change_channel_type(&state, 2, true, "general", ChannelType::ReadOnly).await.unwrap();
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", b"during".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        handle_send_message(&state, 2, true, false, "general", b"admins only".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        handle_change_channel_type(&state, 2, true, "general", ChannelType::Public).await.unwrap();
+        handle_send_message(&state, 1, true, false, "general", b"after".to_vec(), MessageMetadata::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_public_channel_accepts_user_send() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.join_channel(1, "general");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+        }
+
+        handle_send_message(&state, 1, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::MessageReceived { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_super_admin_names_are_held_for_provisioned_accounts() {
+        let config = ServerConfig {
+            super_admins: std::collections::HashSet::from(["Root".to_string()]),
+            ..ServerConfig::default()
+        };
+        let state = Arc::new(AppState::new(&config));
+        {
+            let mut auth = state.auth.write().await;
+            assert_eq!(auth.register("root".to_string(), None).unwrap_err(), "username is reserved");
+            let (alice, _) = auth.register("alice".to_string(), None).unwrap();
+            assert_eq!(auth.rename(alice.id, "rOOt").unwrap_err(), "username is reserved");
+        }
+
+        let created = state.provision_super_admins(&config.super_admins).await;
+        assert!(state.admin.read().await.is_server_super_admin("ROOT"));
+        let (name, password) = &created[0];
+        assert!(state.auth.read().await.login(name, password).is_ok());
+
+        assert!(state.provision_super_admins(&config.super_admins).await.is_empty(), "only a new account gets a password");
+        assert!(state.auth.read().await.login(name, password).is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limited_send_gets_cooldown() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.join_channel(1, "general");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+        }
+        state.rate_limiter.write().await.set_limits(2, chrono::Duration::seconds(60));
+
+        for _ in 0..2 {
+            handle_send_message(&state, 1, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await.unwrap();
+        }
+
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::MessageReceived { .. })));
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::MessageReceived { .. })));
+        match handle_send_message(&state, 1, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await {
+            Err(ServerError::RateLimited { channel, retry_after_ms }) => {
+                assert_eq!(channel, "general");
+                assert!(retry_after_ms > 0 && retry_after_ms <= 60_000);
+            }
+            other => panic!("expected RateLimited, got {other:?}"),
+        }
+        assert_eq!(state.channels.read().await.history("general", 10).len(), 2);
+
+        // A second session of the same account, opened after the first went
+        // away, still draws on the spent budget.
+        cleanup_disconnect(&state, 1).await;
+        let alice = UserInfo { id: 1, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None };
+        let _rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 2, "alice");
+            reg.set_user(2, alice);
+            reg.join_channel(2, "general");
+            rx
+        };
+        state.channels.write().await.join(2, 2, "general", None).unwrap();
+        assert!(matches!(
+            handle_send_message(&state, 2, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::RateLimited { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_encrypted_session_requires_nonce() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.join_channel(1, "general");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+        }
+
+        let nonce = |hex: &str| MessageMetadata::new().with(NONCE_KEY, hex);
+        for metadata in [MessageMetadata::new(), nonce("00ff"), nonce("not hex")] {
+            assert!(matches!(
+                handle_send_message(&state, 1, true, true, "general", b"plaintext".to_vec(), metadata).await,
+                Err(ServerError::InvalidRequest(_))
+            ));
+        }
+        assert!(state.channels.read().await.history("general", 10).is_empty());
+
+        let valid = hex::encode([7u8; NONCE_LEN]);
+        handle_send_message(&state, 1, true, true, "general", vec![0xab; MIN_CIPHERTEXT_LEN], nonce(&valid)).await.unwrap();
+        match rx.try_recv() {
+            Ok(ServerMessage::MessageReceived { message, .. }) => assert_eq!(message.nonce, Some(vec![7u8; NONCE_LEN])),
+            other => panic!("expected MessageReceived, got {other:?}"),
+        }
+
+        // Before ECDH completes, plaintext is still accepted.
+        handle_send_message(&state, 1, true, false, "general", b"plaintext".to_vec(), MessageMetadata::new()).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::MessageReceived { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_slow_mode_send_gets_cooldown() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.join_channel(1, "general");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            channels.set_slow_mode("general", Some(chrono::Duration::seconds(30)));
+        }
+
+        handle_send_message(&state, 1, true, false, "general", b"a".to_vec(), MessageMetadata::new()).await.unwrap();
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", b"b".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::RateLimited { retry_after_ms, .. }) if retry_after_ms > 29_000
+        ));
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::MessageReceived { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_join_and_send_deliver_in_order() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+
+        handle_join_channel(&state, 1, true, "general".to_string(), None, CREATE).await.unwrap();
+        let ch_id = state.channels.read().await.get_channel_id("general").unwrap();
+        state.channels.write().await.set_slow_mode("general", Some(chrono::Duration::seconds(30)));
+        // The creator manages the channel, so slow mode doesn't apply.
+        for text in ["one", "two"] {
+            handle_send_message(&state, 1, true, false, "general", text.as_bytes().to_vec(), MessageMetadata::new()).await.unwrap();
+        }
+        while alice_rx.try_recv().is_ok() {}
+        assert!(matches!(bob_rx.try_recv(), Ok(ServerMessage::ChannelCreated { .. })));
+
+        handle_join_channel(&state, 2, true, "#General".to_string(), None, None).await.unwrap();
+        match bob_rx.try_recv() {
+            Ok(ServerMessage::JoinSuccess { channel, .. }) => {
+                assert_eq!(channel.id, ch_id);
+                assert_eq!(channel.member_count, 2);
+            }
+            other => panic!("expected JoinSuccess, got {other:?}"),
+        }
+        match bob_rx.try_recv() {
+            Ok(ServerMessage::HistoryChunk { messages, .. }) => assert_eq!(messages.len(), 2),
+            other => panic!("expected HistoryChunk, got {other:?}"),
+        }
+        assert!(bob_rx.try_recv().is_err(), "the joiner isn't told about itself");
+        assert!(matches!(alice_rx.try_recv(), Ok(ServerMessage::UserJoined { user, .. }) if user.username == "bob"));
+
+        handle_send_message(&state, 2, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await.unwrap();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            match rx.try_recv() {
+                Ok(ServerMessage::MessageReceived { message, .. }) => {
+                    assert_eq!(message.username, "bob");
+                    assert_eq!(message.seq, 3);
+                }
+                other => panic!("expected MessageReceived, got {other:?}"),
+            }
+        }
+        assert!(matches!(
+            handle_send_message(&state, 2, true, false, "general", b"again".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::RateLimited { .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_rename_broadcasts_to_channel_members() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (alice, bob) = {
+            let mut auth = state.auth.write().await;
+            (auth.register("alice".to_string(), None).unwrap().0, auth.register("bob".to_string(), None).unwrap().0)
+        };
+
+        let (mut alice_rx, mut bob_rx, mut carol_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice_rx = connect_user(&mut reg, 1, "alice");
+            reg.set_user(1, alice.clone());
+            let bob_rx = connect_user(&mut reg, 2, "bob");
+            reg.set_user(2, bob);
+            let carol_rx = connect_user(&mut reg, 3, "carol");
+            reg.join_channel(1, "general");
+            reg.join_channel(2, "general");
+            (alice_rx, bob_rx, carol_rx)
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            channels.join(2, 2, "general", None).unwrap();
+        }
+
+        handle_rename(&state, 1, true, "alicia").await.unwrap();
+
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            match rx.try_recv() {
+                Ok(ServerMessage::UserRenamed { user_id, old_username, new_username, .. }) => {
+                    assert_eq!(user_id, alice.id);
+                    assert_eq!(old_username, "alice");
+                    assert_eq!(new_username, "alicia");
+                }
+                other => panic!("expected UserRenamed, got {other:?}"),
+            }
+        }
+        assert!(carol_rx.try_recv().is_err(), "only channel peers are told");
+        assert_eq!(state.registry.read().await.user(1).unwrap().username, "alicia");
+        assert!(state.auth.read().await.find_user_by_username("alicia").is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rename_collision_rejected() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let alice = {
+            let mut auth = state.auth.write().await;
+            auth.register("bob".to_string(), None).unwrap();
+            auth.register("alice".to_string(), None).unwrap().0
+        };
+
+        let _rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.set_user(1, alice);
+            rx
+        };
+
+        assert_eq!(
+            handle_rename(&state, 1, true, "Bob").await,
+            Err(ServerError::InvalidRequest("username already exists".to_string()))
+        );
+        assert_eq!(state.registry.read().await.user(1).unwrap().username, "alice");
+    }
+
+    #[tokio::test]
+    async fn test_join_read_only_channel_reports_rules() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "alice")
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("news", true, None, ChannelType::Public, None).unwrap();
+            channels.set_channel_type("news", ChannelType::ReadOnly);
+        }
+
+        handle_join_channel(&state, 1, true, "news".to_string(), None, None).await.unwrap();
+
+        match rx.try_recv() {
+            Ok(ServerMessage::JoinSuccess { channel, rules, .. }) => {
+                assert_eq!(channel.channel_type, ChannelType::ReadOnly);
+                assert_eq!(rules, ChannelType::ReadOnly.description());
+            }
+            other => panic!("expected JoinSuccess, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resync_returns_messages_after_sequence() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "alice")
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            for i in 0..channel::MAX_RESYNC_MESSAGES + 5 {
+                let msg = ChatMessage {
+                    id: 0,
+                    seq: 0,
+                    user_id: 7,
+                    username: "bob".to_string(),
+                    content: format!("msg {i}").into_bytes(),
+                    timestamp: Utc::now(),
+                    nonce: None,
+                    metadata: MessageMetadata::new(),
+                    deleted: false,
+                    edited: false,
+                };
+                channels.add_message("general", msg).unwrap();
+            }
+        }
+        let latest = channel::MAX_RESYNC_MESSAGES as u64 + 5;
+        let mut resync = async |since| {
+            handle_resync(&state, 1, true, "general".to_string(), since).await.unwrap();
+            match rx.try_recv() {
+                Ok(ServerMessage::ResyncChunk { messages, truncated, .. }) => {
+                    (messages.iter().map(|m| m.seq).collect::<Vec<_>>(), truncated)
+                }
+                other => panic!("expected ResyncChunk, got {other:?}"),
+            }
+        };
+
+        // Saw up to seq 52, missed the last three.
+        assert_eq!(resync(latest - 3).await, (vec![latest - 2, latest - 1, latest], false));
+        assert_eq!(resync(latest).await, (Vec::new(), false));
+        assert_eq!(resync(latest + 10).await, (Vec::new(), false));
+
+        let (seqs, truncated) = resync(0).await;
+        assert!(truncated);
+        assert_eq!(seqs, (1..=channel::MAX_RESYNC_MESSAGES as u64).collect::<Vec<_>>());
+
+        assert_eq!(
+            handle_resync(&state, 1, true, "missing".to_string(), 0).await,
+            Err(ServerError::NotFound("Channel"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_resync_needs_membership_like_history() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "mallory")
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("staff", false, Some("pw".to_string()), ChannelType::Private, None).unwrap();
+            let msg = ChatMessage {
+                id: 0,
+                seq: 0,
+                user_id: 7,
+                username: "bob".to_string(),
+                content: b"secret".to_vec(),
+                timestamp: Utc::now(),
+                nonce: None,
+                metadata: MessageMetadata::new(),
+                deleted: false,
+                edited: false,
+            };
+            channels.add_message("staff", msg).unwrap();
+        }
+
+        assert!(matches!(
+            handle_resync(&state, 1, true, "staff".to_string(), 0).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert!(rx.try_recv().is_err(), "nothing from the channel was sent");
+    }
+
+    #[tokio::test]
+    async fn test_poll_votes_tallied_once_per_user() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx, mut bob_phone_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice = connect_user(&mut reg, 1, "alice");
+            let bob = connect_user(&mut reg, 2, "bob");
+            // A second session for bob.
+            let bob_phone = connect_user(&mut reg, 3, "bob");
+            let bob_user = reg.user(2).unwrap();
+            reg.set_user(3, bob_user);
+            for id in 1..=3 {
+                reg.join_channel(id, "general");
+            }
+            (alice, bob, bob_phone)
+        };
+        let ch_id = {
+            let mut channels = state.channels.write().await;
+            let id = channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            for (client, user) in [(1, 1), (2, 2), (3, 2)] {
+                channels.join(client, user, "general", None).unwrap();
+            }
+            id
+        };
+        state.admin.write().await.set_role(ch_id, "alice", Role::Admin);
+        let options = vec!["pizza".to_string(), "sushi".to_string()];
+
+        assert_eq!(
+            handle_create_poll(&state, 2, true, "general", "Lunch?".to_string(), options.clone()).await,
+            Err(ServerError::MissingPermission(Permission::ManageChannel))
+        );
+        handle_create_poll(&state, 1, true, "general", "Lunch?".to_string(), options).await.unwrap();
+        let poll_id = match bob_rx.try_recv() {
+            Ok(ServerMessage::PollCreated { poll_id, question, created_by, .. }) => {
+                assert_eq!(question, "Lunch?");
+                assert_eq!(created_by, "alice");
+                poll_id
+            }
+            other => panic!("expected PollCreated, got {other:?}"),
+        };
+        while alice_rx.try_recv().is_ok() {}
+        while bob_phone_rx.try_recv().is_ok() {}
+
+        let mut tallies_after = async |client, option| {
+            handle_vote(&state, client, true, "general", poll_id, option).await.unwrap();
+            match alice_rx.try_recv() {
+                Ok(ServerMessage::PollUpdated { tallies, .. }) => tallies,
+                other => panic!("expected PollUpdated, got {other:?}"),
+            }
+        };
+        assert_eq!(tallies_after(1, 0).await, vec![1, 0]);
+        assert_eq!(tallies_after(2, 1).await, vec![1, 1]);
+        assert_eq!(tallies_after(3, 1).await, vec![1, 1], "bob's second session shares his vote");
+        assert_eq!(tallies_after(3, 0).await, vec![2, 0], "voting again moves the vote");
+
+        assert!(matches!(
+            handle_vote(&state, 2, true, "general", poll_id, 2).await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert_eq!(
+            handle_vote(&state, 2, true, "general", poll_id + 1, 0).await,
+            Err(ServerError::NotFound("Poll"))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_channel_cap_and_creation_policy() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            connect_user(&mut reg, 2, "root");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.set_max_channels(2);
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+        }
+
+        handle_join_channel(&state, 1, true, "random".to_string(), None, CREATE).await.unwrap();
+        assert_eq!(
+            handle_join_channel(&state, 1, true, "spam1".to_string(), None, CREATE).await,
+            Err(ServerError::JoinRefused {
+                channel: "spam1".to_string(),
+                reason: "server channel limit reached".to_string(),
+            })
+        );
+        assert_eq!(state.channels.read().await.list_all().len(), 2);
+
+        // Existing channels can still be joined and rejoined at the cap.
+        handle_leave_channel(&state, 1, true, "random").await.unwrap();
+        handle_join_channel(&state, 1, true, "random".to_string(), None, None).await.unwrap();
+        handle_join_channel(&state, 1, true, "general".to_string(), None, None).await.unwrap();
+        while rx.try_recv().is_ok() {}
+
+        {
+            let mut channels = state.channels.write().await;
+            channels.set_max_channels(10);
+            channels.set_creation_policy(ChannelCreation::Admins);
+        }
+        state.admin.write().await.set_server_super_admins(std::collections::HashSet::from(["root".to_string()]));
+
+        assert!(matches!(
+            handle_join_channel(&state, 1, true, "mine".to_string(), None, CREATE).await,
+            Err(ServerError::JoinRefused { reason, .. }) if reason == "only server admins can create channels"
+        ));
+        handle_join_channel(&state, 2, true, "mine".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 1, true, "mine".to_string(), None, None).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_guest_reads_public_history_but_cannot_send() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let (tx, rx) = outbox();
+            reg.register(1, tx);
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.ensure_channel("staff", false, Some("pw".to_string()), ChannelType::Private, None).unwrap();
+            let msg = ChatMessage {
+                id: 0,
+                seq: 0,
+                user_id: 7,
+                username: "bob".to_string(),
+                content: b"hello".to_vec(),
+                timestamp: Utc::now(),
+                nonce: None,
+                metadata: MessageMetadata::new(),
+                deleted: false,
+                edited: false,
+            };
+            channels.add_message("general", msg).unwrap();
+        }
+
+        handle_guest_login(&state, 1).await;
+        match rx.try_recv() {
+            Ok(ServerMessage::AuthSuccess { user, resume_token, .. }) => {
+                assert!(auth::is_guest(user.id));
+                assert!(user.username.starts_with("guest-"));
+                assert!(resume_token.is_none());
+            }
+            other => panic!("expected AuthSuccess, got {other:?}"),
+        }
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::ServerCapabilities { .. })));
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::ChannelList { .. })));
+
+        handle_join_channel(&state, 1, true, "general".to_string(), None, None).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::JoinSuccess { .. })));
+        match rx.try_recv() {
+            Ok(ServerMessage::HistoryChunk { messages, .. }) => assert_eq!(messages.len(), 1),
+            other => panic!("expected HistoryChunk, got {other:?}"),
+        }
+        while rx.try_recv().is_ok() {}
+
+        handle_get_history(&state, 1, true, "general".to_string(), 10).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::HistoryChunk { .. })));
+
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::PermissionDenied(reason)) if reason.contains("guests are read-only")
+        ));
+        assert_eq!(state.channels.read().await.history("general", 10).len(), 1);
+
+        assert!(matches!(
+            handle_join_channel(&state, 1, true, "staff".to_string(), Some("pw".to_string()), None).await,
+            Err(ServerError::JoinRefused { .. })
+        ));
+        assert!(matches!(
+            handle_join_channel(&state, 1, true, "brand-new".to_string(), None, CREATE).await,
+            Err(ServerError::JoinRefused { .. })
+        ));
+        assert!(matches!(
+            handle_get_history(&state, 1, true, "staff".to_string(), 10).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_send_allowed_in_every_joined_channel() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            reg.join_channel(1, "general");
+            reg.join_channel(1, "random");
+            rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            for name in ["general", "random"] {
+                channels.ensure_channel(name, true, None, ChannelType::Public, None).unwrap();
+                channels.join(1, 1, name, None).unwrap();
+            }
+        }
+
+        handle_send_message(&state, 1, true, false, "general", b"a".to_vec(), MessageMetadata::new()).await.unwrap();
+        handle_send_message(&state, 1, true, false, "random", b"b".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        for expected in ["general", "random"] {
+            match rx.try_recv() {
+                Ok(ServerMessage::MessageReceived { channel, .. }) => assert_eq!(channel, expected),
+                other => panic!("expected MessageReceived, got {other:?}"),
+            }
+        }
+
+        handle_leave_channel(&state, 1, true, "general").await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::UserLeft { .. })));
+
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", b"c".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_resume_restores_membership_without_join_broadcast() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut bob_rx = {
+            let mut reg = state.registry.write().await;
+            let _alice_rx = connect_user(&mut reg, 1, "alice");
+            let bob_rx = connect_user(&mut reg, 2, "bob");
+            reg.join_channel(1, "general");
+            reg.join_channel(2, "general");
+            bob_rx
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            channels.join(2, 2, "general", None).unwrap();
+        }
+        let alice = state.registry.read().await.user(1).unwrap();
+        let token = state.resume.write().await.issue(1, alice);
+
+        cleanup_disconnect(&state, 1).await;
+        assert!(bob_rx.try_recv().is_err(), "parked session must not announce a leave");
+
+        let (tx, mut new_rx) = outbox();
+        state.registry.write().await.register(3, tx);
+        handle_resume(&state, 3, &token).await.unwrap();
+
+        assert!(matches!(new_rx.try_recv(), Ok(ServerMessage::AuthSuccess { resume_token: Some(_), .. })));
+        assert!(matches!(new_rx.try_recv(), Ok(ServerMessage::ServerCapabilities { .. })));
+        assert!(matches!(new_rx.try_recv(), Ok(ServerMessage::ChannelList { .. })));
+        assert!(matches!(new_rx.try_recv(), Ok(ServerMessage::JoinSuccess { .. })));
+        assert!(matches!(new_rx.try_recv(), Ok(ServerMessage::HistoryChunk { .. })));
+
+        assert!(bob_rx.try_recv().is_err(), "resume must not broadcast UserJoined");
+        assert!(state.channels.read().await.members("general").contains(&3));
+        assert!(state.registry.read().await.is_in_channel(3, "general"));
+    }
+
+    #[tokio::test]
+    async fn test_expired_resume_token_requires_login() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let alice = {
+            let mut reg = state.registry.write().await;
+            let _rx = connect_user(&mut reg, 1, "alice");
+            reg.user(1).unwrap()
+        };
+        let token = {
+            let mut resume = state.resume.write().await;
+            let token = resume.issue(1, alice);
+            let long_ago = Utc::now() - chrono::Duration::seconds(crate::resume::RESUME_GRACE_SECS + 5);
+            resume.detach(1, Vec::new(), long_ago);
+            token
+        };
+
+        let (tx, mut rx) = outbox();
+        state.registry.write().await.register(2, tx);
+        assert_eq!(
+            handle_resume(&state, 2, &token).await,
+            Err(ServerError::AuthFailed("resume token expired".to_string()))
+        );
+        assert!(rx.try_recv().is_err());
+        assert!(state.registry.read().await.user(2).is_none());
+    }
+
+    #[tokio::test]
+    async fn test_list_all_channels_requires_super_admin() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (_alice_rx, mut root_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "root"))
+        };
+        {
+            let mut admin = state.admin.write().await;
+            admin.set_server_super_admins(["root".to_string()].into_iter().collect());
+        }
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("staff", false, Some("pw".to_string()), ChannelType::Private, None).unwrap();
+        }
+
+        assert!(matches!(
+            handle_list_all_channels(&state, 1, true).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+
+        handle_list_all_channels(&state, 2, true).await.unwrap();
+        match root_rx.try_recv() {
+            Ok(ServerMessage::AllChannelList { channels, .. }) => {
+                assert!(channels.iter().any(|c| c.name == "staff"));
+            }
+            other => panic!("expected AllChannelList, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rotate_special_key() {
+        let state = Arc::new(AppState::new(&ServerConfig {
+            special_key: "old-key".to_string(),
+            ..ServerConfig::default()
+        }));
+        let (mut root_rx, _bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "root"), connect_user(&mut reg, 2, "bob"))
+        };
+        {
+            let mut admin = state.admin.write().await;
+            admin.set_server_super_admins(["root".to_string()].into_iter().collect());
+        }
+
+        assert!(matches!(
+            handle_rotate_special_key(&state, 2, true, "bobs-key".to_string()).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert_eq!(*state.special_key.read().await, "old-key");
+
+        handle_rotate_special_key(&state, 1, true, "new-key".to_string()).await.unwrap();
+        assert!(matches!(root_rx.try_recv(), Ok(ServerMessage::SystemMessage { .. })));
+
+        let expected = state.special_key.read().await;
+        let auth = state.auth.read().await;
+        assert!(!auth.verify_special_key(&expected, "old-key"));
+        assert!(auth.verify_special_key(&expected, "new-key"));
+    }
+
+    /// The MOTD line a fresh login of alice on `client_id` receives, if any.
+    async fn login_motd(state: &Arc<AppState>, client_id: ClientId, password: &str) -> Option<String> {
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            let (tx, rx) = outbox();
+            reg.register(client_id, tx);
+            rx
+        };
+        handle_login(state, client_id, "alice", password, PublishedKeys::default()).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::AuthSuccess { .. })));
+        std::iter::from_fn(|| rx.try_recv().ok()).find_map(|msg| match msg {
+            ServerMessage::SystemMessage { text, .. } => text.strip_prefix(MOTD_PREFIX).map(str::to_string),
+            _ => None,
+        })
+    }
+
+    #[tokio::test]
+    async fn test_login_delivers_current_motd() {
+        let state = Arc::new(AppState::new(&ServerConfig {
+            motd: Some("  Maintenance Sunday 02:00 UTC\n".to_string()),
+            ..ServerConfig::default()
+        }));
+        let password = {
+            let mut auth = state.auth.write().await;
+            auth.register("alice".to_string(), None).unwrap().1.unwrap()
+        };
+        let mut root_rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "root")
+        };
+        state.admin.write().await.set_server_super_admins(["root".to_string()].into_iter().collect());
+
+        assert_eq!(login_motd(&state, 2, &password).await.as_deref(), Some("Maintenance Sunday 02:00 UTC"));
+
+        assert!(matches!(
+            handle_set_motd(&state, 2, true, Some("alice was here".to_string())).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        handle_set_motd(&state, 1, true, Some("Be kind".to_string())).await.unwrap();
+        assert!(matches!(root_rx.try_recv(), Ok(ServerMessage::SystemMessage { .. })));
+        assert_eq!(login_motd(&state, 3, &password).await.as_deref(), Some("Be kind"));
+
+        assert!(matches!(handle_set_motd(&state, 1, true, None).await, Err(ServerError::Rejected(_))), "no file to reload");
+        handle_set_motd(&state, 1, true, Some(" ".to_string())).await.unwrap();
+        assert_eq!(login_motd(&state, 4, &password).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_channel_creator_is_super_admin() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "alice")
+        };
+
+        handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::JoinSuccess { .. })));
+
+        let ch_id = state.channels.read().await.get_channel_id("project").unwrap();
+        assert_eq!(state.admin.read().await.get_role(ch_id, "alice"), Role::SuperAdmin);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_ban_blocks_racing_rejoin_and_new_connection() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        {
+            let mut auth = state.auth.write().await;
+            auth.register("alice".to_string(), None).unwrap();
+            auth.register("bob".to_string(), None).unwrap();
+        }
+        let (_alice_rx, _bob_rx, _bob_second_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice_rx = connect_user(&mut reg, 1, "alice");
+            let bob_rx = connect_user(&mut reg, 2, "bob");
+            // A second connection for bob, with a client id of its own.
+            let second_rx = connect_user(&mut reg, 7, "bob");
+            let bob = reg.user(2).unwrap();
+            reg.set_user(7, bob);
+            (alice_rx, bob_rx, second_rx)
+        };
+        handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+
+        for _ in 0..20 {
+            handle_join_channel(&state, 2, true, "project".to_string(), None, None).await.unwrap();
+
+            let ban = tokio::spawn({
+                let state = Arc::clone(&state);
+                async move { handle_ban_user(&state, 1, true, "project", "bob", None, None).await }
+            });
+            let rejoin = tokio::spawn({
+                let state = Arc::clone(&state);
+                async move { handle_join_channel(&state, 7, true, "project".to_string(), None, None).await }
+            });
+            ban.await.unwrap().unwrap();
+            let _ = rejoin.await.unwrap();
+
+            let members = state.channels.read().await.members("project");
+            assert!(!members.contains(&2) && !members.contains(&7), "banned user still in channel: {members:?}");
+            assert!(!state.registry.read().await.is_in_channel(7, "project"));
+
+            handle_unban_user(&state, 1, true, "project", "bob").await.unwrap();
+        }
+
+        handle_ban_user(&state, 1, true, "project", "bob", None, None).await.unwrap();
+        assert!(matches!(
+            handle_join_channel(&state, 7, true, "project".to_string(), None, None).await,
+            Err(ServerError::Banned { until: None, .. })
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_allowed_channels_lock_the_namespace() {
+        let allowed = channel::parse_channel_list("support, #News");
+        let state = Arc::new(AppState::new(&ServerConfig { allowed_channels: Some(allowed), ..ServerConfig::default() }));
+        state.open_startup_channels().await;
+        let _rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "alice")
+        };
+        let not_allowed = |name: &str| {
+            Err(ServerError::JoinRefused { channel: name.to_string(), reason: channel::CHANNEL_NOT_ALLOWED.to_string() })
+        };
+
+        assert_eq!(state.channels.read().await.get_channel_id("general"), None, "only the listed channels open");
+        handle_join_channel(&state, 1, true, "#Support".to_string(), None, None).await.unwrap();
+        handle_join_channel(&state, 1, true, "news".to_string(), None, None).await.unwrap();
+        assert_eq!(handle_join_channel(&state, 1, true, "general".to_string(), None, None).await, not_allowed("general"));
+        assert_eq!(handle_join_channel(&state, 1, true, "mine".to_string(), None, CREATE).await, not_allowed("mine"));
+        assert_eq!(
+            state.channels.write().await.rename_channel("support", "help"),
+            Err(channel::CHANNEL_NOT_ALLOWED.to_string())
+        );
+        assert_eq!(state.channels.read().await.list_all().len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_send_racing_channel_delete() {
+        let state = Arc::new(AppState::new(&ServerConfig { rate_limit: (1000, 1), ..ServerConfig::default() }));
+        let (_alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+
+        for i in 0..20 {
+            handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+            handle_join_channel(&state, 2, true, "project".to_string(), None, None).await.unwrap();
+            while bob_rx.try_recv().is_ok() {}
+
+            let delete = tokio::spawn({
+                let state = Arc::clone(&state);
+                async move { handle_delete_channel(&state, 1, true, "project").await }
+            });
+            let send = tokio::spawn({
+                let state = Arc::clone(&state);
+                // A different size each round so the spam check stays quiet.
+                let content = vec![b'x'; i + 1];
+                async move { handle_send_message(&state, 2, true, false, "project", content, MessageMetadata::new()).await }
+            });
+            delete.await.unwrap().unwrap();
+            if let Err(e) = send.await.unwrap() {
+                let reply = e.into_message(MessageMeta::new(0, Utc::now()), None);
+                assert!(matches!(reply, ServerMessage::ProtocolError { .. }), "{reply:?}");
+            }
+
+            assert_eq!(state.channels.read().await.get_channel_id("project"), None);
+            assert!(!state.registry.read().await.is_in_channel(2, "project"));
+            let received: Vec<_> = std::iter::from_fn(|| bob_rx.try_recv().ok()).collect();
+            let deleted_at = received.iter().position(|m| matches!(m, ServerMessage::ChannelDeleted { .. }));
+            assert_eq!(deleted_at, Some(received.len() - 1), "nothing may follow the delete: {received:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_transfer_ownership() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        {
+            let mut auth = state.auth.write().await;
+            auth.register("alice".to_string(), None).unwrap();
+            auth.register("bob".to_string(), None).unwrap();
+        }
+
+        handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 2, true, "project".to_string(), None, None).await.unwrap();
+        while alice_rx.try_recv().is_ok() {}
+        while bob_rx.try_recv().is_ok() {}
+
+        // Bob is only a member, so he cannot take the channel for himself.
+        assert!(matches!(
+            handle_transfer_ownership(&state, 2, true, "project", "bob").await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+
+        handle_transfer_ownership(&state, 1, true, "project", "bob").await.unwrap();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            match rx.try_recv() {
+                Ok(ServerMessage::OwnershipTransferred { previous_owner, new_owner, .. }) => {
+                    assert_eq!(previous_owner, "alice");
+                    assert_eq!(new_owner, "bob");
+                }
+                other => panic!("expected OwnershipTransferred, got {other:?}"),
+            }
+        }
+
+        let ch_id = state.channels.read().await.get_channel_id("project").unwrap();
+        let admin = state.admin.read().await;
+        assert_eq!(admin.get_role(ch_id, "alice"), Role::Admin);
+        assert_eq!(admin.get_role(ch_id, "bob"), Role::SuperAdmin);
+        assert_eq!(admin.get_logs(ch_id, "project", 1).run()[0].action, "transfer_ownership");
+    }
+
+    #[tokio::test]
+    async fn test_view_logs_names_the_user_and_caps_the_limit() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut laptop_rx = {
+            let mut reg = state.registry.write().await;
+            let rx = connect_user(&mut reg, 1, "alice");
+            let (tx, _) = outbox();
+            reg.register(7, tx);
+            let alice = reg.user(1).unwrap();
+            reg.set_user(7, alice);
+            rx
+        };
+        handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+
+        // A second session of alice's, on a client id that isn't her user id.
+        for n in 0..=MAX_LOGS_PER_REQUEST as u32 {
+            handle_set_max_members(&state, 7, true, "project", Some(n + 10)).await.unwrap();
+        }
+        while laptop_rx.try_recv().is_ok() {}
+
+        handle_view_logs(&state, 1, true, "project", u32::MAX).await.unwrap();
+        loop {
+            match laptop_rx.try_recv() {
+                Ok(ServerMessage::LogList { logs, .. }) => {
+                    assert_eq!(logs.len(), MAX_LOGS_PER_REQUEST);
+                    assert!(logs.iter().all(|entry| entry.user_id == 1 && entry.username == "alice"));
+                    break;
+                }
+                Ok(_) => {}
+                other => panic!("expected LogList, got {other:?}"),
+            }
+        }
+    }
+
+    /// Alice logs in on client 1, then again on client 2 under `policy`.
+    async fn duplicate_login(
+        policy: DuplicateLogin,
+    ) -> (Arc<AppState>, Outbox, Outbox, Arc<tokio::sync::Notify>, Result<(), ServerError>) {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let password = {
+            let mut auth = state.auth.write().await;
+            auth.register("alice".to_string(), None).unwrap().1.unwrap()
+        };
+        let (mut first_rx, second_rx, first_disconnect) = {
+            let mut reg = state.registry.write().await;
+            reg.set_duplicate_login(policy);
+            let (tx1, rx1) = outbox();
+            let (tx2, rx2) = outbox();
+            let disconnect = reg.register(1, tx1);
+            reg.register(2, tx2);
+            (rx1, rx2, disconnect)
+        };
+
+        handle_login(&state, 1, "alice", &password, PublishedKeys::default()).await.unwrap();
+        while first_rx.try_recv().is_ok() {}
+
+        let admitted = handle_login(&state, 2, "alice", &password, PublishedKeys::default()).await;
+        (state, first_rx, second_rx, first_disconnect, admitted)
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_login_rejected() {
+        let (state, mut first_rx, mut second_rx, first_disconnect, admitted) =
+            duplicate_login(DuplicateLogin::Reject).await;
+
+        assert_eq!(admitted, Err(ServerError::AuthFailed("already logged in".to_string())));
+        assert!(second_rx.try_recv().is_err());
+
+        assert!(first_rx.try_recv().is_err());
+        assert!(time::timeout(Duration::from_millis(50), first_disconnect.notified()).await.is_err());
+        let reg = state.registry.read().await;
+        assert_eq!(reg.find_clients_by_user_id(1), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_login_kicks_older_session() {
+        let (state, mut first_rx, mut second_rx, first_disconnect, admitted) =
+            duplicate_login(DuplicateLogin::KickOld).await;
+
+        assert_eq!(admitted, Ok(()));
+        assert!(matches!(second_rx.try_recv(), Ok(ServerMessage::AuthSuccess { .. })));
+
+        assert!(matches!(first_rx.try_recv(), Ok(ServerMessage::SystemMessage { .. })));
+        time::timeout(Duration::from_millis(50), first_disconnect.notified())
+            .await
+            .expect("older session should be closed");
+
+        // Once the old connection is torn down only the new session remains.
+        cleanup_disconnect(&state, 1).await;
+        let reg = state.registry.read().await;
+        assert_eq!(reg.find_clients_by_user_id(1), vec![2]);
+    }
+
+    #[tokio::test]
+    async fn test_dm_resolves_recipient_by_username() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (alice, bob) = {
+            let mut auth = state.auth.write().await;
+            (auth.register("alice".to_string(), None).unwrap().0, auth.register("Bob".to_string(), None).unwrap().0)
+        };
+
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, alice.id, "alice"), connect_user(&mut reg, bob.id, "Bob"))
+        };
+
+        handle_send_dm(&state, alice.id, true, "bob", b"hi".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        for rx in [&mut bob_rx, &mut alice_rx] {
+            match rx.try_recv() {
+                Ok(ServerMessage::DMReceived { recipient, message, .. }) => {
+                    assert_eq!(recipient, "Bob");
+                    assert_eq!(message.user_id, alice.id);
+                    assert_eq!(message.content, b"hi");
+                    assert_ne!(message.id, 0);
+                }
+                other => panic!("expected DMReceived, got {other:?}"),
+            }
+        }
+        match alice_rx.try_recv() {
+            Ok(ServerMessage::DMDeliveryStatus { delivered, .. }) => assert!(delivered),
+            other => panic!("expected DMDeliveryStatus, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_dm_to_unknown_user_rejected() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let alice = state.auth.write().await.register("alice".to_string(), None).unwrap().0;
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, alice.id, "alice")
+        };
+
+        assert_eq!(
+            handle_send_dm(&state, alice.id, true, "nobody", b"hi".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::InvalidRequest("unknown user: nobody".to_string()))
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_disconnect_log_fields() {
+        use darkrelayclient::log_capture::LogCapture;
+
+        let capture = LogCapture::default();
+        let subscriber = tracing_subscriber::fmt().json().with_writer(capture.clone()).finish();
+        let stats = ConnectionStats::default();
+        stats.frames_read.store(7, Ordering::Relaxed);
+        stats.frames_written.store(9, Ordering::Relaxed);
+        let user = UserInfo { id: 3, username: "alice".to_string(), joined_at: Utc::now(), signing_key: None, public_key: None };
+
+        tracing::subscriber::with_default(subscriber, || {
+            log_disconnect(5, "203.0.113.7:4000".parse().unwrap(), Duration::from_millis(1500), &stats, Some(&user));
+        });
+
+        let output = capture.contents();
+        let record: serde_json::Value = serde_json::from_str(output.lines().next().unwrap()).unwrap();
+        let fields = &record["fields"];
+        assert_eq!(fields["message"], "client disconnected");
+        assert_eq!(fields["client_id"], 5);
+        assert_eq!(fields["peer_addr"], "203.0.113.7:4000");
+        assert_eq!(fields["duration_ms"], 1500);
+        assert_eq!(fields["frames_read"], 7);
+        assert_eq!(fields["frames_written"], 9);
+        assert_eq!(fields["user_id"], 3);
+        assert_eq!(fields["username"], "alice");
+    }
+
+    #[tokio::test]
+    async fn test_delete_dm_by_participant_only() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (alice, bob, carol) = {
+            let mut auth = state.auth.write().await;
+            (
+                auth.register("alice".to_string(), None).unwrap().0,
+                auth.register("bob".to_string(), None).unwrap().0,
+                auth.register("carol".to_string(), None).unwrap().0,
+            )
+        };
+        let (mut alice_rx, mut bob_rx, mut carol_rx) = {
+            let mut reg = state.registry.write().await;
+            (
+                connect_user(&mut reg, alice.id, "alice"),
+                connect_user(&mut reg, bob.id, "bob"),
+                connect_user(&mut reg, carol.id, "carol"),
+            )
+        };
+
+        handle_send_dm(&state, alice.id, true, "bob", b"oops".to_vec(), MessageMetadata::new()).await.unwrap();
+        let dm_id = match bob_rx.try_recv() {
+            Ok(ServerMessage::DMReceived { message, .. }) => message.id,
+            other => panic!("expected DMReceived, got {other:?}"),
+        };
+        while alice_rx.try_recv().is_ok() {}
+
+        assert!(matches!(
+            handle_delete_dm(&state, carol.id, true, dm_id).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert!(state.dms.read().await.participants(dm_id).is_some());
+
+        handle_delete_dm(&state, alice.id, true, dm_id).await.unwrap();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            match rx.try_recv() {
+                Ok(ServerMessage::DMDeleted { dm_id: id, deleted_by, .. }) => {
+                    assert_eq!((id, deleted_by.as_str()), (dm_id, "alice"));
+                }
+                other => panic!("expected DMDeleted, got {other:?}"),
+            }
+        }
+        assert!(carol_rx.try_recv().is_err());
+        assert_eq!(handle_delete_dm(&state, bob.id, true, dm_id).await, Err(ServerError::NotFound("DM")));
+    }
+
+    #[tokio::test]
+    async fn test_dm_to_offline_user_is_held_until_login() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (alice, bob) = {
+            let mut auth = state.auth.write().await;
+            (auth.register("alice".to_string(), None).unwrap().0, auth.register("bob".to_string(), None).unwrap().0)
+        };
+        let mut alice_rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, alice.id, "alice")
+        };
+
+        handle_send_dm(&state, alice.id, true, "bob", b"later".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        assert!(matches!(alice_rx.try_recv(), Ok(ServerMessage::DMReceived { .. })));
+        let dm_id = match alice_rx.try_recv() {
+            Ok(ServerMessage::DMDeliveryStatus { dm_id, delivered, .. }) => {
+                assert!(!delivered);
+                dm_id
+            }
+            other => panic!("expected DMDeliveryStatus, got {other:?}"),
+        };
+
+        let mut bob_rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, bob.id, "bob")
+        };
+        flush_undelivered_dms(&state, bob.id, &bob).await;
+
+        match bob_rx.try_recv() {
+            Ok(ServerMessage::DMReceived { message, .. }) => {
+                assert_eq!(message.id, dm_id);
+                assert_eq!(message.content, b"later");
+            }
+            other => panic!("expected DMReceived, got {other:?}"),
+        }
+        match alice_rx.try_recv() {
+            Ok(ServerMessage::DMDeliveryStatus { dm_id: id, delivered, .. }) => {
+                assert_eq!(id, dm_id);
+                assert!(delivered);
+            }
+            other => panic!("expected DMDeliveryStatus, got {other:?}"),
+        }
+
+        // Already delivered; a second login gets nothing.
+        flush_undelivered_dms(&state, bob.id, &bob).await;
+        assert!(bob_rx.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_list_connections_shows_channels() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        {
+            let mut admin = state.admin.write().await;
+            admin.set_server_super_admins(["root".to_string()].into_iter().collect());
+        }
+
+        let (mut root_rx, _bob_rx) = {
+            let mut reg = state.registry.write().await;
+            let root_rx = connect_user(&mut reg, 1, "root");
+            let bob_rx = connect_user(&mut reg, 2, "bob");
+            reg.set_peer_addr(1, "203.0.113.7:50000".parse().unwrap());
+            reg.set_client_info(1, Some("darkrelayclient".to_string()), Some("0.3.0".to_string()));
+            reg.join_channel(1, "general");
+            reg.join_channel(2, "general");
+            reg.join_channel(2, "dev");
+            (root_rx, bob_rx)
+        };
+
+        assert!(matches!(
+            handle_list_connections(&state, 2, true).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+
+        handle_list_connections(&state, 1, true).await.unwrap();
+
+        match root_rx.try_recv() {
+            Ok(ServerMessage::ConnectionList { connections, .. }) => {
+                let summary: Vec<_> = connections
+                    .iter()
+                    .map(|c| (c.client_id, c.username.as_deref(), c.current_channel.as_deref()))
+                    .collect();
+                assert_eq!(summary, [(1, Some("root"), Some("general")), (2, Some("bob"), Some("dev"))]);
+                assert_eq!(connections[0].peer_addr.unwrap().to_string(), "203.0.113.7:50000");
+                assert_eq!(connections[1].peer_addr, None);
+                assert_eq!(connections[0].client_name.as_deref(), Some("darkrelayclient"));
+                assert_eq!(connections[0].client_version.as_deref(), Some("0.3.0"));
+                assert_eq!(connections[1].client_name, None);
+            }
+            other => panic!("expected ConnectionList, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_must_match_login() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (alice_pw, bob_pw) = {
+            let mut auth = state.auth.write().await;
+            (auth.register("alice".to_string(), None).unwrap().1.unwrap(), auth.register("bob".to_string(), None).unwrap().1.unwrap())
+        };
+
+        let (tx, mut rx) = outbox();
+        {
+            let mut reg = state.registry.write().await;
+            reg.register(1, tx);
+            reg.set_cert_subject(1, "Alice".to_string());
+        }
+
+        assert_eq!(
+            handle_login(&state, 1, "bob", &bob_pw, PublishedKeys::default()).await,
+            Err(ServerError::AuthFailed("client certificate does not match this user".to_string()))
+        );
+
+        handle_login(&state, 1, "alice", &alice_pw, PublishedKeys::default()).await.unwrap();
+        assert!(matches!(rx.try_recv(), Ok(ServerMessage::AuthSuccess { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_client_cert_without_common_name_is_refused() {
+        let config = ServerConfig { client_ca: Some(std::path::PathBuf::from("ca.pem")), ..ServerConfig::default() };
+        let state = Arc::new(AppState::new(&config));
+        let alice_pw = state.auth.write().await.register("alice".to_string(), None).unwrap().1.unwrap();
+
+        let (tx, mut rx) = outbox();
+        state.registry.write().await.register(1, tx);
+
+        assert_eq!(
+            handle_login(&state, 1, "alice", &alice_pw, PublishedKeys::default()).await,
+            Err(ServerError::AuthFailed("client certificate does not name a user".to_string()))
+        );
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_oversized_name_fields_rejected() {
+        let send = |channel: String| ClientMessage::SendMessage {
+            meta: MessageMeta::new(1, Utc::now()),
+            channel,
+            content: b"hi".to_vec(),
+            metadata: MessageMetadata::new(),
+        };
+        assert_eq!(check_name_fields(&send(format!("#{}", "a".repeat(32)))), Ok(()));
+        assert_eq!(check_name_fields(&send("a".repeat(MAX_NAME_FIELD_LEN))), Ok(()));
+        assert_eq!(
+            check_name_fields(&send("a".repeat(MAX_NAME_FIELD_LEN + 1))),
+            Err("channel name too long".to_string())
+        );
+
+        let kick = ClientMessage::KickUser {
+            meta: MessageMeta::new(2, Utc::now()),
+            channel: "general".to_string(),
+            username: "x".repeat(4 * 1024 * 1024),
+            reason: None,
+        };
+        assert_eq!(check_name_fields(&kick), Err("username too long".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_rename_channel_updates_members() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+
+        handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 2, true, "project".to_string(), None, None).await.unwrap();
+        handle_join_channel(&state, 2, true, "other".to_string(), None, CREATE).await.unwrap();
+        while alice_rx.try_recv().is_ok() {}
+        while bob_rx.try_recv().is_ok() {}
+
+        assert_eq!(
+            handle_rename_channel(&state, 2, true, "project", "renamed").await,
+            Err(ServerError::MissingPermission(Permission::ManageChannel))
+        );
+
+        assert_eq!(
+            handle_rename_channel(&state, 1, true, "project", "other").await,
+            Err(ServerError::Rejected("channel already exists".to_string()))
+        );
+
+        handle_rename_channel(&state, 1, true, "project", "#Renamed").await.unwrap();
+        for rx in [&mut alice_rx, &mut bob_rx] {
+            match rx.try_recv() {
+                Ok(ServerMessage::ChannelRenamed { old_name, new_name, renamed_by, .. }) => {
+                    assert_eq!((old_name.as_str(), new_name.as_str(), renamed_by.as_str()), ("project", "renamed", "alice"));
+                }
+                other => panic!("expected ChannelRenamed, got {other:?}"),
+            }
+        }
+
+        let reg = state.registry.read().await;
+        assert_eq!(reg.channels(2), ["renamed", "other"], "tab position is kept");
+        assert!(reg.is_in_channel(1, "renamed") && !reg.is_in_channel(1, "project"));
+
+        let ch_id = state.channels.read().await.get_channel_id("renamed").unwrap();
+        assert_eq!(state.admin.read().await.get_role(ch_id, "alice"), Role::SuperAdmin);
+    }
+
+    #[tokio::test]
+    async fn test_disconnect_is_acknowledged_before_cleanup() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "alice")
+        };
+        handle_join_channel(&state, 1, true, "general".to_string(), None, CREATE).await.unwrap();
+        while rx.try_recv().is_ok() {}
+
+        acknowledge_disconnect(&state, 1).await;
+        let user = cleanup_disconnect(&state, 1).await;
+        assert_eq!(user.map(|u| u.username), Some("alice".to_string()));
+
+        assert!(matches!(rx.recv().await, Some(ServerMessage::DisconnectAck { .. })));
+        assert!(rx.recv().await.is_none(), "the client's queue closes after the ack");
+        assert!(!state.channels.read().await.members("general").contains(&1));
+    }
+
+    #[tokio::test]
+    async fn test_repeated_identical_sends_mute_non_admins() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, _bob_rx) = {
+            let mut reg = state.registry.write().await;
+            let rxs = (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"));
+            reg.join_channel(1, "general");
+            reg.join_channel(2, "general");
+            rxs
+        };
+        let ch_id = {
+            let mut channels = state.channels.write().await;
+            let ch_id = channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            channels.join(2, 2, "general", None).unwrap();
+            ch_id
+        };
+        state.admin.write().await.set_role(ch_id, "bob", Role::Admin);
+        state.rate_limiter.write().await.set_limits(100, chrono::Duration::seconds(1));
+
+        for text in ["hello", "how are you", "hello", "bye"] {
+            handle_send_message(&state, 1, true, false, "general", text.as_bytes().to_vec(), MessageMetadata::new()).await.unwrap();
+        }
+        for _ in 0..6 {
+            handle_send_message(&state, 2, true, false, "general", b"BUY NOW".to_vec(), MessageMetadata::new()).await.unwrap();
+        }
+        while alice_rx.try_recv().is_ok() {}
+
+        for _ in 0..3 {
+            handle_send_message(&state, 1, true, false, "general", b"BUY NOW".to_vec(), MessageMetadata::new()).await.unwrap();
+        }
+        while alice_rx.try_recv().is_ok() {}
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", b"BUY NOW".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::RateLimited { retry_after_ms: 60_000, .. })
+        ));
+        assert!(matches!(alice_rx.try_recv(), Ok(ServerMessage::SystemMessage { text, .. }) if text.contains("muted")));
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", b"sorry".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::RateLimited { .. })
+        ), "the mute covers every message in the channel");
+        assert_eq!(state.channels.read().await.history("general", 20).len(), 13);
+    }
+
+    #[tokio::test]
+    async fn test_capabilities_follow_auth_success() {
+        let config = ServerConfig { max_message_len: 4096, ..ServerConfig::default() };
+        let state = Arc::new(AppState::new(&config));
+        let (mut alice_rx, mut guest_rx) = {
+            let mut reg = state.registry.write().await;
+            let (alice_tx, alice_rx) = outbox();
+            let (guest_tx, guest_rx) = outbox();
+            reg.register(1, alice_tx);
+            reg.register(2, guest_tx);
+            (alice_rx, guest_rx)
+        };
+
+        handle_register(&state, 1, "alice".to_string(), None, PublishedKeys::default()).await.unwrap();
+        handle_guest_login(&state, 2).await;
+
+        let mut advertised = Vec::new();
+        for rx in [&mut alice_rx, &mut guest_rx] {
+            assert!(matches!(rx.try_recv(), Ok(ServerMessage::AuthSuccess { .. })));
+            match rx.try_recv() {
+                Ok(ServerMessage::ServerCapabilities { features, max_frame_len, max_message_len, .. }) => {
+                    assert_eq!(max_frame_len as usize, frame::MAX_FRAME_LEN);
+                    assert_eq!(max_message_len, 4096);
+                    advertised.push(features);
+                }
+                other => panic!("expected ServerCapabilities, got {other:?}"),
+            }
+        }
+        assert_eq!(advertised[0], ["compression", "resync", "dms", "polls", "my_channels"]);
+        assert_eq!(advertised[1], ["compression", "resync"], "guests can't DM or vote");
+
+        // The advertised limit is the one enforced.
+        state.registry.write().await.join_channel(1, "general");
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+        }
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "general", vec![b'a'; max_content_len(4096) + 1], MessageMetadata::new()).await,
+            Err(ServerError::MessageTooLong { max: 4096 })
+        ));
+        handle_send_message(&state, 1, true, false, "general", vec![b'a'; max_content_len(4096)], MessageMetadata::new()).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_read_only_history_is_readable_without_joining() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let mut rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 1, "alice")
+        };
+        {
+            let mut channels = state.channels.write().await;
+            for (name, channel_type) in [("news", ChannelType::ReadOnly), ("general", ChannelType::Public)] {
+                channels.ensure_channel(name, true, None, channel_type, None).unwrap();
+                let msg = ChatMessage {
+                    id: 0,
+                    seq: 0,
+                    user_id: 7,
+                    username: "bob".to_string(),
+                    content: b"hello".to_vec(),
+                    timestamp: Utc::now(),
+                    nonce: None,
+                    metadata: MessageMetadata::new(),
+                    deleted: false,
+                    edited: false,
+                };
+                channels.add_message(name, msg).unwrap();
+            }
+            channels.ensure_channel("staff-news", false, Some("pw".to_string()), ChannelType::ReadOnly, None).unwrap();
+        }
+
+        handle_get_history(&state, 1, true, "news".to_string(), 10).await.unwrap();
+        match rx.try_recv() {
+            Ok(ServerMessage::HistoryChunk { channel, messages, .. }) => {
+                assert_eq!(channel, "news");
+                assert_eq!(messages.len(), 1);
+            }
+            other => panic!("expected HistoryChunk, got {other:?}"),
+        }
+        assert!(!state.channels.read().await.is_member("news", 1), "reading doesn't join");
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "news", b"hi".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+
+        for name in ["general", "staff-news"] {
+            assert!(matches!(
+                handle_get_history(&state, 1, true, name.to_string(), 10).await,
+                Err(ServerError::PermissionDenied(_))
+            ), "{name} needs membership");
+        }
+        assert!(matches!(
+            handle_get_history(&state, 1, true, "nowhere".to_string(), 10).await,
+            Err(ServerError::NotFound("Channel"))
+        ));
+
+        // Joining lets a plain user read but still not post.
+        handle_join_channel(&state, 1, true, "news".to_string(), None, None).await.unwrap();
+        assert!(matches!(
+            handle_send_message(&state, 1, true, false, "news", b"hi".to_vec(), MessageMetadata::new()).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert_eq!(state.channels.read().await.history("news", 10).len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_welcome_goes_only_to_the_joiner() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+
+        handle_join_channel(&state, 1, true, "lobby".to_string(), None, CREATE).await.unwrap();
+        assert!(matches!(
+            handle_set_welcome(&state, 2, true, "lobby", Some("hi".to_string())).await,
+            Err(ServerError::MissingPermission(Permission::ManageChannel))
+        ));
+        assert!(matches!(
+            handle_set_welcome(&state, 1, true, "lobby", Some("x".repeat(channel::MAX_WELCOME_LEN + 1))).await,
+            Err(ServerError::Rejected(_))
+        ));
+        handle_set_welcome(&state, 1, true, "lobby", Some(" Read the rules at example.org \n".to_string())).await.unwrap();
+        while alice_rx.try_recv().is_ok() {}
+        assert!(matches!(bob_rx.try_recv(), Ok(ServerMessage::ChannelCreated { .. })));
+
+        handle_join_channel(&state, 2, true, "lobby".to_string(), None, None).await.unwrap();
+        assert!(matches!(bob_rx.try_recv(), Ok(ServerMessage::JoinSuccess { .. })));
+        assert!(matches!(bob_rx.try_recv(), Ok(ServerMessage::HistoryChunk { .. })));
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ServerMessage::SystemMessage { text, .. }) if text == "Read the rules at example.org"
+        ));
+        assert!(bob_rx.try_recv().is_err());
+        assert!(matches!(alice_rx.try_recv(), Ok(ServerMessage::UserJoined { .. })));
+        assert!(alice_rx.try_recv().is_err(), "existing members don't see the welcome");
+
+        // The welcome moves with a rename and can be cleared.
+        handle_rename_channel(&state, 1, true, "lobby", "hall").await.unwrap();
+        assert_eq!(state.channels.read().await.welcome("hall").as_deref(), Some("Read the rules at example.org"));
+        handle_set_welcome(&state, 1, true, "hall", Some("  ".to_string())).await.unwrap();
+        assert_eq!(state.channels.read().await.welcome("hall"), None);
+    }
+
+    #[tokio::test]
+    async fn test_presence_goes_to_channel_peers_once() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx, mut carol_rx) = {
+            let mut reg = state.registry.write().await;
+            let rxs = (
+                connect_user(&mut reg, 1, "alice"),
+                connect_user(&mut reg, 2, "bob"),
+                connect_user(&mut reg, 3, "carol"),
+            );
+            reg.join_channel(1, "general");
+            reg.join_channel(1, "dev");
+            reg.join_channel(2, "general");
+            reg.join_channel(2, "dev");
+            reg.join_channel(3, "random");
+            rxs
+        };
+
+        handle_set_presence(&state, 1, true, true).await.unwrap();
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ServerMessage::PresenceChanged { username, away: true, .. }) if username == "alice"
+        ));
+        assert!(bob_rx.try_recv().is_err(), "one update even with two shared channels");
+        assert!(carol_rx.try_recv().is_err(), "no shared channel");
+        assert!(alice_rx.try_recv().is_err());
+
+        handle_set_presence(&state, 1, true, true).await.unwrap();
+        assert!(bob_rx.try_recv().is_err(), "unchanged presence isn't relayed");
+
+        handle_set_presence(&state, 1, true, false).await.unwrap();
+        assert!(matches!(bob_rx.try_recv(), Ok(ServerMessage::PresenceChanged { away: false, .. })));
+        assert!(matches!(handle_set_presence(&state, 4, false, true).await, Err(ServerError::NotAuthenticated)));
+    }
+
+    #[tokio::test]
+    async fn test_oversized_message_is_rejected_with_the_limit() {
+        let config = ServerConfig { max_message_len: 100, ..ServerConfig::default() };
+        let state = Arc::new(AppState::new(&config));
+        let mut bob_rx = {
+            let mut reg = state.registry.write().await;
+            let _ = connect_user(&mut reg, 1, "alice");
+            let bob = connect_user(&mut reg, 2, "bob");
+            reg.join_channel(1, "general");
+            reg.join_channel(2, "general");
+            bob
+        };
+        {
+            let mut channels = state.channels.write().await;
+            channels.ensure_channel("general", true, None, ChannelType::Public, None).unwrap();
+            channels.join(1, 1, "general", None).unwrap();
+            channels.join(2, 2, "general", None).unwrap();
+        }
+
+        let err = handle_send_message(&state, 1, true, false, "general", vec![0; max_content_len(100) + 1], MessageMetadata::new())
+            .await
+            .unwrap_err();
+        assert_eq!(err, ServerError::MessageTooLong { max: 100 });
+        assert!(matches!(
+            err.into_message(MessageMeta::new(1, Utc::now()), None),
+            ServerMessage::ProtocolError { text, .. } if text.contains("100 bytes")
+        ));
+        assert!(bob_rx.try_recv().is_err());
+
+        // A full-length plaintext still fits once encrypted and padded.
+        handle_send_message(&state, 1, true, false, "general", vec![0; max_content_len(100)], MessageMetadata::new())
+            .await
+            .unwrap();
+        assert!(matches!(bob_rx.try_recv(), Ok(ServerMessage::MessageReceived { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_stored_dm_is_delivered_on_next_login_only() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (alice, bob_pw) = {
+            let mut auth = state.auth.write().await;
+            let alice = auth.register("alice".to_string(), None).unwrap().0;
+            (alice, auth.register("bob".to_string(), None).unwrap().1.unwrap())
+        };
+        let (mut alice_rx, mut bob_rx, mut bob_again_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice_rx = connect_user(&mut reg, 1, "alice");
+            let (tx, bob_rx) = outbox();
+            reg.register(2, tx);
+            let (tx, bob_again_rx) = outbox();
+            reg.register(3, tx);
+            (alice_rx, bob_rx, bob_again_rx)
+        };
+        assert_eq!(alice.id, 1);
+
+        handle_send_dm(&state, 1, true, "bob", b"while you were out".to_vec(), MessageMetadata::new()).await.unwrap();
+        while alice_rx.try_recv().is_ok() {}
+
+        handle_login(&state, 2, "bob", &bob_pw, PublishedKeys::default()).await.unwrap();
+        let delivered: Vec<_> = std::iter::from_fn(|| bob_rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                ServerMessage::DMReceived { message, .. } => Some(message.content),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(delivered, [b"while you were out".to_vec()]);
+        assert!(matches!(alice_rx.try_recv(), Ok(ServerMessage::DMDeliveryStatus { delivered: true, .. })));
+
+        // A second session for bob doesn't get it again.
+        handle_login(&state, 3, "bob", &bob_pw, PublishedKeys::default()).await.unwrap();
+        assert!(!std::iter::from_fn(|| bob_again_rx.try_recv().ok()).any(|msg| matches!(msg, ServerMessage::DMReceived { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_stored_staff_names_are_not_free_to_register() {
+        let dir = std::env::temp_dir().join(format!("darkrelay-roles-reserve-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let file = dir.join("channel_roles.json");
+        {
+            let mut admin = crate::admin::AdminManager::new();
+            admin.set_role_store(RoleStore::open(&file).unwrap());
+            admin.open_channel(2, "dev", ChannelType::Public, Some("Alice"));
+        }
+
+        // Restarted: alice's account is gone but her ownership of #dev isn't.
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        state.set_role_store(RoleStore::open(&file).unwrap()).await;
+        let (tx, _rx) = outbox();
+        state.registry.write().await.register(1, tx);
+
+        let refused = handle_register(&state, 1, "ALICE".to_string(), None, PublishedKeys::default()).await.unwrap_err();
+        assert_eq!(refused, ServerError::AuthFailed("username is reserved".to_string()));
+        assert!(state.registry.read().await.user(1).is_none());
+
+        // Only a SuperAdmin re-creating the account gets the role back to her.
+        state.auth.write().await.provision("alice".to_string(), None).unwrap();
+        let mut admin = state.admin.write().await;
+        admin.open_channel(5, "dev", ChannelType::Public, None);
+        assert_eq!(admin.get_role(5, "alice"), Role::SuperAdmin);
+        drop(admin);
+
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+
+    #[tokio::test]
+    async fn test_reserved_name_only_via_admin_create() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        state.admin.write().await.set_server_super_admins(std::collections::HashSet::from(["root".to_string()]));
+        let (mut root_rx, mut alice_rx, mut anon_rx) = {
+            let mut reg = state.registry.write().await;
+            let root = connect_user(&mut reg, 1, "root");
+            let alice = connect_user(&mut reg, 2, "alice");
+            let (tx, anon) = outbox();
+            reg.register(3, tx);
+            (root, alice, anon)
+        };
+
+        let refused = handle_register(&state, 3, "Admin".to_string(), None, PublishedKeys::default()).await.unwrap_err();
+        assert!(matches!(
+            refused.into_message(MessageMeta::new(1, Utc::now()), None),
+            ServerMessage::AuthFailure { reason, .. } if reason == "username is reserved"
+        ));
+        assert!(anon_rx.try_recv().is_err());
+
+        assert!(matches!(
+            handle_create_user(&state, 2, true, "admin".to_string(), None).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert!(alice_rx.try_recv().is_err());
+
+        handle_create_user(&state, 1, true, "Admin".to_string(), None).await.unwrap();
+        let password = match root_rx.try_recv() {
+            Ok(ServerMessage::UserCreated { user, generated_password: Some(pw), .. }) => {
+                assert_eq!(user.username, "Admin");
+                pw
+            }
+            other => panic!("expected UserCreated, got {other:?}"),
+        };
+        assert_eq!(state.registry.read().await.user(1).unwrap().username, "root", "admin stays logged in as themselves");
+
+        handle_login(&state, 3, "ADMIN", &password, PublishedKeys::default()).await.unwrap();
+        assert!(matches!(anon_rx.try_recv(), Ok(ServerMessage::AuthSuccess { user, .. }) if user.username == "Admin"));
+    }
+
+    #[tokio::test]
+    async fn test_force_disconnect_closes_every_session() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        state.admin.write().await.set_server_super_admins(std::collections::HashSet::from(["root".to_string()]));
+        let bob = {
+            let mut auth = state.auth.write().await;
+            assert_eq!(auth.register("root".to_string(), None).unwrap().0.id, 1);
+            auth.register("bob".to_string(), None).unwrap().0
+        };
+        let (mut root_rx, mut bob_rx, bob_disconnect, bob_phone_disconnect) = {
+            let mut reg = state.registry.write().await;
+            let root = connect_user(&mut reg, 1, "root");
+            let (tx, bob_rx) = outbox();
+            let bob_disconnect = reg.register(2, tx);
+            reg.set_user(2, bob.clone());
+            let (tx, _bob_phone_rx) = outbox();
+            let bob_phone_disconnect = reg.register(3, tx);
+            reg.set_user(3, bob.clone());
+            (root, bob_rx, bob_disconnect, bob_phone_disconnect)
+        };
+
+        handle_force_disconnect(&state, 1, true, "BOB", Some("flooding".to_string())).await.unwrap();
+
+        assert!(matches!(
+            bob_rx.try_recv(),
+            Ok(ServerMessage::SystemMessage { text, .. }) if text.contains("flooding")
+        ));
+        for signal in [&bob_disconnect, &bob_phone_disconnect] {
+            time::timeout(Duration::from_millis(50), signal.notified())
+                .await
+                .expect("bob's sessions should be closed");
+        }
+        assert!(matches!(root_rx.try_recv(), Ok(ServerMessage::SystemMessage { .. })));
+        assert!(time::timeout(Duration::from_millis(50), root_rx.recv()).await.is_err(), "root stays connected");
+
+        assert!(matches!(
+            handle_force_disconnect(&state, 2, true, "root", None).await,
+            Err(ServerError::PermissionDenied(_))
+        ));
+        assert!(matches!(handle_force_disconnect(&state, 1, true, "root", None).await, Err(ServerError::Rejected(_))));
+    }
+
+    #[tokio::test]
+    async fn test_terminate_ends_the_connection_loop() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let loop_state = Arc::clone(&state);
+        let session = tokio::spawn(async move { serve_client(loop_state, 7, addr, None, server, &mut shutdown_rx).await });
+
+        assert!(matches!(
+            read_frame::<ServerMessage, _>(&mut client, false).await,
+            Ok(ServerMessage::AuthChallenge { .. })
+        ));
+
+        state.registry.read().await.terminate(7);
+        time::timeout(Duration::from_secs(1), session)
+            .await
+            .expect("terminate should end the loop")
+            .unwrap()
+            .unwrap();
+        assert!(state.registry.read().await.client_info(7).is_none(), "the client is unregistered");
+        let mut rest = Vec::new();
+        assert_eq!(client.read_to_end(&mut rest).await.unwrap(), 0, "the stream is closed");
+    }
+
+    #[tokio::test]
+    async fn test_resume_skips_channels_banned_while_parked() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let _rxs = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        for name in ["lobby", "staff"] {
+            handle_join_channel(&state, 2, true, name.to_string(), None, CREATE).await.unwrap();
+            handle_join_channel(&state, 1, true, name.to_string(), None, None).await.unwrap();
+        }
+        let alice = state.registry.read().await.user(1).unwrap();
+        let token = state.resume.write().await.issue(1, alice.clone());
+        cleanup_disconnect(&state, 1).await;
+
+        let staff = state.channels.read().await.get_channel_id("staff").unwrap();
+        state.bans.write().await.ban_user(staff, alice.id, "alice".to_string(), "bob".to_string(), None, None);
+
+        let mut rx = {
+            let (tx, rx) = outbox();
+            state.registry.write().await.register(3, tx);
+            rx
+        };
+        handle_resume(&state, 3, &token).await.unwrap();
+        let rejoined: Vec<String> = std::iter::from_fn(|| rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                ServerMessage::JoinSuccess { channel, .. } => Some(channel.name),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(rejoined, ["lobby"]);
+        assert!(!state.channels.read().await.is_member("staff", 3));
+    }
+
+    #[tokio::test]
+    async fn test_explicit_disconnect_leaves_at_once_and_cannot_be_resumed() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (_, password) = state.auth.write().await.register("alice".to_string(), None).unwrap();
+        let mut bob_rx = {
+            let mut reg = state.registry.write().await;
+            connect_user(&mut reg, 2, "bob")
+        };
+        handle_join_channel(&state, 2, true, "lobby".to_string(), None, CREATE).await.unwrap();
+
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let loop_state = Arc::clone(&state);
+        let session = tokio::spawn(async move { serve_client(loop_state, 7, addr, None, server, &mut shutdown_rx).await });
+        let meta = || MessageMeta::new(1, Utc::now());
+        let key = state.special_key.read().await.clone();
+
+        for msg in [
+            ClientMessage::Auth { meta: meta(), key },
+            ClientMessage::Login {
+                meta: meta(),
+                username: "alice".to_string(),
+                password: password.unwrap(),
+                signing_key: None,
+                public_key: None,
+            },
+            ClientMessage::JoinChannel { meta: meta(), name: "lobby".to_string(), password: None },
+            ClientMessage::Disconnect { meta: meta() },
+        ] {
+            write_frame(&mut client, &msg, false).await.unwrap();
+        }
+
+        let mut token = None;
+        while let Ok(msg) = read_frame::<ServerMessage, _>(&mut client, false).await {
+            if let ServerMessage::AuthSuccess { resume_token, .. } = msg {
+                token = resume_token;
+            }
+        }
+        time::timeout(Duration::from_secs(1), session).await.unwrap().unwrap().unwrap();
+
+        let left: Vec<String> = std::iter::from_fn(|| bob_rx.try_recv().ok())
+            .filter_map(|msg| match msg {
+                ServerMessage::UserLeft { user, .. } => Some(user.username),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(left, ["alice"], "announced right away, not after the grace period");
+        let redeemed = state.resume.write().await.redeem(&token.expect("a token was issued"), Utc::now());
+        assert_eq!(redeemed.unwrap_err(), "invalid resume token");
+    }
+
+    #[tokio::test]
+    async fn test_logging_in_twice_on_one_connection_is_refused() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut client, server) = tokio::io::duplex(64 * 1024);
+        let (_shutdown_tx, mut shutdown_rx) = broadcast::channel(1);
+        let addr: SocketAddr = "127.0.0.1:4000".parse().unwrap();
+        let loop_state = Arc::clone(&state);
+        tokio::spawn(async move { serve_client(loop_state, 7, addr, None, server, &mut shutdown_rx).await });
+        let meta = || MessageMeta::new(1, Utc::now());
+        let key = state.special_key.read().await.clone();
+
+        for msg in [
+            ClientMessage::Auth { meta: meta(), key },
+            ClientMessage::GuestLogin { meta: meta() },
+            ClientMessage::Login {
+                meta: MessageMeta::new(42, Utc::now()),
+                username: "alice".to_string(),
+                password: "hunter2hunter2".to_string(),
+                signing_key: None,
+                public_key: None,
+            },
+        ] {
+            write_frame(&mut client, &msg, false).await.unwrap();
+        }
+
+        let mut guest_logins = 0;
+        loop {
+            let msg = time::timeout(Duration::from_secs(1), read_frame::<ServerMessage, _>(&mut client, false))
+                .await
+                .expect("the second login should be answered")
+                .unwrap();
+            match msg {
+                ServerMessage::AuthSuccess { .. } => guest_logins += 1,
+                ServerMessage::ProtocolError { text, request_id, .. } => {
+                    assert_eq!(text, "already logged in");
+                    assert_eq!(request_id, Some(42), "the error names the refused request");
+                    break;
+                }
+                ServerMessage::AuthFailure { reason, .. } => panic!("login was attempted: {reason}"),
+                _ => {}
+            }
+        }
+        assert_eq!(guest_logins, 1);
+        assert!(state.registry.read().await.user(7).is_some_and(|u| auth::is_guest(u.id)), "still the guest");
+    }
+
+    #[tokio::test]
+    async fn test_public_channel_creation_is_announced() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx, mut anon_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice = connect_user(&mut reg, 1, "alice");
+            let bob = connect_user(&mut reg, 2, "bob");
+            let (tx, anon) = outbox();
+            reg.register(3, tx);
+            (alice, bob, anon)
+        };
+
+        handle_join_channel(&state, 1, true, "Lobby".to_string(), None, CREATE).await.unwrap();
+        let created = |rx: &mut Outbox| {
+            std::iter::from_fn(|| rx.try_recv().ok())
+                .filter_map(|msg| match msg {
+                    ServerMessage::ChannelCreated { channel, created_by, .. } => Some((channel.name, created_by)),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+        let expected = vec![("lobby".to_string(), "alice".to_string())];
+        assert_eq!(created(&mut bob_rx), expected);
+        assert_eq!(created(&mut alice_rx), expected);
+        assert!(anon_rx.try_recv().is_err(), "not logged in");
+
+        handle_join_channel(&state, 1, true, "staff".to_string(), Some("secret".to_string()), CREATE).await.unwrap();
+        handle_join_channel(&state, 2, true, "lobby".to_string(), None, None).await.unwrap();
+        assert!(created(&mut bob_rx).is_empty(), "private channels and existing ones aren't announced");
+    }
+    #[tokio::test]
+    async fn test_join_never_creates_and_create_never_joins_existing() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let _rxs = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        let refused = |reason: &str| {
+            Err(ServerError::JoinRefused { channel: "project".to_string(), reason: reason.to_string() })
+        };
+
+        assert_eq!(handle_join_channel(&state, 1, true, "project".to_string(), None, None).await, refused("channel not found"));
+        assert_eq!(
+            handle_join_channel(&state, 1, true, "project".to_string(), Some("pw".to_string()), None).await,
+            refused("channel not found")
+        );
+        assert_eq!(state.channels.read().await.get_channel_id("project"), None, "a failed join leaves nothing behind");
+
+        handle_join_channel(&state, 1, true, "project".to_string(), Some("pw".to_string()), CREATE).await.unwrap();
+        assert_eq!(state.channels.read().await.is_public("project"), Some(false));
+        assert_eq!(
+            handle_join_channel(&state, 2, true, "#Project".to_string(), None, CREATE).await,
+            refused("channel already exists")
+        );
+        assert!(!state.channels.read().await.is_member("project", 2));
+
+        handle_join_channel(&state, 2, true, "project".to_string(), Some("pw".to_string()), None).await.unwrap();
+        assert!(state.channels.read().await.is_member("project", 2));
+        let ch_id = state.channels.read().await.get_channel_id("project").unwrap();
+        assert_eq!(state.admin.read().await.get_role(ch_id, "bob"), Role::User, "joining doesn't make you the creator");
+    }
+    #[tokio::test]
+    async fn test_create_sets_type_and_topic_up_front() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        let options = |channel_type, topic: &str| Some(ChannelOptions { channel_type, topic: Some(topic.to_string()) });
+
+        assert!(matches!(
+            handle_join_channel(&state, 1, true, "news".to_string(), None, options(ChannelType::ReadOnly, &"x".repeat(channel::MAX_TOPIC_LEN + 1))).await,
+            Err(ServerError::JoinRefused { reason, .. }) if reason.starts_with("topic is limited")
+        ));
+        handle_join_channel(&state, 1, true, "news".to_string(), None, options(ChannelType::ReadOnly, "  Release notes ")).await.unwrap();
+        match alice_rx.try_recv() {
+            Ok(ServerMessage::JoinSuccess { channel, .. }) => {
+                assert_eq!(channel.channel_type, ChannelType::ReadOnly);
+                assert_eq!(channel.topic.as_deref(), Some("Release notes"));
+                assert_eq!(channel.user_role, Some(Role::SuperAdmin));
+            }
+            other => panic!("expected JoinSuccess, got {other:?}"),
+        }
+        match bob_rx.try_recv() {
+            Ok(ServerMessage::ChannelCreated { channel, .. }) => assert_eq!(channel.topic.as_deref(), Some("Release notes")),
+            other => panic!("expected ChannelCreated, got {other:?}"),
+        }
+        handle_send_message(&state, 1, true, false, "news", b"v1.2".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        // Private without a password: unlisted and unannounced.
+        handle_join_channel(&state, 1, true, "ops".to_string(), None, options(ChannelType::Private, "")).await.unwrap();
+        let channels = state.channels.read().await;
+        assert_eq!(channels.is_public("ops"), Some(false));
+        assert_eq!(channels.list_public().iter().map(|c| c.name.as_str()).collect::<Vec<_>>(), ["news"]);
+        assert!(channels.list_all().iter().all(|c| c.name != "ops" || c.topic.is_none()), "a blank topic is no topic");
+        assert!(bob_rx.try_recv().is_err());
+    }
+    #[tokio::test]
+    async fn test_my_channels_cover_every_session() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (_alice_rx, mut laptop_rx, _phone_rx) = {
+            let mut reg = state.registry.write().await;
+            let alice = connect_user(&mut reg, 1, "alice");
+            let laptop = connect_user(&mut reg, 2, "bob");
+            let phone = connect_user(&mut reg, 3, "bob");
+            let bob = reg.user(2).unwrap();
+            reg.set_user(3, bob);
+            (alice, laptop, phone)
+        };
+        handle_join_channel(&state, 1, true, "general".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 1, true, "alice-only".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 2, true, "general".to_string(), None, None).await.unwrap();
+        handle_join_channel(&state, 3, true, "project".to_string(), None, CREATE).await.unwrap();
+        while laptop_rx.try_recv().is_ok() {}
+
+        handle_list_my_channels(&state, 2, true).await.unwrap();
+        match laptop_rx.try_recv() {
+            Ok(ServerMessage::MyChannels { channels, .. }) => {
+                let listed: Vec<_> = channels.iter().map(|c| (c.name.as_str(), c.user_role)).collect();
+                assert_eq!(listed, [("general", Some(Role::User)), ("project", Some(Role::SuperAdmin))]);
+            }
+            other => panic!("expected MyChannels, got {other:?}"),
+        }
+        assert_eq!(handle_list_my_channels(&state, 4, false).await, Err(ServerError::NotAuthenticated));
+    }
+    #[tokio::test]
+    async fn test_replaced_session_hands_its_channels_over() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let password = state.auth.write().await.register("alice".to_string(), None).unwrap().1.unwrap();
+        let (_old_rx, mut new_rx) = {
+            let mut reg = state.registry.write().await;
+            let (tx1, rx1) = outbox();
+            let (tx2, rx2) = outbox();
+            reg.register(1, tx1);
+            reg.register(2, tx2);
+            (rx1, rx2)
+        };
+        handle_login(&state, 1, "alice", &password, PublishedKeys::default()).await.unwrap();
+        handle_join_channel(&state, 1, true, "general".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 1, true, "project".to_string(), None, CREATE).await.unwrap();
+
+        // The default policy closes the old session; by the time the new one
+        // asks, it is gone.
+        handle_login(&state, 2, "alice", &password, PublishedKeys::default()).await.unwrap();
+        cleanup_disconnect(&state, 1).await;
+        while new_rx.try_recv().is_ok() {}
+
+        let listed = |rx: &mut Outbox| match rx.try_recv() {
+            Ok(ServerMessage::MyChannels { channels, .. }) => channels.into_iter().map(|c| c.name).collect::<Vec<_>>(),
+            other => panic!("expected MyChannels, got {other:?}"),
+        };
+        handle_list_my_channels(&state, 2, true).await.unwrap();
+        assert_eq!(listed(&mut new_rx), ["general", "project"]);
+
+        handle_join_channel(&state, 2, true, "general".to_string(), None, None).await.unwrap();
+        while new_rx.try_recv().is_ok() {}
+        handle_list_my_channels(&state, 2, true).await.unwrap();
+        assert_eq!(listed(&mut new_rx), ["general"], "handed over once; after that only live membership counts");
+    }
+
+    #[tokio::test]
+    async fn test_sending_updates_last_seen_in_user_info() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        for name in ["alice", "bob", "carol"] {
+            state.auth.write().await.register(name.to_string(), None).unwrap();
+        }
+        let (mut alice_rx, _bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        let user_details = |rx: &mut Outbox| loop {
+            match rx.try_recv() {
+                Ok(ServerMessage::UserDetails { user, last_seen, online, .. }) => break (user.username, last_seen, online),
+                Ok(_) => continue,
+                Err(e) => panic!("expected UserDetails, got {e:?}"),
+            }
+        };
+
+        handle_join_channel(&state, 2, true, "general".to_string(), None, CREATE).await.unwrap();
+        let before = Utc::now();
+        // As the frame loop does for every message from a logged-in session.
+        touch_last_seen(&state, 2).await;
+        handle_send_message(&state, 2, true, false, "general", b"hi".to_vec(), MessageMetadata::new()).await.unwrap();
+
+        handle_get_user_info(&state, 1, true, "BOB").await.unwrap();
+        let (username, last_seen, online) = user_details(&mut alice_rx);
+        assert_eq!(username, "bob");
+        assert!(last_seen.is_some_and(|at| at >= before));
+        assert!(online);
+
+        handle_get_user_info(&state, 1, true, "carol").await.unwrap();
+        assert_eq!(user_details(&mut alice_rx), ("carol".to_string(), None, false));
+
+        assert!(matches!(handle_get_user_info(&state, 1, true, "dave").await, Err(ServerError::NotFound(_))));
+        assert!(matches!(handle_get_user_info(&state, 1, false, "bob").await, Err(ServerError::NotAuthenticated)));
+    }
+
+    #[tokio::test]
+    async fn test_published_keys_reach_lookups_and_join_broadcasts() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        state.auth.write().await.register("bob".to_string(), None).unwrap();
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            let (tx, rx) = outbox();
+            reg.register(1, tx);
+            (rx, connect_user(&mut reg, 2, "bob"))
+        };
+        handle_join_channel(&state, 2, true, "general".to_string(), None, CREATE).await.unwrap();
+        while bob_rx.try_recv().is_ok() {}
+
+        let oversized = PublishedKeys { public_key: Some(vec![0; MAX_USER_KEY_LEN + 1]), ..Default::default() };
+        let refused = handle_register(&state, 1, "alice".to_string(), None, oversized).await;
+        assert!(matches!(refused, Err(ServerError::AuthFailed(_))));
+        let keys = PublishedKeys { signing_key: Some(vec![7; 32]), public_key: Some(vec![9; 32]) };
+        handle_register(&state, 1, "alice".to_string(), None, keys).await.unwrap();
+        match alice_rx.try_recv() {
+            Ok(ServerMessage::AuthSuccess { user, .. }) => assert_eq!(user.public_key, Some(vec![9; 32])),
+            other => panic!("expected AuthSuccess, got {other:?}"),
+        }
+
+        handle_join_channel(&state, 1, true, "general".to_string(), None, None).await.unwrap();
+        let joined = loop {
+            match bob_rx.try_recv() {
+                Ok(ServerMessage::UserJoined { user, .. }) => break user,
+                Ok(_) => continue,
+                Err(e) => panic!("expected UserJoined, got {e:?}"),
+            }
+        };
+        assert_eq!((joined.signing_key, joined.public_key), (Some(vec![7; 32]), Some(vec![9; 32])));
+
+        // A login that leaves a key out keeps the published one.
+        let kept = state.auth.write().await.publish_keys(joined.id, PublishedKeys { signing_key: Some(vec![8; 32]), public_key: None });
+        assert_eq!(kept.map(|user| user.public_key), Some(Some(vec![9; 32])));
+        handle_get_user_info(&state, 2, true, "alice").await.unwrap();
+        match bob_rx.try_recv() {
+            Ok(ServerMessage::UserDetails { user, .. }) => {
+                assert_eq!((user.signing_key, user.public_key), (Some(vec![8; 32]), Some(vec![9; 32])));
+            }
+            other => panic!("expected UserDetails, got {other:?}"),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_empty_messages_are_rejected() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        state.auth.write().await.register("bob".to_string(), None).unwrap();
+        let (_alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        handle_join_channel(&state, 1, true, "general".to_string(), None, CREATE).await.unwrap();
+        while bob_rx.try_recv().is_ok() {}
+        let mut nonce = MessageMetadata::new();
+        nonce.set_nonce(&[7u8; NONCE_LEN]);
+
+        for content in [&b""[..], b"   ", b" \n\t ", "\u{3000}".as_bytes()] {
+            let err = handle_send_message(&state, 1, true, false, "general", content.to_vec(), MessageMetadata::new()).await.unwrap_err();
+            assert!(matches!(err.into_message(server_meta(&state), None), ServerMessage::ProtocolError { .. }));
+            assert!(matches!(
+                handle_send_dm(&state, 1, true, "bob", content.to_vec(), MessageMetadata::new()).await,
+                Err(ServerError::InvalidRequest(_))
+            ));
+        }
+        assert!(matches!(
+            handle_send_message(&state, 1, true, true, "general", vec![0xab; MIN_CIPHERTEXT_LEN - 1], nonce.clone()).await,
+            Err(ServerError::InvalidRequest(_))
+        ));
+        assert!(state.channels.read().await.history("general", 10).is_empty());
+        assert!(bob_rx.try_recv().is_err(), "nothing reached the DM recipient");
+
+        handle_send_message(&state, 1, true, false, "general", b" hi ".to_vec(), MessageMetadata::new()).await.unwrap();
+        handle_send_message(&state, 1, true, true, "general", vec![0xab; MIN_CIPHERTEXT_LEN], nonce).await.unwrap();
+        assert_eq!(state.channels.read().await.history("general", 10).len(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_deleted_message_stays_in_history_as_a_tombstone() {
+        let state = Arc::new(AppState::new(&ServerConfig::default()));
+        let (mut alice_rx, mut bob_rx) = {
+            let mut reg = state.registry.write().await;
+            (connect_user(&mut reg, 1, "alice"), connect_user(&mut reg, 2, "bob"))
+        };
+        handle_join_channel(&state, 1, true, "general".to_string(), None, CREATE).await.unwrap();
+        handle_join_channel(&state, 2, true, "general".to_string(), None, None).await.unwrap();
+        for text in ["keep", "oops"] {
+            handle_send_message(&state, 2, true, false, "general", text.as_bytes().to_vec(), MessageMetadata::new().with(TYPE_KEY, "action")).await.unwrap();
+        }
+        let oops = state.channels.read().await.history("general", 10)[1].id;
+
+        assert!(matches!(
+            handle_delete_message(&state, 2, true, "general", oops).await,
+            Err(ServerError::MissingPermission(Permission::DeleteMessage))
+        ));
+        handle_delete_message(&state, 1, true, "general", oops).await.unwrap();
+        assert!(matches!(handle_delete_message(&state, 1, true, "general", oops).await, Err(ServerError::NotFound(_))));
+
+        while alice_rx.try_recv().is_ok() {}
+        while bob_rx.try_recv().is_ok() {}
+        handle_get_history(&state, 2, true, "general".to_string(), 10).await.unwrap();
+        let messages = match bob_rx.try_recv() {
+            Ok(ServerMessage::HistoryChunk { messages, .. }) => messages,
+            other => panic!("expected HistoryChunk, got {other:?}"),
+        };
+        assert_eq!(messages.len(), 2);
+        assert!(!messages[0].deleted);
+        assert_eq!(messages[0].content, b"keep");
+        let tombstone = &messages[1];
+        assert_eq!((tombstone.id, tombstone.seq, tombstone.username.as_str()), (oops, 2, "bob"));
+        assert!(tombstone.deleted);
+        assert!(tombstone.content.is_empty() && tombstone.metadata.is_empty() && tombstone.nonce.is_none());
+
+        let (since, _) = state.channels.read().await.messages_since("general", 1, 10).unwrap();
+        assert!(since[0].deleted, "resyncs see the tombstone too");
+    }
 }
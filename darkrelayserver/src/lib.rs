@@ -0,0 +1,188 @@
+//! The server's state and handlers. `main.rs` wires them to a listener; the
+//! benches drive the handlers directly.
+
+pub mod admin;
+pub mod admin_log;
+pub mod auth;
+pub mod ban_manager;
+pub mod channel;
+pub mod config;
+pub mod crypto;
+pub mod dm;
+pub mod error;
+pub mod handler;
+#[cfg(feature = "lock-stats")]
+pub mod lock_stats;
+pub mod motd;
+pub mod poll;
+pub mod ratelimit;
+pub mod registry;
+pub mod resume;
+pub mod role_store;
+pub mod spam;
+pub mod tls;
+pub mod user_store;
+
+use std::{
+    collections::HashSet,
+    path::PathBuf,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use darkrelayprotocol::channel::ChannelType;
+use tracing::error;
+
+use crate::{
+    admin::AdminManager,
+    auth::AuthService,
+    ban_manager::BanManager,
+    channel::ChannelManager,
+    config::ServerConfig,
+    crypto::EcdhManager,
+    dm::DMManager,
+    poll::PollManager,
+    ratelimit::RateLimiter,
+    registry::Registry,
+    resume::ResumeManager,
+    role_store::RoleStore,
+    spam::SpamDetector,
+};
+
+/// The lock around each part of `AppState`. The `lock-stats` feature swaps
+/// in one that counts acquisitions, for the benches.
+#[cfg(not(feature = "lock-stats"))]
+pub type StateLock<T> = tokio::sync::RwLock<T>;
+#[cfg(feature = "lock-stats")]
+pub type StateLock<T> = lock_stats::CountingRwLock<T>;
+
+pub struct AppState {
+    pub auth: StateLock<AuthService>,
+    pub channels: StateLock<ChannelManager>,
+    pub registry: StateLock<Registry>,
+    pub ecdh: StateLock<EcdhManager>,
+    pub admin: StateLock<AdminManager>,
+    pub bans: StateLock<BanManager>,
+    pub resume: StateLock<ResumeManager>,
+    pub rate_limiter: StateLock<RateLimiter>,
+    pub dms: StateLock<DMManager>,
+    pub polls: StateLock<PollManager>,
+    pub spam: StateLock<SpamDetector>,
+
+    pub special_key: StateLock<String>,
+    /// Largest message `content` accepted, advertised in `ServerCapabilities`.
+    pub max_message_len: usize,
+    /// Sent after `AuthSuccess`; changed at runtime by `SetMotd`.
+    pub motd: StateLock<Option<String>>,
+    pub motd_file: Option<PathBuf>,
+    /// Set when `client_ca` makes a client certificate mandatory; a login
+    /// whose certificate names no one is then refused.
+    pub require_client_cert: bool,
+
+    pub next_client_id: AtomicU64,
+    pub next_server_msg_id: AtomicU64,
+}
+
+impl AppState {
+    pub fn new(config: &ServerConfig) -> Self {
+        let mut admin = AdminManager::new();
+        admin.set_server_super_admins(config.super_admins.clone());
+
+        let mut auth = AuthService::new();
+        auth.set_password_policy(config.password_policy.clone());
+        auth.set_reserved_usernames(config.reserved_usernames.iter().cloned());
+        // Only the accounts `provision_super_admins` creates may hold these.
+        auth.reserve(config.super_admins.iter().cloned());
+
+        let mut registry = Registry::new();
+        registry.set_outbound_capacity(config.outbound_queue);
+        registry.set_duplicate_login(config.duplicate_login);
+
+        let mut dms = DMManager::new();
+        dms.set_ttl(config.dm_ttl.and_then(|ttl| chrono::Duration::from_std(ttl).ok()));
+
+        let mut channels = ChannelManager::new();
+        channels.set_max_channels(config.max_channels);
+        channels.set_creation_policy(config.channel_creation);
+        channels.set_allowed_names(config.allowed_channels.clone());
+
+        let mut rate_limiter = RateLimiter::new();
+        let (count, secs) = config.rate_limit;
+        rate_limiter.set_limits(count, chrono::Duration::seconds(secs));
+
+        Self {
+            auth: StateLock::new(auth),
+            channels: StateLock::new(channels),
+            registry: StateLock::new(registry),
+            ecdh: StateLock::new(EcdhManager::new()),
+            admin: StateLock::new(admin),
+            bans: StateLock::new(BanManager::new()),
+            resume: StateLock::new(ResumeManager::new()),
+            rate_limiter: StateLock::new(rate_limiter),
+            dms: StateLock::new(dms),
+            polls: StateLock::new(PollManager::new()),
+            spam: StateLock::new(SpamDetector::new(config.spam)),
+            special_key: StateLock::new(config.special_key.clone()),
+            max_message_len: config.max_message_len,
+            motd: StateLock::new(config.motd.as_deref().and_then(|text| motd::normalize(text).ok().flatten())),
+            motd_file: config.motd_file.clone(),
+            require_client_cert: config.client_ca.is_some(),
+            next_client_id: AtomicU64::new(1),
+            next_server_msg_id: AtomicU64::new(1),
+        }
+    }
+
+    pub fn next_client_id(&self) -> u64 {
+        self.next_client_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    pub fn next_server_msg_id(&self) -> u64 {
+        self.next_server_msg_id.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Restore channel roles from `store`. A role can outlive its account
+    /// (the accounts file may be missing or unwritable), so every stored
+    /// name is reserved: only a SuperAdmin's `/createuser` can bring one
+    /// back, not whoever registers it first.
+    pub async fn set_role_store(&self, store: RoleStore) {
+        self.auth.write().await.reserve(store.usernames().cloned());
+        self.admin.write().await.set_role_store(store);
+    }
+
+    /// Create an account for each configured SuperAdmin name, since those
+    /// names can't be registered. Names that already have an account, from
+    /// an earlier start, are left alone. Returns the generated passwords of
+    /// the accounts created now.
+    pub async fn provision_super_admins(&self, names: &HashSet<String>) -> Vec<(String, String)> {
+        let mut auth = self.auth.write().await;
+        let mut created = Vec::new();
+        for name in names {
+            if auth.find_user_by_username(name).is_some() {
+                continue;
+            }
+            match auth.provision(name.clone(), None) {
+                Ok((user, Some(password))) => created.push((user.username, password)),
+                Ok((_, None)) => {}
+                Err(reason) => error!(user = name, reason, "could not create SuperAdmin account"),
+            }
+        }
+        created
+    }
+
+    /// Open the allowed channels, or just `general` when any name is
+    /// allowed, each with the type it was saved with.
+    pub async fn open_startup_channels(&self) {
+        let mut admin = self.admin.write().await;
+        let mut channels = self.channels.write().await;
+        let names: Vec<String> = match channels.allowed_names() {
+            Some(allowed) => allowed.iter().cloned().collect(),
+            None => vec!["general".to_string()],
+        };
+        for name in names {
+            let channel_type = admin.stored_channel_type(&name).unwrap_or(ChannelType::Public);
+            match channels.ensure_channel(&name, channel_type != ChannelType::Private, None, channel_type, None) {
+                Ok(id) => admin.open_channel(id, &name, channel_type, None),
+                Err(reason) => error!(channel = name, reason, "could not open startup channel"),
+            }
+        }
+    }
+}
@@ -0,0 +1,30 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+static ACQUISITIONS: AtomicU64 = AtomicU64::new(0);
+
+/// Every `read` and `write` taken on any `AppState` lock so far.
+pub fn acquisitions() -> u64 {
+    ACQUISITIONS.load(Ordering::Relaxed)
+}
+
+/// A `tokio::sync::RwLock` that counts its acquisitions.
+#[derive(Debug, Default)]
+pub struct CountingRwLock<T>(RwLock<T>);
+
+impl<T> CountingRwLock<T> {
+    pub fn new(value: T) -> Self {
+        Self(RwLock::new(value))
+    }
+
+    pub async fn read(&self) -> RwLockReadGuard<'_, T> {
+        ACQUISITIONS.fetch_add(1, Ordering::Relaxed);
+        self.0.read().await
+    }
+
+    pub async fn write(&self) -> RwLockWriteGuard<'_, T> {
+        ACQUISITIONS.fetch_add(1, Ordering::Relaxed);
+        self.0.write().await
+    }
+}
@@ -1,133 +1,148 @@
-mod auth;
-mod channel;
-mod handler;
-mod registry;
-mod tls;
-mod crypto;
-mod admin;
-mod ban_manager;
-
-use std::{
-    env,
-    fs,
-    path::Path,
-    sync::{
-        atomic::{AtomicU64, Ordering},
-        Arc,
-    },
-};
-
-use tokio::{
-    net::TcpListener,
-    sync::{broadcast, RwLock},
+use std::{fs, sync::Arc};
+
+use darkrelayserver::{
+    admin_log::AdminLogStore,
+    channel,
+    config::{ServerConfig, DEFAULT_LOG_DIR, DEFAULT_LOG_FILTER},
+    handler, motd,
+    role_store::RoleStore,
+    tls,
+    user_store::UserStore,
+    AppState,
 };
+use tokio::{net::TcpListener, sync::broadcast};
 use tokio_rustls::TlsAcceptor;
 use tracing::{error, info};
+use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 
-use crate::{
-    admin::AdminManager,
-    auth::AuthService,
-    ban_manager::BanManager,
-    channel::ChannelManager,
-    crypto::EcdhManager,
-    registry::Registry,
-};
-
-pub struct AppState {
-    pub auth: RwLock<AuthService>,
-    pub channels: RwLock<ChannelManager>,
-    pub registry: RwLock<Registry>,
-    pub ecdh: RwLock<EcdhManager>,
-    pub admin: RwLock<AdminManager>,
-    pub bans: RwLock<BanManager>,
-
-    pub special_key: String,
-
-    pub next_client_id: AtomicU64,
-    pub next_server_msg_id: AtomicU64,
-}
-
-impl AppState {
-    pub fn new(special_key: String) -> Self {
-        Self {
-            auth: RwLock::new(AuthService::new()),
-            channels: RwLock::new(ChannelManager::new()),
-            registry: RwLock::new(Registry::new()),
-            ecdh: RwLock::new(EcdhManager::new()),
-            admin: RwLock::new(AdminManager::new()),
-            bans: RwLock::new(BanManager::new()),
-            special_key,
-            next_client_id: AtomicU64::new(1),
-            next_server_msg_id: AtomicU64::new(1),
-        }
-    }
-
-    pub fn next_client_id(&self) -> u64 {
-        self.next_client_id.fetch_add(1, Ordering::Relaxed)
-    }
-
-    pub fn next_server_msg_id(&self) -> u64 {
-        self.next_server_msg_id.fetch_add(1, Ordering::Relaxed)
-    }
-}
-
-fn init_tracing() {
-    let log_dir = Path::new("darkrelayserver/logs");
-    let _ = fs::create_dir_all(log_dir);
-
-    let file_path = log_dir.join("server.log");
-
-    let file_writer = move || {
-        fs::OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open(&file_path)
-            .expect("open log file")
-    };
+/// Lines are written off the async threads; keep the guard until exit so
+/// the last of them are flushed.
+fn init_tracing(config: &ServerConfig) -> WorkerGuard {
+    let appender = config.log_appender(DEFAULT_LOG_DIR).expect("open log file");
+    let (writer, guard) = tracing_appender::non_blocking(appender);
 
-    let filter = EnvFilter::try_from_default_env()
-        .unwrap_or_else(|_| EnvFilter::new("info,darkrelayserver=debug"));
+    let filter = EnvFilter::try_new(&config.log_filter).unwrap_or_else(|e| {
+        eprintln!("invalid DARKRELAY_LOG ({e}), using {DEFAULT_LOG_FILTER}");
+        EnvFilter::new(DEFAULT_LOG_FILTER)
+    });
 
     let layer = fmt::layer()
         .with_ansi(false)
         .with_target(true)
         .json()
-        .with_writer(file_writer);
+        .with_writer(writer);
 
     tracing_subscriber::registry().with(filter).with(layer).init();
+    guard
+}
+
+/// Periodic sweeps for expired bans, retention (channel messages and DMs),
+/// spam mutes and resume tokens. The retention sweep also saves `last_seen`.
+fn spawn_cleanup_tasks(state: &Arc<AppState>, config: &ServerConfig) {
+    let ban_cleanup_state = Arc::clone(state);
+    let mut ban_interval = tokio::time::interval(config.ban_cleanup_interval);
+    tokio::spawn(async move {
+        loop {
+            ban_interval.tick().await;
+            let mut bans = ban_cleanup_state.bans.write().await;
+            bans.cleanup_expired();
+        }
+    });
+
+    let retention_state = Arc::clone(state);
+    let mut retention_interval = tokio::time::interval(config.retention_interval);
+    tokio::spawn(async move {
+        loop {
+            retention_interval.tick().await;
+            let now = chrono::Utc::now();
+            {
+                let mut channels = retention_state.channels.write().await;
+                channels.prune_expired(now);
+            }
+            {
+                let mut dms = retention_state.dms.write().await;
+                dms.prune_expired(now);
+            }
+            {
+                let mut limiter = retention_state.rate_limiter.write().await;
+                limiter.sweep(now);
+            }
+            {
+                let mut spam = retention_state.spam.write().await;
+                spam.sweep(now);
+            }
+            retention_state.auth.write().await.flush_last_seen();
+        }
+    });
+
+    let resume_cleanup_state = Arc::clone(state);
+    let mut resume_interval = tokio::time::interval(config.resume_sweep_interval);
+    tokio::spawn(async move {
+        loop {
+            resume_interval.tick().await;
+            handler::expire_resume_tokens(&resume_cleanup_state).await;
+        }
+    });
 }
 
 #[tokio::main]
 async fn main() {
-    init_tracing();
+    let config = ServerConfig::from_env();
+    let _log_guard = init_tracing(&config);
+    let state = Arc::new(AppState::new(&config));
 
-    let special_key = env::var("DARKRELAY_SPECIAL_KEY").unwrap_or_else(|_| "darkrelay-dev-key".to_string());
-    let state = Arc::new(AppState::new(special_key));
+    match AdminLogStore::open(&config.admin_log_dir) {
+        Ok(store) => state.admin.write().await.set_log_store(store),
+        Err(e) => error!(dir = %config.admin_log_dir.display(), error = %e, "admin log directory unavailable, keeping logs in memory only"),
+    }
+    match UserStore::open(&config.users_file) {
+        Ok(store) => state.auth.write().await.set_user_store(store),
+        Err(e) => error!(file = %config.users_file.display(), error = %e, "accounts file unavailable, keeping accounts in memory only"),
+    }
+    // Printed rather than logged so the passwords stay out of the log files.
+    for (name, password) in state.provision_super_admins(&config.super_admins).await {
+        eprintln!("SuperAdmin account {name} created with password {password}");
+    }
 
-    {
-        let mut channels = state.channels.write().await;
-        channels.ensure_channel("general", true, None, None);
+    match RoleStore::open(&config.roles_file) {
+        Ok(store) => state.set_role_store(store).await,
+        Err(e) => error!(file = %config.roles_file.display(), error = %e, "channel roles file unavailable, keeping roles in memory only"),
     }
 
-    let ban_cleanup_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        let mut interval = tokio::time::interval(tokio::time::Duration::from_secs(60));
-        loop {
-            interval.tick().await;
-            let mut bans = ban_cleanup_state.bans.write().await;
-            bans.cleanup_expired();
+    match (&config.motd, &config.motd_file) {
+        (Some(text), _) => {
+            if let Err(e) = motd::normalize(text) {
+                error!(error = %e, "DARKRELAY_MOTD ignored");
+            }
         }
-    });
+        (None, Some(path)) => match motd::read_file(path) {
+            Ok(text) => *state.motd.write().await = text,
+            Err(e) => error!(file = %path.display(), error = %e, "message of the day file unreadable, starting without one"),
+        },
+        (None, None) => {}
+    }
+
+    if let (None, Some(path)) = (&config.allowed_channels, &config.allowed_channels_file) {
+        // Starting with any name allowed would defeat the point of the list.
+        let text = fs::read_to_string(path).unwrap_or_else(|e| panic!("read {}: {e}", path.display()));
+        state.channels.write().await.set_allowed_names(Some(channel::parse_channel_list(&text)));
+    }
+    if let Some(allowed) = state.channels.read().await.allowed_names() {
+        info!(channels = ?allowed, "channel names locked to the allowed list");
+    }
+    state.open_startup_channels().await;
+
+    spawn_cleanup_tasks(&state, &config);
 
-    let tls_config = tls::load_or_generate_tls_config(None, None).expect("load TLS config");
+    let tls_config = tls::load_or_generate_tls_config(None, None, &tls::SelfSignedOptions::from_env(), config.client_ca.as_deref()).expect("load TLS config");
     let tls_acceptor = TlsAcceptor::from(tls_config);
 
-    let listener = TcpListener::bind("0.0.0.0:8080")
+    let listener = TcpListener::bind(&config.listen_addr)
         .await
-        .expect("bind to 0.0.0.0:8080");
+        .unwrap_or_else(|e| panic!("bind to {}: {e}", config.listen_addr));
 
-    info!(addr = "0.0.0.0:8080", tls = true, "darkrelay server started");
+    info!(addr = config.listen_addr, tls = true, "darkrelay server started");
 
     let (shutdown_tx, _) = broadcast::channel::<()>(16);
     let mut shutdown_rx = shutdown_tx.subscribe();
@@ -161,7 +176,7 @@ async fn main() {
                                 }
                             };
 
-                            if let Err(e) = handler::handle_client(state, client_id, tls_stream, &mut shutdown_rx).await {
+                            if let Err(e) = handler::handle_client(state, client_id, peer_addr, tls_stream, &mut shutdown_rx).await {
                                 error!(client_id, error = %e, "client handler error");
                             }
                         });
@@ -174,5 +189,6 @@ async fn main() {
         }
     }
 
+    state.auth.write().await.flush_last_seen();
     info!("server exiting");
 }
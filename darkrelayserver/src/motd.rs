@@ -0,0 +1,30 @@
+use std::{fs, io, path::Path};
+
+use darkrelayprotocol::protocol::MAX_MOTD_LEN;
+
+/// Trim `text`; blank means no message of the day.
+pub fn normalize(text: &str) -> Result<Option<String>, String> {
+    let text = text.trim();
+    if text.len() > MAX_MOTD_LEN {
+        return Err(format!("message of the day is longer than {MAX_MOTD_LEN} bytes"));
+    }
+    Ok((!text.is_empty()).then(|| text.to_string()))
+}
+
+pub fn read_file(path: &Path) -> io::Result<Option<String>> {
+    let text = fs::read_to_string(path)?;
+    normalize(&text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_normalize() {
+        assert_eq!(normalize("  maintenance at 02:00 UTC\n"), Ok(Some("maintenance at 02:00 UTC".to_string())));
+        assert_eq!(normalize(" \n\t"), Ok(None));
+        assert!(normalize(&"x".repeat(MAX_MOTD_LEN + 1)).is_err());
+        assert!(normalize(&format!("  {}  ", "x".repeat(MAX_MOTD_LEN))).unwrap().is_some(), "the limit applies after trimming");
+    }
+}
@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use darkrelayprotocol::protocol::{ChannelId, PollId, UserId};
+
+pub const MAX_POLL_OPTIONS: usize = 10;
+pub const MAX_POLL_TEXT_LEN: usize = 200;
+
+#[derive(Debug)]
+struct Poll {
+    channel_id: ChannelId,
+    options: usize,
+    /// Each user's current choice; voting again replaces it.
+    votes: HashMap<UserId, usize>,
+}
+
+impl Poll {
+    fn tallies(&self) -> Vec<u32> {
+        let mut tallies = vec![0; self.options];
+        for &choice in self.votes.values() {
+            tallies[choice] += 1;
+        }
+        tallies
+    }
+}
+
+/// Open polls by id. A poll belongs to one channel and stays open until the
+/// channel is deleted.
+#[derive(Debug)]
+pub struct PollManager {
+    polls: HashMap<PollId, Poll>,
+    next_id: PollId,
+}
+
+impl Default for PollManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PollManager {
+    pub fn new() -> Self {
+        Self {
+            polls: HashMap::new(),
+            next_id: 1,
+        }
+    }
+
+    /// Check the question and options, then open the poll.
+    pub fn create(&mut self, channel_id: ChannelId, question: &str, options: &[String]) -> Result<PollId, String> {
+        if question.trim().is_empty() {
+            return Err("Poll question is empty".to_string());
+        }
+        if !(2..=MAX_POLL_OPTIONS).contains(&options.len()) {
+            return Err(format!("A poll needs 2 to {MAX_POLL_OPTIONS} options"));
+        }
+        if options.iter().any(|o| o.trim().is_empty()) {
+            return Err("Poll options can't be empty".to_string());
+        }
+        if std::iter::once(question).chain(options.iter().map(String::as_str)).any(|t| t.len() > MAX_POLL_TEXT_LEN) {
+            return Err(format!("Poll text is limited to {MAX_POLL_TEXT_LEN} bytes"));
+        }
+
+        let id = self.next_id;
+        self.next_id += 1;
+        self.polls.insert(id, Poll { channel_id, options: options.len(), votes: HashMap::new() });
+        Ok(id)
+    }
+
+    /// Record `user_id`'s choice, replacing any earlier vote, and return the
+    /// new tallies. `None` if the poll isn't open in `channel_id`.
+    pub fn vote(&mut self, channel_id: ChannelId, poll_id: PollId, user_id: UserId, option: usize) -> Option<Result<Vec<u32>, String>> {
+        let poll = self.polls.get_mut(&poll_id).filter(|p| p.channel_id == channel_id)?;
+        if option >= poll.options {
+            return Some(Err(format!("Poll #{poll_id} has {} options", poll.options)));
+        }
+        poll.votes.insert(user_id, option);
+        Some(Ok(poll.tallies()))
+    }
+
+    pub fn remove_channel(&mut self, channel_id: ChannelId) {
+        self.polls.retain(|_, poll| poll.channel_id != channel_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(names: &[&str]) -> Vec<String> {
+        names.iter().map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn test_one_vote_per_user() {
+        let mut polls = PollManager::new();
+        let id = polls.create(1, "Lunch?", &options(&["pizza", "sushi", "tacos"])).unwrap();
+
+        assert_eq!(polls.vote(1, id, 10, 0), Some(Ok(vec![1, 0, 0])));
+        assert_eq!(polls.vote(1, id, 11, 2), Some(Ok(vec![1, 0, 1])));
+        assert_eq!(polls.vote(1, id, 10, 0), Some(Ok(vec![1, 0, 1])), "repeat vote counts once");
+        assert_eq!(polls.vote(1, id, 10, 1), Some(Ok(vec![0, 1, 1])), "changing a vote moves it");
+
+        assert!(matches!(polls.vote(1, id, 12, 3), Some(Err(_))));
+        assert_eq!(polls.vote(2, id, 12, 0), None, "poll belongs to channel 1");
+        assert_eq!(polls.vote(1, id + 1, 12, 0), None);
+
+        polls.remove_channel(1);
+        assert_eq!(polls.vote(1, id, 10, 0), None);
+    }
+
+    #[test]
+    fn test_poll_validation() {
+        let mut polls = PollManager::new();
+        assert!(polls.create(1, " ", &options(&["a", "b"])).is_err());
+        assert!(polls.create(1, "q", &options(&["only"])).is_err());
+        assert!(polls.create(1, "q", &options(&["a", " "])).is_err());
+        assert!(polls.create(1, "q", &vec!["x".to_string(); MAX_POLL_OPTIONS + 1]).is_err());
+        assert!(polls.create(1, &"q".repeat(MAX_POLL_TEXT_LEN + 1), &options(&["a", "b"])).is_err());
+        assert_eq!(polls.create(1, "q", &options(&["a", "b"])), Ok(1));
+        assert_eq!(polls.create(1, "q", &options(&["a", "b"])), Ok(2));
+    }
+}
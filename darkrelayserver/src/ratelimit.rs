@@ -0,0 +1,146 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+
+use darkrelayprotocol::protocol::UserId;
+
+pub const DEFAULT_MAX_MESSAGES: usize = 5;
+pub const DEFAULT_WINDOW_SECS: i64 = 5;
+
+/// Per-user message rate limit plus per-channel slow mode. Keyed by user so
+/// that opening more sessions, or reconnecting, doesn't buy a fresh budget.
+#[derive(Debug)]
+pub struct RateLimiter {
+    max_messages: usize,
+    window: Duration,
+    recent: HashMap<UserId, VecDeque<DateTime<Utc>>>,
+    /// When a user may next send in a slow-mode channel, from the interval
+    /// in force when they last sent there.
+    slow_until: HashMap<(UserId, String), DateTime<Utc>>,
+}
+
+impl Default for RateLimiter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        Self {
+            max_messages: DEFAULT_MAX_MESSAGES,
+            window: Duration::seconds(DEFAULT_WINDOW_SECS),
+            recent: HashMap::new(),
+            slow_until: HashMap::new(),
+        }
+    }
+
+    pub fn set_limits(&mut self, max_messages: usize, window: Duration) {
+        self.max_messages = max_messages.max(1);
+        self.window = window;
+    }
+
+    /// Record a send if allowed; otherwise return how long the user must wait.
+    /// `slow_mode` is the channel's minimum interval between a user's messages.
+    pub fn check(
+        &mut self,
+        user_id: UserId,
+        channel: &str,
+        slow_mode: Option<Duration>,
+        now: DateTime<Utc>,
+    ) -> Result<(), Duration> {
+        let key = (user_id, channel.to_string());
+
+        if let (Some(_), Some(&ready_at)) = (slow_mode, self.slow_until.get(&key)) {
+            if ready_at > now {
+                return Err(ready_at - now);
+            }
+        }
+
+        let recent = self.recent.entry(user_id).or_default();
+        while recent.front().is_some_and(|t| *t + self.window <= now) {
+            recent.pop_front();
+        }
+        if recent.len() >= self.max_messages {
+            let oldest = *recent.front().expect("non-empty window");
+            return Err(oldest + self.window - now);
+        }
+
+        recent.push_back(now);
+        match slow_mode {
+            Some(interval) => self.slow_until.insert(key, now + interval),
+            None => self.slow_until.remove(&key),
+        };
+        Ok(())
+    }
+
+    /// Forget users with nothing left in the window and slow-mode waits that
+    /// are over. Each guest session is a new user id, so without this the
+    /// maps only grow.
+    pub fn sweep(&mut self, now: DateTime<Utc>) {
+        let window = self.window;
+        self.recent.retain(|_, sends| sends.back().is_some_and(|at| *at + window > now));
+        self.slow_until.retain(|_, ready_at| *ready_at > now);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_window_limit_reports_retry_after() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_limits(2, Duration::seconds(10));
+        let now = Utc::now();
+
+        assert!(limiter.check(1, "general", None, now).is_ok());
+        assert!(limiter.check(1, "random", None, now + Duration::seconds(1)).is_ok());
+        assert_eq!(
+            limiter.check(1, "general", None, now + Duration::seconds(4)),
+            Err(Duration::seconds(6))
+        );
+        assert!(limiter.check(2, "general", None, now).is_ok(), "limits are per user");
+        assert!(limiter.check(1, "general", None, now + Duration::seconds(10)).is_ok());
+    }
+
+    #[test]
+    fn test_slow_mode_is_per_channel() {
+        let mut limiter = RateLimiter::new();
+        let now = Utc::now();
+        let slow = Some(Duration::seconds(30));
+
+        assert!(limiter.check(1, "general", slow, now).is_ok());
+        assert_eq!(
+            limiter.check(1, "general", slow, now + Duration::seconds(10)),
+            Err(Duration::seconds(20))
+        );
+        assert!(limiter.check(1, "random", None, now + Duration::seconds(10)).is_ok());
+        assert!(limiter.check(1, "general", slow, now + Duration::seconds(30)).is_ok());
+    }
+
+    #[test]
+    fn test_sweep_forgets_idle_users() {
+        let mut limiter = RateLimiter::new();
+        limiter.set_limits(5, Duration::seconds(10));
+        let now = Utc::now();
+        let slow = Some(Duration::seconds(60));
+
+        assert!(limiter.check(1, "general", slow, now).is_ok());
+        assert!(limiter.check(crate::auth::GUEST_ID_BASE + 1, "general", None, now).is_ok());
+
+        limiter.sweep(now + Duration::seconds(5));
+        assert_eq!(limiter.recent.len(), 2, "both still inside the window");
+
+        limiter.sweep(now + Duration::seconds(10));
+        assert!(limiter.recent.is_empty());
+        assert_eq!(limiter.slow_until.len(), 1, "slow mode outlasts the window");
+        assert_eq!(
+            limiter.check(1, "general", slow, now + Duration::seconds(30)),
+            Err(Duration::seconds(30))
+        );
+
+        limiter.sweep(now + Duration::seconds(60));
+        assert!(limiter.recent.is_empty() && limiter.slow_until.is_empty());
+    }
+}
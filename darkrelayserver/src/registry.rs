@@ -1,39 +1,117 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, net::SocketAddr, sync::Arc};
 
-use darkrelayprotocol::protocol::{ServerMessage, UserInfo};
-use tokio::sync::mpsc;
+use darkrelayprotocol::protocol::{ConnectionInfo, ServerMessage, UserId, UserInfo};
+use tokio::sync::{mpsc, Notify};
+use tracing::warn;
 
 use crate::channel::ClientId;
 
+/// Default per-client outbound queue size.
+pub const DEFAULT_OUTBOUND_CAPACITY: usize = 256;
+
 #[derive(Clone)]
 pub struct ClientHandle {
     pub id: ClientId,
     pub user: Option<UserInfo>,
-    pub current_channel: Option<String>,
-    pub sender: mpsc::UnboundedSender<ServerMessage>,
+    /// Channels this client is a member of, in join order.
+    pub channels: Vec<String>,
+    /// Channels of the sessions this one replaced on login, reported once by
+    /// `ListMyChannels` so the user can pick them up here.
+    pub handed_over: Vec<String>,
+    pub client_name: Option<String>,
+    pub client_version: Option<String>,
+    pub peer_addr: Option<SocketAddr>,
+    /// Common name of the client certificate, when mutual TLS is on.
+    pub cert_subject: Option<String>,
+    /// Set by the client when its user goes idle.
+    pub away: bool,
+    /// Shared so a broadcast is one allocation however many members get it.
+    pub sender: mpsc::Sender<Arc<ServerMessage>>,
+    /// Signalled when the server wants the connection closed (see
+    /// `Registry::terminate`); the handler loop exits and cleans up.
+    pub disconnect: Arc<Notify>,
+}
+
+/// What to do when a user logs in while already connected elsewhere.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DuplicateLogin {
+    /// Refuse the new login.
+    Reject,
+    /// Disconnect the existing session and admit the new one.
+    #[default]
+    KickOld,
+}
+
+impl DuplicateLogin {
+    /// Parse `DARKRELAY_DUPLICATE_LOGIN` (`reject` or `kick`).
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_lowercase().as_str() {
+            "reject" => Some(Self::Reject),
+            "kick" => Some(Self::KickOld),
+            _ => None,
+        }
+    }
 }
 
 pub struct Registry {
     clients: HashMap<ClientId, ClientHandle>,
+    outbound_capacity: usize,
+    duplicate_login: DuplicateLogin,
+}
+
+impl Default for Registry {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Registry {
     pub fn new() -> Self {
         Self {
             clients: HashMap::new(),
+            outbound_capacity: DEFAULT_OUTBOUND_CAPACITY,
+            duplicate_login: DuplicateLogin::default(),
         }
     }
 
-    pub fn register(&mut self, id: ClientId, sender: mpsc::UnboundedSender<ServerMessage>) {
+    pub fn set_outbound_capacity(&mut self, capacity: usize) {
+        self.outbound_capacity = capacity.max(1);
+    }
+
+    pub fn outbound_capacity(&self) -> usize {
+        self.outbound_capacity
+    }
+
+    pub fn set_duplicate_login(&mut self, policy: DuplicateLogin) {
+        self.duplicate_login = policy;
+    }
+
+    pub fn duplicate_login(&self) -> DuplicateLogin {
+        self.duplicate_login
+    }
+
+    /// Register a client's outbound queue. The returned `Notify` fires when the
+    /// connection should be closed (see `terminate`), including when the
+    /// client stops draining its queue.
+    pub fn register(&mut self, id: ClientId, sender: mpsc::Sender<Arc<ServerMessage>>) -> Arc<Notify> {
+        let disconnect = Arc::new(Notify::new());
         self.clients.insert(
             id,
             ClientHandle {
                 id,
                 user: None,
-                current_channel: None,
+                channels: Vec::new(),
+                handed_over: Vec::new(),
+                client_name: None,
+                client_version: None,
+                peer_addr: None,
+                cert_subject: None,
+                away: false,
                 sender,
+                disconnect: Arc::clone(&disconnect),
             },
         );
+        disconnect
     }
 
     pub fn set_user(&mut self, id: ClientId, user: UserInfo) {
@@ -46,35 +124,187 @@ impl Registry {
         self.clients.get(&id).and_then(|h| h.user.clone())
     }
 
-    pub fn set_channel(&mut self, id: ClientId, channel: Option<String>) {
+    pub fn set_client_info(&mut self, id: ClientId, name: Option<String>, version: Option<String>) {
+        if let Some(h) = self.clients.get_mut(&id) {
+            h.client_name = name;
+            h.client_version = version;
+        }
+    }
+
+    pub fn client_info(&self, id: ClientId) -> Option<(Option<String>, Option<String>)> {
+        self.clients
+            .get(&id)
+            .map(|h| (h.client_name.clone(), h.client_version.clone()))
+    }
+
+    pub fn set_peer_addr(&mut self, id: ClientId, addr: SocketAddr) {
+        if let Some(h) = self.clients.get_mut(&id) {
+            h.peer_addr = Some(addr);
+        }
+    }
+
+    pub fn set_cert_subject(&mut self, id: ClientId, subject: String) {
+        if let Some(h) = self.clients.get_mut(&id) {
+            h.cert_subject = Some(subject);
+        }
+    }
+
+    pub fn cert_subject(&self, id: ClientId) -> Option<String> {
+        self.clients.get(&id).and_then(|h| h.cert_subject.clone())
+    }
+
+    /// A snapshot of every registered connection, ordered by client id.
+    pub fn connections(&self) -> Vec<ConnectionInfo> {
+        let mut connections: Vec<_> = self
+            .clients
+            .values()
+            .map(|h| ConnectionInfo {
+                client_id: h.id,
+                username: h.user.as_ref().map(|u| u.username.clone()),
+                current_channel: h.channels.last().cloned(),
+                peer_addr: h.peer_addr,
+                client_name: h.client_name.clone(),
+                client_version: h.client_version.clone(),
+            })
+            .collect();
+        connections.sort_by_key(|c| c.client_id);
+        connections
+    }
+
+    pub fn join_channel(&mut self, id: ClientId, channel: &str) {
+        if let Some(h) = self.clients.get_mut(&id) {
+            if !h.channels.iter().any(|c| c == channel) {
+                h.channels.push(channel.to_string());
+            }
+        }
+    }
+
+    pub fn leave_channel(&mut self, id: ClientId, channel: &str) -> bool {
+        if let Some(h) = self.clients.get_mut(&id) {
+            let len_before = h.channels.len();
+            h.channels.retain(|c| c != channel);
+            h.channels.len() < len_before
+        } else {
+            false
+        }
+    }
+
+    /// Point every client that is in `old` at `new` instead, keeping its position.
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for h in self.clients.values_mut() {
+            for channel in h.channels.iter_mut().filter(|c| *c == old) {
+                *channel = new.to_string();
+            }
+        }
+    }
+
+    pub fn channels(&self, id: ClientId) -> Vec<String> {
+        self.clients
+            .get(&id)
+            .map(|h| h.channels.clone())
+            .unwrap_or_default()
+    }
+
+    /// Channels any session of `user_id` is in, plus what was handed over to
+    /// `id`, which is cleared. Sorted and without repeats.
+    pub fn take_user_channels(&mut self, id: ClientId, user_id: UserId) -> Vec<String> {
+        let mut channels = self.clients.get_mut(&id).map(|h| std::mem::take(&mut h.handed_over)).unwrap_or_default();
+        channels.extend(
+            self.clients
+                .values()
+                .filter(|h| h.user.as_ref().is_some_and(|u| u.id == user_id))
+                .flat_map(|h| h.channels.iter().cloned()),
+        );
+        channels.sort_unstable();
+        channels.dedup();
+        channels
+    }
+
+    /// Remember the channels of `replaced` on `id`, before the replaced
+    /// sessions close and their membership goes with them.
+    pub fn hand_over_channels(&mut self, id: ClientId, replaced: &[ClientId]) {
+        let channels: Vec<String> = replaced.iter().flat_map(|r| self.channels(*r)).collect();
         if let Some(h) = self.clients.get_mut(&id) {
-            h.current_channel = channel;
+            h.handed_over.extend(channels);
         }
     }
 
-    pub fn channel(&self, id: ClientId) -> Option<String> {
+    pub fn is_in_channel(&self, id: ClientId, channel: &str) -> bool {
         self.clients
             .get(&id)
-            .and_then(|h| h.current_channel.clone())
+            .is_some_and(|h| h.channels.iter().any(|c| c == channel))
+    }
+
+    /// Returns whether the flag changed.
+    pub fn set_away(&mut self, id: ClientId, away: bool) -> bool {
+        match self.clients.get_mut(&id) {
+            Some(h) if h.away != away => {
+                h.away = away;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Other clients sharing at least one channel with `id`.
+    pub fn channel_peers(&self, id: ClientId) -> Vec<ClientId> {
+        let Some(me) = self.clients.get(&id) else {
+            return Vec::new();
+        };
+        self.clients
+            .values()
+            .filter(|h| h.id != id && h.channels.iter().any(|c| me.channels.contains(c)))
+            .map(|h| h.id)
+            .collect()
     }
 
-    pub fn sender(&self, id: ClientId) -> Option<mpsc::UnboundedSender<ServerMessage>> {
-        self.clients.get(&id).map(|h| h.sender.clone())
+    /// Clients that have logged in (as a user or a guest).
+    pub fn authenticated(&self) -> Vec<ClientId> {
+        self.clients.values().filter(|h| h.user.is_some()).map(|h| h.id).collect()
+    }
+
+    /// Ask a client's connection to close, e.g. a kicked duplicate login or a
+    /// forced disconnect. Messages already queued are still flushed.
+    pub fn terminate(&self, id: ClientId) {
+        if let Some(h) = self.clients.get(&id) {
+            h.disconnect.notify_one();
+        }
     }
 
     pub fn remove(&mut self, id: ClientId) {
         self.clients.remove(&id);
     }
 
+    /// Queue a message without waiting. A client whose queue is full is
+    /// lagging: the message is dropped and the client is disconnected rather
+    /// than letting its backlog grow.
     pub fn send(&self, id: ClientId, msg: ServerMessage) {
+        self.send_shared(id, Arc::new(msg));
+    }
+
+    fn send_shared(&self, id: ClientId, msg: Arc<ServerMessage>) {
         if let Some(h) = self.clients.get(&id) {
-            let _ = h.sender.send(msg);
+            if let Err(mpsc::error::TrySendError::Full(_)) = h.sender.try_send(msg) {
+                warn!(client_id = id, "outbound queue full, disconnecting lagging client");
+                h.disconnect.notify_one();
+            }
         }
     }
 
+    /// Every recipient's queue holds the same `Arc`; the payload is cloned once.
     pub fn send_many(&self, ids: &[ClientId], msg: &ServerMessage) {
+        let msg = Arc::new(msg.clone());
         for id in ids {
-            self.send(*id, msg.clone());
+            self.send_shared(*id, Arc::clone(&msg));
+        }
+    }
+
+    /// Like `send_many`, but skips `except`, for events about a client that
+    /// it should not see echoed back (presence, typing).
+    pub fn send_many_except(&self, ids: &[ClientId], except: ClientId, msg: &ServerMessage) {
+        let msg = Arc::new(msg.clone());
+        for id in ids.iter().filter(|id| **id != except) {
+            self.send_shared(*id, Arc::clone(&msg));
         }
     }
 
@@ -92,3 +322,112 @@ impl Registry {
             .collect()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_client_info_recorded() {
+        let mut reg = Registry::new();
+        let (tx, _rx) = mpsc::channel(DEFAULT_OUTBOUND_CAPACITY);
+        reg.register(1, tx);
+
+        assert_eq!(reg.client_info(1), Some((None, None)));
+
+        reg.set_client_info(1, Some("darkrelayclient".to_string()), Some("0.1.0".to_string()));
+        assert_eq!(
+            reg.client_info(1),
+            Some((Some("darkrelayclient".to_string()), Some("0.1.0".to_string())))
+        );
+        assert_eq!(reg.client_info(2), None);
+    }
+
+    #[test]
+    fn test_multiple_channel_membership() {
+        let mut reg = Registry::new();
+        let (tx, _rx) = mpsc::channel(DEFAULT_OUTBOUND_CAPACITY);
+        reg.register(1, tx);
+
+        reg.join_channel(1, "general");
+        reg.join_channel(1, "random");
+        reg.join_channel(1, "general");
+        assert_eq!(reg.channels(1), vec!["general".to_string(), "random".to_string()]);
+        assert!(reg.is_in_channel(1, "random"));
+
+        assert!(reg.leave_channel(1, "general"));
+        assert!(!reg.leave_channel(1, "general"));
+        assert!(!reg.is_in_channel(1, "general"));
+        assert_eq!(reg.channels(1), vec!["random".to_string()]);
+    }
+
+    #[test]
+    fn test_send_many_except_skips_one_client() {
+        let mut reg = Registry::new();
+        let (tx1, mut rx1) = mpsc::channel(DEFAULT_OUTBOUND_CAPACITY);
+        let (tx2, mut rx2) = mpsc::channel(DEFAULT_OUTBOUND_CAPACITY);
+        let (tx3, mut rx3) = mpsc::channel(DEFAULT_OUTBOUND_CAPACITY);
+        reg.register(1, tx1);
+        reg.register(2, tx2);
+        reg.register(3, tx3);
+
+        let msg = ServerMessage::SystemMessage {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, chrono::Utc::now()),
+            text: "hi".to_string(),
+        };
+        reg.send_many_except(&[1, 2, 3], 2, &msg);
+
+        assert!(rx1.try_recv().is_ok());
+        assert!(rx2.try_recv().is_err());
+        assert!(rx3.try_recv().is_ok());
+    }
+
+    #[test]
+    fn test_broadcast_shares_one_payload() {
+        let mut reg = Registry::new();
+        let mut queues = Vec::new();
+        for id in 0..1000 {
+            let (tx, rx) = mpsc::channel(DEFAULT_OUTBOUND_CAPACITY);
+            reg.register(id, tx);
+            queues.push(rx);
+        }
+        let ids: Vec<ClientId> = (0..1000).collect();
+
+        let msg = ServerMessage::SystemMessage {
+            meta: darkrelayprotocol::protocol::MessageMeta::new(1, chrono::Utc::now()),
+            text: "x".repeat(4096),
+        };
+        reg.send_many(&ids, &msg);
+
+        let received: Vec<Arc<ServerMessage>> = queues.iter_mut().map(|rx| rx.try_recv().unwrap()).collect();
+        assert!(received.iter().all(|m| Arc::ptr_eq(m, &received[0])), "one allocation for every recipient");
+        assert_eq!(Arc::strong_count(&received[0]), 1000);
+        assert!(matches!(&*received[0], ServerMessage::SystemMessage { text, .. } if *text == "x".repeat(4096)));
+    }
+
+    #[tokio::test]
+    async fn test_full_queue_disconnects_instead_of_growing() {
+        use chrono::Utc;
+        use darkrelayprotocol::protocol::MessageMeta;
+
+        let mut reg = Registry::new();
+        let (tx, mut rx) = mpsc::channel(2);
+        let lagged = reg.register(1, tx);
+
+        let sys = |id| ServerMessage::SystemMessage {
+            meta: MessageMeta::new(id, Utc::now()),
+            text: "hi".to_string(),
+        };
+        for id in 0..5 {
+            reg.send(1, sys(id));
+        }
+
+        tokio::time::timeout(std::time::Duration::from_millis(100), lagged.notified())
+            .await
+            .expect("lagging client should be flagged");
+
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_ok());
+        assert!(rx.try_recv().is_err(), "queue must stay bounded");
+    }
+}
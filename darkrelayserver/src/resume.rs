@@ -0,0 +1,194 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use darkrelayprotocol::protocol::UserInfo;
+use rand::RngCore;
+
+use crate::channel::ClientId;
+
+/// How long a disconnected session can be resumed.
+pub const RESUME_GRACE_SECS: i64 = 60;
+
+#[derive(Debug, Clone)]
+struct ResumeEntry {
+    user: UserInfo,
+    client_id: Option<ClientId>,
+    channels: Vec<String>,
+    detached_at: Option<DateTime<Utc>>,
+}
+
+/// Resume tokens handed out in `AuthSuccess`.
+///
+/// A token stays bound to its live session; once that session disconnects it
+/// can be redeemed by a new connection for `RESUME_GRACE_SECS`.
+#[derive(Debug, Default)]
+pub struct ResumeManager {
+    entries: HashMap<String, ResumeEntry>,
+    by_client: HashMap<ClientId, String>,
+}
+
+impl ResumeManager {
+    pub fn new() -> Self {
+        Self {
+            entries: HashMap::new(),
+            by_client: HashMap::new(),
+        }
+    }
+
+    /// Issue a fresh token for `client_id`, replacing any it already held.
+    pub fn issue(&mut self, client_id: ClientId, user: UserInfo) -> String {
+        if let Some(old) = self.by_client.remove(&client_id) {
+            self.entries.remove(&old);
+        }
+
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = hex::encode(bytes);
+
+        self.entries.insert(
+            token.clone(),
+            ResumeEntry {
+                user,
+                client_id: Some(client_id),
+                channels: Vec::new(),
+                detached_at: None,
+            },
+        );
+        self.by_client.insert(client_id, token.clone());
+        token
+    }
+
+    /// Park the session of a disconnecting client. Returns false if it had no token.
+    pub fn detach(&mut self, client_id: ClientId, channels: Vec<String>, now: DateTime<Utc>) -> bool {
+        let Some(token) = self.by_client.remove(&client_id) else {
+            return false;
+        };
+        let Some(entry) = self.entries.get_mut(&token) else {
+            return false;
+        };
+
+        entry.client_id = None;
+        entry.channels = channels;
+        entry.detached_at = Some(now);
+        true
+    }
+
+    /// Follow a channel rename in parked sessions, so resuming rejoins it.
+    pub fn rename_channel(&mut self, old: &str, new: &str) {
+        for entry in self.entries.values_mut() {
+            for channel in entry.channels.iter_mut().filter(|c| *c == old) {
+                *channel = new.to_string();
+            }
+        }
+    }
+
+    /// Invalidate the token of a live session so it is not parked when it closes.
+    pub fn revoke(&mut self, client_id: ClientId) {
+        if let Some(token) = self.by_client.remove(&client_id) {
+            self.entries.remove(&token);
+        }
+    }
+
+    /// Redeem a detached token, returning the user and the channels to restore.
+    pub fn redeem(&mut self, token: &str, now: DateTime<Utc>) -> Result<(UserInfo, Vec<String>), String> {
+        let entry = self
+            .entries
+            .get(token)
+            .ok_or_else(|| "invalid resume token".to_string())?;
+
+        let Some(detached_at) = entry.detached_at else {
+            return Err("session still active".to_string());
+        };
+
+        if now - detached_at > Duration::seconds(RESUME_GRACE_SECS) {
+            self.entries.remove(token);
+            return Err("resume token expired".to_string());
+        }
+
+        let entry = self.entries.remove(token).expect("entry present");
+        Ok((entry.user, entry.channels))
+    }
+
+    /// Keep the user info parked with tokens in step with a rename.
+    pub fn rename(&mut self, user: &UserInfo) {
+        for entry in self.entries.values_mut() {
+            if entry.user.id == user.id {
+                entry.user = user.clone();
+            }
+        }
+    }
+
+    /// Drop detached sessions past the grace period, returning them so their
+    /// departure can be announced.
+    pub fn expire(&mut self, now: DateTime<Utc>) -> Vec<(UserInfo, Vec<String>)> {
+        let cutoff = Duration::seconds(RESUME_GRACE_SECS);
+        let expired: Vec<String> = self
+            .entries
+            .iter()
+            .filter(|(_, e)| e.detached_at.is_some_and(|at| now - at > cutoff))
+            .map(|(token, _)| token.clone())
+            .collect();
+
+        expired
+            .into_iter()
+            .filter_map(|token| self.entries.remove(&token))
+            .map(|e| (e.user, e.channels))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user() -> UserInfo {
+        UserInfo {
+            id: 1,
+            username: "alice".to_string(),
+            joined_at: Utc::now(),
+            signing_key: None,
+            public_key: None,
+        }
+    }
+
+    #[test]
+    fn test_redeem_within_grace() {
+        let mut mgr = ResumeManager::new();
+        let token = mgr.issue(10, user());
+        let now = Utc::now();
+
+        assert_eq!(mgr.redeem(&token, now).unwrap_err(), "session still active");
+
+        assert!(mgr.detach(10, vec!["general".to_string()], now));
+        let (u, channels) = mgr.redeem(&token, now + Duration::seconds(30)).unwrap();
+        assert_eq!(u.username, "alice");
+        assert_eq!(channels, vec!["general".to_string()]);
+
+        assert!(mgr.redeem(&token, now).is_err());
+    }
+
+    #[test]
+    fn test_expired_token_rejected() {
+        let mut mgr = ResumeManager::new();
+        let token = mgr.issue(10, user());
+        let now = Utc::now();
+        mgr.detach(10, Vec::new(), now);
+
+        let later = now + Duration::seconds(RESUME_GRACE_SECS + 1);
+        assert_eq!(mgr.redeem(&token, later).unwrap_err(), "resume token expired");
+    }
+
+    #[test]
+    fn test_expire_returns_detached_sessions() {
+        let mut mgr = ResumeManager::new();
+        mgr.issue(10, user());
+        let live = mgr.issue(11, user());
+        let now = Utc::now();
+        mgr.detach(10, vec!["general".to_string()], now);
+
+        let expired = mgr.expire(now + Duration::seconds(RESUME_GRACE_SECS + 1));
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].1, vec!["general".to_string()]);
+        assert!(mgr.entries.contains_key(&live));
+    }
+}
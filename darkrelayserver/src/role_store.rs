@@ -0,0 +1,80 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::PathBuf,
+};
+
+use darkrelayprotocol::{channel::ChannelType, permissions::Role};
+use serde::{Deserialize, Serialize};
+
+/// What survives a restart of one channel: its type and its staff.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StoredChannel {
+    pub channel_type: ChannelType,
+    /// Keyed by normalized username; plain members aren't stored.
+    pub roles: BTreeMap<String, Role>,
+}
+
+/// Channel types and roles in one JSON file, keyed by channel name because
+/// channel ids are reassigned on restart. The whole file is rewritten on
+/// each change, through a temporary file so a crash never leaves half of it;
+/// callers serialize through the `AdminManager` lock.
+#[derive(Debug)]
+pub struct RoleStore {
+    path: PathBuf,
+    channels: BTreeMap<String, StoredChannel>,
+}
+
+impl RoleStore {
+    /// Load `path`, or start empty if it doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let channels = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self { path, channels })
+    }
+
+    pub fn get(&self, channel: &str) -> Option<&StoredChannel> {
+        self.channels.get(channel)
+    }
+
+    /// Everyone holding a stored role in some channel.
+    pub fn usernames(&self) -> impl Iterator<Item = &String> {
+        self.channels.values().flat_map(|stored| stored.roles.keys())
+    }
+
+    pub fn put(&mut self, channel: &str, stored: StoredChannel) -> io::Result<()> {
+        if self.channels.get(channel) == Some(&stored) {
+            return Ok(());
+        }
+        self.channels.insert(channel.to_string(), stored);
+        self.save()
+    }
+
+    pub fn remove(&mut self, channel: &str) -> io::Result<()> {
+        if self.channels.remove(channel).is_none() {
+            return Ok(());
+        }
+        self.save()
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str) -> io::Result<()> {
+        let Some(stored) = self.channels.remove(old) else {
+            return Ok(());
+        };
+        self.channels.insert(new.to_string(), stored);
+        self.save()
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(&self.channels)?)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
@@ -0,0 +1,140 @@
+use std::collections::{HashMap, VecDeque};
+
+use chrono::{DateTime, Duration, Utc};
+use darkrelayprotocol::protocol::UserId;
+
+pub const DEFAULT_SPAM_REPEATS: usize = 4;
+pub const DEFAULT_SPAM_WINDOW_SECS: i64 = 10;
+pub const DEFAULT_SPAM_MUTE_SECS: i64 = 60;
+
+/// When to treat a run of sends as spam and how long to mute for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SpamPolicy {
+    /// Identical-size sends that trip the heuristic; 0 turns it off.
+    pub repeats: usize,
+    pub window: Duration,
+    pub mute: Duration,
+}
+
+impl Default for SpamPolicy {
+    fn default() -> Self {
+        Self {
+            repeats: DEFAULT_SPAM_REPEATS,
+            window: Duration::seconds(DEFAULT_SPAM_WINDOW_SECS),
+            mute: Duration::seconds(DEFAULT_SPAM_MUTE_SECS),
+        }
+    }
+}
+
+/// A user's sends in one channel.
+type SendKey = (UserId, String);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SpamVerdict {
+    Allowed,
+    /// This send tripped the heuristic; the user is now muted for the policy's duration.
+    Flagged,
+    /// Still muted from an earlier flag.
+    Muted { remaining: Duration },
+}
+
+/// Flags users who send the same thing over and over. The server only sees
+/// ciphertext, so "the same thing" means the same content length, sent
+/// `repeats` times within `window` in one channel.
+#[derive(Debug, Default)]
+pub struct SpamDetector {
+    policy: SpamPolicy,
+    /// Content length and time of the trailing run of same-size sends.
+    recent: HashMap<SendKey, VecDeque<(usize, DateTime<Utc>)>>,
+    muted_until: HashMap<SendKey, DateTime<Utc>>,
+}
+
+impl SpamDetector {
+    pub fn new(policy: SpamPolicy) -> Self {
+        Self { policy, ..Self::default() }
+    }
+
+    pub fn policy(&self) -> SpamPolicy {
+        self.policy
+    }
+
+    /// Record a send of `len` content bytes and judge it.
+    pub fn check(&mut self, user_id: UserId, channel: &str, len: usize, now: DateTime<Utc>) -> SpamVerdict {
+        if self.policy.repeats == 0 {
+            return SpamVerdict::Allowed;
+        }
+        let key = (user_id, channel.to_string());
+
+        if let Some(until) = self.muted_until.get(&key) {
+            if *until > now {
+                return SpamVerdict::Muted { remaining: *until - now };
+            }
+            self.muted_until.remove(&key);
+        }
+
+        let recent = self.recent.entry(key.clone()).or_default();
+        while recent.front().is_some_and(|(_, at)| *at + self.policy.window <= now) {
+            recent.pop_front();
+        }
+        // Only the trailing run of same-size sends matters.
+        if recent.back().is_some_and(|(last, _)| *last != len) {
+            recent.clear();
+        }
+        recent.push_back((len, now));
+
+        if recent.len() < self.policy.repeats {
+            return SpamVerdict::Allowed;
+        }
+        self.recent.remove(&key);
+        self.muted_until.insert(key, now + self.policy.mute);
+        SpamVerdict::Flagged
+    }
+
+    /// Drop expired mutes and stale history.
+    pub fn sweep(&mut self, now: DateTime<Utc>) {
+        let window = self.policy.window;
+        self.muted_until.retain(|_, until| *until > now);
+        self.recent.retain(|_, sends| sends.back().is_some_and(|(_, at)| *at + window > now));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_identical_rapid_sends_are_flagged() {
+        let mut spam = SpamDetector::new(SpamPolicy::default());
+        let now = Utc::now();
+        let at = |secs| now + Duration::seconds(secs);
+
+        for i in 0..3 {
+            assert_eq!(spam.check(1, "general", 48, at(i)), SpamVerdict::Allowed);
+        }
+        assert_eq!(spam.check(1, "general", 48, at(3)), SpamVerdict::Flagged);
+        assert_eq!(
+            spam.check(1, "general", 17, at(4)),
+            SpamVerdict::Muted { remaining: Duration::seconds(59) }
+        );
+        assert_eq!(spam.check(1, "random", 48, at(4)), SpamVerdict::Allowed, "mutes are per channel");
+        assert_eq!(spam.check(1, "general", 48, at(63)), SpamVerdict::Allowed, "mute expires");
+    }
+
+    #[test]
+    fn test_varied_or_slow_sends_pass() {
+        let mut spam = SpamDetector::new(SpamPolicy::default());
+        let now = Utc::now();
+
+        for (i, len) in [48, 48, 48, 52, 48, 48, 48, 61].into_iter().enumerate() {
+            assert_eq!(spam.check(1, "general", len, now + Duration::seconds(i as i64)), SpamVerdict::Allowed);
+        }
+        for i in 0..8 {
+            assert_eq!(spam.check(2, "general", 48, now + Duration::seconds(i * 4)), SpamVerdict::Allowed);
+        }
+
+        let mut off = SpamDetector::new(SpamPolicy { repeats: 0, ..SpamPolicy::default() });
+        for _ in 0..10 {
+            assert_eq!(off.check(1, "general", 48, now), SpamVerdict::Allowed);
+        }
+    }
+}
@@ -1,23 +1,123 @@
-use std::{fs, io, path::Path, sync::Arc};
-use rcgen::{Certificate, CertificateParams, DistinguishedName};
-use rustls::{ServerConfig, server::AllowAnyAuthenticatedClient, RootCertStore};
+use std::{
+    env, fs, io,
+    net::IpAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use rcgen::{Certificate, CertificateParams, DistinguishedName, SanType};
+use rustls::{
+    server::{AllowAnyAuthenticatedClient, ClientCertVerifier, NoClientAuth},
+    RootCertStore, ServerConfig,
+};
 use rustls_pemfile::{certs, pkcs8_private_keys};
 use tracing::{info, warn};
+use x509_parser::{certificate::X509Certificate, der_parser::asn1_rs::Tag, prelude::FromDer};
 
-pub fn load_or_generate_tls_config(cert_path: Option<&str>, key_path: Option<&str>) -> io::Result<Arc<ServerConfig>> {
+const DEFAULT_VALIDITY_DAYS: i64 = 365;
+const CERT_FILE: &str = "darkrelay-cert.pem";
+const KEY_FILE: &str = "darkrelay-key.pem";
+
+/// Settings for the generated self-signed certificate.
+#[derive(Debug, Clone)]
+pub struct SelfSignedOptions {
+    /// Extra DNS names / IPs on top of localhost, 127.0.0.1 and 0.0.0.0.
+    pub extra_sans: Vec<String>,
+    pub validity_days: i64,
+    /// Directory the generated cert/key are written to and reloaded from.
+    pub persist_dir: Option<PathBuf>,
+}
+
+impl Default for SelfSignedOptions {
+    fn default() -> Self {
+        Self {
+            extra_sans: Vec::new(),
+            validity_days: DEFAULT_VALIDITY_DAYS,
+            persist_dir: None,
+        }
+    }
+}
+
+impl SelfSignedOptions {
+    /// Reads `DARKRELAY_SANS` (comma-separated), `DARKRELAY_CERT_VALIDITY_DAYS`
+    /// and `DARKRELAY_CERT_DIR` (defaults to the working directory).
+    pub fn from_env() -> Self {
+        let extra_sans = env::var("DARKRELAY_SANS")
+            .map(|v| {
+                v.split(',')
+                    .map(|s| s.trim().to_string())
+                    .filter(|s| !s.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        let validity_days = env::var("DARKRELAY_CERT_VALIDITY_DAYS")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .filter(|d| *d > 0)
+            .unwrap_or(DEFAULT_VALIDITY_DAYS);
+
+        let persist_dir = Some(env::var("DARKRELAY_CERT_DIR").map(PathBuf::from).unwrap_or_else(|_| PathBuf::from(".")));
+
+        Self {
+            extra_sans,
+            validity_days,
+            persist_dir,
+        }
+    }
+}
+
+/// `client_ca` turns on mutual TLS: clients must present a certificate signed
+/// by one of the CAs in that PEM file.
+pub fn load_or_generate_tls_config(
+    cert_path: Option<&str>,
+    key_path: Option<&str>,
+    options: &SelfSignedOptions,
+    client_ca: Option<&Path>,
+) -> io::Result<Arc<ServerConfig>> {
+    let verifier = client_verifier(client_ca)?;
     match (cert_path, key_path) {
         (Some(cert), Some(key)) => {
             info!("loading TLS certificate from {}", cert);
-            load_tls_config(cert, key)
+            load_tls_config(cert, key, verifier)
         }
-        _ => {
-            info!("generating self-signed TLS certificate");
-            generate_self_signed_config()
+        _ => self_signed_config(options, verifier),
+    }
+}
+
+/// No client authentication unless a client CA is configured.
+fn client_verifier(client_ca: Option<&Path>) -> io::Result<Arc<dyn ClientCertVerifier>> {
+    let Some(path) = client_ca else {
+        return Ok(NoClientAuth::boxed());
+    };
+
+    let mut reader = io::BufReader::new(fs::File::open(path)?);
+    let mut roots = RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(&certs(&mut reader)?);
+    if added == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "no usable CA certificates in client CA file"));
+    }
+
+    info!(path = %path.display(), cas = added, "requiring client certificates");
+    Ok(AllowAnyAuthenticatedClient::new(roots).boxed())
+}
+
+/// Reuse a previously persisted self-signed cert so client pins survive
+/// restarts; otherwise generate one and persist it.
+fn self_signed_config(options: &SelfSignedOptions, verifier: Arc<dyn ClientCertVerifier>) -> io::Result<Arc<ServerConfig>> {
+    if let Some(dir) = &options.persist_dir {
+        let cert = dir.join(CERT_FILE);
+        let key = dir.join(KEY_FILE);
+        if cert.exists() && key.exists() {
+            info!("loading persisted self-signed TLS certificate from {}", cert.display());
+            return load_tls_config(&cert.to_string_lossy(), &key.to_string_lossy(), verifier);
         }
     }
+
+    info!("generating self-signed TLS certificate");
+    generate_self_signed_config(options, verifier)
 }
 
-fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConfig>> {
+fn load_tls_config(cert_path: &str, key_path: &str, verifier: Arc<dyn ClientCertVerifier>) -> io::Result<Arc<ServerConfig>> {
     let cert_file = fs::File::open(cert_path)?;
     let key_file = fs::File::open(key_path)?;
     
@@ -40,29 +140,52 @@ fn load_tls_config(cert_path: &str, key_path: &str) -> io::Result<Arc<ServerConf
     
     let config = ServerConfig::builder()
         .with_safe_defaults()
-        .with_no_client_auth()
+        .with_client_cert_verifier(verifier)
         .with_single_cert(cert_chain, keys.remove(0))
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
     
     Ok(Arc::new(config))
 }
 
-fn generate_self_signed_config() -> io::Result<Arc<ServerConfig>> {
+fn self_signed_params(options: &SelfSignedOptions) -> CertificateParams {
     let mut params = CertificateParams::default();
     params.distinguished_name = DistinguishedName::new();
     params.distinguished_name.push(rcgen::DnType::CommonName, "darkrelay-server");
     params.subject_alt_names = vec![
-        rcgen::SanType::DnsName("localhost".to_string()),
-        rcgen::SanType::IpAddress(std::net::IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))),
-        rcgen::SanType::IpAddress(std::net::IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))),
+        SanType::DnsName("localhost".to_string()),
+        SanType::IpAddress(IpAddr::V4(std::net::Ipv4Addr::new(127, 0, 0, 1))),
+        SanType::IpAddress(IpAddr::V4(std::net::Ipv4Addr::new(0, 0, 0, 0))),
     ];
-    
-    let cert = Certificate::from_params(params)
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+    for name in &options.extra_sans {
+        let san = match name.parse::<IpAddr>() {
+            Ok(ip) => SanType::IpAddress(ip),
+            Err(_) => SanType::DnsName(name.clone()),
+        };
+        if !params.subject_alt_names.contains(&san) {
+            params.subject_alt_names.push(san);
+        }
+    }
+
+    let now = time::OffsetDateTime::now_utc();
+    params.not_before = now - time::Duration::days(1);
+    params.not_after = now + time::Duration::days(options.validity_days);
+    params
+}
+
+fn generate_self_signed_config(options: &SelfSignedOptions, verifier: Arc<dyn ClientCertVerifier>) -> io::Result<Arc<ServerConfig>> {
+    let cert = Certificate::from_params(self_signed_params(options))
+        .map_err(io::Error::other)?;
     
     let cert_der = cert.serialize_der()
-        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
+        .map_err(io::Error::other)?;
     let key_der = cert.serialize_private_key_der();
+
+    if let Some(dir) = &options.persist_dir {
+        let cert_pem = cert.serialize_pem().map_err(io::Error::other)?;
+        if let Err(e) = persist_pem(dir, &cert_pem, &cert.serialize_private_key_pem()) {
+            warn!(error = %e, "failed to persist self-signed certificate");
+        }
+    }
     
     let cert_chain = vec![rustls::Certificate(cert_der)];
     let private_key = rustls::PrivateKey(key_der);
@@ -71,9 +194,215 @@ fn generate_self_signed_config() -> io::Result<Arc<ServerConfig>> {
     
     let config = ServerConfig::builder()
         .with_safe_defaults()
-        .with_no_client_auth()
+        .with_client_cert_verifier(verifier)
         .with_single_cert(cert_chain, private_key)
         .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
     
     Ok(Arc::new(config))
 }
+
+fn persist_pem(dir: &Path, cert_pem: &str, key_pem: &str) -> io::Result<()> {
+    fs::create_dir_all(dir)?;
+    fs::write(dir.join(CERT_FILE), cert_pem)?;
+
+    let key_path = dir.join(KEY_FILE);
+    // Create the key owner-only from the start rather than tightening it
+    // after the fact, so it is never readable by anyone else. `mode` only
+    // applies to a new file; a key left behind from an earlier run keeps its
+    // permissions, so tighten those before writing.
+    #[cfg(unix)]
+    {
+        use std::{
+            io::Write,
+            os::unix::fs::{OpenOptionsExt, PermissionsExt},
+        };
+        let mut file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(&key_path)?;
+        file.set_permissions(fs::Permissions::from_mode(0o600))?;
+        file.write_all(key_pem.as_bytes())?;
+    }
+    #[cfg(not(unix))]
+    fs::write(&key_path, key_pem)?;
+
+    info!("persisted self-signed TLS certificate to {}", dir.display());
+    Ok(())
+}
+
+/// The subject common name of a DER certificate, used to tie a client
+/// certificate to the account it logs in as. Returns `None` when the
+/// certificate does not parse or carries no readable common name.
+pub fn cert_common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = X509Certificate::from_der(der).ok()?;
+    let attr = cert.subject().iter_common_name().next()?;
+    let value = attr.attr_value();
+    match value.tag() {
+        Tag::Utf8String | Tag::PrintableString | Tag::Ia5String => {
+            std::str::from_utf8(value.data).ok().map(str::to_string)
+        }
+        Tag::BmpString => {
+            if value.data.len() % 2 != 0 {
+                return None;
+            }
+            let units: Vec<u16> = value.data.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]])).collect();
+            String::from_utf16(&units).ok()
+        }
+        Tag::UniversalString => {
+            if value.data.len() % 4 != 0 {
+                return None;
+            }
+            value
+                .data
+                .chunks_exact(4)
+                .map(|c| char::from_u32(u32::from_be_bytes([c[0], c[1], c[2], c[3]])))
+                .collect()
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_extra_sans_included() {
+        let options = SelfSignedOptions {
+            extra_sans: vec!["chat.example.com".to_string(), "10.0.0.5".to_string(), "localhost".to_string()],
+            validity_days: 30,
+            persist_dir: None,
+        };
+        let params = self_signed_params(&options);
+
+        assert!(params
+            .subject_alt_names
+            .contains(&SanType::DnsName("chat.example.com".to_string())));
+        assert!(params
+            .subject_alt_names
+            .contains(&SanType::IpAddress("10.0.0.5".parse().unwrap())));
+        assert_eq!(
+            params
+                .subject_alt_names
+                .iter()
+                .filter(|s| **s == SanType::DnsName("localhost".to_string()))
+                .count(),
+            1
+        );
+        assert_eq!((params.not_after - params.not_before).whole_days(), 31);
+    }
+
+    #[test]
+    fn test_generated_cert_is_persisted_and_reused() {
+        let dir = env::temp_dir().join(format!("darkrelay-tls-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let options = SelfSignedOptions {
+            persist_dir: Some(dir.clone()),
+            ..SelfSignedOptions::default()
+        };
+
+        self_signed_config(&options, NoClientAuth::boxed()).unwrap();
+        let first = fs::read(dir.join(CERT_FILE)).unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(dir.join(KEY_FILE)).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+
+        self_signed_config(&options, NoClientAuth::boxed()).unwrap();
+        assert_eq!(fs::read(dir.join(CERT_FILE)).unwrap(), first);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_leftover_key_file_is_made_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = env::temp_dir().join(format!("darkrelay-tls-perm-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(KEY_FILE), "stale").unwrap();
+        fs::set_permissions(dir.join(KEY_FILE), fs::Permissions::from_mode(0o644)).unwrap();
+
+        let options = SelfSignedOptions {
+            persist_dir: Some(dir.clone()),
+            ..SelfSignedOptions::default()
+        };
+        self_signed_config(&options, NoClientAuth::boxed()).unwrap();
+        let mode = fs::metadata(dir.join(KEY_FILE)).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_client_ca_requires_client_certs() {
+        let dir = env::temp_dir().join(format!("darkrelay-mtls-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let mut ca_params = self_signed_params(&SelfSignedOptions::default());
+        ca_params.is_ca = rcgen::IsCa::Ca(rcgen::BasicConstraints::Unconstrained);
+        let ca = Certificate::from_params(ca_params).unwrap();
+        let ca_path = dir.join("client-ca.pem");
+        fs::write(&ca_path, ca.serialize_pem().unwrap()).unwrap();
+
+        assert!(client_verifier(Some(&ca_path)).unwrap().client_auth_mandatory());
+        assert!(!client_verifier(None).unwrap().offer_client_auth());
+
+        let empty = dir.join("empty.pem");
+        fs::write(&empty, "").unwrap();
+        assert!(client_verifier(Some(&empty)).is_err());
+
+        let options = SelfSignedOptions::default();
+        assert!(load_or_generate_tls_config(None, None, &options, Some(&ca_path)).is_ok());
+
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn test_cert_common_name() {
+        let mut params = CertificateParams::new(vec!["client".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::OrganizationName, "darkrelay");
+        params.distinguished_name.push(rcgen::DnType::CommonName, "alice");
+        let cert = Certificate::from_params(params).unwrap();
+
+        assert_eq!(cert_common_name(&cert.serialize_der().unwrap()).as_deref(), Some("alice"));
+        assert_eq!(cert_common_name(&[0x30, 0x03, 0x02]), None);
+    }
+
+    fn der_with_common_name(value: Option<rcgen::DnValue>) -> Vec<u8> {
+        let mut params = CertificateParams::new(vec!["client".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        params.distinguished_name.push(rcgen::DnType::OrganizationName, "darkrelay");
+        if let Some(value) = value {
+            params.distinguished_name.push(rcgen::DnType::CommonName, value);
+        }
+        Certificate::from_params(params).unwrap().serialize_der().unwrap()
+    }
+
+    #[test]
+    fn test_cert_common_name_wide_strings() {
+        let bmp: Vec<u8> = "zoë".encode_utf16().flat_map(u16::to_be_bytes).collect();
+        let der = der_with_common_name(Some(rcgen::DnValue::BmpString(bmp)));
+        assert_eq!(cert_common_name(&der).as_deref(), Some("zoë"));
+
+        let universal: Vec<u8> = "bob".chars().flat_map(|c| (c as u32).to_be_bytes()).collect();
+        let der = der_with_common_name(Some(rcgen::DnValue::UniversalString(universal)));
+        assert_eq!(cert_common_name(&der).as_deref(), Some("bob"));
+
+        let der = der_with_common_name(Some(rcgen::DnValue::BmpString(vec![0x00])));
+        assert_eq!(cert_common_name(&der), None);
+    }
+
+    #[test]
+    fn test_cert_common_name_missing() {
+        assert_eq!(cert_common_name(&der_with_common_name(None)), None);
+    }
+}
@@ -0,0 +1,90 @@
+use std::{
+    collections::BTreeMap,
+    fs, io,
+    path::PathBuf,
+};
+
+use chrono::{DateTime, Utc};
+use darkrelayprotocol::protocol::UserInfo;
+use serde::{Deserialize, Serialize};
+
+/// One account as it survives a restart.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StoredUser {
+    pub user: UserInfo,
+    pub password_hash: String,
+    #[serde(default)]
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Accounts in one JSON file, keyed by normalized username. Like `RoleStore`
+/// the whole file is rewritten through a temporary file on each change;
+/// callers serialize through the `AuthService` lock.
+#[derive(Debug)]
+pub struct UserStore {
+    path: PathBuf,
+    users: BTreeMap<String, StoredUser>,
+}
+
+impl UserStore {
+    /// Load `path`, or start empty if it doesn't exist yet.
+    pub fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+        let path = path.into();
+        let users = match fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => return Err(e),
+        };
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        Ok(Self { path, users })
+    }
+
+    pub fn users(&self) -> impl Iterator<Item = (&String, &StoredUser)> {
+        self.users.iter()
+    }
+
+    pub fn put(&mut self, key: &str, stored: StoredUser) -> io::Result<()> {
+        self.users.insert(key.to_string(), stored);
+        self.save()
+    }
+
+    pub fn rename(&mut self, old: &str, new: &str, stored: StoredUser) -> io::Result<()> {
+        self.users.remove(old);
+        self.users.insert(new.to_string(), stored);
+        self.save()
+    }
+
+    /// Write whichever `last_seen` stamps moved, in one save.
+    pub fn record_last_seen(&mut self, seen: impl IntoIterator<Item = (String, DateTime<Utc>)>) -> io::Result<()> {
+        let mut changed = false;
+        for (key, at) in seen {
+            if let Some(stored) = self.users.get_mut(&key) {
+                if stored.last_seen != Some(at) {
+                    stored.last_seen = Some(at);
+                    changed = true;
+                }
+            }
+        }
+        if changed {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let tmp = self.path.with_extension("tmp");
+        let data = serde_json::to_vec_pretty(&self.users)?;
+        // Password hashes are in here, so keep the file to its owner.
+        #[cfg(unix)]
+        {
+            use std::{io::Write, os::unix::fs::OpenOptionsExt};
+            let mut file = fs::OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(&tmp)?;
+            file.write_all(&data)?;
+        }
+        #[cfg(not(unix))]
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, &self.path)
+    }
+}
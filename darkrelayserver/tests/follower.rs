@@ -0,0 +1,137 @@
+//! Runs the server binary and follows a channel with a headless read-only
+//! `Connection`, the way a bot or log collector would.
+
+use std::{
+    env, fs,
+    net::TcpListener,
+    path::PathBuf,
+    process::{Child, Command, Stdio},
+    time::{Duration, Instant},
+};
+
+use chrono::Utc;
+use darkrelayclient::connection::Connection;
+use darkrelayprotocol::{
+    metadata::MessageMetadata,
+    protocol::{ClientMessage, MessageMeta, ServerMessage},
+};
+
+const SPECIAL_KEY: &str = "follower-test-key";
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The server process and its working directory, both cleaned up on drop.
+struct Server {
+    child: Child,
+    dir: PathBuf,
+    addr: String,
+}
+
+impl Server {
+    fn start() -> Self {
+        let dir = env::temp_dir().join(format!("darkrelay-follower-test-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let port = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+        let addr = format!("127.0.0.1:{port}");
+        // Logs, the generated certificate and the roles and accounts files all land in `dir`.
+        let child = Command::new(env!("CARGO_BIN_EXE_darkrelayserver"))
+            .current_dir(&dir)
+            .env("DARKRELAY_LISTEN", &addr)
+            .env("DARKRELAY_SPECIAL_KEY", SPECIAL_KEY)
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .unwrap();
+        Self { child, dir, addr }
+    }
+}
+
+impl Drop for Server {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+fn meta() -> MessageMeta {
+    MessageMeta::new(0, Utc::now())
+}
+
+/// Skip everything until a message `want` accepts.
+async fn recv_until<T>(conn: &mut Connection, mut want: impl FnMut(ServerMessage) -> Option<T>) -> T {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let msg = tokio::time::timeout(remaining, conn.recv())
+            .await
+            .expect("timed out waiting for the server")
+            .unwrap()
+            .expect("server closed the connection");
+        if let Some(found) = want(msg) {
+            return found;
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_read_only_connection_follows_a_channel() {
+    let server = Server::start();
+
+    // The server generates its certificate before it listens.
+    let deadline = Instant::now() + TIMEOUT;
+    let mut follower = loop {
+        match Connection::connect_read_only(&server.addr, TIMEOUT, None, SPECIAL_KEY).await {
+            Ok(conn) => break conn,
+            Err(e) if Instant::now() < deadline => {
+                eprintln!("server not up yet: {e}");
+                tokio::time::sleep(Duration::from_millis(100)).await;
+            }
+            Err(e) => panic!("server never came up: {e}"),
+        }
+    };
+    follower
+        .send_wait(ClientMessage::JoinChannel { meta: meta(), name: "general".to_string(), password: None })
+        .await
+        .unwrap();
+    recv_until(&mut follower, |msg| matches!(msg, ServerMessage::JoinSuccess { .. }).then_some(())).await;
+
+    // A registered user posts without setting up ECDH, so the content is plaintext.
+    let mut alice = Connection::connect(&server.addr, TIMEOUT, None).await.unwrap();
+    alice.send_wait(ClientMessage::Auth { meta: meta(), key: SPECIAL_KEY.to_string() }).await.unwrap();
+    alice
+        .send_wait(ClientMessage::RegisterUser {
+            meta: meta(),
+            username: "alice".to_string(),
+            password: None,
+            signing_key: None,
+            public_key: None,
+        })
+        .await
+        .unwrap();
+    recv_until(&mut alice, |msg| matches!(msg, ServerMessage::AuthSuccess { .. }).then_some(())).await;
+    alice
+        .send_wait(ClientMessage::JoinChannel { meta: meta(), name: "general".to_string(), password: None })
+        .await
+        .unwrap();
+    recv_until(&mut alice, |msg| matches!(msg, ServerMessage::JoinSuccess { .. }).then_some(())).await;
+    alice
+        .send_wait(ClientMessage::SendMessage {
+            meta: meta(),
+            channel: "general".to_string(),
+            content: b"hello followers".to_vec(),
+            metadata: MessageMetadata::new(),
+        })
+        .await
+        .unwrap();
+
+    let (channel, message) = recv_until(&mut follower, |msg| match msg {
+        ServerMessage::MessageReceived { channel, message, .. } => Some((channel, message)),
+        _ => None,
+    })
+    .await;
+    assert_eq!(channel, "general");
+    assert_eq!(message.username, "alice");
+    assert_eq!(message.content, b"hello followers");
+}